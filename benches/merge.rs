@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tempfile::TempDir;
+use torrent_combine::merger::{MergeOptions, SanityOutcome, check_sanity_and_completes};
+
+const STRIPE: usize = 64 * 1024;
+
+// Member `i` holds the stripes whose index is `i` modulo the member count, so
+// every member is incomplete and together they cover the whole file.
+fn build_group(members: usize, size: usize) -> (TempDir, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+    let paths = (0..members)
+        .map(|m| {
+            let mut partial = data.clone();
+            for (stripe, chunk) in partial.chunks_mut(STRIPE).enumerate() {
+                if stripe % members != m {
+                    chunk.fill(0);
+                }
+            }
+            let path = dir.path().join(format!("member-{}", m));
+            fs::write(&path, &partial).unwrap();
+            path
+        })
+        .collect();
+    (dir, paths)
+}
+
+// Every member has the whole file except one small hole per MiB, each at a
+// different offset, so most blocks already match the OR.
+fn build_dense_group(members: usize, size: usize) -> (TempDir, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+    let paths = (0..members)
+        .map(|m| {
+            let mut partial = data.clone();
+            for chunk in partial.chunks_mut(1 << 20) {
+                let len = chunk.len();
+                let hole = (m * 8192) % len;
+                chunk[hole..(hole + 512).min(len)].fill(0);
+            }
+            let path = dir.path().join(format!("member-{}", m));
+            fs::write(&path, &partial).unwrap();
+            path
+        })
+        .collect();
+    (dir, paths)
+}
+
+fn bench_check_sanity_and_completes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_sanity_and_completes");
+    group.sample_size(10);
+    for size in [1 << 20, 64 << 20] {
+        for members in [2, 4, 8, 16] {
+            let (_dir, paths) = build_group(members, size);
+            let mtimes = vec![SystemTime::UNIX_EPOCH; members];
+            group.throughput(Throughput::Bytes((size * members) as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}MiB", size >> 20), members),
+                &paths,
+                |b, paths| {
+                    b.iter(|| {
+                        let outcome = check_sanity_and_completes(
+                            paths,
+                            None,
+                            &mtimes,
+                            &MergeOptions::default(),
+                        )
+                        .unwrap();
+                        assert!(matches!(outcome, SanityOutcome::Passed { .. }));
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_dense_members(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_sanity_dense_members");
+    group.sample_size(10);
+    let size = 64 << 20;
+    for members in [2, 4] {
+        let (_dir, paths) = build_dense_group(members, size);
+        let mtimes = vec![SystemTime::UNIX_EPOCH; members];
+        group.throughput(Throughput::Bytes((size * members) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(members), &paths, |b, paths| {
+            b.iter(|| {
+                let outcome =
+                    check_sanity_and_completes(paths, None, &mtimes, &MergeOptions::default())
+                        .unwrap();
+                assert!(matches!(outcome, SanityOutcome::Passed { .. }));
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_check_sanity_and_completes,
+    bench_dense_members
+);
+criterion_main!(benches);
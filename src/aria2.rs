@@ -0,0 +1,297 @@
+//! aria2 `.aria2` control files, which record the pieces of a download that
+//! aria2 has verified. A member with one next to it is judged by its pieces
+//! instead of by which of its bytes are zero.
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Extension aria2 appends to a download's name for its control file.
+pub const CONTROL_SUFFIX: &str = ".aria2";
+
+/// What a control file says about one piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    /// Not downloaded: whatever the member holds there is missing data.
+    Missing,
+    /// Being downloaded when aria2 stopped; only some blocks may be written.
+    Partial,
+    /// Downloaded and verified, zeros included.
+    Complete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFile {
+    pub piece_length: u64,
+    pub total_length: u64,
+    // One bit per piece, most significant bit first.
+    bitfield: Vec<u8>,
+    in_flight: Vec<u64>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid aria2 control file: {}", msg),
+    )
+}
+
+// Reads the integers of a control file: big-endian for version 1, host
+// (assumed little-endian) order for version 0.
+struct Fields<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid("truncated"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.bytes(4)?.try_into().expect("4 bytes");
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let bytes = self.bytes(8)?.try_into().expect("8 bytes");
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+}
+
+/// The control file aria2 keeps for the download at `member`.
+pub fn control_path(member: &Path) -> PathBuf {
+    let mut path = member.as_os_str().to_os_string();
+    path.push(CONTROL_SUFFIX);
+    PathBuf::from(path)
+}
+
+impl ControlFile {
+    /// Reads the control file next to `member`, if there is one.
+    pub fn load(member: &Path) -> io::Result<Option<ControlFile>> {
+        match fs::read(control_path(member)) {
+            Ok(data) => ControlFile::parse(&data).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<ControlFile> {
+        let mut fields = Fields {
+            data,
+            big_endian: false,
+        };
+        fields.big_endian = match fields.bytes(2)? {
+            [0, 0] => false,
+            [0, 1] => true,
+            _ => return Err(invalid("unknown version")),
+        };
+        let _extension = fields.u32()?;
+        let info_hash_length = fields.u32()? as usize;
+        fields.bytes(info_hash_length)?;
+        let piece_length = fields.u32()? as u64;
+        if piece_length == 0 {
+            return Err(invalid("zero piece length"));
+        }
+        let total_length = fields.u64()?;
+        let _upload_length = fields.u64()?;
+        let bitfield_length = fields.u32()? as usize;
+        if bitfield_length as u64 != total_length.div_ceil(piece_length).div_ceil(8) {
+            return Err(invalid("bitfield does not match the piece count"));
+        }
+        let bitfield = fields.bytes(bitfield_length)?.to_vec();
+        let mut in_flight = Vec::new();
+        for _ in 0..fields.u32()? {
+            in_flight.push(fields.u32()? as u64);
+            let _piece_length = fields.u32()?;
+            let block_bitfield_length = fields.u32()? as usize;
+            fields.bytes(block_bitfield_length)?;
+        }
+        Ok(ControlFile {
+            piece_length,
+            total_length,
+            bitfield,
+            in_flight,
+        })
+    }
+
+    pub fn piece_state(&self, index: u64) -> PieceState {
+        let byte = self.bitfield.get((index / 8) as usize).copied();
+        if byte.is_some_and(|b| b & (0x80 >> (index % 8)) != 0) {
+            PieceState::Complete
+        } else if self.in_flight.contains(&index) {
+            PieceState::Partial
+        } else {
+            PieceState::Missing
+        }
+    }
+
+    /// The pieces overlapping `len` bytes at `offset`, as ranges relative to
+    /// `offset` with the state of their piece.
+    pub fn pieces_in(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (Range<usize>, PieceState)> + '_ {
+        let end = offset + len as u64;
+        let first = offset / self.piece_length;
+        (first..end.div_ceil(self.piece_length)).map(move |index| {
+            let start = (index * self.piece_length).max(offset);
+            let stop = ((index + 1) * self.piece_length).min(end);
+            (
+                (start - offset) as usize..(stop - offset) as usize,
+                self.piece_state(index),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merger::{
+        GroupStatus, MergeOptions, ProbeVerdict, StagingRecovery, probe_group, process_group,
+        recover_staging, recoverable_bytes, staging_path,
+    };
+    use tempfile::tempdir;
+
+    // A version 1 control file with `pieces` marking the downloaded pieces.
+    fn control_file(
+        piece_length: u32,
+        total_length: u64,
+        pieces: &[bool],
+        in_flight: &[u32],
+    ) -> Vec<u8> {
+        let mut data = vec![0, 1];
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&20u32.to_be_bytes());
+        data.extend_from_slice(&[0xab; 20]);
+        data.extend_from_slice(&piece_length.to_be_bytes());
+        data.extend_from_slice(&total_length.to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        let mut bitfield = vec![0u8; pieces.len().div_ceil(8)];
+        for (i, _) in pieces.iter().enumerate().filter(|&(_, &have)| have) {
+            bitfield[i / 8] |= 0x80 >> (i % 8);
+        }
+        data.extend_from_slice(&(bitfield.len() as u32).to_be_bytes());
+        data.extend_from_slice(&bitfield);
+        data.extend_from_slice(&(in_flight.len() as u32).to_be_bytes());
+        for &index in in_flight {
+            data.extend_from_slice(&index.to_be_bytes());
+            data.extend_from_slice(&piece_length.to_be_bytes());
+            data.extend_from_slice(&1u32.to_be_bytes());
+            data.push(0x80);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_control_file() -> io::Result<()> {
+        let data = control_file(16, 40, &[true, false, false], &[2]);
+        let control = ControlFile::parse(&data)?;
+        assert_eq!(control.piece_length, 16);
+        assert_eq!(control.total_length, 40);
+        assert_eq!(control.piece_state(0), PieceState::Complete);
+        assert_eq!(control.piece_state(1), PieceState::Missing);
+        assert_eq!(control.piece_state(2), PieceState::Partial);
+        assert_eq!(
+            control.pieces_in(10, 20).collect::<Vec<_>>(),
+            vec![(0..6, PieceState::Complete), (6..20, PieceState::Missing)]
+        );
+
+        assert!(ControlFile::parse(&data[..data.len() - 2]).is_err());
+        assert!(ControlFile::parse(&control_file(16, 200, &[true], &[])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_file_classifies_real_zeros() -> io::Result<()> {
+        let dir = tempdir()?;
+        // Piece 0 of `a` is verified and really holds zeros; piece 1 was
+        // never downloaded but holds leftover junk. `b` has only piece 1.
+        let a = dir.path().join("a");
+        let mut data_a = vec![7u8; 32];
+        data_a[4..12].fill(0);
+        data_a[16..].fill(0xee);
+        fs::write(&a, &data_a)?;
+        let b = dir.path().join("b");
+        let mut data_b = vec![0u8; 32];
+        data_b[16..].fill(9);
+        fs::write(&b, &data_b)?;
+        let paths = vec![a.clone(), b.clone()];
+
+        // By zeros alone the junk conflicts with `b`'s data.
+        let options = MergeOptions::default();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::LikelyConflict
+        );
+        assert_eq!(recoverable_bytes(&paths, None, &options)?, None);
+
+        fs::write(control_path(&a), control_file(16, 32, &[true, false], &[]))?;
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::LikelyRecoverable
+        );
+        assert_eq!(recoverable_bytes(&paths, None, &options)?, Some(24));
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        let mut expected = data_a.clone();
+        expected[16..].fill(9);
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, expected);
+        assert_eq!(fs::read(dir.path().join("b.merged"))?, expected);
+
+        // A staged replacement is not mistaken for a conflict either.
+        fs::write(staging_path(&a), &expected)?;
+        assert_eq!(
+            recover_staging(dir.path())?,
+            vec![(a.clone(), StagingRecovery::Completed)]
+        );
+        assert_eq!(fs::read(&a)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verified_pieces_that_disagree_conflict() -> io::Result<()> {
+        let dir = tempdir()?;
+        // Both claim piece 0, but only `a` holds zeros in it.
+        let a = dir.path().join("a");
+        let mut data_a = vec![7u8; 32];
+        data_a[4..12].fill(0);
+        data_a[16..].fill(0);
+        fs::write(&a, &data_a)?;
+        fs::write(control_path(&a), control_file(16, 32, &[true, false], &[]))?;
+        let b = dir.path().join("b");
+        let mut data_b = vec![7u8; 32];
+        data_b[16..].fill(0);
+        fs::write(&b, &data_b)?;
+        fs::write(control_path(&b), control_file(16, 32, &[true, false], &[]))?;
+        let paths = vec![a, b];
+
+        let options = MergeOptions::default();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        assert_eq!(stats.first_conflict, Some(4));
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::LikelyConflict
+        );
+        Ok(())
+    }
+}
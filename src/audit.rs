@@ -0,0 +1,71 @@
+//! Append-only journal of the file operations that write each output,
+//! synced after every entry so it shows what was in flight after a crash.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::report::json_escape;
+
+/// One step of writing an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// About to overwrite a member in place.
+    IntendReplace,
+    /// About to create a new output.
+    IntendWrite,
+    /// The data is complete in the temp file; replacements also synced it.
+    WroteTemp,
+    /// The temp file was renamed over the target.
+    Renamed,
+}
+
+impl AuditEvent {
+    pub fn label(self) -> &'static str {
+        match self {
+            AuditEvent::IntendReplace => "intend-replace",
+            AuditEvent::IntendWrite => "intend-write",
+            AuditEvent::WroteTemp => "wrote-temp",
+            AuditEvent::Renamed => "renamed",
+        }
+    }
+}
+
+/// Journal entries of one group, appended to the file at `path`. Without a
+/// path recording does nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct AuditLog<'a> {
+    path: Option<&'a Path>,
+    group: &'a str,
+}
+
+impl<'a> AuditLog<'a> {
+    pub fn new(path: Option<&'a Path>, group: &'a str) -> Self {
+        AuditLog { path, group }
+    }
+
+    /// Appends `event` for `target`, whose data is staged in `temp`, as one
+    /// JSON line and syncs the journal before returning. Each line is a
+    /// single append, so concurrent groups don't interleave within a line.
+    pub fn record(&self, event: AuditEvent, target: &Path, temp: &Path) -> io::Result<()> {
+        let Some(path) = self.path else {
+            return Ok(());
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = format!(
+            "{{\"time\":{:.6},\"group\":{},\"event\":\"{}\",\"target\":{},\"temp\":{}}}\n",
+            time,
+            json_escape(self.group),
+            event.label(),
+            json_escape(&target.to_string_lossy()),
+            json_escape(&temp.to_string_lossy()),
+        );
+        let mut journal = OpenOptions::new().create(true).append(true).open(path)?;
+        journal.write_all(line.as_bytes())?;
+        journal.sync_data()
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(msg: &str, pos: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid bencode at byte {}: {}", pos, msg),
+    )
+}
+
+/// Deepest nesting of lists and dictionaries `parse` accepts. Torrents need a
+/// handful of levels; the limit keeps hostile input from exhausting the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// Parses a single bencoded value that must span all of `data`.
+pub fn parse(data: &[u8]) -> io::Result<Value> {
+    let mut pos = 0;
+    let value = parse_value(data, &mut pos, 0)?;
+    if pos != data.len() {
+        return Err(invalid("trailing data", pos));
+    }
+    Ok(value)
+}
+
+fn parse_value(data: &[u8], pos: &mut usize, depth: usize) -> io::Result<Value> {
+    if depth >= MAX_DEPTH && matches!(data.get(*pos), Some(b'l' | b'd')) {
+        return Err(invalid("nested too deeply", *pos));
+    }
+    match data.get(*pos) {
+        Some(b'i') => {
+            *pos += 1;
+            let digits = take_until(data, pos, b'e')?;
+            let text = std::str::from_utf8(digits).map_err(|_| invalid("bad integer", *pos))?;
+            text.parse()
+                .map(Value::Int)
+                .map_err(|_| invalid("bad integer", *pos))
+        }
+        Some(b'l') => {
+            *pos += 1;
+            let mut list = Vec::new();
+            while data.get(*pos) != Some(&b'e') {
+                list.push(parse_value(data, pos, depth + 1)?);
+            }
+            *pos += 1;
+            Ok(Value::List(list))
+        }
+        Some(b'd') => {
+            *pos += 1;
+            let mut dict = BTreeMap::new();
+            while data.get(*pos) != Some(&b'e') {
+                let key = parse_bytes(data, pos)?;
+                let value = parse_value(data, pos, depth + 1)?;
+                dict.insert(key, value);
+            }
+            *pos += 1;
+            Ok(Value::Dict(dict))
+        }
+        Some(b'0'..=b'9') => parse_bytes(data, pos).map(Value::Bytes),
+        Some(_) => Err(invalid("unexpected byte", *pos)),
+        None => Err(invalid("unexpected end", *pos)),
+    }
+}
+
+fn parse_bytes(data: &[u8], pos: &mut usize) -> io::Result<Vec<u8>> {
+    let digits = take_until(data, pos, b':')?;
+    let len: usize = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| invalid("bad string length", *pos))?;
+    let end = pos
+        .checked_add(len)
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| invalid("string past end", *pos))?;
+    let bytes = data[*pos..end].to_vec();
+    *pos = end;
+    Ok(bytes)
+}
+
+// Returns the bytes up to `delimiter` and moves past it.
+fn take_until<'a>(data: &'a [u8], pos: &mut usize, delimiter: u8) -> io::Result<&'a [u8]> {
+    let start = *pos;
+    let len = data[start..]
+        .iter()
+        .position(|&b| b == delimiter)
+        .ok_or_else(|| invalid("unterminated token", start))?;
+    *pos = start + len + 1;
+    Ok(&data[start..start + len])
+}
+
+/// Encodes `value`; dictionary keys come out sorted as bencode requires.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+        Value::Bytes(b) => {
+            out.extend_from_slice(format!("{}:", b.len()).as_bytes());
+            out.extend_from_slice(b);
+        }
+        Value::List(list) => {
+            out.push(b'l');
+            for v in list {
+                encode_into(v, out);
+            }
+            out.push(b'e');
+        }
+        Value::Dict(dict) => {
+            out.push(b'd');
+            for (k, v) in dict {
+                out.extend_from_slice(format!("{}:", k.len()).as_bytes());
+                out.extend_from_slice(k);
+                encode_into(v, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_encode() -> io::Result<()> {
+        let data = b"d4:infod6:lengthi42e4:name5:x.mkve4:listli-3e0:ee";
+        let value = parse(data)?;
+        let info = value.get("info").unwrap();
+        assert_eq!(info.get("length").and_then(Value::as_int), Some(42));
+        assert_eq!(
+            info.get("name").and_then(Value::as_bytes),
+            Some(&b"x.mkv"[..])
+        );
+        assert_eq!(
+            value.get("list").and_then(Value::as_list),
+            Some(&[Value::Int(-3), Value::Bytes(Vec::new())][..])
+        );
+        assert_eq!(encode(&value), data);
+
+        for bad in [&b"i12"[..], b"5:abc", b"d1:ae", b"i1ei2e", b"x"] {
+            assert!(parse(bad).is_err(), "{:?} should not parse", bad);
+        }
+
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        let err = parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Far deeper than any stack could recurse.
+        assert!(parse(&nested(1 << 20)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Naming conventions torrent clients use for downloads still in progress.
+
+/// Torrent clients whose incomplete-file naming is understood. A profile only
+/// changes the names members are grouped by: missing data is taken to be
+/// zeros whatever the client, and no client's resume data is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Client {
+    #[value(name = "utorrent")]
+    UTorrent,
+    #[value(name = "bitcomet")]
+    BitComet,
+    #[value(name = "qbittorrent")]
+    QBittorrent,
+    #[value(name = "rtorrent")]
+    RTorrent,
+    Transmission,
+}
+
+impl Client {
+    /// Suffixes the client appends to files that are still downloading.
+    pub fn incomplete_suffixes(self) -> &'static [&'static str] {
+        match self {
+            Client::UTorrent => &[".!ut"],
+            Client::BitComet => &[".bc!"],
+            Client::QBittorrent => &[".!qB"],
+            Client::RTorrent => &[],
+            Client::Transmission => &[".part"],
+        }
+    }
+
+    /// Returns `name` without the client's incomplete suffix, so a partial
+    /// `video.mkv.!ut` groups with a finished `video.mkv`.
+    pub fn strip_incomplete_suffix(self, name: &str) -> &str {
+        self.incomplete_suffixes()
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+            .filter(|stripped| !stripped.is_empty())
+            .unwrap_or(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_incomplete_suffix() {
+        assert_eq!(
+            Client::UTorrent.strip_incomplete_suffix("video.mkv.!ut"),
+            "video.mkv"
+        );
+        assert_eq!(
+            Client::UTorrent.strip_incomplete_suffix("video.mkv"),
+            "video.mkv"
+        );
+        assert_eq!(
+            Client::BitComet.strip_incomplete_suffix("video.mkv.bc!"),
+            "video.mkv"
+        );
+        assert_eq!(
+            Client::QBittorrent.strip_incomplete_suffix("video.mkv.!qB"),
+            "video.mkv"
+        );
+        assert_eq!(
+            Client::Transmission.strip_incomplete_suffix("video.mkv.!ut"),
+            "video.mkv.!ut"
+        );
+        assert_eq!(Client::UTorrent.strip_incomplete_suffix(".!ut"), ".!ut");
+    }
+}
@@ -0,0 +1,446 @@
+//! Config files: the long options of the command line as TOML keys, turned
+//! back into arguments that go before the real ones so the command line
+//! wins. Only the subset of TOML needed for that is understood: top-level
+//! `key = value` pairs with strings, integers, floats, booleans and arrays.
+
+use std::any::TypeId;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{ArgAction, Command};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    fn render(&self) -> io::Result<String> {
+        match self {
+            ConfigValue::Bool(b) => Ok(b.to_string()),
+            ConfigValue::Int(i) => Ok(i.to_string()),
+            ConfigValue::Float(f) => Ok(f.to_string()),
+            ConfigValue::String(s) => Ok(s.clone()),
+            ConfigValue::Array(_) => Err(invalid("nested arrays are not supported", 0)),
+        }
+    }
+}
+
+fn invalid(msg: &str, line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid config at line {}: {}", line, msg),
+    )
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    // Skips spaces and tabs, and with `newlines` also line breaks and
+    // comments, as between the elements of an array.
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => {}
+                '\r' | '\n' if newlines => {}
+                '#' if newlines => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.next();
+                    }
+                    continue;
+                }
+                _ => break,
+            }
+            self.next();
+        }
+    }
+
+    // Consumes the rest of a line after a value: blanks and a comment.
+    fn end_of_line(&mut self) -> io::Result<()> {
+        self.skip_blank(false);
+        if self.peek() == Some('#') {
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.next();
+            }
+        }
+        if self.peek() == Some('\r') {
+            self.next();
+        }
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(invalid(&format!("unexpected {:?}", c), self.line)),
+        }
+    }
+
+    fn key(&mut self) -> io::Result<String> {
+        let mut key = String::new();
+        while let Some(c) = self.peek()
+            && (c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            key.push(c);
+            self.next();
+        }
+        match self.peek() {
+            _ if key.is_empty() => Err(invalid("expected a key", self.line)),
+            Some('.') => Err(invalid("dotted keys are not supported", self.line)),
+            _ => Ok(key),
+        }
+    }
+
+    fn value(&mut self) -> io::Result<ConfigValue> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(ConfigValue::String),
+            Some('\'') => {
+                self.next();
+                let mut s = String::new();
+                loop {
+                    match self.next() {
+                        Some('\'') => return Ok(ConfigValue::String(s)),
+                        Some('\n') | None => {
+                            return Err(invalid("unterminated string", self.line));
+                        }
+                        Some(c) => s.push(c),
+                    }
+                }
+            }
+            Some('[') => {
+                self.next();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank(true);
+                    if self.peek() == Some(']') {
+                        self.next();
+                        return Ok(ConfigValue::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank(true);
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(ConfigValue::Array(items)),
+                        _ => return Err(invalid("expected , or ] in array", self.line)),
+                    }
+                }
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(c) = self.peek()
+                    && (c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_'))
+                {
+                    token.push(c);
+                    self.next();
+                }
+                let number = token.replace('_', "");
+                match token.as_str() {
+                    "true" => Ok(ConfigValue::Bool(true)),
+                    "false" => Ok(ConfigValue::Bool(false)),
+                    _ => number
+                        .parse()
+                        .map(ConfigValue::Int)
+                        .or_else(|_| number.parse().map(ConfigValue::Float))
+                        .map_err(|_| invalid(&format!("bad value {:?}", token), self.line)),
+                }
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> io::Result<String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(match self.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    _ => return Err(invalid("unsupported escape", self.line)),
+                }),
+                Some('\n') | None => return Err(invalid("unterminated string", self.line)),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+/// Parses the `key = value` pairs of a config file in order. Tables,
+/// dotted keys and repeated keys are rejected.
+pub fn parse_config(text: &str) -> io::Result<Vec<(String, ConfigValue)>> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut seen = BTreeSet::new();
+    let mut entries = Vec::new();
+    loop {
+        parser.skip_blank(true);
+        match parser.peek() {
+            None => return Ok(entries),
+            Some('[') => return Err(invalid("tables are not supported", parser.line)),
+            _ => {}
+        }
+        let line = parser.line;
+        let key = parser.key()?;
+        parser.skip_blank(false);
+        if parser.next() != Some('=') {
+            return Err(invalid("expected =", line));
+        }
+        parser.skip_blank(false);
+        let value = parser.value()?;
+        parser.end_of_line()?;
+        if !seen.insert(key.clone()) {
+            return Err(invalid(&format!("{} is set twice", key), line));
+        }
+        entries.push((key, value));
+    }
+}
+
+/// Turns config entries into the arguments of `command`. Keys name long
+/// options, with `_` or `-` between words. `true` gives a flag and `false`
+/// leaves it out; arrays repeat the option, or pass every value at once to
+/// options that take several. Relative paths are taken from `base`, the
+/// directory holding the config file, rather than the working directory.
+pub fn config_args(
+    entries: &[(String, ConfigValue)],
+    command: &Command,
+    base: &Path,
+) -> io::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in entries {
+        let long = key.replace('_', "-");
+        let unknown = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown config key {:?}", key),
+            )
+        };
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .ok_or_else(unknown)?;
+        let option = format!("--{}", long);
+        let is_path = arg.get_value_parser().type_id() == TypeId::of::<PathBuf>();
+        let render = |item: &ConfigValue| -> io::Result<OsString> {
+            let rendered = item.render()?;
+            if is_path {
+                Ok(base.join(rendered).into_os_string())
+            } else {
+                Ok(rendered.into())
+            }
+        };
+        let with_option = |item: &ConfigValue| -> io::Result<OsString> {
+            let mut joined = OsString::from(format!("{}=", option));
+            joined.push(render(item)?);
+            Ok(joined)
+        };
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, ConfigValue::Bool(set)) => {
+                if *set {
+                    args.push(option.into());
+                }
+            }
+            (ArgAction::SetTrue, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Config key {:?} takes true or false", key),
+                ));
+            }
+            (_, ConfigValue::Array(items)) => {
+                let together = arg
+                    .get_num_args()
+                    .is_some_and(|range| range.max_values() > 1);
+                if together {
+                    args.push(option.into());
+                    for item in items {
+                        args.push(render(item)?);
+                    }
+                } else {
+                    for item in items {
+                        args.push(with_option(item)?);
+                    }
+                }
+            }
+            (_, value) => args.push(with_option(value)?),
+        }
+    }
+    Ok(args)
+}
+
+/// Whether `args`, up to any `--`, give the option `key` names, by its long
+/// name or its short one. Short flags may be bundled as in `-yv`.
+pub fn given(args: &[OsString], key: &str, command: &Command) -> bool {
+    let long = key.replace('_', "-");
+    let Some(wanted) = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()))
+    else {
+        return false;
+    };
+    let option = format!("--{}", long);
+    let takes_value = |short: char| {
+        command
+            .get_arguments()
+            .find(|arg| arg.get_short() == Some(short))
+            .is_some_and(|arg| !matches!(arg.get_action(), ArgAction::SetTrue))
+    };
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| {
+            if let Some(tail) = arg.strip_prefix(option.as_str()) {
+                return tail.is_empty() || tail.starts_with('=');
+            }
+            let (Some(short), Some(bundle)) = (wanted.get_short(), arg_shorts(arg)) else {
+                return false;
+            };
+            for c in bundle.chars() {
+                if c == short {
+                    return true;
+                }
+                // The rest of the word is this option's value.
+                if takes_value(c) {
+                    return false;
+                }
+            }
+            false
+        })
+}
+
+// The letters of a word of short options, e.g. `yv` of `-yv`.
+fn arg_shorts(word: &str) -> Option<&str> {
+    word.strip_prefix('-')
+        .filter(|rest| !rest.is_empty() && !rest.starts_with('-'))
+}
+
+/// Rewrites `--flag=true` and `--flag=false` for the flags of `command`
+/// into `--flag` or nothing, so the command line can turn off a flag the
+/// config file sets.
+pub fn flag_values(args: Vec<OsString>, command: &Command) -> Vec<OsString> {
+    let mut done = false;
+    args.into_iter()
+        .filter_map(|word| {
+            done |= word == "--";
+            let flag = word
+                .to_str()
+                .filter(|_| !done)
+                .and_then(|w| w.strip_prefix("--"))
+                .and_then(|w| w.split_once('='))
+                .filter(|(long, _)| {
+                    command.get_arguments().any(|arg| {
+                        arg.get_long() == Some(*long)
+                            && matches!(arg.get_action(), ArgAction::SetTrue)
+                    })
+                });
+            match flag {
+                Some((long, "true")) => Some(format!("--{}", long).into()),
+                Some((_, "false")) => None,
+                _ => Some(word),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> io::Result<()> {
+        let text = "# settings\nreplace = true\nmin-chunk-size = 65_536 # bytes\n\
+                    dedup_mode = \"size-only\"\nratio = 1.5\ntemp-prefix = 'x\\y'\n\
+                    include-ext = [\n  \"mkv\", # video\n  \"mp4\",\n]\n";
+        assert_eq!(
+            parse_config(text)?,
+            vec![
+                ("replace".to_string(), ConfigValue::Bool(true)),
+                ("min-chunk-size".to_string(), ConfigValue::Int(65536)),
+                (
+                    "dedup_mode".to_string(),
+                    ConfigValue::String("size-only".to_string())
+                ),
+                ("ratio".to_string(), ConfigValue::Float(1.5)),
+                (
+                    "temp-prefix".to_string(),
+                    ConfigValue::String("x\\y".to_string())
+                ),
+                (
+                    "include-ext".to_string(),
+                    ConfigValue::Array(vec![
+                        ConfigValue::String("mkv".to_string()),
+                        ConfigValue::String("mp4".to_string()),
+                    ])
+                ),
+            ]
+        );
+
+        assert!(parse_config("[merge]\nreplace = true\n").is_err());
+        assert!(parse_config("a = 1\na = 2\n").is_err());
+        assert!(parse_config("a = \"open\n").is_err());
+        assert!(parse_config("a = 1 2\n").is_err());
+        assert!(parse_config("a.b = 1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_line_options_given() {
+        let command = Command::new("t")
+            .arg(
+                clap::Arg::new("yes")
+                    .long("assume-yes")
+                    .short('y')
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                clap::Arg::new("verbose")
+                    .long("verbose")
+                    .short('v')
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(clap::Arg::new("out").long("output").short('o'));
+        let args = |words: &[&str]| -> Vec<OsString> { words.iter().map(OsString::from).collect() };
+
+        assert!(given(&args(&["--assume-yes"]), "assume_yes", &command));
+        assert!(given(&args(&["-y"]), "assume-yes", &command));
+        assert!(given(&args(&["-vy"]), "assume-yes", &command));
+        assert!(given(&args(&["--output=x"]), "output", &command));
+        assert!(!given(&args(&["-oy"]), "assume-yes", &command));
+        assert!(!given(&args(&["--", "-y"]), "assume-yes", &command));
+        assert!(!given(&args(&["--assume-yes-not"]), "assume-yes", &command));
+
+        assert_eq!(
+            flag_values(
+                args(&[
+                    "--verbose=true",
+                    "--assume-yes=false",
+                    "--output=false",
+                    "--",
+                    "--verbose=false"
+                ]),
+                &command
+            ),
+            args(&["--verbose", "--output=false", "--", "--verbose=false"])
+        );
+    }
+}
@@ -0,0 +1,1288 @@
+//! Finding candidate members and grouping the copies of each download.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, BufRead, Read};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use crate::client::Client;
+use crate::ignore::{self, IgnoreRules};
+use crate::merger;
+use crate::torrent::{TorrentIndex, TorrentVersion};
+
+/// What makes two files copies of the same download.
+#[derive(Debug, Clone, Default, ValueEnum)]
+pub enum DedupKey {
+    #[default]
+    #[value(name = "filename-and-size")]
+    FilenameAndSize,
+    #[value(name = "size-only")]
+    SizeOnly,
+    /// Size plus the lowercased extension, so renamed files of one type group
+    #[value(name = "extension-and-size")]
+    ExtensionAndSize,
+    /// Size plus the piece length and first piece hash of the matching `.torrent`
+    #[value(name = "size-and-piece-length")]
+    SizeAndPieceLength,
+    /// Size plus a hash of the non-zero bytes in the first --head-bytes
+    #[value(name = "size-and-head-hash")]
+    SizeAndHeadHash,
+    /// Size plus a hash of the first piece-length block that isn't all zeros
+    #[value(name = "size-and-first-piece-hash")]
+    SizeAndFirstPieceHash,
+    /// Size plus the modification time rounded down to a multiple of --mtime-window
+    #[value(name = "size-and-mtime-window")]
+    SizeAndMtimeWindow,
+    /// Device and inode, to report hardlinked paths; nothing is merged
+    #[cfg(unix)]
+    #[value(name = "inode")]
+    Inode,
+}
+
+/// Order of the members within each group. The first member seeds the merge
+/// and wins some tie-breaks, so `Stable` makes runs reproducible.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InputOrder {
+    /// The order the filesystem or `--paths-from` list produced.
+    #[default]
+    Fs,
+    /// Sorted by path.
+    Stable,
+}
+
+/// Default for `--head-bytes`.
+pub const DEFAULT_HEAD_BYTES: u64 = 1 << 20;
+
+/// Default for `--mtime-window`.
+pub const DEFAULT_MTIME_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Parameters of the grouping modes that look past name and size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySettings {
+    /// Bytes hashed by `SizeAndHeadHash`.
+    pub head_bytes: u64,
+    /// Bucket width of `SizeAndMtimeWindow`.
+    pub mtime_window: Duration,
+    /// Block size of `SizeAndFirstPieceHash`; a file's matching `.torrent`
+    /// supplies it when unset.
+    pub piece_length: Option<u64>,
+}
+
+impl Default for KeySettings {
+    fn default() -> Self {
+        KeySettings {
+            head_bytes: DEFAULT_HEAD_BYTES,
+            mtime_window: DEFAULT_MTIME_WINDOW,
+            piece_length: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GroupKey {
+    FilenameAndSize(String, u64),
+    SizeOnly(u64),
+    /// Lowercased extension, empty for files without one, and size.
+    ExtensionAndSize(String, u64),
+    /// Size and torrent version. Files no `.torrent` describes are keyed
+    /// by `FilenameAndSize` instead.
+    SizeAndPieceLength(u64, TorrentVersion),
+    SizeAndHeadHash(u64, u64),
+    /// Size, piece length, and the index and hash of the first block that
+    /// isn't all zeros; `None` for files of only zeros.
+    SizeAndFirstPiece(u64, u64, Option<(u64, u64)>),
+    /// Size and the modification time divided by the mtime window.
+    SizeAndMtimeBucket(u64, u64),
+    /// Size, device and inode of paths hardlinked to the same file.
+    #[cfg(unix)]
+    Inode(u64, u64, u64),
+    WithinDir(PathBuf, Box<GroupKey>),
+    /// One of several content-prefix clusters split from a size-only group.
+    Cluster(Box<GroupKey>, usize),
+}
+
+impl GroupKey {
+    /// Name used for the group in logs, reports and `--group`.
+    pub fn name(&self) -> String {
+        match self {
+            GroupKey::FilenameAndSize(basename, size) => format!("{}@{}", basename, size),
+            GroupKey::SizeOnly(size) => format!("size-{}", size),
+            GroupKey::ExtensionAndSize(ext, size) if ext.is_empty() => format!("*@{}", size),
+            GroupKey::ExtensionAndSize(ext, size) => format!("*.{}@{}", ext, size),
+            GroupKey::SizeAndPieceLength(size, version) => {
+                let mut name = format!("size-{}-piece-{}", size, version.piece_length);
+                if let Some(hash) = version.first_piece_hash {
+                    name.push('-');
+                    for b in &hash[..4] {
+                        name.push_str(&format!("{:02x}", b));
+                    }
+                }
+                name
+            }
+            GroupKey::SizeAndHeadHash(size, hash) => format!("size-{}-head-{:016x}", size, hash),
+            GroupKey::SizeAndFirstPiece(size, piece_length, None) => {
+                format!("size-{}-block-{}-zeros", size, piece_length)
+            }
+            GroupKey::SizeAndFirstPiece(size, piece_length, Some((index, hash))) => format!(
+                "size-{}-block-{}-{}-{:016x}",
+                size, piece_length, index, hash
+            ),
+            GroupKey::SizeAndMtimeBucket(size, bucket) => format!("size-{}-mtime-{}", size, bucket),
+            #[cfg(unix)]
+            GroupKey::Inode(_, dev, ino) => format!("inode-{}-{}", dev, ino),
+            GroupKey::WithinDir(parent, key) => {
+                format!("{}/{}", parent.display(), key.name())
+            }
+            GroupKey::Cluster(key, index) => format!("{}#{}", key.name(), index),
+        }
+    }
+
+    /// Piece length of the group's torrent when the members are its first
+    /// file, so byte offsets map directly onto piece indices.
+    pub fn piece_length(&self) -> Option<u64> {
+        match self {
+            GroupKey::SizeAndPieceLength(_, version) => version
+                .first_piece_hash
+                .is_some()
+                .then_some(version.piece_length),
+            GroupKey::WithinDir(_, key) | GroupKey::Cluster(key, _) => key.piece_length(),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            GroupKey::FilenameAndSize(_, size)
+            | GroupKey::SizeOnly(size)
+            | GroupKey::ExtensionAndSize(_, size)
+            | GroupKey::SizeAndPieceLength(size, _)
+            | GroupKey::SizeAndHeadHash(size, _)
+            | GroupKey::SizeAndFirstPiece(size, ..)
+            | GroupKey::SizeAndMtimeBucket(size, _) => *size,
+            #[cfg(unix)]
+            GroupKey::Inode(size, ..) => *size,
+            GroupKey::WithinDir(_, key) | GroupKey::Cluster(key, _) => key.size(),
+        }
+    }
+
+    fn is_size_only(&self) -> bool {
+        match self {
+            GroupKey::FilenameAndSize(..)
+            | GroupKey::SizeAndHeadHash(..)
+            | GroupKey::SizeAndPieceLength(..)
+            | GroupKey::SizeAndFirstPiece(..) => false,
+            #[cfg(unix)]
+            GroupKey::Inode(..) => false,
+            GroupKey::SizeOnly(_)
+            | GroupKey::ExtensionAndSize(..)
+            | GroupKey::SizeAndMtimeBucket(..) => true,
+            GroupKey::WithinDir(_, key) | GroupKey::Cluster(key, _) => key.is_size_only(),
+        }
+    }
+}
+
+/// Extension whitelist and blacklist for scanned files. An extension in
+/// both lists is excluded.
+#[derive(Debug, Default)]
+pub struct ExtFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ExtFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> ExtFilter {
+        let normalize = |exts: &[String]| {
+            exts.iter()
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        };
+        ExtFilter {
+            include: normalize(include),
+            exclude: normalize(exclude),
+        }
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        let listed = |list: &[String]| ext.as_ref().is_some_and(|e| list.contains(e));
+        !listed(&self.exclude) && (self.include.is_empty() || listed(&self.include))
+    }
+}
+
+/// Smallest member `collect_large_files` keeps, other than zero-byte files.
+pub const MIN_MEMBER_SIZE: u64 = 1_048_576;
+
+/// Whether a file of `len` bytes can be a member: over `MIN_MEMBER_SIZE`, or
+/// empty with `include_empty`, and no larger than `max_size`.
+pub fn member_size_allowed(len: u64, max_size: Option<u64>, include_empty: bool) -> bool {
+    (len > MIN_MEMBER_SIZE || include_empty && len == 0) && max_size.is_none_or(|max| len <= max)
+}
+
+/// Recursively finds files over 1 MiB, and with `include_empty` zero-byte
+/// files, under `dir` that pass `ext_filter`, are no larger than `max_size`
+/// and, with `modified_since`, were modified at or after it. Descends at
+/// most `max_depth` directories below `dir` and skips paths excluded by a
+/// `.combineignore` file. Staging files of an interrupted `--replace` are
+/// never members.
+pub fn collect_large_files(
+    dir: &Path,
+    max_size: Option<u64>,
+    max_depth: Option<usize>,
+    modified_since: Option<SystemTime>,
+    ext_filter: &ExtFilter,
+    include_empty: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), 0, Rc::new(Vec::new()))];
+
+    while let Some((current_dir, depth, mut rules)) = dirs.pop() {
+        if let Some(own) = IgnoreRules::load(&current_dir)? {
+            Rc::make_mut(&mut rules).push(own);
+        }
+        for entry in fs::read_dir(&current_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_dir = path.is_dir();
+            if ignore::is_ignored(&rules, &path, is_dir) {
+                log::debug!("Skipping {:?}: excluded by a {}", path, ignore::IGNORE_FILE);
+            } else if is_dir {
+                if max_depth.is_none_or(|max| depth < max) {
+                    dirs.push((path, depth + 1, Rc::clone(&rules)));
+                }
+            } else if ext_filter.allows(&path)
+                && !path.to_string_lossy().ends_with(merger::STAGING_SUFFIX)
+                && !path.to_string_lossy().ends_with(merger::RESUME_SUFFIX)
+                && let Ok(metadata) = fs::metadata(&path)
+                && member_size_allowed(metadata.len(), max_size, include_empty)
+                && modified_since
+                    .is_none_or(|since| metadata.modified().is_ok_and(|modified| modified >= since))
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Drops files that are another spelling of an earlier one, such as `./a`,
+/// `a/` or `x/../a` for `a`, keeping the first spelling as given.
+pub fn dedup_spellings(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    files
+        .into_iter()
+        .filter(|file| {
+            let spelling = normalize_spelling(file).unwrap_or_else(|_| file.clone());
+            let first = seen.insert(spelling);
+            if !first {
+                log::debug!("Dropping {:?}: it names a file already listed", file);
+            }
+            first
+        })
+        .collect()
+}
+
+// Spells `path` absolutely without `.` components or trailing slashes. `..`
+// drops the component before it unless that is a symlink, whose parent may be
+// elsewhere; symlinks are never followed.
+fn normalize_spelling(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if !normalized.is_symlink() => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Hashes the non-zero bytes among the first `head_bytes` of `path`. Zeros
+/// are skipped so that bytes a partial hasn't downloaded yet don't shift the
+/// content, but a partial only matches a complete copy when it holds every
+/// non-zero byte of the head.
+fn head_hash(path: &Path, head_bytes: u64) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    let mut reader = io::BufReader::new(File::open(path)?.take(head_bytes));
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &b in buf.iter().filter(|&&b| b != 0) {
+            hasher.write_u8(b);
+        }
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok(hasher.finish())
+}
+
+/// Finds the first `piece_length` block of `path` that isn't all zeros and
+/// returns its index and a hash of it, or `None` if every block is zero. A
+/// copy holding the first piece reads just that block; a partial missing it
+/// only groups with copies whose first held piece is the same.
+fn first_piece_hash(path: &Path, piece_length: u64) -> io::Result<Option<(u64, u64)>> {
+    let mut reader = File::open(path)?;
+    let mut block = Vec::new();
+    let mut index = 0;
+    loop {
+        block.clear();
+        (&mut reader).take(piece_length).read_to_end(&mut block)?;
+        if block.is_empty() {
+            return Ok(None);
+        }
+        if block.iter().any(|&b| b != 0) {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(&block);
+            return Ok(Some((index, hasher.finish())));
+        }
+        index += 1;
+    }
+}
+
+// File name `file` groups under, without `client`'s incomplete suffix.
+fn grouping_name(file: &Path, client: Option<Client>) -> Option<String> {
+    let basename = file.file_name()?.to_string_lossy();
+    Some(match client {
+        Some(client) => client.strip_incomplete_suffix(&basename).to_string(),
+        None => basename.into_owned(),
+    })
+}
+
+/// Groups `files` by `dedup_mode`. Files whose metadata can't be read are
+/// left out.
+pub fn group_files(
+    files: Vec<PathBuf>,
+    dedup_mode: &DedupKey,
+    within_dir_only: bool,
+    torrents: &TorrentIndex,
+    client: Option<Client>,
+    settings: &KeySettings,
+) -> HashMap<GroupKey, Vec<PathBuf>> {
+    let mut groups: HashMap<GroupKey, Vec<PathBuf>> = HashMap::new();
+    for file in files {
+        if let Ok(metadata) = fs::metadata(&file) {
+            let size = metadata.len();
+            let mut key = match dedup_mode {
+                DedupKey::FilenameAndSize => match grouping_name(&file, client) {
+                    Some(basename) => GroupKey::FilenameAndSize(basename, size),
+                    None => continue,
+                },
+                DedupKey::SizeOnly => GroupKey::SizeOnly(size),
+                DedupKey::ExtensionAndSize => {
+                    let basename = file.file_name().unwrap_or_default().to_string_lossy();
+                    let basename = match client {
+                        Some(client) => client.strip_incomplete_suffix(&basename),
+                        None => &basename,
+                    };
+                    let ext = Path::new(basename)
+                        .extension()
+                        .map(|ext| ext.to_string_lossy().to_lowercase())
+                        .unwrap_or_default();
+                    GroupKey::ExtensionAndSize(ext, size)
+                }
+                // Files no torrent describes group as `FilenameAndSize` would.
+                DedupKey::SizeAndPieceLength => match torrents.lookup(&file, size) {
+                    Some(version) => GroupKey::SizeAndPieceLength(size, version),
+                    None => match grouping_name(&file, client) {
+                        Some(basename) => GroupKey::FilenameAndSize(basename, size),
+                        None => continue,
+                    },
+                },
+                DedupKey::SizeAndHeadHash => match head_hash(&file, settings.head_bytes) {
+                    Ok(hash) => GroupKey::SizeAndHeadHash(size, hash),
+                    Err(e) => {
+                        log::warn!("Skipping {:?}: could not hash its head: {}", file, e);
+                        continue;
+                    }
+                },
+                DedupKey::SizeAndMtimeWindow => {
+                    let Ok(mtime) = metadata.modified() else {
+                        continue;
+                    };
+                    let secs = mtime
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    let window = settings.mtime_window.as_secs().max(1);
+                    GroupKey::SizeAndMtimeBucket(size, secs / window)
+                }
+                DedupKey::SizeAndFirstPieceHash => {
+                    let piece_length = settings.piece_length.or_else(|| {
+                        torrents
+                            .lookup(&file, size)
+                            .map(|version| version.piece_length)
+                    });
+                    let Some(piece_length) = piece_length.filter(|&length| length > 0) else {
+                        log::warn!(
+                            "Skipping {:?}: no --piece-length and no .torrent describes it",
+                            file
+                        );
+                        continue;
+                    };
+                    match first_piece_hash(&file, piece_length) {
+                        Ok(block) => GroupKey::SizeAndFirstPiece(size, piece_length, block),
+                        Err(e) => {
+                            log::warn!(
+                                "Skipping {:?}: could not hash its first piece: {}",
+                                file,
+                                e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                #[cfg(unix)]
+                DedupKey::Inode => {
+                    use std::os::unix::fs::MetadataExt;
+                    GroupKey::Inode(size, metadata.dev(), metadata.ino())
+                }
+            };
+            if within_dir_only {
+                let parent = file.parent().map(Path::to_path_buf).unwrap_or_default();
+                key = GroupKey::WithinDir(parent, Box::new(key));
+            }
+            groups.entry(key).or_default().push(file);
+        }
+    }
+    groups
+}
+
+/// Splits size-only groups into clusters with compatible content prefixes so
+/// unrelated files that happen to share a size don't fail each other's merge.
+pub fn split_size_only_groups(
+    groups: HashMap<GroupKey, Vec<PathBuf>>,
+    by_content: bool,
+) -> HashMap<GroupKey, Vec<PathBuf>> {
+    let mut split = HashMap::with_capacity(groups.len());
+    for (key, paths) in groups {
+        if !key.is_size_only() || paths.len() < 2 {
+            split.insert(key, paths);
+            continue;
+        }
+        let (clusters, basis) = if by_content {
+            let clusters = merger::cluster_by_content(
+                &paths,
+                merger::CONTENT_CLUSTER_SAMPLES,
+                merger::PREFIX_CLUSTER_BYTES,
+            );
+            (clusters, "sampled content")
+        } else {
+            let clusters = merger::cluster_by_prefix(&paths, merger::PREFIX_CLUSTER_BYTES);
+            (clusters, "content prefix")
+        };
+        match clusters {
+            Ok(clusters) if clusters.len() > 1 => {
+                log::debug!(
+                    "Split group {} into {} clusters by {}",
+                    key.name(),
+                    clusters.len(),
+                    basis
+                );
+                for (index, cluster) in clusters.into_iter().enumerate() {
+                    split.insert(GroupKey::Cluster(Box::new(key.clone()), index), cluster);
+                }
+            }
+            Ok(_) => {
+                split.insert(key, paths);
+            }
+            Err(e) => {
+                log::warn!("Could not read {} for group {}: {}", basis, key.name(), e);
+                split.insert(key, paths);
+            }
+        }
+    }
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{self, Discovery, MergeReport, RunConfig};
+
+    fn process_groups(groups: HashMap<GroupKey, Vec<PathBuf>>) -> io::Result<MergeReport> {
+        let discovery = Discovery {
+            groups: groups.into_iter().collect(),
+            ..Discovery::default()
+        };
+        run::process(&RunConfig::default(), discovery)
+    }
+
+    #[test]
+    fn test_dedup_key_enum_variants() {
+        assert_eq!(
+            format!("{:?}", DedupKey::FilenameAndSize),
+            "FilenameAndSize"
+        );
+        assert_eq!(format!("{:?}", DedupKey::SizeOnly), "SizeOnly");
+    }
+
+    #[test]
+    fn test_group_key_equality() {
+        let key1 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
+        let key2 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
+        let key3 = GroupKey::FilenameAndSize("other.mkv".to_string(), 1024);
+        let key4 = GroupKey::SizeOnly(1024);
+        let key5 = GroupKey::SizeOnly(1024);
+        let key6 = GroupKey::SizeOnly(2048);
+
+        assert_eq!(key1, key2);
+        assert_ne!(key1, key3);
+        assert_ne!(key1, key4);
+        assert_eq!(key4, key5);
+        assert_ne!(key4, key6);
+    }
+
+    #[test]
+    fn test_group_key_hash() {
+        let mut map: HashMap<GroupKey, Vec<PathBuf>> = HashMap::new();
+
+        let key1 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
+        let key2 = GroupKey::SizeOnly(1024);
+
+        map.insert(key1, vec![PathBuf::from("/path1")]);
+        map.insert(key2, vec![PathBuf::from("/path2")]);
+
+        assert_eq!(map.len(), 2);
+
+        let key1_dup = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
+        map.entry(key1_dup)
+            .or_default()
+            .push(PathBuf::from("/path3"));
+
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_group_name_formatting() {
+        let key1 = GroupKey::FilenameAndSize("video.mkv".to_string(), 2097152);
+        let key2 = GroupKey::SizeOnly(1048576);
+
+        assert_eq!(key1.name(), "video.mkv@2097152");
+        assert_eq!(key2.name(), "size-1048576");
+    }
+
+    #[test]
+    fn test_collect_large_files_max_size() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let small = dir.path().join("small.mkv");
+        fs::write(&small, vec![0u8; 2_000_000])?;
+        let large = dir.path().join("large.mkv");
+        fs::write(&large, vec![0u8; 3_000_000])?;
+
+        let mut files =
+            collect_large_files(dir.path(), None, None, None, &ExtFilter::default(), false)?;
+        files.sort();
+        assert_eq!(files, vec![large.clone(), small.clone()]);
+
+        let files = collect_large_files(
+            dir.path(),
+            Some(2_500_000),
+            None,
+            None,
+            &ExtFilter::default(),
+            false,
+        )?;
+        assert_eq!(files, vec![small]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_files_max_depth() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("extras/deep"))?;
+        let top = dir.path().join("top.mkv");
+        let extras = dir.path().join("extras/extra.mkv");
+        let deep = dir.path().join("extras/deep/deep.mkv");
+        for path in [&top, &extras, &deep] {
+            fs::write(path, vec![0u8; 2_000_000])?;
+        }
+
+        let collect = |max_depth| -> io::Result<Vec<PathBuf>> {
+            let mut files = collect_large_files(
+                dir.path(),
+                None,
+                max_depth,
+                None,
+                &ExtFilter::default(),
+                false,
+            )?;
+            files.sort();
+            Ok(files)
+        };
+        assert_eq!(collect(Some(0))?, vec![top.clone()]);
+        assert_eq!(collect(Some(1))?, vec![extras.clone(), top.clone()]);
+        assert_eq!(collect(None)?, vec![deep, extras, top]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_files_ext_filter() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for name in ["movie.MKV", "cover.jpg", "info.nfo", "noext"] {
+            let path = dir.path().join(name);
+            fs::write(&path, vec![0u8; 2_000_000])?;
+            paths.push(path);
+        }
+        let collect = |include: &[&str], exclude: &[&str]| -> io::Result<Vec<PathBuf>> {
+            let to_strings = |exts: &[&str]| exts.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            let filter = ExtFilter::new(&to_strings(include), &to_strings(exclude));
+            let mut files = collect_large_files(dir.path(), None, None, None, &filter, false)?;
+            files.sort();
+            Ok(files)
+        };
+
+        assert_eq!(
+            collect(&[], &["nfo", ".JPG"])?,
+            vec![paths[0].clone(), paths[3].clone()]
+        );
+        assert_eq!(
+            collect(&["mkv", "jpg"], &[])?,
+            vec![paths[1].clone(), paths[0].clone()]
+        );
+        // Exclude wins when an extension is in both lists.
+        assert_eq!(collect(&["mkv", "jpg"], &["jpg"])?, vec![paths[0].clone()]);
+        assert_eq!(collect(&["mkv"], &["mkv"])?, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_within_dir_only() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = Vec::new();
+        for sub in ["torrent-a", "torrent-b"] {
+            fs::create_dir(dir.path().join(sub))?;
+            let file = dir.path().join(sub).join("video.mkv");
+            fs::write(&file, vec![0u8; 16])?;
+            files.push(file);
+        }
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::FilenameAndSize,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups.values().next().unwrap().len(), 2);
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::FilenameAndSize,
+            true,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 2);
+        assert!(groups.values().all(|paths| paths.len() == 1));
+        let key = GroupKey::WithinDir(
+            dir.path().join("torrent-a"),
+            Box::new(GroupKey::FilenameAndSize("video.mkv".to_string(), 16)),
+        );
+        assert_eq!(groups[&key], vec![files[0].clone()]);
+        assert_eq!(key.size(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by_size_and_head_hash() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content: Vec<u8> = (0..16_384u32).map(|i| (i % 251 + 1) as u8).collect();
+        let unrelated: Vec<u8> = (0..16_384u32).map(|i| (i % 241 + 1) as u8).collect();
+        let mut partial = content.clone();
+        partial[8192..].fill(0);
+        let original = dir.path().join("movie.mkv");
+        fs::write(&original, &content)?;
+        // A renamed partial whose head is fully downloaded.
+        let renamed = dir.path().join("renamed.bin");
+        fs::write(&renamed, &partial)?;
+        let other = dir.path().join("other.mkv");
+        fs::write(&other, &unrelated)?;
+
+        let groups = group_files(
+            vec![original.clone(), renamed.clone(), other.clone()],
+            &DedupKey::SizeAndHeadHash,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings {
+                head_bytes: 4096,
+                ..KeySettings::default()
+            },
+        );
+        assert_eq!(groups.len(), 2);
+        let mut members: Vec<Vec<PathBuf>> = groups.into_values().collect();
+        members.sort_by_key(|paths| paths.len());
+        assert_eq!(members[0], vec![other]);
+        assert_eq!(members[1], vec![original, renamed]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_size_only_group_by_content() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let size = 64 * 1024;
+        let first: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+        let second: Vec<u8> = (0..size).map(|i| (i % 241 + 2) as u8).collect();
+        let mut files = Vec::new();
+        // Both partials missing the start have all-zero prefixes, which
+        // prefix clustering can't tell apart.
+        for (name, data, missing) in [
+            ("a1", &first, 0..16 * 1024),
+            ("b1", &second, 0..16 * 1024),
+            ("a2", &first, 48 * 1024..size),
+            ("b2", &second, 48 * 1024..size),
+        ] {
+            let mut partial = data.clone();
+            partial[missing].fill(0);
+            let path = dir.path().join(name);
+            fs::write(&path, &partial)?;
+            files.push(path);
+        }
+
+        let group = |by_content| {
+            split_size_only_groups(
+                group_files(
+                    files.clone(),
+                    &DedupKey::SizeOnly,
+                    false,
+                    &TorrentIndex::default(),
+                    None,
+                    &KeySettings::default(),
+                ),
+                by_content,
+            )
+        };
+        let groups = group(true);
+        assert_eq!(groups.len(), 2);
+        let mut clusters: Vec<Vec<String>> = groups
+            .values()
+            .map(|paths| {
+                let mut names: Vec<String> = paths
+                    .iter()
+                    .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                    .collect();
+                names.sort();
+                names
+            })
+            .collect();
+        clusters.sort();
+        assert_eq!(clusters, vec![vec!["a1", "a2"], vec!["b1", "b2"]]);
+
+        let report = process_groups(groups)?;
+        assert_eq!(report.counts.merged, 2);
+        assert!(report.failures.is_empty());
+        assert_eq!(fs::read(dir.path().join("a1.merged"))?, first);
+        assert_eq!(fs::read(dir.path().join("b2.merged"))?, second);
+
+        assert!(!process_groups(group(false))?.failures.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_size_only_group_by_prefix() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first: Vec<u8> = (0..64u8).map(|b| b + 1).collect();
+        let second: Vec<u8> = (0..64u8).map(|b| 200 - b).collect();
+        let mut files = Vec::new();
+        for (name, data, missing) in [
+            ("a1", &first, 0..32),
+            ("b1", &second, 0..32),
+            ("a2", &first, 32..64),
+            ("b2", &second, 32..64),
+        ] {
+            let mut partial = data.clone();
+            partial[missing].fill(0);
+            let path = dir.path().join(name);
+            fs::write(&path, &partial)?;
+            files.push(path);
+        }
+
+        let groups = split_size_only_groups(
+            group_files(
+                files,
+                &DedupKey::SizeOnly,
+                false,
+                &TorrentIndex::default(),
+                None,
+                &KeySettings::default(),
+            ),
+            false,
+        );
+        assert_eq!(groups.len(), 2);
+        let mut clusters: Vec<Vec<String>> = groups
+            .values()
+            .map(|paths| {
+                let mut names: Vec<String> = paths
+                    .iter()
+                    .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                    .collect();
+                names.sort();
+                names
+            })
+            .collect();
+        clusters.sort();
+        assert_eq!(clusters, vec![vec!["a1", "a2"], vec!["b1", "b2"]]);
+
+        let report = process_groups(groups)?;
+        assert_eq!(report.counts.merged, 2);
+        assert!(report.failures.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrelated_same_size_files_split_on_head() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Unrelated complete files: their heads conflict, so only the first
+        // PREFIX_CLUSTER_BYTES of each are read and neither is merged.
+        let size = 16 * merger::PREFIX_CLUSTER_BYTES as usize;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, vec![1u8; size])?;
+        fs::write(&b, vec![2u8; size])?;
+
+        let groups = split_size_only_groups(
+            group_files(
+                vec![a, b],
+                &DedupKey::SizeOnly,
+                false,
+                &TorrentIndex::default(),
+                None,
+                &KeySettings::default(),
+            ),
+            false,
+        );
+        assert_eq!(groups.len(), 2);
+        assert!(groups.values().all(|paths| paths.len() == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_by_piece_length() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("release-a").join("video.mkv");
+        let b = dir.path().join("release-b").join("video.mkv");
+        let c = dir.path().join("release-c").join("video.mkv");
+        for (path, piece_length) in [(&a, 16384), (&b, 32768), (&c, 16384)] {
+            let release = path.parent().unwrap();
+            fs::create_dir_all(release)?;
+            fs::write(path, vec![0u8; 8])?;
+            let name = release.file_name().unwrap().to_str().unwrap();
+            let torrent = format!(
+                "d4:infod5:filesld6:lengthi8e4:pathl9:video.mkveee4:name{}:{}12:piece lengthi{}eee",
+                name.len(),
+                name,
+                piece_length
+            );
+            fs::write(dir.path().join(format!("{}.torrent", name)), torrent)?;
+        }
+        let torrents = TorrentIndex::load(dir.path())?;
+        assert_eq!(torrents.len(), 3);
+        // No torrent describes these, so they keep their name and size groups.
+        let untracked = dir.path().join("untracked").join("video.mkv");
+        let other = dir.path().join("untracked").join("other.mkv");
+        fs::create_dir_all(untracked.parent().unwrap())?;
+        fs::write(&untracked, vec![0u8; 8])?;
+        fs::write(&other, vec![0u8; 8])?;
+
+        let files = vec![a.clone(), b.clone(), c.clone()];
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::FilenameAndSize,
+            false,
+            &torrents,
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 1);
+
+        let groups = group_files(
+            [files, vec![untracked.clone(), other.clone()]].concat(),
+            &DedupKey::SizeAndPieceLength,
+            false,
+            &torrents,
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 4);
+        let version = |piece_length| TorrentVersion {
+            piece_length,
+            first_piece_hash: None,
+        };
+        let key = GroupKey::SizeAndPieceLength(8, version(16384));
+        assert_eq!(groups[&key], vec![a, c]);
+        assert!(!key.is_size_only());
+        assert_eq!(
+            groups[&GroupKey::SizeAndPieceLength(8, version(32768))],
+            vec![b]
+        );
+        assert_eq!(
+            groups[&GroupKey::FilenameAndSize("video.mkv".to_string(), 8)],
+            vec![untracked]
+        );
+        assert_eq!(
+            groups[&GroupKey::FilenameAndSize("other.mkv".to_string(), 8)],
+            vec![other]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_utorrent_profile() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let partial = dir.path().join("a").join("video.mkv.!ut");
+        let complete = dir.path().join("b").join("video.mkv");
+        for path in [&partial, &complete] {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, vec![1u8; 8])?;
+        }
+        let files = vec![partial.clone(), complete.clone()];
+        let torrents = TorrentIndex::default();
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::FilenameAndSize,
+            false,
+            &torrents,
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 2);
+
+        let groups = group_files(
+            files,
+            &DedupKey::FilenameAndSize,
+            false,
+            &torrents,
+            Some(Client::UTorrent),
+            &KeySettings::default(),
+        );
+        let key = GroupKey::FilenameAndSize("video.mkv".to_string(), 8);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[&key], vec![partial, complete]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_group_files_by_inode() -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir()?;
+        let original = dir.path().join("a.mkv");
+        fs::write(&original, vec![1u8; 64])?;
+        fs::create_dir(dir.path().join("sub"))?;
+        let linked = dir.path().join("sub").join("b.mkv");
+        fs::hard_link(&original, &linked)?;
+        // Same name, size and content, but its own storage.
+        let copy = dir.path().join("sub").join("a.mkv");
+        fs::copy(&original, &copy)?;
+
+        let groups = group_files(
+            vec![original.clone(), linked.clone(), copy.clone()],
+            &DedupKey::Inode,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings::default(),
+        );
+        let meta = fs::metadata(&original)?;
+        let key = GroupKey::Inode(64, meta.dev(), meta.ino());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&key], vec![original, linked]);
+        assert_eq!(key.name(), format!("inode-{}-{}", meta.dev(), meta.ino()));
+
+        let groups: Vec<_> = groups.into_iter().collect();
+        assert_eq!(run::hardlink_savings(&groups), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_spellings_count_once() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir(root.join("sub"))?;
+        let file = root.join("a.mkv");
+        fs::write(&file, vec![1u8; 64])?;
+        std::os::unix::fs::symlink(root.join("sub"), root.join("link"))?;
+
+        let spellings = vec![
+            file.clone(),
+            root.join("./a.mkv"),
+            root.join("sub/../a.mkv"),
+            PathBuf::from(format!("{}/", file.display())),
+        ];
+        let config = RunConfig {
+            paths: Some(spellings.clone()),
+            ..RunConfig::default()
+        };
+        let discovery = run::discover(&config)?;
+        assert_eq!(discovery.files, 1);
+        assert!(discovery.groups.is_empty());
+
+        // `link/..` is the parent of the link's target, so it is never
+        // resolved lexically.
+        let through_link = root.join("link/../a.mkv");
+        assert_eq!(
+            dedup_spellings(vec![file.clone(), through_link.clone()]),
+            vec![file.clone(), through_link]
+        );
+
+        let config = RunConfig {
+            paths: Some(spellings),
+            normalize_paths: false,
+            ..RunConfig::default()
+        };
+        let discovery = run::discover(&config)?;
+        assert_eq!(discovery.files, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stable_input_order() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = Vec::new();
+        for sub in ["c", "a", "b"] {
+            fs::create_dir(dir.path().join(sub))?;
+            let file = dir.path().join(sub).join("video.mkv");
+            fs::write(&file, vec![1u8; 16])?;
+            files.push(file);
+        }
+        let members = |paths: Vec<PathBuf>, input_order| -> io::Result<Vec<PathBuf>> {
+            let config = RunConfig {
+                paths: Some(paths),
+                input_order,
+                ..RunConfig::default()
+            };
+            let mut discovery = run::discover(&config)?;
+            Ok(discovery.groups.remove(0).1)
+        };
+
+        let mut sorted = files.clone();
+        sorted.sort();
+        let mut reversed = files.clone();
+        reversed.reverse();
+        assert_eq!(members(files.clone(), InputOrder::Stable)?, sorted);
+        assert_eq!(members(reversed.clone(), InputOrder::Stable)?, sorted);
+        assert_eq!(members(reversed.clone(), InputOrder::Fs)?, reversed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_by_extension_and_size() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = Vec::new();
+        for name in [
+            "episode.mkv",
+            "Renamed.MKV",
+            "episode.mp4",
+            "README",
+            "LICENSE",
+        ] {
+            let file = dir.path().join(name);
+            fs::write(&file, vec![0u8; 16])?;
+            files.push(file);
+        }
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::ExtensionAndSize,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings::default(),
+        );
+        assert_eq!(groups.len(), 3);
+        let mkv = GroupKey::ExtensionAndSize("mkv".to_string(), 16);
+        assert_eq!(groups[&mkv], vec![files[0].clone(), files[1].clone()]);
+        let mp4 = GroupKey::ExtensionAndSize("mp4".to_string(), 16);
+        assert_eq!(groups[&mp4], vec![files[2].clone()]);
+        let bare = GroupKey::ExtensionAndSize(String::new(), 16);
+        assert_eq!(groups[&bare], vec![files[3].clone(), files[4].clone()]);
+        assert_eq!(mkv.name(), "*.mkv@16");
+        assert_eq!(bare.name(), "*@16");
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_files_modified_since() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let old = dir.path().join("old.mkv");
+        let fresh = dir.path().join("fresh.mkv");
+        for path in [&old, &fresh] {
+            fs::write(path, vec![0u8; 2_000_000])?;
+        }
+        let now = SystemTime::now();
+        let week = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(&old)?
+            .set_modified(now - week)?;
+
+        let since = now - week / 7;
+        let files = collect_large_files(
+            dir.path(),
+            None,
+            None,
+            Some(since),
+            &ExtFilter::default(),
+            false,
+        )?;
+        assert_eq!(files, vec![fresh]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_by_size_and_mtime_window() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Two minutes into an hour, so ten minutes later is the same bucket.
+        let base = 1_700_000_000 / 3600 * 3600 + 120;
+        let mut files = Vec::new();
+        for (name, offset) in [("snap-1", 0), ("snap-2", 600), ("snap-3", 3 * 3600)] {
+            fs::create_dir(dir.path().join(name))?;
+            let file = dir.path().join(name).join("db.bin");
+            fs::write(&file, vec![0u8; 16])?;
+            File::options()
+                .write(true)
+                .open(&file)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(base + offset))?;
+            files.push(file);
+        }
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::SizeAndMtimeWindow,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings {
+                mtime_window: Duration::from_secs(3600),
+                ..KeySettings::default()
+            },
+        );
+        assert_eq!(groups.len(), 2);
+        let key = GroupKey::SizeAndMtimeBucket(16, base / 3600);
+        assert_eq!(groups[&key], vec![files[0].clone(), files[1].clone()]);
+        assert_eq!(
+            groups[&GroupKey::SizeAndMtimeBucket(16, base / 3600 + 3)],
+            vec![files[2].clone()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_files_include_empty() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let large = dir.path().join("large.mkv");
+        fs::write(&large, vec![1u8; 2_000_000])?;
+        let empty = dir.path().join("empty.mkv");
+        fs::write(&empty, b"")?;
+        fs::write(dir.path().join("small.mkv"), b"small")?;
+
+        let files =
+            collect_large_files(dir.path(), None, None, None, &ExtFilter::default(), false)?;
+        assert_eq!(files, vec![large.clone()]);
+        let mut files =
+            collect_large_files(dir.path(), None, None, None, &ExtFilter::default(), true)?;
+        files.sort();
+        assert_eq!(files, vec![empty, large]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_files_combineignore() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let show = dir.path().join("show");
+        let extras = show.join("extras");
+        fs::create_dir_all(&extras)?;
+        let episode = show.join("episode.mkv");
+        fs::write(&episode, vec![1u8; 2_000_000])?;
+        fs::write(extras.join("trailer.mkv"), vec![1u8; 2_000_000])?;
+        let sample = dir.path().join("sample.mkv");
+        fs::write(&sample, vec![1u8; 2_000_000])?;
+        fs::write(dir.path().join(ignore::IGNORE_FILE), "sample.mkv\n")?;
+        fs::write(show.join(ignore::IGNORE_FILE), "extras/\n")?;
+
+        let files =
+            collect_large_files(dir.path(), None, None, None, &ExtFilter::default(), false)?;
+        assert_eq!(files, vec![episode]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_files_size_and_first_piece_hash() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let complete: Vec<u8> = (1..=64u8).collect();
+        let mut partial = complete.clone();
+        partial[32..].fill(0);
+        let mut other_release = complete.clone();
+        other_release[3] = 99;
+        let mut missing_first = complete.clone();
+        missing_first[..16].fill(0);
+        let mut files = Vec::new();
+        for (name, data) in [
+            ("complete", &complete),
+            ("partial", &partial),
+            ("other", &other_release),
+            ("late", &missing_first),
+        ] {
+            let file = dir.path().join(name);
+            fs::write(&file, data)?;
+            files.push(file);
+        }
+        let settings = KeySettings {
+            piece_length: Some(16),
+            ..KeySettings::default()
+        };
+
+        let groups = group_files(
+            files.clone(),
+            &DedupKey::SizeAndFirstPieceHash,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &settings,
+        );
+        assert_eq!(groups.len(), 3);
+        let shared = groups
+            .iter()
+            .find(|(_, paths)| paths.len() == 2)
+            .expect("Copies sharing a first piece should group");
+        assert_eq!(shared.1, &vec![files[0].clone(), files[1].clone()]);
+        assert!(matches!(
+            shared.0,
+            GroupKey::SizeAndFirstPiece(64, 16, Some((0, _)))
+        ));
+        // The copy missing its first piece is keyed on its second.
+        assert!(
+            groups
+                .keys()
+                .any(|key| matches!(key, GroupKey::SizeAndFirstPiece(64, 16, Some((1, _)))))
+        );
+
+        // Without a piece length or a torrent to take it from, files are skipped.
+        let groups = group_files(
+            files,
+            &DedupKey::SizeAndFirstPieceHash,
+            false,
+            &TorrentIndex::default(),
+            None,
+            &KeySettings::default(),
+        );
+        assert!(groups.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! `.combineignore` files: gitignore-style patterns that keep paths out of
+//! discovery for the directory holding the file and everything below it.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file whose patterns exclude paths from discovery.
+pub const IGNORE_FILE: &str = ".combineignore";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    glob: Vec<u8>,
+    /// `!pattern`: re-include what an earlier pattern excluded.
+    negated: bool,
+    /// `pattern/`: only match directories.
+    dir_only: bool,
+    /// Holds a slash, so it matches the path relative to the ignore file's
+    /// directory rather than the bare file name at any depth.
+    anchored: bool,
+}
+
+/// The patterns of one `.combineignore` file, scoped to `base`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreRules {
+    base: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    /// Reads `dir`'s ignore file, if it has one.
+    pub fn load(dir: &Path) -> io::Result<Option<IgnoreRules>> {
+        match fs::read_to_string(dir.join(IGNORE_FILE)) {
+            Ok(text) => Ok(Some(IgnoreRules::parse(dir, &text))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parses gitignore syntax: one pattern per line, `#` comments, `!` to
+    /// negate, a trailing `/` for directories only and `*`, `?`, `[...]`
+    /// and `**` wildcards. A backslash escapes the next character.
+    pub fn parse(base: &Path, text: &str) -> IgnoreRules {
+        let patterns = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let anchored = line.contains('/');
+                Pattern {
+                    glob: line.trim_start_matches('/').as_bytes().to_vec(),
+                    negated,
+                    dir_only,
+                    anchored,
+                }
+            })
+            .filter(|pattern| !pattern.glob.is_empty())
+            .collect();
+        IgnoreRules {
+            base: base.to_path_buf(),
+            patterns,
+        }
+    }
+
+    // Whether the last pattern matching `path` excludes it, or None when no
+    // pattern matches or `path` is outside `base`.
+    fn verdict(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let relative = relative.as_os_str().as_encoded_bytes();
+        let name = path.file_name()?.as_encoded_bytes();
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| {
+                (is_dir || !pattern.dir_only)
+                    && glob_match(
+                        &pattern.glob,
+                        if pattern.anchored { relative } else { name },
+                    )
+            })
+            .map(|pattern| !pattern.negated)
+    }
+}
+
+/// Whether `path` is excluded by the ignore files in effect for it, ordered
+/// from the outermost directory in. The innermost file with a matching
+/// pattern decides, as with `.gitignore`.
+pub fn is_ignored(rules: &[IgnoreRules], path: &Path, is_dir: bool) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find_map(|rules| rules.verdict(path, is_dir))
+        .unwrap_or(false)
+}
+
+// Matches `text`, a `/`-separated path, against a glob. `*` and `?` stop at
+// slashes while `**` crosses them, and `**/` also matches no directory.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            if let Some(after) = rest.strip_prefix(b"/")
+                && glob_match(after, text)
+            {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|&i| !text[..i].contains(&b'/'))
+            .any(|i| glob_match(rest, &text[i..])),
+        Some((b'?', rest)) => {
+            text.first().is_some_and(|&c| c != b'/') && glob_match(rest, &text[1..])
+        }
+        Some((b'[', rest)) => match text.first() {
+            Some(b'/') | None => false,
+            Some(&c) => match match_class(rest, c) {
+                Some((matched, after)) => matched && glob_match(after, &text[1..]),
+                // An unclosed `[` is a literal.
+                None => c == b'[' && glob_match(rest, &text[1..]),
+            },
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((&c, rest)) => text.first() == Some(&c) && glob_match(rest, &text[1..]),
+    }
+}
+
+// Matches `c` against the bracket class starting just past its `[`, giving
+// whether it matched and the pattern after the `]`. None if it never closes.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, start) = match pattern.first() {
+        Some(b'!' | b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut i = start;
+    while i < pattern.len() {
+        if pattern[i] == b']' && i > start {
+            return Some((matched != negated, &pattern[i + 1..]));
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&hi)) if hi != b']' => {
+                matched |= (pattern[i]..=hi).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= pattern[i] == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_patterns() {
+        let base = Path::new("/library");
+        let rules = vec![IgnoreRules::parse(
+            base,
+            "# comment\n*.nfo\nextras/\n/top.mkv\nsamples/**/*.mkv\nfile[0-9].bin\n!keep.nfo\n",
+        )];
+        let ignored = |path: &str, is_dir| is_ignored(&rules, &base.join(path), is_dir);
+
+        assert!(ignored("info.nfo", false));
+        assert!(ignored("show/s01/info.nfo", false));
+        assert!(!ignored("keep.nfo", false));
+        assert!(ignored("show/extras", true));
+        assert!(!ignored("show/extras", false));
+        assert!(ignored("top.mkv", false));
+        assert!(!ignored("show/top.mkv", false));
+        assert!(ignored("samples/a.mkv", false));
+        assert!(ignored("samples/x/y/a.mkv", false));
+        assert!(!ignored("show/samples/a.mkv", false));
+        assert!(ignored("file7.bin", false));
+        assert!(!ignored("fileA.bin", false));
+        assert!(!ignored("movie.mkv", false));
+
+        // A nested file overrides the outer one below its directory.
+        let mut rules = rules;
+        rules.push(IgnoreRules::parse(&base.join("show"), "!*.nfo\n"));
+        assert!(!is_ignored(&rules, &base.join("show/info.nfo"), false));
+        assert!(is_ignored(&rules, &base.join("other/info.nfo"), false));
+    }
+}
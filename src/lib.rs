@@ -0,0 +1,22 @@
+//! Merging of partially downloaded torrent files. The `torrent-combine`
+//! binary drives this library; it is also used by the benchmarks.
+
+pub mod aria2;
+pub mod audit;
+pub mod bencode;
+pub mod client;
+pub mod config;
+pub mod group;
+pub mod ignore;
+pub mod merger;
+pub mod metrics;
+pub mod patch;
+pub mod report;
+pub mod run;
+pub mod self_test;
+pub mod space;
+pub mod torrent;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(unix)]
+pub mod xattr;
@@ -1,188 +1,1197 @@
-use clap::{Parser, ValueEnum};
-use std::collections::HashMap;
-use std::fs;
-use std::io;
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use log::error;
-use rayon::prelude::*;
-
-mod merger;
-
-#[derive(Debug, Clone, ValueEnum)]
-enum DedupKey {
-    #[value(name = "filename-and-size")]
-    FilenameAndSize,
-    #[value(name = "size-only")]
-    SizeOnly,
-}
-
-#[derive(Debug, PartialEq, Eq, Hash)]
-enum GroupKey {
-    FilenameAndSize(String, u64),
-    SizeOnly(u64),
-}
+use torrent_combine::client::Client;
+use torrent_combine::group::{DEFAULT_HEAD_BYTES, DedupKey, InputOrder, member_size_allowed};
+use torrent_combine::{config, merger, metrics, patch, report, run, self_test};
 
+// `args_override_self` lets a later value of an option replace an earlier
+// one instead of being an error, which the config file's arguments rely on.
+// Options that collect every value, like --include-ext, still add up, so
+// those the command line gives are dropped from the file's.
 #[derive(Parser, Debug)]
-#[command(name = "torrent-combine")]
+#[command(name = "torrent-combine", args_override_self = true)]
 struct Args {
-    root_dir: PathBuf,
+    /// Read options from this TOML file of long option names and values; flags on the command line override it
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[arg(required_unless_present_any = ["paths_from", "apply_patch", "self_test"])]
+    root_dir: Option<PathBuf>,
+    /// Read member paths from this file ("-" for stdin) instead of scanning root_dir
+    #[arg(long, conflicts_with = "root_dir")]
+    paths_from: Option<PathBuf>,
+    /// Paths given to --paths-from are NUL-delimited instead of newline-delimited
+    #[arg(long, requires = "paths_from")]
+    null: bool,
     #[arg(long)]
     replace: bool,
+    /// Don't ask for confirmation before --replace or --distribute overwrites files
+    #[arg(long, short = 'y')]
+    assume_yes: bool,
+    /// With --replace, also replace complete members of merged groups
+    #[arg(long, requires = "replace")]
+    normalize_all: bool,
     #[arg(long)]
     num_threads: Option<usize>,
+    /// Only log per-group lines for groups of at least this many bytes
+    #[arg(long, default_value_t = 0)]
+    group_report_threshold: u64,
+    /// Say why each skipped group was skipped and break the summary's skipped count down by reason
+    #[arg(long)]
+    report_skipped_reason: bool,
+    /// Skip files larger than this many bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+    /// Don't descend more than N directories below root_dir; 0 scans only root_dir itself
+    #[arg(long, value_name = "N", conflicts_with = "paths_from")]
+    max_depth: Option<usize>,
+    /// Only scan files modified within this long, e.g. 90m, 24h or 7d (units s, m, h, d, w)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "paths_from")]
+    since: Option<Duration>,
+    /// Scan zero-byte files too and report groups of only zero-byte files as empty instead of skipped
+    #[arg(long)]
+    include_zero_byte_files: bool,
+    /// Only scan files with these extensions (comma-separated, case-insensitive)
+    #[arg(long, value_delimiter = ',')]
+    include_ext: Vec<String>,
+    /// Skip files with these extensions (comma-separated, case-insensitive); wins over --include-ext
+    #[arg(long, value_delimiter = ',')]
+    exclude_ext: Vec<String>,
     #[arg(long, value_enum, default_value = "filename-and-size")]
     dedup_mode: DedupKey,
+    /// Count a file listed under several spellings (./a, a/, x/../a) once; pass false to keep them all
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    normalize_paths: bool,
+    /// Color merged, skipped and failed statuses in per-group log lines; auto colors only on a terminal
+    #[arg(long, value_enum, default_value = "auto")]
+    color: run::ColorMode,
+    /// Order of each group's members: as the filesystem lists them, or sorted by path
+    #[arg(long, value_enum, default_value = "fs")]
+    input_order: InputOrder,
+    /// Where --dedup-mode size-and-piece-length looks for .torrent files (default root_dir)
+    #[arg(long)]
+    torrent_dir: Option<PathBuf>,
+    /// Torrent client whose incomplete-file naming to understand when grouping
+    #[arg(long, value_enum)]
+    client: Option<Client>,
+    /// Only group files that share the same parent directory
+    #[arg(long)]
+    dedup_within_dir_only: bool,
+    /// Bytes hashed from the start of each file by --dedup-mode size-and-head-hash
+    #[arg(long, default_value_t = DEFAULT_HEAD_BYTES)]
+    head_bytes: u64,
+    /// Bucket width for --dedup-mode size-and-mtime-window, e.g. 30m or 1h
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1h")]
+    mtime_window: Duration,
+    /// Block size for --dedup-mode size-and-first-piece-hash (default: each file's .torrent piece length)
+    #[arg(long, value_name = "BYTES")]
+    piece_length: Option<u64>,
+    /// Split size-only groups by content sampled across the whole file instead of the first 4 KiB
+    #[arg(long)]
+    content_clusters: bool,
+    /// Known-good complete copy merged into matching groups; never modified
+    #[arg(long)]
+    reference_file: Option<PathBuf>,
+    /// Only merge the reference file into the group with this name
+    #[arg(long, requires = "reference_file")]
+    reference_group: Option<String>,
+    /// Write the failed groups, their members and first conflict offsets to this file
+    #[arg(long)]
+    only_failed_report: Option<PathBuf>,
+    /// Write which member donated each recovered run of every merged group to this JSON file
+    #[arg(long)]
+    members_report: Option<PathBuf>,
+    /// Write the byte ranges of every group that no member could fill to this JSON file
+    #[arg(long)]
+    report_unrecoverable_ranges: Option<PathBuf>,
+    /// Merge even when the free-space preflight finds too little room for outputs
+    #[arg(long)]
+    ignore_space_check: bool,
+    /// Format of --only-failed-report; inferred from its extension by default
+    #[arg(long, value_enum, requires = "only_failed_report")]
+    report_format: Option<report::ReportFormat>,
+    /// Show a hexdump around the first conflicting offset of failed groups
+    #[arg(long)]
+    preview_diff: bool,
+    /// Output naming template with {name}, {stem}, {ext} and {parent} placeholders, relative to the member's directory and at most one level above it
+    #[arg(long, default_value = merger::DEFAULT_OUTPUT_TEMPLATE)]
+    output_template: String,
+    /// Mirror merged outputs into this directory, keeping their path relative to root_dir
+    #[arg(long, conflicts_with = "replace")]
+    output_dir: Option<PathBuf>,
+    /// What to do when a mirrored output already exists
+    #[arg(long, value_enum, default_value = "skip", requires = "output_dir")]
+    on_collision: merger::OnCollision,
+    /// Write a patch of the recovered bytes ({output}.patch) instead of a full merged copy
+    #[arg(long, conflicts_with_all = ["replace", "distribute", "verify_against"])]
+    emit_patch: bool,
+    /// Apply a patch written by --emit-patch to FILE in place, then exit
+    #[arg(long, num_args = 2, value_names = ["PATCH", "FILE"], conflicts_with_all = ["root_dir", "paths_from"])]
+    apply_patch: Option<Vec<PathBuf>>,
+    /// Complete or discard the staging files an interrupted --replace left under root_dir, then exit
+    #[arg(long, conflicts_with_all = ["paths_from", "apply_patch", "probe"])]
+    recover: bool,
+    /// Merge generated groups in a temp dir (under --temp-dir or root_dir if given), check the results and exit
+    #[arg(long, conflicts_with_all = ["paths_from", "apply_patch", "probe", "recover"])]
+    self_test: bool,
+    /// Skip the full pass over groups whose members show no zeros in a quick probe of a few blocks
+    #[arg(long)]
+    combine_only_incomplete_groups: bool,
+    /// Read every group in full even with --combine-only-incomplete-groups
+    #[arg(long)]
+    force_full_check: bool,
+    /// Write nothing for groups that already have a complete member
+    #[arg(long)]
+    skip_if_any_complete: bool,
+    /// Only write the merged file next to the member with the fewest missing bytes
+    #[arg(long)]
+    output_most_complete: bool,
+    /// After --output-most-complete, hardlink (or copy across filesystems) the merged file over every member
+    #[arg(long, requires = "output_most_complete", conflicts_with = "replace")]
+    distribute: bool,
+    /// Drop members that fail to read mid-merge instead of failing the group
+    #[arg(long)]
+    drop_unreadable: bool,
+    /// In strict mode, keep scanning failed groups to count every conflicting position
+    #[arg(long)]
+    strict_sanity_all_positions: bool,
+    /// Warn about zero ranges in merged output that no member could fill
+    #[arg(long)]
+    warn_unrecovered: bool,
+    /// Accept a trailing zero run no member can fill as a known gap in merged output
+    #[arg(long)]
+    ignore_trailing_zeros: bool,
+    /// Drop members whose data disagrees with the group's majority at more than this percentage of positions
+    #[arg(long, value_parser = parse_percent)]
+    xor_detect: Option<f64>,
+    /// Re-read this percentage of each written output and compare it to the merge
+    #[arg(long, value_parser = parse_percent)]
+    sample_verify: Option<f64>,
+    /// Seed choosing the blocks --sample-verify re-reads
+    #[arg(long, default_value_t = 0, requires = "sample_verify")]
+    sample_seed: u64,
+    /// Only read the first and last chunk of each member, print how many groups look recoverable, and exit
+    #[arg(long)]
+    probe: bool,
+    /// Print only the groups a merge would improve, with their member paths, and exit without merging
+    #[arg(long)]
+    only_recoverable: bool,
+    /// Stage each group's merged data in this directory instead of next to its first member
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+    /// Prefix for temp file names
+    #[arg(long, default_value = merger::DEFAULT_TEMP_PREFIX)]
+    temp_prefix: String,
+    /// Debug aid: name temp files after their group so an interrupted run's intermediates can be found
+    #[arg(long)]
+    deterministic_temp: bool,
+    /// Leave members whose size differs from the majority of their group out of the merge
+    #[arg(long)]
+    drop_size_outliers: bool,
+    /// Let --replace overwrite members that resolve outside the root directory through symlinks
+    #[arg(long, requires = "replace")]
+    allow_outside_root: bool,
+    /// Check and merge every group but write no outputs
+    #[arg(long, conflicts_with_all = ["replace", "probe"])]
+    verify_only: bool,
+    /// Copy each group's merged data into this directory for inspection
+    #[arg(long)]
+    keep_merged_artifact: Option<PathBuf>,
+    /// After merging, byte-compare each output with the file at its member's relative path in this trusted mirror
+    #[arg(long)]
+    verify_against: Option<PathBuf>,
+    /// Start no new group once N groups have failed their sanity check, and exit with an error
+    #[arg(long, value_name = "N")]
+    max_failures: Option<usize>,
+    /// Sync each output and its directory to disk before reporting success (slower)
+    #[arg(long)]
+    fsync: bool,
+    /// Read every merged chunk back from disk right after writing it and fail the group on any difference (slow)
+    #[arg(long)]
+    paranoid: bool,
+    /// Append each step of writing an output (intend, wrote temp, renamed) to this journal, synced per entry
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// Checkpoint merges into a predictable temp file so a rerun continues an interrupted merge
+    #[arg(long)]
+    resumable_temp: bool,
+    /// Stage every output of a group before renaming any, so a failed write leaves none behind
+    #[arg(long)]
+    atomic_group: bool,
+    /// Compare each member in full first and skip groups whose members are all the same
+    #[arg(long)]
+    fast_identical_check: bool,
+    /// Copy each member's extended attributes onto its merged output or replacement
+    #[arg(long)]
+    preserve_xattrs: bool,
+    /// Read members through io_uring (Linux, needs the io-uring feature)
+    #[arg(long)]
+    io_uring: bool,
+    /// Read this many members of a group concurrently; --num-threads sets how many groups run at once
+    #[arg(long, default_value_t = 1)]
+    parallel_files_per_group: usize,
+    /// Lower bound for the adaptive per-member read chunk size in bytes
+    #[arg(long, default_value_t = merger::DEFAULT_CHUNK_SIZE)]
+    min_chunk_size: usize,
+    /// Upper bound for the adaptive per-member read chunk size in bytes
+    #[arg(long, default_value_t = merger::DEFAULT_CHUNK_SIZE)]
+    max_chunk_size: usize,
+    /// Cap the read buffers of all concurrently running groups at this many bytes; further groups wait until theirs fit
+    #[arg(long)]
+    max_memory: Option<u64>,
+    /// Abandon a group still running after this many seconds, e.g. reads stalled on flaky storage
+    #[arg(long, value_name = "SECS")]
+    group_timeout: Option<u64>,
+    /// How conflicting bytes between members are handled
+    #[arg(long, value_enum, default_value = "strict")]
+    conflict_mode: merger::ConflictMode,
+    /// In the salvage and majority modes, fail a group as divergent once it has more conflicting positions than this
+    #[arg(long)]
+    abort_if_conflicts_exceed: Option<u64>,
+    /// Tie-break policy for the salvage and majority conflict modes
+    #[arg(long, value_enum, default_value = "first")]
+    prefer: merger::Prefer,
+    /// Only process the group with this name, as printed in the logs (e.g. video.mkv@1048576 or size-1048576)
+    #[arg(long)]
+    group: Option<String>,
+    /// Stop after the first N groups (after --shuffle-order), for trial runs on large libraries
+    #[arg(long)]
+    max_groups: Option<usize>,
+    /// Wait before starting each group while the one-minute load average is above this
+    #[arg(long)]
+    pause_on_high_load: Option<f64>,
+    /// Keep running, rescanning every N seconds and merging only groups that changed since the last pass
+    #[arg(long, value_name = "SECS", conflicts_with_all = ["probe", "apply_patch", "recover"])]
+    watch: Option<u64>,
+    /// Print a one-line JSON object with each pass's totals to stdout
+    #[arg(long)]
+    stats_json_stream: bool,
+    /// Push run totals to this Prometheus Pushgateway (http://host:port) after each pass
+    #[arg(long, value_name = "URL")]
+    metrics_pushgateway: Option<String>,
+    /// Process groups in random order
+    #[arg(long)]
+    shuffle_order: bool,
+    /// Seed for --shuffle-order to make the order reproducible
+    #[arg(long, requires = "shuffle_order")]
+    seed: Option<u64>,
 }
 
-fn collect_large_files(dir: &PathBuf) -> io::Result<Vec<PathBuf>> {
+/// Parses a newline- or NUL-delimited path list, keeping regular files of a
+/// size a directory scan would keep. Blank entries are ignored. Entries
+/// prefixed with `*` mark their group's seed member, unless the entry names
+/// an existing path as written; seeds are returned as the second list.
+/// Relative entries are taken relative to `base`.
+fn read_path_list(
+    mut reader: impl Read,
+    base: &Path,
+    null: bool,
+    max_size: Option<u64>,
+    include_empty: bool,
+) -> io::Result<(Vec<PathBuf>, BTreeSet<PathBuf>)> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let delimiter = if null { b'\0' } else { b'\n' };
+
     let mut files = Vec::new();
-    let mut dirs = vec![dir.clone()];
-
-    while let Some(current_dir) = dirs.pop() {
-        for entry in fs::read_dir(&current_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                dirs.push(path);
-            } else if let Ok(metadata) = fs::metadata(&path) {
-                if metadata.len() > 1_048_576 {
+    let mut seeds = BTreeSet::new();
+    for entry in input.split(|&b| b == delimiter) {
+        let entry = if null {
+            entry
+        } else {
+            entry.strip_suffix(b"\r").unwrap_or(entry)
+        };
+        // A file whose name starts with `*` is listed as it is.
+        let (seed, entry) = match entry.strip_prefix(b"*") {
+            Some(rest) if !path_from_bytes(entry).is_some_and(|p| base.join(p).exists()) => {
+                (true, rest)
+            }
+            _ => (false, entry),
+        };
+        if entry.is_empty() {
+            continue;
+        }
+        let Some(path) = path_from_bytes(entry).map(|p| base.join(p)) else {
+            log::warn!(
+                "Skipping path that isn't UTF-8: {:?}",
+                String::from_utf8_lossy(entry)
+            );
+            continue;
+        };
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {
+                if member_size_allowed(metadata.len(), max_size, include_empty) {
+                    if seed {
+                        seeds.insert(path.clone());
+                    }
                     files.push(path);
                 }
             }
+            Ok(_) => log::warn!("Skipping non-file path {:?}", path),
+            Err(e) => log::warn!("Skipping unreadable path {:?}: {}", path, e),
+        }
+    }
+    Ok((files, seeds))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+// Paths elsewhere aren't arbitrary bytes, so lists must be UTF-8.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+// The path given to --config, found before parsing since the file may supply
+// options the command line needs to parse.
+fn config_path(argv: &[OsString]) -> Option<PathBuf> {
+    let mut iter = argv.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Puts the options of the --config file, if any, before the command line's
+/// own arguments, so the command line wins over the file and the file over
+/// the defaults. Options the command line gives are left out of the file's,
+/// so a list on the command line replaces the file's rather than adding to
+/// it, and `--flag=false` turns off a flag the file sets.
+fn args_with_config(argv: Vec<OsString>) -> io::Result<Vec<OsString>> {
+    let command = Args::command();
+    let Some(path) = config_path(&argv) else {
+        return Ok(config::flag_values(argv, &command));
+    };
+    let mut entries = config::parse_config(&fs::read_to_string(&path)?)?;
+    if entries.iter().any(|(key, _)| key == "config") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A config file can't name another config file",
+        ));
+    }
+    let mut args = argv;
+    let rest = args.split_off(1.min(args.len()));
+    entries.retain(|(key, _)| !config::given(&rest, key, &command));
+    let base = path.parent().unwrap_or(Path::new(""));
+    args.extend(config::config_args(&entries, &command, base)?);
+    args.extend(rest);
+    Ok(config::flag_values(args, &command))
+}
+
+/// Parses a count with a unit suffix, e.g. `30s`, `90m`, `24h`, `7d` or
+/// `2w`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("{:?} is not a duration like 24h or 7d", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let count: u64 = s[..split].parse().map_err(|_| invalid())?;
+    let unit = match &s[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    count
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(p),
+        _ => Err(format!("{:?} is not a percentage in (0, 100]", s)),
+    }
+}
+
+impl Args {
+    // The option that writes over members in place, if any, so the run has
+    // to be confirmed first.
+    fn overwriting_option(&self) -> Option<&'static str> {
+        if self.distribute {
+            Some("--distribute")
+        } else if self.replace {
+            Some("--replace")
+        } else {
+            None
+        }
+    }
+
+    // Replacements stay inside the scanned tree unless allowed out; lists
+    // from --paths-from have no tree to stay in.
+    fn replace_within(&self) -> Option<PathBuf> {
+        if self.allow_outside_root {
+            return None;
+        }
+        let root_dir = self
+            .root_dir
+            .as_ref()
+            .filter(|_| self.paths_from.is_none())?;
+        match fs::canonicalize(root_dir) {
+            Ok(root) => Some(root),
+            Err(e) => {
+                log::warn!("Could not resolve root directory {:?}: {}", root_dir, e);
+                None
+            }
+        }
+    }
+
+    fn merge_options(&self) -> merger::MergeOptions {
+        merger::MergeOptions::builder()
+            .replace(self.replace)
+            .normalize_all(self.normalize_all)
+            .preview_diff(self.preview_diff)
+            .output_template(self.output_template.as_str())
+            .conflict_mode(self.conflict_mode)
+            .prefer(self.prefer)
+            .conflict_budget(self.abort_if_conflicts_exceed)
+            .output_most_complete(self.output_most_complete)
+            .scan_root(self.root_dir.clone().filter(|_| self.paths_from.is_none()))
+            .output_dir(self.output_dir.clone().map(|dir| merger::OutputDir {
+                dir,
+                root: self.root_dir.clone().filter(|_| self.paths_from.is_none()),
+            }))
+            .on_collision(self.on_collision)
+            .emit_patch(self.emit_patch)
+            .skip_if_any_complete(self.skip_if_any_complete)
+            .only_incomplete_groups(self.combine_only_incomplete_groups && !self.force_full_check)
+            .distribute(self.distribute)
+            .io_uring(self.io_uring)
+            .fsync(self.fsync)
+            .paranoid(self.paranoid)
+            .audit_log(self.audit_log.clone())
+            .resumable_temp(self.resumable_temp)
+            .atomic_group(self.atomic_group)
+            .fast_identical_check(self.fast_identical_check)
+            .preserve_xattrs(self.preserve_xattrs)
+            .include_zero_byte_files(self.include_zero_byte_files)
+            .verify_only(self.verify_only)
+            .replace_within(self.replace_within())
+            .drop_size_outliers(self.drop_size_outliers)
+            .keep_merged_artifact(self.keep_merged_artifact.clone())
+            .chunk_bounds(merger::ChunkBounds {
+                min: self.min_chunk_size,
+                max: self.max_chunk_size,
+            })
+            .max_memory(self.max_memory)
+            .group_timeout(self.group_timeout.map(Duration::from_secs))
+            .drop_unreadable(self.drop_unreadable)
+            .count_all_conflicts(self.strict_sanity_all_positions)
+            .warn_unrecovered(self.warn_unrecovered)
+            .ignore_trailing_zeros(self.ignore_trailing_zeros)
+            .sample_verify(self.sample_verify)
+            .sample_seed(self.sample_seed)
+            .parallel_files_per_group(self.parallel_files_per_group)
+            .temp_prefix(&self.temp_prefix)
+            .temp_dir(self.temp_dir.clone())
+            .xor_detect(self.xor_detect)
+            .deterministic_temp(self.deterministic_temp)
+            .track_recovered(self.members_report.is_some())
+            .report_unrecoverable(self.report_unrecoverable_ranges.is_some())
+            .build()
+    }
+    fn run_config(&self, paths: Option<Vec<PathBuf>>) -> run::RunConfig {
+        run::RunConfig {
+            root_dir: self.root_dir.clone(),
+            paths,
+            max_size: self.max_size,
+            max_depth: self.max_depth,
+            since: self.since,
+            include_ext: self.include_ext.clone(),
+            exclude_ext: self.exclude_ext.clone(),
+            dedup_mode: self.dedup_mode.clone(),
+            dedup_within_dir_only: self.dedup_within_dir_only,
+            torrent_dir: self.torrent_dir.clone(),
+            client: self.client,
+            head_bytes: self.head_bytes,
+            mtime_window: self.mtime_window,
+            piece_length: self.piece_length,
+            content_clusters: self.content_clusters,
+            reference_file: self.reference_file.clone(),
+            reference_group: self.reference_group.clone(),
+            group: self.group.clone(),
+            shuffle_order: self.shuffle_order,
+            seed: self.seed,
+            max_groups: self.max_groups,
+            normalize_paths: self.normalize_paths,
+            input_order: self.input_order,
+            // The binary owns the process, so `main` sizes the global pool.
+            num_threads: None,
+            pause_on_high_load: self.pause_on_high_load,
+            ignore_space_check: self.ignore_space_check,
+            verify_against: self.verify_against.clone(),
+            max_failures: self.max_failures,
+            on_group: Some(
+                GroupLines::new(self, self.color.enabled(io::stderr().is_terminal())).callback(),
+            ),
+            options: self.merge_options(),
         }
     }
+}
 
-    Ok(files)
+#[derive(Debug, PartialEq, Eq)]
+enum ReplaceGate {
+    Proceed,
+    Prompt,
+    Refuse,
+}
+
+// `overwrites` is whether the run writes over members in place, as
+// --replace and --distribute do.
+fn replace_gate(overwrites: bool, assume_yes: bool, stdin_is_tty: bool) -> ReplaceGate {
+    match (overwrites, assume_yes, stdin_is_tty) {
+        (false, _, _) | (true, true, _) => ReplaceGate::Proceed,
+        (true, false, true) => ReplaceGate::Prompt,
+        (true, false, false) => ReplaceGate::Refuse,
+    }
+}
+
+fn confirm_replace(option: &str, candidates: usize, total_groups: usize) -> io::Result<bool> {
+    eprint!(
+        "{} may overwrite up to {} files in {} groups. Continue? [y/N] ",
+        option, candidates, total_groups
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
 
 fn main() -> io::Result<()> {
     if std::env::var("RUST_LOG").is_err() {
         unsafe { std::env::set_var("RUST_LOG", "info") };
     }
-    env_logger::init();
+    let args = Args::parse_from(args_with_config(std::env::args_os().collect())?);
+    env_logger::Builder::from_default_env()
+        .write_style(match args.color {
+            run::ColorMode::Auto => env_logger::WriteStyle::Auto,
+            run::ColorMode::Always => env_logger::WriteStyle::Always,
+            run::ColorMode::Never => env_logger::WriteStyle::Never,
+        })
+        .init();
+    if let Some([patch, target]) = args.apply_patch.as_deref() {
+        let applied = patch::apply_patch(File::open(patch)?, target)?;
+        log::info!(
+            "Applied {} patched bytes from {:?} to {:?}",
+            applied,
+            patch,
+            target
+        );
+        return Ok(());
+    }
+    if args.self_test {
+        let parent = match (&args.temp_dir, &args.root_dir) {
+            (Some(dir), _) | (None, Some(dir)) => dir.clone(),
+            (None, None) => std::env::temp_dir(),
+        };
+        let seed = fastrand::u64(..);
+        let cases = self_test::self_test(&parent, seed)?;
+        for case in &cases {
+            if case.passed {
+                log::info!("Self-test {}: pass", case.name);
+            } else {
+                log::error!("Self-test {}: FAIL: {}", case.name, case.detail);
+            }
+        }
+        let failed = cases.iter().filter(|case| !case.passed).count();
+        if failed > 0 {
+            return Err(io::Error::other(format!(
+                "{} of {} self-test cases failed in {:?} (seed {})",
+                failed,
+                cases.len(),
+                parent,
+                seed
+            )));
+        }
+        log::info!("All {} self-test cases passed in {:?}", cases.len(), parent);
+        return Ok(());
+    }
 
-    let args = Args::parse();
-    log::info!("Processing root directory: {:?}", args.root_dir);
+    if args.recover
+        && let Some(root_dir) = &args.root_dir
+    {
+        let recovered = merger::recover_staging(root_dir)?;
+        for (member, outcome) in &recovered {
+            log::info!("Staged replacement of {:?} {}", member, outcome.label());
+        }
+        log::info!(
+            "Recovered {} staging files under {:?}",
+            recovered.len(),
+            root_dir
+        );
+        return Ok(());
+    }
+    if args.preserve_xattrs && !cfg!(unix) {
+        log::warn!(
+            "--preserve-xattrs is only supported on unix; extended attributes won't be copied"
+        );
+    }
+    if args.pause_on_high_load.is_some() && run::load_average().is_none() {
+        log::warn!(
+            "The system doesn't report a load average, so --pause-on-high-load does nothing"
+        );
+    }
+    match &args.root_dir {
+        Some(root_dir) => log::info!("Processing root directory: {:?}", root_dir),
+        None => log::info!("Processing paths from {:?}", args.paths_from),
+    }
 
     if let Some(num_threads) = args.num_threads {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(num_threads)
-            .build_global()
-            .unwrap();
-    }
-
-    let files = collect_large_files(&args.root_dir)?;
-    log::info!("Found {} large files", files.len());
-
-    let mut groups: HashMap<GroupKey, Vec<PathBuf>> = HashMap::new();
-    for file in files {
-        if let Ok(metadata) = fs::metadata(&file) {
-            let size = metadata.len();
-            let key = match args.dedup_mode {
-                DedupKey::FilenameAndSize => {
-                    if let Some(basename) =
-                        file.file_name().map(|s| s.to_string_lossy().to_string())
-                    {
-                        GroupKey::FilenameAndSize(basename, size)
-                    } else {
-                        continue;
-                    }
+        init_global_pool(num_threads);
+    }
+
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let readers = rayon::current_num_threads().saturating_mul(args.parallel_files_per_group);
+    if args.parallel_files_per_group > 1 && readers > cores {
+        log::warn!(
+            "{} group threads x {} readers per group exceeds {} cores; reads may contend",
+            rayon::current_num_threads(),
+            args.parallel_files_per_group,
+            cores
+        );
+    }
+
+    if args.io_uring && !cfg!(all(feature = "io-uring", target_os = "linux")) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--io-uring needs a Linux build with the io-uring feature",
+        ));
+    }
+
+    if args.parallel_files_per_group == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--parallel-files-per-group must be at least 1",
+        ));
+    }
+
+    if args.max_failures == Some(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--max-failures must be at least 1",
+        ));
+    }
+
+    if args.min_chunk_size == 0 || args.min_chunk_size > args.max_chunk_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--min-chunk-size must be non-zero and not above --max-chunk-size",
+        ));
+    }
+
+    // Catch template typos before doing any work.
+    merger::render_output_path(&args.output_template, Path::new("dir/example.mkv"), None)?;
+
+    // Listed relative paths are relative to the current directory.
+    let (paths, seeds) = match &args.paths_from {
+        Some(list) if list.as_os_str() == "-" => {
+            let (files, seeds) = read_path_list(
+                io::stdin().lock(),
+                Path::new(""),
+                args.null,
+                args.max_size,
+                args.include_zero_byte_files,
+            )?;
+            (Some(files), seeds)
+        }
+        Some(list) => {
+            let (files, seeds) = read_path_list(
+                File::open(list)?,
+                Path::new(""),
+                args.null,
+                args.max_size,
+                args.include_zero_byte_files,
+            )?;
+            (Some(files), seeds)
+        }
+        None => (None, BTreeSet::new()),
+    };
+    let mut config = args.run_config(paths);
+    config.options.seed_members = seeds;
+
+    let discovery = run::discover(&config)?;
+    log::info!("Found {} large files", discovery.files);
+    if let Some((path, size)) = &discovery.reference {
+        log::info!("Using reference file {:?} ({} bytes)", path, size);
+    }
+    if let (Some(torrents), Some(dir)) = (
+        discovery.torrents,
+        config.torrent_dir.as_ref().or(config.root_dir.as_ref()),
+    ) {
+        log::info!("Loaded {} torrents from {:?}", torrents, dir);
+    }
+    log::info!("Found {} groups to process", discovery.total_groups);
+
+    #[cfg(unix)]
+    if matches!(config.dedup_mode, DedupKey::Inode) {
+        log::info!("--------------------");
+        for (group_key, paths) in &discovery.groups {
+            log::info!(
+                "{} ({} bytes) is shared by {} paths:",
+                group_key.name(),
+                group_key.size(),
+                paths.len()
+            );
+            for path in paths {
+                log::info!("  {}", path.display());
+            }
+        }
+        log::info!(
+            "Hardlinks save {} bytes across {} groups; nothing was merged",
+            run::hardlink_savings(&discovery.groups),
+            discovery.groups.len()
+        );
+        return Ok(());
+    }
+
+    if args.only_recoverable {
+        let recoverable = run::recoverable_groups(&config, &discovery, args.probe);
+        log::info!(
+            "{} of {} groups are recoverable{}",
+            recoverable.len(),
+            discovery.groups.len(),
+            if args.probe { " (probed)" } else { "" }
+        );
+        let mut stdout = io::stdout().lock();
+        for (group_key, paths) in &recoverable {
+            writeln!(stdout, "{}", group_key.name())?;
+            for path in paths {
+                writeln!(stdout, "  {}", path.display())?;
+            }
+        }
+        return Ok(());
+    }
+
+    if args.probe {
+        let verdicts = run::probe(&config, &discovery);
+        log::info!("--------------------");
+        log::info!("Probed {} groups:", discovery.groups.len());
+        for (verdict, count) in &verdicts {
+            log::info!("  {}: {}", verdict.label(), count);
+        }
+        log::info!(
+            "Verdicts only consider the first and last chunk of each member; the middle was not read"
+        );
+        return Ok(());
+    }
+
+    // Only the first pass asks before replacing; later passes are plain
+    // cycles of `run_watched`.
+    let Some(mut report) = process_discovery(&config, &args, discovery)? else {
+        return Ok(());
+    };
+    let mut seen = run::Seen::default();
+    seen.record(&report);
+    for cycle in 1.. {
+        if cycle > 1 {
+            log::info!(
+                "Pass {}: {} groups unchanged since they were last processed, {} processed",
+                cycle,
+                report.unchanged,
+                report.groups.len()
+            );
+        }
+        log_report(&report, &args)?;
+        if args.stats_json_stream {
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout, "{}", report::stats_json(cycle, &report))?;
+            stdout.flush()?;
+        }
+        if let Some(url) = &args.metrics_pushgateway
+            && let Err(e) = metrics::push_metrics(url, &metrics::metrics_text(&report))
+        {
+            log::warn!("Could not push metrics to {}: {}", url, e);
+        }
+        if report.counts.not_started > 0 {
+            return Err(io::Error::other(format!(
+                "Stopped after {} failed groups, {} groups were not started",
+                args.max_failures.unwrap_or_default(),
+                report.counts.not_started
+            )));
+        }
+        let Some(secs) = args.watch else {
+            return Ok(());
+        };
+        std::thread::sleep(Duration::from_secs(secs));
+        report = run::run_watched(&config, &mut seen)?;
+    }
+    Ok(())
+}
+
+// Sizes rayon's global pool. It can only be built once and thread creation
+// can fail; either way the run goes on with the pool rayon provides.
+fn init_global_pool(num_threads: usize) {
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+    {
+        log::warn!(
+            "Could not set up {} threads ({}); using {} threads",
+            num_threads,
+            e,
+            rayon::current_num_threads()
+        );
+    }
+}
+
+// Merges one pass's groups after the free-space check, and the replace
+// confirmation when `confirm` is set. Returns None if the user declined.
+fn process_discovery(
+    config: &run::RunConfig,
+    args: &Args,
+    discovery: run::Discovery,
+) -> io::Result<Option<run::MergeReport>> {
+    if let Some(max) = discovery.capped {
+        log::info!(
+            "Processing only the first {} of {} groups (--max-groups)",
+            max,
+            discovery.total_groups
+        );
+    }
+
+    run::ensure_free_space(config, &discovery.groups).map_err(|e| match e.kind() {
+        io::ErrorKind::StorageFull => io::Error::new(
+            e.kind(),
+            format!("{}; pass --ignore-space-check to merge anyway", e),
+        ),
+        _ => e,
+    })?;
+
+    let option = args.overwriting_option().unwrap_or("--replace");
+    let overwrites = args.overwriting_option().is_some();
+    match replace_gate(overwrites, args.assume_yes, io::stdin().is_terminal()) {
+        ReplaceGate::Proceed => {}
+        ReplaceGate::Prompt => {
+            let candidates = discovery.groups.iter().map(|(_, paths)| paths.len()).sum();
+            if !confirm_replace(option, candidates, discovery.groups.len())? {
+                log::info!("Aborted, no files were replaced");
+                return Ok(None);
+            }
+        }
+        ReplaceGate::Refuse => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} needs --assume-yes when stdin is not a terminal", option),
+            ));
+        }
+    }
+
+    run::process(config, discovery).map(Some)
+}
+
+// How the binary renders each group's line as the run hands it over.
+#[derive(Clone, Copy)]
+struct GroupLines {
+    threshold: u64,
+    skipped_reason: bool,
+    color: bool,
+}
+
+impl GroupLines {
+    fn new(args: &Args, color: bool) -> Self {
+        GroupLines {
+            threshold: args.group_report_threshold,
+            skipped_reason: args.report_skipped_reason,
+            color,
+        }
+    }
+
+    fn callback(self) -> run::OnGroup {
+        run::OnGroup(Arc::new(move |group, progress| self.log(group, progress)))
+    }
+
+    // Wraps a group line's status in an ANSI color when coloring is on.
+    fn paint(&self, status: &str, color: Color) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", color as u8, status)
+        } else {
+            status.to_string()
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+    Gray = 90,
+}
+
+impl GroupLines {
+    // Logs one group's line, with the merged files and trailing gap of a
+    // merged group. Groups below `--group-report-threshold` only log
+    // failures.
+    fn log(&self, group: &run::GroupResult, progress: run::GroupProgress) {
+        let name = &group.name;
+        let stats = match &group.result {
+            Ok(stats) => stats,
+            Err(e) => {
+                match merger::MergeError::from_io(e) {
+                    Some(merger::MergeError::TimedOut(timeout)) => log::error!(
+                        "Group {} {} after {:.1}s and was abandoned",
+                        name,
+                        self.paint("timed out", Color::Red),
+                        timeout.as_secs_f64()
+                    ),
+                    Some(merger::MergeError::OutputNotWritable(path)) => log::error!(
+                        "Group {} could not write {:?}: permission denied or read-only; set an output or temp directory to write elsewhere",
+                        name,
+                        path
+                    ),
+                    _ => log::error!("Error processing group {}: {:?}", name, e),
                 }
-                DedupKey::SizeOnly => GroupKey::SizeOnly(size),
-            };
-            groups.entry(key).or_insert(Vec::new()).push(file);
-        }
-    }
-
-    let groups_to_process: Vec<_> = groups
-        .into_iter()
-        .filter(|(_, paths)| paths.len() >= 2)
-        .collect();
-    let total_groups = groups_to_process.len();
-    log::info!("Found {} groups to process", total_groups);
-
-    let groups_processed = Arc::new(AtomicUsize::new(0));
-    let merged_groups_count = Arc::new(AtomicUsize::new(0));
-    let skipped_groups_count = Arc::new(AtomicUsize::new(0));
-
-    groups_to_process
-        .into_par_iter()
-        .for_each(|(group_key, paths)| {
-            let groups_processed_cloned = Arc::clone(&groups_processed);
-            let merged_groups_count_cloned = Arc::clone(&merged_groups_count);
-            let skipped_groups_count_cloned = Arc::clone(&skipped_groups_count);
-
-            let group_name = match &group_key {
-                GroupKey::FilenameAndSize(basename, size) => format!("{}@{}", basename, size),
-                GroupKey::SizeOnly(size) => format!("size-{}", size),
-            };
-
-            match merger::process_group(&paths, &group_name, args.replace) {
-                Ok(stats) => {
-                    let processed_count =
-                        groups_processed_cloned.fetch_add(1, Ordering::SeqCst) + 1;
-                    let percentage_complete =
-                        (processed_count as f64 / total_groups as f64) * 100.0;
-
-                    match stats.status {
-                        merger::GroupStatus::Merged => {
-                            merged_groups_count_cloned.fetch_add(1, Ordering::SeqCst);
-                            let mb_per_sec = (stats.bytes_processed as f64 / 1_048_576.0)
-                                / stats.processing_time.as_secs_f64();
-                            log::info!(
-                                "[{}/{}] Group '{}' merged at {:.2} MB/s. {:.1}% complete.",
-                                processed_count,
-                                total_groups,
-                                group_name,
-                                mb_per_sec,
-                                percentage_complete
-                            );
-                            if !stats.merged_files.is_empty() {
-                                for file in stats.merged_files {
-                                    log::info!("  -> Created merged file: {}", file.display());
-                                }
-                            }
-                        }
-                        merger::GroupStatus::Skipped => {
-                            skipped_groups_count_cloned.fetch_add(1, Ordering::SeqCst);
-                            log::info!(
-                                "[{}/{}] Group '{}' skipped (all files complete). {:.1}% complete.",
-                                processed_count,
-                                total_groups,
-                                group_name,
-                                percentage_complete
-                            );
-                        }
-                        merger::GroupStatus::Failed => {
-                            log::warn!(
-                                "[{}/{}] Group '{}' failed sanity check. {:.1}% complete.",
-                                processed_count,
-                                total_groups,
-                                group_name,
-                                percentage_complete
-                            );
-                        }
-                    }
+                return;
+            }
+        };
+        let run::GroupProgress {
+            done,
+            total,
+            percentage,
+        } = progress;
+        let report = stats.bytes_processed >= self.threshold;
+        match stats.status {
+            merger::GroupStatus::Merged => {
+                if !report {
+                    return;
+                }
+                log::info!(
+                    "[{}/{}] Group '{}' {} at {:.2} MB/s. {:.1}% complete.",
+                    done,
+                    total,
+                    name,
+                    self.paint("merged", Color::Green),
+                    run::mb_per_sec(stats.bytes_processed, stats.processing_time),
+                    percentage
+                );
+                for file in &stats.merged_files {
+                    log::info!("  -> Created merged file: {}", file.display());
+                }
+                if let Some(gap) = stats.trailing_gap {
+                    log::info!("  -> Trailing gap of {} bytes left unfilled", gap);
+                }
+            }
+            merger::GroupStatus::Skipped => {
+                if !report {
+                    return;
+                }
+                let reason = stats.skip_reason.unwrap_or(merger::SkipReason::AllComplete);
+                // A verified group was checked, not found complete.
+                let (status, detail) = match reason {
+                    merger::SkipReason::VerifyOnly => ("verified", "nothing written"),
+                    _ if self.skipped_reason => ("skipped", reason.label()),
+                    _ => ("skipped", "all files complete"),
+                };
+                log::info!(
+                    "[{}/{}] Group '{}' {} ({}). {:.1}% complete.",
+                    done,
+                    total,
+                    name,
+                    self.paint(status, Color::Gray),
+                    detail,
+                    percentage
+                );
+            }
+            merger::GroupStatus::Empty => {
+                if !report {
+                    return;
                 }
-                Err(e) => {
-                    error!("Error processing group {}: {:?}", group_name, e);
+                log::info!(
+                    "[{}/{}] Group '{}' {} (every member is zero bytes). {:.1}% complete.",
+                    done,
+                    total,
+                    name,
+                    self.paint("empty", Color::Yellow),
+                    percentage
+                );
+            }
+            merger::GroupStatus::LikelyComplete => {
+                if !report {
+                    return;
                 }
+                log::info!(
+                    "[{}/{}] Group '{}' {}, skipped without a full pass. {:.1}% complete.",
+                    done,
+                    total,
+                    name,
+                    self.paint("likely complete", Color::Gray),
+                    percentage
+                );
             }
-        });
+            merger::GroupStatus::Failed => log::warn!(
+                "[{}/{}] Group '{}' {}. {:.1}% complete.",
+                done,
+                total,
+                name,
+                self.paint("failed sanity check", Color::Red),
+                percentage
+            ),
+            merger::GroupStatus::Divergent => log::warn!(
+                "[{}/{}] Group '{}' has {}. {:.1}% complete.",
+                done,
+                total,
+                name,
+                self.paint("complete members that differ", Color::Red),
+                percentage
+            ),
+            merger::GroupStatus::SizeMismatch => log::warn!(
+                "[{}/{}] Group '{}' has {}. {:.1}% complete.",
+                done,
+                total,
+                name,
+                self.paint("members of differing sizes", Color::Red),
+                percentage
+            ),
+        }
+    }
+}
+
+// Renders the end-of-run summary, and writes the failed-groups report if
+// one was asked for.
+fn log_report(report: &run::MergeReport, args: &Args) -> io::Result<()> {
+    if let Some(path) = &args.only_failed_report {
+        report::write_failed_report(path, &report.failures, args.report_format)?;
+        if !report.failures.is_empty() {
+            log::info!(
+                "Wrote {} failed groups to {:?}",
+                report.failures.len(),
+                path
+            );
+        }
+    }
 
-    let final_processed = groups_processed.load(Ordering::SeqCst);
-    let final_merged = merged_groups_count.load(Ordering::SeqCst);
-    let final_skipped = skipped_groups_count.load(Ordering::SeqCst);
+    if let Some(path) = &args.members_report {
+        let mut groups: Vec<(&str, &merger::CompletenessMap)> = report
+            .groups
+            .iter()
+            .filter_map(|group| {
+                let stats = group.result.as_ref().ok()?;
+                matches!(stats.status, merger::GroupStatus::Merged)
+                    .then_some((group.name.as_str(), stats.completeness.as_ref()?))
+            })
+            .collect();
+        groups.sort_by_key(|(name, _)| *name);
+        report::write_members_report(path, &groups)?;
+        log::info!(
+            "Wrote donors of {} merged groups to {:?}",
+            groups.len(),
+            path
+        );
+    }
+
+    if let Some(path) = &args.report_unrecoverable_ranges {
+        let mut groups: Vec<(&str, Option<u64>, &merger::UnrecoveredHoles)> = report
+            .groups
+            .iter()
+            .filter_map(|group| {
+                let holes = group.result.as_ref().ok()?.unrecovered.as_ref()?;
+                (holes.bytes > 0).then_some((group.name.as_str(), group.piece_length, holes))
+            })
+            .collect();
+        groups.sort_by_key(|(name, ..)| *name);
+        report::write_unrecoverable_report(path, &groups)?;
+        log::info!(
+            "Wrote unrecoverable ranges of {} groups to {:?}",
+            groups.len(),
+            path
+        );
+    }
 
+    if let Some(verification) = &report.verification {
+        let (mut mismatched, mut failed) = (0, 0);
+        for (output, outcome) in &verification.checked {
+            match outcome {
+                run::VerifyOutcome::Matched => {}
+                run::VerifyOutcome::Differs(offset) => {
+                    mismatched += 1;
+                    log::warn!(
+                        "Output {:?} differs from its trusted copy at offset {}",
+                        output,
+                        offset
+                    );
+                }
+                run::VerifyOutcome::Failed(e) => {
+                    failed += 1;
+                    log::warn!("Could not verify output {:?}: {}", output, e);
+                }
+            }
+        }
+        log::info!(
+            "Verified {} of {} outputs against {:?}: {} matched, {} differed, {} failed",
+            verification.checked.len(),
+            verification.outputs,
+            args.verify_against.as_deref().unwrap_or(Path::new("")),
+            verification.checked.len() - mismatched - failed,
+            mismatched,
+            failed
+        );
+    }
+
+    let counts = &report.counts;
     log::info!("--------------------");
     log::info!("Processing Summary:");
-    log::info!("Total groups: {}", total_groups);
-    log::info!("  - Processed: {}", final_processed);
-    log::info!("  - Merged: {}", final_merged);
-    log::info!("  - Skipped: {}", final_skipped);
+    log::info!("Total groups: {}", report.total_groups);
+    if let Some(max) = report.capped {
+        log::info!("  - Capped at {} by --max-groups", max);
+    }
+    log::info!("  - Processed: {}", counts.processed);
+    log::info!("  - Merged: {}", counts.merged);
+    log::info!("  - Skipped: {}", counts.skipped);
+    if args.report_skipped_reason {
+        for (reason, count) in merger::SkipReason::ALL.iter().zip(counts.skipped_by_reason) {
+            if count > 0 {
+                log::info!("    - {}: {}", reason.label(), count);
+            }
+        }
+    }
+    log::info!("  - Identical duplicates: {}", counts.identical);
+    log::info!("  - Size mismatch: {}", counts.size_mismatch);
+    log::info!("  - Complete but divergent: {}", counts.divergent);
+    if counts.likely_complete > 0 {
+        log::info!(
+            "  - Likely complete, not fully read: {}",
+            counts.likely_complete
+        );
+    }
+    if counts.empty > 0 {
+        log::info!("  - Only zero-byte members: {}", counts.empty);
+    }
+    if counts.timed_out > 0 {
+        log::info!("  - Timed out: {}", counts.timed_out);
+    }
+    if counts.not_started > 0 {
+        log::info!("  - Not started (--max-failures): {}", counts.not_started);
+    }
+    if counts.not_writable > 0 {
+        log::info!("  - Output not writable: {}", counts.not_writable);
+    }
+    let (aggregate, wall_clock) =
+        run::aggregate_throughput(counts.bytes_read, counts.busy_time, report.elapsed);
+    log::info!(
+        "Total bytes read: {} ({:.2} MB/s aggregate, {:.2} MB/s wall-clock)",
+        counts.bytes_read,
+        aggregate,
+        wall_clock
+    );
     log::info!("--------------------");
     Ok(())
 }
@@ -190,69 +1199,316 @@ fn main() -> io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use torrent_combine::group::{GroupKey, KeySettings, MIN_MEMBER_SIZE, group_files};
+    use torrent_combine::torrent::TorrentIndex;
+
+    #[test]
+    fn test_read_path_list_groups_members() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("one dir").join("movie file.mkv");
+        let b = dir.path().join("two").join("movie file.mkv");
+        let size = MIN_MEMBER_SIZE + 1;
+        for p in [&a, &b] {
+            fs::create_dir_all(p.parent().unwrap())?;
+            fs::write(p, vec![7u8; size as usize])?;
+        }
+        let missing = dir.path().join("missing.mkv");
+        // Too small for a directory scan, so too small from a list.
+        let small = dir.path().join("small.mkv");
+        fs::write(&small, b"data")?;
+
+        for null in [false, true] {
+            let sep = if null { "\0" } else { "\n" };
+            let list = format!(
+                "{}{sep}{}{sep}{sep}{}{sep}{}{sep}",
+                a.display(),
+                missing.display(),
+                small.display(),
+                b.display()
+            );
+            let (files, seeds) = read_path_list(list.as_bytes(), Path::new(""), null, None, false)?;
+            assert_eq!(files, vec![a.clone(), b.clone()]);
+            assert!(seeds.is_empty());
+
+            let groups = group_files(
+                files,
+                &DedupKey::FilenameAndSize,
+                false,
+                &TorrentIndex::default(),
+                None,
+                &KeySettings::default(),
+            );
+            let key = GroupKey::FilenameAndSize("movie file.mkv".to_string(), size);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[&key].len(), 2);
+        }
+        Ok(())
+    }
 
     #[test]
-    fn test_dedup_key_enum_variants() {
+    fn test_replace_gate() {
+        assert_eq!(replace_gate(false, false, false), ReplaceGate::Proceed);
+        assert_eq!(replace_gate(false, false, true), ReplaceGate::Proceed);
+        assert_eq!(replace_gate(true, true, false), ReplaceGate::Proceed);
+        assert_eq!(replace_gate(true, true, true), ReplaceGate::Proceed);
+        assert_eq!(replace_gate(true, false, true), ReplaceGate::Prompt);
+        assert_eq!(replace_gate(true, false, false), ReplaceGate::Refuse);
+
+        // --distribute overwrites members in place too.
+        let args = Args::parse_from([
+            "torrent-combine",
+            "root",
+            "--output-most-complete",
+            "--distribute",
+        ]);
+        assert_eq!(args.overwriting_option(), Some("--distribute"));
         assert_eq!(
-            format!("{:?}", DedupKey::FilenameAndSize),
-            "FilenameAndSize"
+            replace_gate(args.overwriting_option().is_some(), args.assume_yes, false),
+            ReplaceGate::Refuse
         );
-        assert_eq!(format!("{:?}", DedupKey::SizeOnly), "SizeOnly");
     }
 
     #[test]
-    fn test_group_key_equality() {
-        let key1 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
-        let key2 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
-        let key3 = GroupKey::FilenameAndSize("other.mkv".to_string(), 1024);
-        let key4 = GroupKey::SizeOnly(1024);
-        let key5 = GroupKey::SizeOnly(1024);
-        let key6 = GroupKey::SizeOnly(2048);
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        for bad in ["", "7", "d", "7 d", "1.5h", "3y"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+    }
 
-        assert_eq!(key1, key2);
-        assert_ne!(key1, key3);
-        assert_ne!(key1, key4);
-        assert_eq!(key4, key5);
-        assert_ne!(key4, key6);
+    #[test]
+    fn test_init_global_pool_twice() {
+        // The second build fails because the pool exists; that is only a warning.
+        init_global_pool(2);
+        init_global_pool(3);
+        assert!(rayon::current_num_threads() >= 1);
     }
 
     #[test]
-    fn test_group_key_hash() {
-        let mut map: HashMap<GroupKey, Vec<PathBuf>> = HashMap::new();
+    fn test_read_path_list_marks_seeds() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.mkv");
+        let b = dir.path().join("b.mkv");
+        let empty = dir.path().join("empty.mkv");
+        fs::write(&a, vec![7u8; MIN_MEMBER_SIZE as usize + 1])?;
+        fs::write(&b, vec![7u8; MIN_MEMBER_SIZE as usize + 1])?;
+        fs::write(&empty, b"")?;
+        let list = format!(
+            "{}\n*{}\n*{}\n{}\n",
+            a.display(),
+            b.display(),
+            dir.path().join("gone").display(),
+            empty.display()
+        );
+        let (files, seeds) = read_path_list(list.as_bytes(), Path::new(""), false, None, false)?;
+        assert_eq!(files, vec![a.clone(), b.clone()]);
+        assert_eq!(seeds, BTreeSet::from([b.clone()]));
 
-        let key1 = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
-        let key2 = GroupKey::SizeOnly(1024);
+        // Zero-byte files are only kept when asked for, as in a scan.
+        let (files, _) = read_path_list(list.as_bytes(), Path::new(""), false, None, true)?;
+        assert_eq!(files, vec![a, b, empty]);
 
-        map.insert(key1, vec![PathBuf::from("/path1")]);
-        map.insert(key2, vec![PathBuf::from("/path2")]);
+        // A relative name starting with `*` that exists is a plain entry.
+        let starred = dir.path().join("*starred.mkv");
+        fs::write(&starred, vec![7u8; MIN_MEMBER_SIZE as usize + 1])?;
+        let (files, seeds) = read_path_list(&b"*starred.mkv"[..], dir.path(), false, None, false)?;
+        assert_eq!(files, vec![starred.clone()]);
+        assert!(seeds.is_empty());
+        let (_, seeds) = read_path_list(&b"**starred.mkv"[..], dir.path(), false, None, false)?;
+        assert_eq!(seeds, BTreeSet::from([starred]));
+        Ok(())
+    }
 
-        assert_eq!(map.len(), 2);
+    #[test]
+    fn test_config_file_under_command_line() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = dir.path().join("combine.toml");
+        fs::write(
+            &config,
+            "paths-from = \"list.txt\"\nmin-chunk-size = 2048\nmax_chunk_size = 8192\n\
+             verify-only = true\ndedup-mode = \"size-only\"\ninclude-ext = [\"mkv\", \"mp4\"]\n",
+        )?;
+        let argv = |args: &[&str]| -> Vec<OsString> {
+            std::iter::once("torrent-combine")
+                .chain(args.iter().copied())
+                .map(OsString::from)
+                .collect()
+        };
 
-        let key1_dup = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
-        map.entry(key1_dup)
-            .or_insert(Vec::new())
-            .push(PathBuf::from("/path3"));
+        let config_arg = format!("--config={}", config.display());
+        let args = Args::try_parse_from(args_with_config(argv(&[&config_arg]))?)
+            .map_err(io::Error::other)?;
+        assert_eq!(args.paths_from, Some(dir.path().join("list.txt")));
+        assert_eq!(args.min_chunk_size, 2048);
+        assert_eq!(args.max_chunk_size, 8192);
+        assert!(args.verify_only);
+        assert!(matches!(args.dedup_mode, DedupKey::SizeOnly));
+        assert_eq!(args.include_ext, vec!["mkv", "mp4"]);
 
-        assert_eq!(map.len(), 2);
+        let args = Args::try_parse_from(args_with_config(argv(&[
+            "--min-chunk-size",
+            "4096",
+            "--config",
+            config.to_str().unwrap(),
+            "--dedup-mode",
+            "filename-and-size",
+            "--include-ext",
+            "avi",
+        ]))?)
+        .map_err(io::Error::other)?;
+        assert_eq!(args.min_chunk_size, 4096);
+        assert_eq!(args.max_chunk_size, 8192);
+        assert!(matches!(args.dedup_mode, DedupKey::FilenameAndSize));
+        assert_eq!(args.include_ext, vec!["avi"]);
+        assert!(args.verify_only);
+
+        let args = Args::try_parse_from(args_with_config(argv(&[
+            &config_arg,
+            "--verify-only=false",
+            "--paths-from=/abs/list.txt",
+        ]))?)
+        .map_err(io::Error::other)?;
+        assert!(!args.verify_only);
+        assert_eq!(args.paths_from, Some(PathBuf::from("/abs/list.txt")));
+
+        fs::write(&config, "no-such-option = 1\n")?;
+        assert!(args_with_config(argv(&[&config_arg])).is_err());
+        Ok(())
+    }
+
+    // Merges `groups` under `config`, logging each group's line the way
+    // `args` asks, and returns the report of the run.
+    fn logged_report(
+        config: run::RunConfig,
+        groups: Vec<(GroupKey, Vec<PathBuf>)>,
+        args: &Args,
+        color: bool,
+    ) -> io::Result<run::MergeReport> {
+        // `log` records only reach the test subscriber through the bridge.
+        let _ = tracing_log::LogTracer::init();
+        let discovery = run::Discovery {
+            groups,
+            ..run::Discovery::default()
+        };
+        // Groups finish on pool threads, outside the test's span, so each
+        // line is logged from within it for the captured logs to see.
+        let lines = GroupLines::new(args, color);
+        let span = tracing::Span::current();
+        let on_group = run::OnGroup(Arc::new(move |group, progress| {
+            let _entered = span.enter();
+            lines.log(group, progress);
+        }));
+        let config = run::RunConfig {
+            on_group: Some(on_group),
+            ..config
+        };
+        run::process(&config, discovery)
     }
 
     #[test]
-    fn test_group_name_formatting() {
-        let key1 = GroupKey::FilenameAndSize("video.mkv".to_string(), 2097152);
-        let key2 = GroupKey::SizeOnly(1048576);
+    #[tracing_test::traced_test]
+    fn test_group_report_threshold_suppresses_small_groups() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut groups = Vec::new();
+        for (name, size) in [("tiny.mkv", 10usize), ("large.mkv", 4096)] {
+            let a = dir.path().join(format!("a-{}", name));
+            let b = dir.path().join(format!("b-{}", name));
+            fs::write(&a, [0u8, 1].repeat(size / 2))?;
+            fs::write(&b, [1u8, 0].repeat(size / 2))?;
+            groups.push((
+                GroupKey::FilenameAndSize(name.to_string(), size as u64),
+                vec![a, b],
+            ));
+        }
+        let args = Args::parse_from(["torrent-combine", "root", "--group-report-threshold=1000"]);
+        let report = logged_report(run::RunConfig::default(), groups, &args, false)?;
+        assert_eq!(report.counts.merged, 2);
+        assert!(logs_contain("Group 'large.mkv@4096' merged"));
+        assert!(!logs_contain("Group 'tiny.mkv@10' merged"));
+        Ok(())
+    }
 
-        let name1 = match &key1 {
-            GroupKey::FilenameAndSize(basename, size) => format!("{}@{}", basename, size),
-            GroupKey::SizeOnly(size) => format!("size-{}", size),
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_color_mode_paints_status() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let args = Args::parse_from(["torrent-combine", "root"]);
+        let run_with = |mode: run::ColorMode, name: &str| -> io::Result<()> {
+            let a = dir.path().join(format!("a-{}", name));
+            let b = dir.path().join(format!("b-{}", name));
+            fs::write(&a, [1u8, 0])?;
+            fs::write(&b, [0u8, 2])?;
+            let group_key = GroupKey::FilenameAndSize(name.to_string(), 2);
+            let report = logged_report(
+                run::RunConfig::default(),
+                vec![(group_key, vec![a, b])],
+                &args,
+                mode.enabled(false),
+            )?;
+            assert_eq!(report.counts.merged, 1);
+            Ok(())
         };
 
-        let name2 = match &key2 {
-            GroupKey::FilenameAndSize(basename, size) => format!("{}@{}", basename, size),
-            GroupKey::SizeOnly(size) => format!("size-{}", size),
+        // The captured logs escape ESC, so match its escaped spelling.
+        run_with(run::ColorMode::Never, "plain")?;
+        assert!(logs_contain("Group 'plain@2' merged"));
+        assert!(!logs_contain("\\x1b["));
+        run_with(run::ColorMode::Always, "colored")?;
+        assert!(logs_contain("Group 'colored@2' \\x1b[32mmerged\\x1b[0m"));
+        assert!(!run::ColorMode::Auto.enabled(false));
+        Ok(())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_verify_only_groups_are_not_reported_complete() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.mkv");
+        let b = dir.path().join("b.mkv");
+        fs::write(&a, [1u8, 0])?;
+        fs::write(&b, [0u8, 2])?;
+        let config = run::RunConfig {
+            options: merger::MergeOptions::builder().verify_only(true).build(),
+            ..run::RunConfig::default()
         };
+        let args = Args::parse_from(["torrent-combine", "root", "--verify-only"]);
+        let group_key = GroupKey::FilenameAndSize("video.mkv".to_string(), 2);
+        let report = logged_report(config, vec![(group_key, vec![a, b])], &args, false)?;
+        assert_eq!(report.counts.skipped, 1);
+        assert!(logs_contain(
+            "Group 'video.mkv@2' verified (nothing written)"
+        ));
+        assert!(!logs_contain("all files complete"));
+        Ok(())
+    }
 
-        assert_eq!(name1, "video.mkv@2097152");
-        assert_eq!(name2, "size-1048576");
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_empty_groups_have_their_own_label() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.mkv");
+        let b = dir.path().join("b.mkv");
+        fs::write(&a, b"")?;
+        fs::write(&b, b"")?;
+        let config = run::RunConfig {
+            options: merger::MergeOptions::builder()
+                .include_zero_byte_files(true)
+                .build(),
+            ..run::RunConfig::default()
+        };
+        let args = Args::parse_from(["torrent-combine", "root", "--include-zero-byte-files"]);
+        let group_key = GroupKey::FilenameAndSize("video.mkv".to_string(), 0);
+        let report = logged_report(config, vec![(group_key, vec![a, b])], &args, true)?;
+        assert_eq!(report.counts.empty, 1);
+        assert!(logs_contain(
+            "Group 'video.mkv@0' \\x1b[33mempty\\x1b[0m (every member is zero bytes)"
+        ));
+        assert!(!logs_contain("skipped"));
+        Ok(())
     }
 }
@@ -1,16 +1,158 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use log::error;
-use tempfile::NamedTempFile;
+use rayon::prelude::*;
+use tempfile::{NamedTempFile, TempPath};
+
+use crate::aria2::{ControlFile, PieceState};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::patch;
+use crate::space;
+#[cfg(unix)]
+use crate::xattr;
+
+// Emits a `tracing` event for the group outcome inside the `process_group`
+// span when the `tracing` feature is enabled.
+macro_rules! trace_status {
+    ($status:literal) => {
+        #[cfg(feature = "tracing")]
+        tracing::info!(status = $status, "group finished");
+    };
+}
+
+/// Merge failures that need more context than the underlying I/O error.
+/// They travel inside `io::Error` and can be recovered with
+/// [`MergeError::from_io`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// The member ended at the given offset, before the group size.
+    TruncatedMember(PathBuf, u64),
+    /// An output or replacement could not be written at this path, or the
+    /// directory that would hold it is read-only.
+    OutputNotWritable(PathBuf),
+    /// Merged data read back from the temp file differed from what was
+    /// written, first at this offset.
+    WriteBackMismatch(u64),
+    /// The group ran longer than `group_timeout` and was abandoned before
+    /// writing any output.
+    TimedOut(Duration),
+    /// `drop_unreadable` left this many readable members, too few to merge.
+    TooFewReadable(usize),
+}
+
+impl MergeError {
+    pub fn from_io(e: &io::Error) -> Option<&MergeError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::TruncatedMember(path, offset) => {
+                write!(f, "member {:?} is truncated at offset {}", path, offset)
+            }
+            MergeError::OutputNotWritable(path) => {
+                write!(f, "output {:?} is not writable", path)
+            }
+            MergeError::WriteBackMismatch(offset) => {
+                write!(f, "merged data read back differs at offset {}", offset)
+            }
+            MergeError::TimedOut(timeout) => {
+                write!(f, "timed out after {:.1}s", timeout.as_secs_f64())
+            }
+            MergeError::TooFewReadable(left) => {
+                write!(f, "only {} member(s) left readable, too few to merge", left)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl From<MergeError> for io::Error {
+    fn from(e: MergeError) -> Self {
+        let kind = match e {
+            MergeError::TruncatedMember(..) => io::ErrorKind::UnexpectedEof,
+            MergeError::OutputNotWritable(..) => io::ErrorKind::PermissionDenied,
+            MergeError::WriteBackMismatch(..) => io::ErrorKind::InvalidData,
+            MergeError::TimedOut(..) => io::ErrorKind::TimedOut,
+            MergeError::TooFewReadable(..) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
 
 #[derive(Debug)]
 pub enum GroupStatus {
     Merged,
+    /// Nothing to write: every member is complete, or every member is zero
+    /// bytes and `include_zero_byte_files` is not set.
     Skipped,
+    /// Every member is zero bytes and `include_zero_byte_files` is set.
+    /// Zero-byte members of a group with data are dropped instead.
+    Empty,
     Failed,
+    /// Every member is complete yet they differ, e.g. different releases
+    /// that share a size.
+    Divergent,
+    SizeMismatch,
+    /// No member showed a zero byte in the quick probe of
+    /// `only_incomplete_groups`, so the full pass was skipped.
+    LikelyComplete,
+}
+
+/// Why a group was [`GroupStatus::Skipped`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Every member is complete.
+    AllComplete,
+    /// Every member is zero bytes.
+    ZeroByte,
+    /// A complete member makes writing unnecessary under
+    /// `skip_if_any_complete` or `output_most_complete`.
+    HasComplete,
+    /// Every output target is in use or already exists.
+    OutputsExist,
+    /// `verify_only` is set, so nothing is written.
+    VerifyOnly,
+    /// `fast_identical_check` found every member byte for byte equal.
+    AllIdentical,
+    /// Dropping members and counting hardlinks once left a single file.
+    SingleAfterDedup,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 7] = [
+        SkipReason::AllComplete,
+        SkipReason::ZeroByte,
+        SkipReason::HasComplete,
+        SkipReason::OutputsExist,
+        SkipReason::VerifyOnly,
+        SkipReason::AllIdentical,
+        SkipReason::SingleAfterDedup,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SkipReason::AllComplete => "all complete",
+            SkipReason::ZeroByte => "only zero-byte members",
+            SkipReason::HasComplete => "a member is already complete",
+            SkipReason::OutputsExist => "every output exists or is in use",
+            SkipReason::VerifyOnly => "verify only",
+            SkipReason::AllIdentical => "all identical",
+            SkipReason::SingleAfterDedup => "single member after dedup",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -19,63 +161,1136 @@ pub struct GroupStats {
     pub processing_time: Duration,
     pub bytes_processed: u64,
     pub merged_files: Vec<PathBuf>,
+    /// Every member is byte-for-byte identical to every other member and to
+    /// the merged data, so the reference or a verified piece filled nothing.
+    pub all_identical: bool,
+    /// Offset of the first conflicting byte for failed groups.
+    pub first_conflict: Option<u64>,
+    /// Every conflicting position, when the scan continued past the first.
+    pub conflicts: Option<ConflictCounts>,
+    /// Zero ranges of the merged output that no member could fill.
+    pub unrecovered: Option<UnrecoveredHoles>,
+    /// Length of a zero run at the end of the output that no member could
+    /// fill, when it is the only hole and `ignore_trailing_zeros` is set.
+    pub trailing_gap: Option<u64>,
+    /// Every member was incomplete and the merged output has no zero byte
+    /// left, apart from a `trailing_gap`. Data that legitimately contains
+    /// zeros never counts as fully reconstructed.
+    pub fully_reconstructed: bool,
+    /// Ranges each member gained, when `track_recovered` is set.
+    pub completeness: Option<CompletenessMap>,
+    /// Each written member paired with where its merged data landed;
+    /// replaced members pair with themselves.
+    pub outputs: Vec<(PathBuf, PathBuf)>,
+    /// Bytes the merge filled in the members it wrote, summed over them.
+    pub bytes_recovered: u64,
+    /// Why nothing was written, for skipped groups.
+    pub skip_reason: Option<SkipReason>,
+}
+
+// Only the first few hole ranges are kept; the totals still cover every hole.
+const MAX_REPORTED_HOLES: usize = 16;
+
+/// Byte ranges where the merged output is zero because every member was zero
+/// there. Such holes may be genuine zero data or data no member downloaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnrecoveredHoles {
+    pub bytes: u64,
+    pub holes: u64,
+    pub ranges: Vec<Range<u64>>,
+    // End of the last hole seen, so holes spanning chunks are joined.
+    last_end: Option<u64>,
+    // Keep every range instead of the first MAX_REPORTED_HOLES.
+    keep_all: bool,
+}
+
+impl UnrecoveredHoles {
+    /// Length of the only hole when it runs to the end of a `size` byte file.
+    pub fn trailing_gap(&self, size: u64) -> Option<u64> {
+        match self.ranges.as_slice() {
+            [range] if self.holes == 1 && range.end == size => Some(range.end - range.start),
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, offset: u64, merged: &[u8]) {
+        let mut i = 0;
+        while i < merged.len() {
+            if merged[i] != 0 {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < merged.len() && merged[i] == 0 {
+                i += 1;
+            }
+            let (start, end) = (offset + start as u64, offset + i as u64);
+            self.bytes += end - start;
+            if self.last_end == Some(start) {
+                if let Some(range) = self.ranges.last_mut()
+                    && range.end == start
+                {
+                    range.end = end;
+                }
+            } else {
+                self.holes += 1;
+                if self.keep_all || self.ranges.len() < MAX_REPORTED_HOLES {
+                    self.ranges.push(start..end);
+                }
+            }
+            self.last_end = Some(end);
+        }
+    }
+}
+
+/// Byte ranges each member gained from the merge, i.e. where the member was
+/// zero and the merged output is not. Only collected with `track_recovered`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompletenessMap {
+    /// One entry per member in group order, with `(offset, len)` runs.
+    pub files: Vec<(PathBuf, Vec<(u64, u64)>)>,
+    /// `(offset, len, donor)` runs of the output that some member lacked,
+    /// where `donor` indexes `files` and is the first member holding the
+    /// merged bytes, or `None` when only the reference file had them.
+    pub donors: Vec<(u64, u64, Option<usize>)>,
+}
+
+impl CompletenessMap {
+    fn new(paths: &[PathBuf]) -> CompletenessMap {
+        CompletenessMap {
+            files: paths.iter().map(|p| (p.clone(), Vec::new())).collect(),
+            donors: Vec::new(),
+        }
+    }
+
+    /// Recovered runs for `path`, if it is a member.
+    pub fn recovered(&self, path: &Path) -> Option<&[(u64, u64)]> {
+        self.files
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, runs)| runs.as_slice())
+    }
+
+    fn record(&mut self, member: usize, offset: u64, buffer: &[u8], merged: &[u8]) {
+        if buffer == merged {
+            return;
+        }
+        let runs = &mut self.files[member].1;
+        let recovered = |i: usize| buffer[i] == 0 && merged[i] != 0;
+        let mut i = 0;
+        while i < merged.len() {
+            if !recovered(i) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < merged.len() && recovered(i) {
+                i += 1;
+            }
+            let (start, len) = (offset + start as u64, (i - start) as u64);
+            match runs.last_mut() {
+                // Runs spanning chunks are joined.
+                Some((last, last_len)) if *last + *last_len == start => *last_len += len,
+                _ => runs.push((start, len)),
+            }
+        }
+    }
+
+    fn record_donors(&mut self, offset: u64, members: &[&[u8]], merged: &[u8]) {
+        if members.iter().all(|m| *m == merged) {
+            return;
+        }
+        for i in 0..merged.len() {
+            if merged[i] == 0 || members.iter().all(|m| m[i] != 0) {
+                continue;
+            }
+            let donor = members.iter().position(|m| m[i] == merged[i]);
+            let pos = offset + i as u64;
+            match self.donors.last_mut() {
+                Some((start, len, last)) if *start + *len == pos && *last == donor => *len += 1,
+                _ => self.donors.push((pos, 1, donor)),
+            }
+        }
+    }
+}
+
+/// Conflicting positions found by a full strict scan of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictCounts {
+    pub positions: u64,
+    pub first: u64,
+    pub last: u64,
+}
+
+/// How conflicting non-zero bytes between members are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConflictMode {
+    /// Any conflict fails the group.
+    #[default]
+    Strict,
+    /// Take the byte from the most preferred member that has data.
+    Salvage,
+    /// Take the most common non-zero byte, breaking ties by preference.
+    Majority,
+}
+
+/// What happens when a mirrored output already exists under `output_dir`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OnCollision {
+    /// Leave the existing file alone and skip the output.
+    #[default]
+    Skip,
+    /// Write to the first free `{stem}-N{ext}` next to it instead.
+    Suffix,
+    /// Replace the existing file.
+    Overwrite,
+}
+
+/// Directory merged outputs are mirrored into instead of sitting next to
+/// their members.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDir {
+    pub dir: PathBuf,
+    /// Outputs keep their path relative to this root; outputs outside it, or
+    /// without a root, land directly in `dir`.
+    pub root: Option<PathBuf>,
+}
+
+/// Member preference used to break ties in the non-strict conflict modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Prefer {
+    /// Group order.
+    #[default]
+    First,
+    /// Most recently modified member first.
+    Newer,
+    /// Least recently modified member first.
+    Older,
+}
+
+/// Member indices ordered from most to least preferred.
+fn preference_order(prefer: Prefer, mtimes: &[SystemTime]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..mtimes.len()).collect();
+    match prefer {
+        Prefer::First => {}
+        Prefer::Newer => order.sort_by(|&a, &b| mtimes[b].cmp(&mtimes[a])),
+        Prefer::Older => order.sort_by(|&a, &b| mtimes[a].cmp(&mtimes[b])),
+    }
+    order
+}
+
+/// Settings shared by every group of a run. `Default` merges strictly and
+/// writes `{name}.merged` next to each incomplete member.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeOptions {
+    pub replace: bool,
+    pub normalize_all: bool,
+    pub preview_diff: bool,
+    pub output_template: String,
+    pub conflict_mode: ConflictMode,
+    pub prefer: Prefer,
+    pub output_most_complete: bool,
+    pub chunk_bounds: ChunkBounds,
+    pub drop_unreadable: bool,
+    pub count_all_conflicts: bool,
+    pub warn_unrecovered: bool,
+    pub ignore_trailing_zeros: bool,
+    /// Percentage of the output re-read after writing to check it matches.
+    pub sample_verify: Option<f64>,
+    pub sample_seed: u64,
+    /// How many members of one group are read concurrently.
+    pub parallel_files_per_group: usize,
+    /// Prefix of every temp file name.
+    pub temp_prefix: String,
+    /// Name temp files after what they hold instead of randomly.
+    pub deterministic_temp: bool,
+    /// Collect a `CompletenessMap` of the ranges each member gained.
+    pub track_recovered: bool,
+    /// With `output_most_complete`, hardlink or copy the single merged file
+    /// over every member afterwards.
+    pub distribute: bool,
+    /// Leave members whose size differs from the majority out of the merge
+    /// instead of failing the group.
+    pub drop_size_outliers: bool,
+    /// Canonical directory that replaced members must resolve into; members
+    /// reached through symlinks pointing elsewhere are left alone.
+    pub replace_within: Option<PathBuf>,
+    /// Run the sanity check and merge but write no outputs.
+    pub verify_only: bool,
+    /// Directory to copy each group's merged temp into for inspection.
+    pub keep_merged_artifact: Option<PathBuf>,
+    /// Sync outputs and their directories before reporting success.
+    pub fsync: bool,
+    /// Read members through io_uring. Needs the `io-uring` feature on Linux;
+    /// otherwise, or when the ring can't be set up, reads stay synchronous.
+    pub io_uring: bool,
+    /// Mirror merged outputs into this directory.
+    pub output_dir: Option<OutputDir>,
+    /// Directory the members were scanned from, which rendered output
+    /// templates must stay inside; unset for path lists.
+    pub scan_root: Option<PathBuf>,
+    /// How an existing file at a mirrored output path is handled.
+    pub on_collision: OnCollision,
+    /// Write a patch of the recovered bytes, named after the output with
+    /// `.patch` appended, instead of a full merged copy.
+    pub emit_patch: bool,
+    /// Write nothing for groups that already have a complete member.
+    pub skip_if_any_complete: bool,
+    /// In the salvage and majority modes, give up on a group as divergent
+    /// once more than this many conflicting positions were resolved.
+    pub conflict_budget: Option<u64>,
+    /// Skip the full pass over groups whose members show no zero byte in a
+    /// quick probe of a few blocks each.
+    pub only_incomplete_groups: bool,
+    /// Directory the merged data is staged in before outputs are written,
+    /// instead of next to the group's first member.
+    pub temp_dir: Option<PathBuf>,
+    /// Drop members whose non-zero bytes disagree with the group's majority
+    /// at more than this percentage of positions, e.g. encrypted or garbage
+    /// files that only share a size.
+    pub xor_detect: Option<f64>,
+    /// Cap on the read buffers reserved by all concurrently running groups.
+    /// Clones of the options share it, so every group of a run draws on the
+    /// same budget; see [`MemoryBudget`].
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Collect every range of the merged output that no member could fill,
+    /// not just the first few, for a report of what is left to download.
+    pub report_unrecoverable: bool,
+    /// Read every merged chunk back from disk right after writing it and
+    /// fail the group if it differs.
+    pub paranoid: bool,
+    /// Append-only journal recording each step of writing an output.
+    pub audit_log: Option<PathBuf>,
+    /// Merge into a predictable temp file and checkpoint the progress, so
+    /// a merge interrupted part way continues where it stopped if its
+    /// inputs are unchanged.
+    pub resumable_temp: bool,
+    /// Scan zero-byte files too and report a group whose members are all
+    /// zero bytes as [`GroupStatus::Empty`] instead of skipped.
+    pub include_zero_byte_files: bool,
+    /// Abandon a group that takes longer than this. The merge gives up
+    /// between chunks and never starts writing outputs once it has passed.
+    pub group_timeout: Option<Duration>,
+    /// Stage every output of a group before renaming any into place, so a
+    /// failure while writing one leaves none of them behind.
+    pub atomic_group: bool,
+    /// Members that seed their group's merge, marked with `*` in a path
+    /// list. A seed is moved to the front of its group, sets the group's
+    /// size and is preferred over every other member when conflicts are
+    /// resolved.
+    pub seed_members: BTreeSet<PathBuf>,
+    /// Compare every member in full before the merge and skip the group
+    /// when all are equal, sparing the interleaved chunk reads.
+    pub fast_identical_check: bool,
+    /// Copy each member's extended attributes onto its merged output or
+    /// replacement.
+    pub preserve_xattrs: bool,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            replace: false,
+            normalize_all: false,
+            preview_diff: false,
+            output_template: DEFAULT_OUTPUT_TEMPLATE.to_string(),
+            conflict_mode: ConflictMode::default(),
+            prefer: Prefer::default(),
+            output_most_complete: false,
+            chunk_bounds: ChunkBounds::default(),
+            drop_unreadable: false,
+            count_all_conflicts: false,
+            warn_unrecovered: false,
+            ignore_trailing_zeros: false,
+            sample_verify: None,
+            sample_seed: 0,
+            parallel_files_per_group: 1,
+            temp_prefix: DEFAULT_TEMP_PREFIX.to_string(),
+            deterministic_temp: false,
+            track_recovered: false,
+            distribute: false,
+            drop_size_outliers: false,
+            replace_within: None,
+            scan_root: None,
+            verify_only: false,
+            keep_merged_artifact: None,
+            fsync: false,
+            io_uring: false,
+            output_dir: None,
+            on_collision: OnCollision::default(),
+            emit_patch: false,
+            skip_if_any_complete: false,
+            conflict_budget: None,
+            only_incomplete_groups: false,
+            temp_dir: None,
+            xor_detect: None,
+            memory_budget: None,
+            report_unrecoverable: false,
+            paranoid: false,
+            audit_log: None,
+            resumable_temp: false,
+            include_zero_byte_files: false,
+            group_timeout: None,
+            atomic_group: false,
+            seed_members: BTreeSet::new(),
+            fast_identical_check: false,
+            preserve_xattrs: false,
+        }
+    }
+}
+
+impl MergeOptions {
+    pub fn builder() -> MergeOptionsBuilder {
+        MergeOptionsBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptionsBuilder {
+    options: MergeOptions,
+}
+
+impl MergeOptionsBuilder {
+    /// Overwrite incomplete members in place instead of writing new files.
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.options.replace = replace;
+        self
+    }
+
+    /// With `replace`, also overwrite complete members of merged groups.
+    pub fn normalize_all(mut self, normalize_all: bool) -> Self {
+        self.options.normalize_all = normalize_all;
+        self
+    }
+
+    /// Log a hexdump around the first conflict of failed groups.
+    pub fn preview_diff(mut self, preview_diff: bool) -> Self {
+        self.options.preview_diff = preview_diff;
+        self
+    }
+
+    /// Naming template for merged files, see [`render_output_path`].
+    pub fn output_template(mut self, template: impl Into<String>) -> Self {
+        self.options.output_template = template.into();
+        self
+    }
+
+    pub fn conflict_mode(mut self, mode: ConflictMode) -> Self {
+        self.options.conflict_mode = mode;
+        self
+    }
+
+    pub fn prefer(mut self, prefer: Prefer) -> Self {
+        self.options.prefer = prefer;
+        self
+    }
+
+    /// Only write output for the member with the fewest missing bytes.
+    pub fn output_most_complete(mut self, output_most_complete: bool) -> Self {
+        self.options.output_most_complete = output_most_complete;
+        self
+    }
+
+    pub fn chunk_bounds(mut self, bounds: ChunkBounds) -> Self {
+        self.options.chunk_bounds = bounds;
+        self
+    }
+
+    /// Continue without members that fail to read mid-merge.
+    pub fn drop_unreadable(mut self, drop_unreadable: bool) -> Self {
+        self.options.drop_unreadable = drop_unreadable;
+        self
+    }
+
+    /// In strict mode, keep scanning failed groups to count every conflict.
+    pub fn count_all_conflicts(mut self, count_all_conflicts: bool) -> Self {
+        self.options.count_all_conflicts = count_all_conflicts;
+        self
+    }
+
+    /// Report zero ranges of the merged output that no member could fill.
+    pub fn warn_unrecovered(mut self, warn_unrecovered: bool) -> Self {
+        self.options.warn_unrecovered = warn_unrecovered;
+        self
+    }
+
+    /// Treat a zero run at the end that no member could fill as a known gap.
+    pub fn ignore_trailing_zeros(mut self, ignore_trailing_zeros: bool) -> Self {
+        self.options.ignore_trailing_zeros = ignore_trailing_zeros;
+        self
+    }
+
+    /// Re-read about `percent`% of each output after writing it.
+    pub fn sample_verify(mut self, percent: Option<f64>) -> Self {
+        self.options.sample_verify = percent;
+        self
+    }
+
+    /// Seed choosing which blocks `sample_verify` re-reads.
+    pub fn sample_seed(mut self, seed: u64) -> Self {
+        self.options.sample_seed = seed;
+        self
+    }
+
+    pub fn parallel_files_per_group(mut self, parallel: usize) -> Self {
+        self.options.parallel_files_per_group = parallel;
+        self
+    }
+
+    pub fn temp_prefix(mut self, prefix: &str) -> Self {
+        self.options.temp_prefix = prefix.to_string();
+        self
+    }
+
+    pub fn deterministic_temp(mut self, deterministic: bool) -> Self {
+        self.options.deterministic_temp = deterministic;
+        self
+    }
+
+    pub fn track_recovered(mut self, track_recovered: bool) -> Self {
+        self.options.track_recovered = track_recovered;
+        self
+    }
+
+    pub fn distribute(mut self, distribute: bool) -> Self {
+        self.options.distribute = distribute;
+        self
+    }
+
+    pub fn drop_size_outliers(mut self, drop_size_outliers: bool) -> Self {
+        self.options.drop_size_outliers = drop_size_outliers;
+        self
+    }
+
+    pub fn replace_within(mut self, root: Option<PathBuf>) -> Self {
+        self.options.replace_within = root;
+        self
+    }
+
+    pub fn verify_only(mut self, verify_only: bool) -> Self {
+        self.options.verify_only = verify_only;
+        self
+    }
+
+    pub fn keep_merged_artifact(mut self, dir: Option<PathBuf>) -> Self {
+        self.options.keep_merged_artifact = dir;
+        self
+    }
+
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.options.fsync = fsync;
+        self
+    }
+
+    pub fn io_uring(mut self, io_uring: bool) -> Self {
+        self.options.io_uring = io_uring;
+        self
+    }
+
+    pub fn scan_root(mut self, root: Option<PathBuf>) -> Self {
+        self.options.scan_root = root;
+        self
+    }
+
+    pub fn output_dir(mut self, output_dir: Option<OutputDir>) -> Self {
+        self.options.output_dir = output_dir;
+        self
+    }
+
+    pub fn on_collision(mut self, on_collision: OnCollision) -> Self {
+        self.options.on_collision = on_collision;
+        self
+    }
+
+    pub fn emit_patch(mut self, emit_patch: bool) -> Self {
+        self.options.emit_patch = emit_patch;
+        self
+    }
+
+    pub fn skip_if_any_complete(mut self, skip_if_any_complete: bool) -> Self {
+        self.options.skip_if_any_complete = skip_if_any_complete;
+        self
+    }
+
+    pub fn conflict_budget(mut self, conflict_budget: Option<u64>) -> Self {
+        self.options.conflict_budget = conflict_budget;
+        self
+    }
+
+    pub fn only_incomplete_groups(mut self, only_incomplete_groups: bool) -> Self {
+        self.options.only_incomplete_groups = only_incomplete_groups;
+        self
+    }
+
+    pub fn temp_dir(mut self, temp_dir: Option<PathBuf>) -> Self {
+        self.options.temp_dir = temp_dir;
+        self
+    }
+
+    pub fn xor_detect(mut self, percent: Option<f64>) -> Self {
+        self.options.xor_detect = percent;
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: Option<u64>) -> Self {
+        self.options.memory_budget = bytes.map(|limit| Arc::new(MemoryBudget::new(limit)));
+        self
+    }
+
+    pub fn report_unrecoverable(mut self, report_unrecoverable: bool) -> Self {
+        self.options.report_unrecoverable = report_unrecoverable;
+        self
+    }
+
+    pub fn paranoid(mut self, paranoid: bool) -> Self {
+        self.options.paranoid = paranoid;
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: Option<PathBuf>) -> Self {
+        self.options.audit_log = audit_log;
+        self
+    }
+
+    pub fn resumable_temp(mut self, resumable: bool) -> Self {
+        self.options.resumable_temp = resumable;
+        self
+    }
+
+    pub fn include_zero_byte_files(mut self, include: bool) -> Self {
+        self.options.include_zero_byte_files = include;
+        self
+    }
+
+    pub fn group_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.group_timeout = timeout;
+        self
+    }
+
+    pub fn atomic_group(mut self, atomic: bool) -> Self {
+        self.options.atomic_group = atomic;
+        self
+    }
+
+    pub fn seed_members(mut self, seeds: BTreeSet<PathBuf>) -> Self {
+        self.options.seed_members = seeds;
+        self
+    }
+
+    pub fn fast_identical_check(mut self, check: bool) -> Self {
+        self.options.fast_identical_check = check;
+        self
+    }
+
+    pub fn preserve_xattrs(mut self, preserve: bool) -> Self {
+        self.options.preserve_xattrs = preserve;
+        self
+    }
+
+    pub fn build(self) -> MergeOptions {
+        self.options
+    }
+}
+
+pub enum SanityOutcome {
+    Passed {
+        temp: NamedTempFile,
+        is_complete: Vec<bool>,
+        all_identical: bool,
+        resolved_conflicts: u64,
+        missing_bytes: Vec<u64>,
+        unrecovered: Option<UnrecoveredHoles>,
+        /// The merged output still holds a zero byte no member could fill.
+        has_gaps: bool,
+        completeness: Option<CompletenessMap>,
+        samples: Vec<Sample>,
+    },
+    Conflict {
+        offset: u64,
+        counts: Option<ConflictCounts>,
+    },
+    /// A tolerant conflict mode resolved more conflicting positions than
+    /// `conflict_budget` allows; `offset` is where the budget ran out.
+    ConflictBudgetExceeded {
+        offset: u64,
+        conflicts: u64,
+    },
+    SizeMismatch {
+        path: PathBuf,
+    },
+    Empty,
 }
 
-pub fn process_group(paths: &[PathBuf], basename: &str, replace: bool) -> io::Result<GroupStats> {
+pub fn process_group(
+    paths: &[PathBuf],
+    basename: &str,
+    reference: Option<&Path>,
+    options: &MergeOptions,
+) -> io::Result<GroupStats> {
     let start_time = Instant::now();
     log::debug!("Processing paths for group {}: {:?}", basename, paths);
 
-    let bytes_processed = if !paths.is_empty() {
-        fs::metadata(&paths[0])?.len()
+    let seeded: Vec<PathBuf>;
+    let paths = match seed_first(paths, options, basename) {
+        Some(reordered) => {
+            seeded = reordered;
+            &seeded
+        }
+        None => paths,
+    };
+    let with_data: Vec<PathBuf>;
+    let paths = match drop_zero_byte_members(paths, basename)? {
+        Some(kept) => {
+            with_data = kept;
+            &with_data
+        }
+        None => paths,
+    };
+    let bytes_processed = group_size(paths, options)?;
+    let kept: Vec<PathBuf>;
+    let paths = if options.drop_size_outliers {
+        kept = drop_size_outliers(paths, bytes_processed, basename)?;
+        &kept
     } else {
-        0
+        paths
+    };
+    let kept_content: Vec<PathBuf>;
+    let paths = match options.xor_detect {
+        Some(percent) => {
+            kept_content = drop_junk_members(paths, bytes_processed, percent, basename)?;
+            &kept_content
+        }
+        None => paths,
     };
 
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "process_group",
+        group = basename,
+        members = paths.len(),
+        size = bytes_processed
+    )
+    .entered();
+
     if bytes_processed == 0 {
+        let status = if options.include_zero_byte_files {
+            trace_status!("empty");
+            log::debug!("Group {} has only zero-byte members", basename);
+            GroupStatus::Empty
+        } else {
+            trace_status!("skipped");
+            GroupStatus::Skipped
+        };
+        return Ok(GroupStats {
+            skip_reason: matches!(status, GroupStatus::Skipped).then_some(SkipReason::ZeroByte),
+            status,
+            processing_time: start_time.elapsed(),
+            bytes_processed,
+            merged_files: Vec::new(),
+            all_identical: false,
+            first_conflict: None,
+            conflicts: None,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+        });
+    }
+
+    // The steps above may have dropped members, and hardlinked paths are one
+    // file; with a single file left there is nothing to merge, unless a
+    // reference can complete it.
+    if reference.is_none() && distinct_files(paths)? < 2 {
+        trace_status!("skipped");
+        log::debug!(
+            "Skipped group {} (a single member after dropping members and hardlinks)",
+            basename
+        );
+        return Ok(GroupStats {
+            status: GroupStatus::Skipped,
+            processing_time: start_time.elapsed(),
+            bytes_processed,
+            merged_files: Vec::new(),
+            all_identical: false,
+            first_conflict: None,
+            conflicts: None,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+            skip_reason: Some(SkipReason::SingleAfterDedup),
+        });
+    }
+
+    if options.only_incomplete_groups && !quick_probe_has_zeros(paths, bytes_processed, options)? {
+        trace_status!("likely-complete");
+        log::debug!(
+            "Skipped group {} (no zeros in a quick probe, likely complete)",
+            basename
+        );
+        return Ok(GroupStats {
+            status: GroupStatus::LikelyComplete,
+            processing_time: start_time.elapsed(),
+            bytes_processed,
+            merged_files: Vec::new(),
+            all_identical: false,
+            first_conflict: None,
+            conflicts: None,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+            skip_reason: None,
+        });
+    }
+
+    // A reference can add data and an artifact needs the merged data, so
+    // both still take the full pass.
+    if options.fast_identical_check
+        && reference.is_none()
+        && options.keep_merged_artifact.is_none()
+        && members_identical(paths, bytes_processed, options, start_time)?
+    {
+        trace_status!("skipped");
+        log::debug!(
+            "Skipped group {} (all identical by full-file comparison)",
+            basename
+        );
         return Ok(GroupStats {
             status: GroupStatus::Skipped,
             processing_time: start_time.elapsed(),
             bytes_processed,
             merged_files: Vec::new(),
+            all_identical: true,
+            first_conflict: None,
+            conflicts: None,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+            skip_reason: Some(SkipReason::AllIdentical),
         });
     }
 
-    let res = check_sanity_and_completes(paths)?;
+    let mut mtimes = Vec::with_capacity(paths.len());
+    for p in paths {
+        mtimes.push(fs::metadata(p)?.modified()?);
+    }
+    let res = check_sanity_and_completes(paths, reference, &mtimes, options)?;
 
-    if let Some((temp, is_complete)) = res {
-        log::info!("Sanity check passed for group {}", basename);
+    if let SanityOutcome::Passed {
+        temp,
+        is_complete,
+        all_identical,
+        resolved_conflicts,
+        missing_bytes,
+        unrecovered,
+        has_gaps,
+        completeness,
+        samples,
+    } = res
+    {
+        log::debug!("Sanity check passed for group {}", basename);
+        if let Some(timeout) = options.group_timeout
+            && start_time.elapsed() >= timeout
+        {
+            trace_status!("timed-out");
+            return Err(MergeError::TimedOut(timeout).into());
+        }
+        let trailing_gap = unrecovered
+            .as_ref()
+            .filter(|_| options.ignore_trailing_zeros)
+            .and_then(|holes| holes.trailing_gap(bytes_processed));
+        if let Some(gap) = trailing_gap {
+            log::debug!(
+                "Group {} has a trailing gap of {} bytes no member could fill",
+                basename,
+                gap
+            );
+        } else if let Some(holes) = &unrecovered
+            && options.warn_unrecovered
+            && holes.bytes > 0
+        {
+            log::warn!(
+                "Group {} has {} unrecovered bytes in {} holes no member could fill: {:?}{}",
+                basename,
+                holes.bytes,
+                holes.holes,
+                &holes.ranges[..holes.ranges.len().min(MAX_REPORTED_HOLES)],
+                if holes.holes > MAX_REPORTED_HOLES as u64 {
+                    " ..."
+                } else {
+                    ""
+                }
+            );
+        }
+        if resolved_conflicts > 0 {
+            log::warn!(
+                "Resolved {} conflicting bytes in group {} ({:?}, prefer {:?})",
+                resolved_conflicts,
+                basename,
+                options.conflict_mode,
+                options.prefer
+            );
+        }
+        let all_incomplete = is_complete.iter().all(|c| !c);
+        // With `ignore_trailing_zeros` an unfilled tail is not a gap.
+        let fully_reconstructed = all_incomplete && (!has_gaps || trailing_gap.is_some());
+        if fully_reconstructed {
+            log::debug!(
+                "Group {} fully reconstructed from {} incomplete members",
+                basename,
+                paths.len()
+            );
+        } else if all_incomplete {
+            log::debug!(
+                "Group {} merged from incomplete members but still has gaps",
+                basename
+            );
+        }
 
-        let any_incomplete = is_complete.iter().any(|&c| !c);
-        if any_incomplete {
+        let targets: Vec<usize> = if options.output_most_complete {
+            let best = (0..paths.len())
+                .min_by_key(|&j| missing_bytes[j])
+                .expect("group has members");
+            if is_complete[best] {
+                log::debug!(
+                    "Most complete member {:?} of group {} is already complete",
+                    paths[best],
+                    basename
+                );
+                Vec::new()
+            } else {
+                vec![best]
+            }
+        } else {
+            (0..paths.len()).filter(|&j| !is_complete[j]).collect()
+        };
+        let targets = match is_complete.iter().position(|&c| c) {
+            Some(complete) if options.skip_if_any_complete && !targets.is_empty() => {
+                log::debug!(
+                    "Not merging group {}: {:?} is already complete",
+                    basename,
+                    paths[complete]
+                );
+                Vec::new()
+            }
+            _ => targets,
+        };
+        let targets = if options.replace && options.normalize_all && !targets.is_empty() {
+            (0..paths.len()).collect()
+        } else {
+            targets
+        };
+        if let Some(dir) = &options.keep_merged_artifact {
+            let artifact = keep_artifact(temp.path(), dir, basename, options)?;
+            log::debug!(
+                "Kept merged artifact of group {} at {:?}",
+                basename,
+                artifact
+            );
+        }
+        let targets = if options.verify_only {
+            log::debug!(
+                "Verified group {}: {} of {} members would be written",
+                basename,
+                targets.len(),
+                paths.len()
+            );
+            Vec::new()
+        } else {
+            targets
+        };
+        if !targets.is_empty() {
+            let audit = AuditLog::new(options.audit_log.as_deref(), basename);
             let mut merged_files = Vec::new();
-            for (j, &complete) in is_complete.iter().enumerate() {
-                if !complete {
-                    let path = &paths[j];
-                    let parent = path.parent().ok_or(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "No parent directory",
-                    ))?;
-                    let local_temp = NamedTempFile::new_in(parent)?;
-                    fs::copy(temp.path(), local_temp.path())?;
-                    if replace {
-                        fs::rename(local_temp.path(), path)?;
-                        log::debug!("Replaced original {:?} with merged content", path);
+            let mut outputs = Vec::new();
+            let mut written = 0;
+            let mut bytes_recovered = 0;
+            let mut committed = |(j, target): (usize, PathBuf)| {
+                if options.replace {
+                    outputs.push((target.clone(), target));
+                } else {
+                    if !options.emit_patch {
+                        outputs.push((paths[j].clone(), target.clone()));
+                    }
+                    merged_files.push(target);
+                }
+                written += 1;
+                bytes_recovered += missing_bytes[j];
+            };
+            // Every output path is resolved and checked before any is
+            // written, so a bad template fails the group without leaving
+            // outputs behind. `None` marks an output skipped as existing.
+            let mut rendered: Vec<Option<PathBuf>> = Vec::with_capacity(targets.len());
+            if !options.replace {
+                for &j in &targets {
+                    let mut merged_path = output_path(options, &paths[j])?;
+                    if options.emit_patch {
+                        merged_path.as_mut_os_string().push(".patch");
+                    }
+                    let taken: Vec<&PathBuf> = rendered.iter().flatten().collect();
+                    if collides_with_member(&merged_path, paths)? {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Output {:?} collides with a group member", merged_path),
+                        ));
+                    }
+                    if options.output_dir.is_some() {
+                        match resolve_collision(merged_path, options.on_collision, &taken) {
+                            Ok(target) => rendered.push(Some(target)),
+                            Err(existing) => {
+                                log::warn!(
+                                    "Skipping output {:?} for group {}: it already exists",
+                                    existing,
+                                    basename
+                                );
+                                rendered.push(None);
+                            }
+                        }
+                    } else if taken.contains(&&merged_path) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "Output {:?} collides with another output of the group",
+                                merged_path
+                            ),
+                        ));
                     } else {
-                        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
-                        let merged_path = parent.join(format!("{}.merged", file_name));
-                        local_temp.persist(&merged_path)?;
-                        log::debug!(
-                            "Created merged file {:?} for incomplete original {:?}",
-                            merged_path,
-                            path
+                        rendered.push(Some(merged_path));
+                    }
+                }
+            }
+            let mut staged = Vec::new();
+            for (k, &j) in targets.iter().enumerate() {
+                let path = &paths[j];
+                let parent = member_dir(path);
+                let target = if options.replace {
+                    path.clone()
+                } else {
+                    match rendered[k].take() {
+                        Some(target) => target,
+                        None => continue,
+                    }
+                };
+                let Some(claim) = TargetClaim::acquire(&target) else {
+                    log::warn!(
+                        "Skipping write of {:?} for group {}: another group is writing it",
+                        target,
+                        basename
+                    );
+                    continue;
+                };
+                let staged_temp = if options.replace {
+                    if let Some(root) = &options.replace_within
+                        && !fs::canonicalize(path)?.starts_with(root)
+                    {
+                        log::warn!(
+                            "Refusing to replace {:?} in group {}: it resolves outside {:?}",
+                            path,
+                            basename,
+                            root
                         );
-                        merged_files.push(merged_path);
+                        continue;
                     }
+                    ensure_writable(parent)?;
+                    audit.record(AuditEvent::IntendReplace, path, &staging_path(path))?;
+                    stage_replacement(temp.path(), path, &audit)
+                        .map_err(|e| output_error(e, path))?
+                } else {
+                    let merged_dir = target.parent().unwrap_or(parent);
+                    fs::create_dir_all(merged_dir).map_err(|e| output_error(e, &target))?;
+                    ensure_writable(member_dir(&target))?;
+                    let local_temp = create_temp(merged_dir, &target, options)
+                        .map_err(|e| output_error(e, &target))?;
+                    let local_path = local_temp.path().to_path_buf();
+                    audit.record(AuditEvent::IntendWrite, &target, &local_path)?;
+                    if options.emit_patch {
+                        let carried = patch::write_patch(path, temp.path(), local_temp.as_file())?;
+                        log::debug!("Patch {:?} carries {} bytes", target, carried);
+                    } else {
+                        fs::copy(temp.path(), local_temp.path())?;
+                    }
+                    if options.fsync {
+                        sync_path(&local_path)?;
+                    }
+                    audit.record(AuditEvent::WroteTemp, &target, &local_path)?;
+                    local_temp.into_temp_path()
+                };
+                let output = StagedOutput {
+                    member: j,
+                    target,
+                    temp: staged_temp,
+                    _claim: claim,
+                };
+                if options.atomic_group {
+                    staged.push(output);
+                } else {
+                    committed(output.commit(paths, options, &samples, &audit)?);
                 }
             }
-            log::info!(
+            // Every output is staged; an error before here dropped them all.
+            if !staged.is_empty() {
+                log::debug!(
+                    "Staged all {} outputs of group {}, renaming them into place",
+                    staged.len(),
+                    basename
+                );
+            }
+            for output in staged {
+                committed(output.commit(paths, options, &samples, &audit)?);
+            }
+            if options.distribute
+                && options.output_most_complete
+                && let [merged] = merged_files.as_slice()
+            {
+                let linked = distribute(merged, paths, options, &audit)?;
+                log::debug!(
+                    "Distributed merged output of group {} to {} members ({} hardlinked)",
+                    basename,
+                    paths.len(),
+                    linked
+                );
+            }
+            if written == 0 {
+                trace_status!("skipped");
+                log::warn!(
+                    "Skipped group {}: every target is in use or already exists",
+                    basename
+                );
+                return Ok(GroupStats {
+                    status: GroupStatus::Skipped,
+                    skip_reason: Some(SkipReason::OutputsExist),
+                    processing_time: start_time.elapsed(),
+                    bytes_processed,
+                    merged_files,
+                    all_identical,
+                    first_conflict: None,
+                    conflicts: None,
+                    unrecovered,
+                    trailing_gap,
+                    fully_reconstructed,
+                    completeness,
+                    bytes_recovered,
+                    outputs,
+                });
+            }
+            trace_status!("merged");
+            log::debug!(
                 "Completed {} for group {}",
-                if replace { "replacement" } else { "merge" },
+                if options.replace {
+                    "replacement"
+                } else {
+                    "merge"
+                },
                 basename
             );
             Ok(GroupStats {
@@ -83,28 +1298,976 @@ pub fn process_group(paths: &[PathBuf], basename: &str, replace: bool) -> io::Re
                 processing_time: start_time.elapsed(),
                 bytes_processed,
                 merged_files,
+                all_identical,
+                first_conflict: None,
+                conflicts: None,
+                unrecovered,
+                trailing_gap,
+                fully_reconstructed,
+                completeness,
+                bytes_recovered,
+                outputs,
+                skip_reason: None,
             })
         } else {
-            log::info!(
-                "Skipped group {} (all complete, no action needed)",
-                basename
-            );
+            trace_status!("skipped");
+            let reason = if is_complete.iter().all(|&c| c) {
+                SkipReason::AllComplete
+            } else if options.verify_only {
+                SkipReason::VerifyOnly
+            } else {
+                SkipReason::HasComplete
+            };
+            if !options.verify_only {
+                log::debug!(
+                    "Skipped group {} ({}{}, no action needed)",
+                    basename,
+                    reason.label(),
+                    if all_identical { ", identical" } else { "" }
+                );
+            }
             Ok(GroupStats {
                 status: GroupStatus::Skipped,
+                skip_reason: Some(reason),
                 processing_time: start_time.elapsed(),
                 bytes_processed,
                 merged_files: Vec::new(),
+                all_identical,
+                first_conflict: None,
+                conflicts: None,
+                unrecovered,
+                trailing_gap,
+                fully_reconstructed: false,
+                completeness,
+                bytes_recovered: 0,
+                outputs: Vec::new(),
             })
         }
-    } else {
-        error!("Failed sanity check for group: {}", basename);
+    } else if let SanityOutcome::SizeMismatch { path } = res {
+        trace_status!("size-mismatch");
+        error!("Size mismatch in group {} for path {:?}", basename, path);
         Ok(GroupStats {
-            status: GroupStatus::Failed,
+            status: GroupStatus::SizeMismatch,
             processing_time: start_time.elapsed(),
             bytes_processed,
             merged_files: Vec::new(),
+            all_identical: false,
+            first_conflict: None,
+            conflicts: None,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+            skip_reason: None,
         })
-    }
+    } else {
+        let status = match res {
+            SanityOutcome::Conflict { .. } if !any_member_has_zeros(paths)? => {
+                trace_status!("divergent");
+                error!(
+                    "Group {} has only complete members, but they differ",
+                    basename
+                );
+                GroupStatus::Divergent
+            }
+            SanityOutcome::ConflictBudgetExceeded { offset, conflicts } => {
+                trace_status!("divergent");
+                error!(
+                    "Group {} exceeded its conflict budget with {} conflicting positions by offset {}",
+                    basename, conflicts, offset
+                );
+                GroupStatus::Divergent
+            }
+            _ => {
+                trace_status!("failed");
+                error!("Failed sanity check for group: {}", basename);
+                GroupStatus::Failed
+            }
+        };
+        let mut first_conflict = None;
+        let mut conflicts = None;
+        if let SanityOutcome::ConflictBudgetExceeded { offset, .. } = res {
+            first_conflict = Some(offset);
+        }
+        if let SanityOutcome::Conflict { offset, counts } = res {
+            first_conflict = Some(offset);
+            conflicts = counts;
+            log::debug!("First conflict for group {} at offset {}", basename, offset);
+            if let Some(c) = counts {
+                error!(
+                    "Group {} has {} conflicting positions between offsets {} and {}",
+                    basename, c.positions, c.first, c.last
+                );
+            }
+            if options.preview_diff {
+                log::warn!(
+                    "Conflict preview for group {}:\n{}",
+                    basename,
+                    format_conflict_preview(paths, offset)?
+                );
+            }
+        }
+        Ok(GroupStats {
+            status,
+            processing_time: start_time.elapsed(),
+            bytes_processed,
+            merged_files: Vec::new(),
+            all_identical: false,
+            first_conflict,
+            conflicts,
+            unrecovered: None,
+            trailing_gap: None,
+            fully_reconstructed: false,
+            completeness: None,
+            bytes_recovered: 0,
+            outputs: Vec::new(),
+            skip_reason: None,
+        })
+    }
+}
+
+// Whether any member still holds a zero byte, i.e. may be incomplete. Stops
+// at the first zero, so only complete members are read to the end.
+fn any_member_has_zeros(paths: &[PathBuf]) -> io::Result<bool> {
+    let mut buffer = vec![0; DEFAULT_CHUNK_SIZE];
+    for path in paths {
+        let mut file = File::open(path)?;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            if buffer[..read].contains(&0) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Blocks the quick zero probe reads from the middle of each member, besides
+// its first and last chunk.
+const QUICK_PROBE_BLOCKS: u64 = 16;
+
+// Whether any member shows a zero byte in its first or last chunk or in
+// `QUICK_PROBE_BLOCKS` evenly spaced blocks. A gap elsewhere goes unseen.
+// Members not of `size` bytes count as having zeros, so the full pass
+// reports them.
+fn quick_probe_has_zeros(paths: &[PathBuf], size: u64, options: &MergeOptions) -> io::Result<bool> {
+    let chunk = DEFAULT_CHUNK_SIZE.clamp(options.chunk_bounds.min, options.chunk_bounds.max) as u64;
+    let mut windows = vec![(0, chunk.min(size))];
+    windows.push((size.saturating_sub(chunk), chunk.min(size)));
+    for i in 1..=QUICK_PROBE_BLOCKS {
+        let offset = size / (QUICK_PROBE_BLOCKS + 1) * i;
+        windows.push((offset, SAMPLE_BLOCK.min(size - offset)));
+    }
+    let mut buffer = Vec::new();
+    for path in paths {
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() != size {
+            return Ok(true);
+        }
+        for &(offset, len) in &windows {
+            buffer.resize(len as usize, 0);
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            if buffer.contains(&0) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Copies the merged temp of group `basename` into `dir` for inspection,
+// replacing an artifact left by an earlier run.
+fn keep_artifact(
+    temp: &Path,
+    dir: &Path,
+    basename: &str,
+    options: &MergeOptions,
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let artifact = dir.join(format!("{}.or", basename.replace('/', "_")));
+    let local_temp = create_temp(dir, &artifact, options)?;
+    fs::copy(temp, local_temp.path())?;
+    local_temp.persist(&artifact)?;
+    Ok(artifact)
+}
+
+// Flushes the file or directory at `path` to stable storage. Outputs are
+// synced before they are renamed into place and their directory after.
+fn sync_path(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()?;
+    record_sync(path);
+    Ok(())
+}
+
+// Tests check which paths were synced; outside them nothing is recorded.
+#[cfg(test)]
+use tests::record_sync;
+#[cfg(not(test))]
+fn record_sync(_path: &Path) {}
+
+// Replaces every member with `source`, hardlinking when the filesystem
+// allows it and copying otherwise. Returns how many members were hardlinked.
+fn distribute(
+    source: &Path,
+    members: &[PathBuf],
+    options: &MergeOptions,
+    audit: &AuditLog,
+) -> io::Result<usize> {
+    let mut linked = 0;
+    for member in members {
+        if same_file(member, source)? {
+            // Renaming over another link to the same file would be a no-op.
+            linked += 1;
+            continue;
+        }
+        if let Some(root) = &options.replace_within
+            && !fs::canonicalize(member)?.starts_with(root)
+        {
+            log::warn!(
+                "Not distributing to {:?}: it resolves outside {:?}",
+                member,
+                root
+            );
+            continue;
+        }
+        let Some(_claim) = TargetClaim::acquire(member) else {
+            log::warn!(
+                "Not distributing to {:?}: another group is writing it",
+                member
+            );
+            continue;
+        };
+        let temp = create_temp(member_dir(member), member, options)?.into_temp_path();
+        audit.record(AuditEvent::IntendReplace, member, &temp)?;
+        fs::remove_file(&temp)?;
+        match fs::hard_link(source, &temp) {
+            Ok(()) => linked += 1,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported
+                ) =>
+            {
+                log::debug!("Copying to {:?} instead of hardlinking: {}", member, e);
+                fs::copy(source, &temp)?;
+            }
+            Err(e) => return Err(e),
+        }
+        if options.fsync {
+            sync_path(&temp)?;
+        }
+        let temp_path = temp.to_path_buf();
+        audit.record(AuditEvent::WroteTemp, member, &temp_path)?;
+        temp.persist(member).map_err(|e| e.error)?;
+        audit.record(AuditEvent::Renamed, member, &temp_path)?;
+        if options.fsync {
+            sync_path(member_dir(member))?;
+        }
+    }
+    Ok(linked)
+}
+
+// Whether `a` and `b` are links to the same file.
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// Without inode numbers only different spellings of one path are caught.
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(fs::canonicalize(a)? == fs::canonicalize(b)?)
+}
+
+// Output and replacement paths currently being written. The same physical
+// file can reach two groups through overlapping roots or symlinks, and two
+// threads writing it at once would race.
+static IN_FLIGHT_TARGETS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Exclusive claim on a write target, released on drop.
+struct TargetClaim {
+    key: PathBuf,
+}
+
+impl TargetClaim {
+    /// Claims `path`, or returns `None` if another group holds it.
+    fn acquire(path: &Path) -> Option<TargetClaim> {
+        let key = target_key(path);
+        let mut in_flight = IN_FLIGHT_TARGETS.lock().unwrap_or_else(|e| e.into_inner());
+        if !in_flight.insert(key.clone()) {
+            return None;
+        }
+        Some(TargetClaim { key })
+    }
+}
+
+impl Drop for TargetClaim {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT_TARGETS.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(&self.key);
+    }
+}
+
+/// Read buffer bytes reserved by running groups, shared across threads so
+/// `--max-memory` bounds their sum. [`run::process`](crate::run::process)
+/// reserves each group's buffers before starting it and holds the group back
+/// until they fit; a group merged on its own only sizes its buffers to it.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    usage: Mutex<BudgetUsage>,
+    freed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BudgetUsage {
+    in_use: u64,
+    peak: u64,
+}
+
+// Options are equal when they share a budget.
+impl PartialEq for MemoryBudget {
+    fn eq(&self, other: &MemoryBudget) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// How long a reservation waiting on a rayon worker with nothing else to run
+// sleeps before looking again.
+const RESERVE_POLL: Duration = Duration::from_millis(10);
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            usage: Mutex::new(BudgetUsage::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Bytes a group of `count` buffers reserves, and the chunk bounds it
+    /// keeps to: the largest chunks within `bounds` that fit the limit. A
+    /// group too big for the limit even at `bounds.min` reserves all of it,
+    /// so it runs alone.
+    pub fn plan(&self, count: usize, bounds: ChunkBounds) -> (u64, ChunkBounds) {
+        let count = count.max(1) as u64;
+        let max = (self.limit / count).clamp(bounds.min as u64, bounds.max as u64);
+        let bytes = (count * max).min(self.limit);
+        (
+            bytes,
+            ChunkBounds {
+                min: bounds.min,
+                max: max as usize,
+            },
+        )
+    }
+
+    /// Reserves `bytes`, at most the whole limit, waiting until they fit
+    /// next to what is already reserved. On a rayon worker the wait runs
+    /// other queued jobs, so the groups holding the budget can finish.
+    pub fn reserve(&self, bytes: u64) -> MemoryReservation<'_> {
+        let bytes = bytes.min(self.limit);
+        let lock = || self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let mut usage = lock();
+        while usage.in_use + bytes > self.limit {
+            if rayon::current_thread_index().is_some() {
+                drop(usage);
+                let ran = rayon::yield_now() == Some(rayon::Yield::Executed);
+                usage = lock();
+                if !ran {
+                    usage = self
+                        .freed
+                        .wait_timeout(usage, RESERVE_POLL)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+            } else {
+                usage = self.freed.wait(usage).unwrap_or_else(|e| e.into_inner());
+            }
+        }
+        usage.in_use += bytes;
+        usage.peak = usage.peak.max(usage.in_use);
+        MemoryReservation {
+            budget: self,
+            bytes,
+        }
+    }
+
+    /// The most bytes ever reserved at once.
+    pub fn peak(&self) -> u64 {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner()).peak
+    }
+
+    #[cfg(test)]
+    fn in_use(&self) -> u64 {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner()).in_use
+    }
+}
+
+/// Share of a [`MemoryBudget`], released on drop.
+pub struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        let mut usage = self.budget.usage.lock().unwrap_or_else(|e| e.into_inner());
+        usage.in_use -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+/// Read buffers a group of `members` allocates: one per member plus the OR
+/// chunk, and the reference and read-back chunks when used.
+pub fn buffer_count(members: usize, has_reference: bool, options: &MergeOptions) -> usize {
+    members + 1 + has_reference as usize + options.paranoid as usize
+}
+
+// Most chunk-buffer bytes a thread keeps between groups.
+const BUFFER_POOL_BYTES: usize = 64 << 20;
+
+thread_local! {
+    // Chunk buffers the last groups on this thread gave back, so runs of many
+    // small groups reuse them instead of allocating per group.
+    static BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Chunk buffers taken from this thread's pool, returned to it on drop.
+struct PooledBuffers {
+    buffers: Vec<Vec<u8>>,
+    keep: bool,
+}
+
+impl PooledBuffers {
+    /// Takes `count` buffers of `len` bytes, allocating only those the pool
+    /// lacks or that are too small. Reused buffers keep stale bytes, which is
+    /// fine as every chunk is filled before it is read. With `keep` unset they
+    /// are freed on drop rather than pooled, e.g. so `max_memory` stays exact.
+    fn take(count: usize, len: usize, keep: bool) -> PooledBuffers {
+        let mut buffers = BUFFER_POOL.with_borrow_mut(|pool| {
+            let reused = pool.len().saturating_sub(count);
+            pool.split_off(reused)
+        });
+        for buffer in &mut buffers {
+            buffer.resize(len, 0);
+        }
+        buffers.resize_with(count, || vec![0; len]);
+        PooledBuffers { buffers, keep }
+    }
+}
+
+impl Drop for PooledBuffers {
+    fn drop(&mut self) {
+        if !self.keep {
+            return;
+        }
+        BUFFER_POOL.with_borrow_mut(|pool| {
+            let mut pooled: usize = pool.iter().map(Vec::capacity).sum();
+            for buffer in self.buffers.drain(..) {
+                if pooled + buffer.capacity() <= BUFFER_POOL_BYTES {
+                    pooled += buffer.capacity();
+                    pool.push(buffer);
+                }
+            }
+        });
+    }
+}
+
+// Canonicalizes so aliases of one file share a key. Outputs that don't exist
+// yet fall back to canonicalizing their parent directory.
+fn target_key(path: &Path) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent)
+            .map(|p| p.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Prefix `tempfile` itself uses, kept as the default so temp names look the
+/// same as before `--temp-prefix` existed.
+pub const DEFAULT_TEMP_PREFIX: &str = ".tmp";
+
+// Temp files that cannot be created under their deterministic name are
+// retried with a numeric suffix this many times.
+const MAX_TEMP_NAME_ATTEMPTS: u32 = 100;
+
+/// Creates a temp file in `dir` for data derived from `key`. With
+/// `deterministic_temp` the name is the prefix, the key's file name and a hash
+/// of the full key, so an interrupted run's intermediate files can be found.
+/// Names are claimed with create-new semantics, so an existing file is never
+/// reused.
+fn create_temp(dir: &Path, key: &Path, options: &MergeOptions) -> io::Result<NamedTempFile> {
+    if !options.deterministic_temp {
+        return tempfile::Builder::new()
+            .prefix(&options.temp_prefix)
+            .tempfile_in(dir);
+    }
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key.as_os_str().as_encoded_bytes());
+    let file_name = key.file_name().unwrap_or_default().to_string_lossy();
+    let stem = format!(
+        "{}{}-{:016x}",
+        options.temp_prefix,
+        file_name,
+        hasher.finish()
+    );
+    for attempt in 0..MAX_TEMP_NAME_ATTEMPTS {
+        let suffix = if attempt == 0 {
+            String::new()
+        } else {
+            format!(".{}", attempt)
+        };
+        match tempfile::Builder::new()
+            .prefix(&stem)
+            .suffix(&suffix)
+            .rand_bytes(0)
+            .tempfile_in(dir)
+        {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free temp name for {:?} in {:?}", stem, dir),
+    ))
+}
+
+// Whether nothing should be created in `dir`: its permissions deny writing to
+// everyone or its filesystem is mounted read-only.
+fn dir_is_read_only(dir: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(dir)?.permissions().readonly() || space::read_only_filesystem(dir)?)
+}
+
+// Checked before writing into `dir`, so a read-only directory stops the group
+// with a clear error even where the process could write anyway.
+fn ensure_writable(dir: &Path) -> io::Result<()> {
+    if dir_is_read_only(dir)? {
+        return Err(MergeError::OutputNotWritable(dir.to_path_buf()).into());
+    }
+    Ok(())
+}
+
+// Reports a permission error while writing `path` as `OutputNotWritable`
+// rather than as an opaque I/O failure.
+fn output_error(e: io::Error, path: &Path) -> io::Error {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+    ) {
+        MergeError::OutputNotWritable(path.to_path_buf()).into()
+    } else {
+        e
+    }
+}
+
+/// Suffix of the staging file a replacement is written to before it is
+/// renamed over its member.
+pub const STAGING_SUFFIX: &str = ".combine-staging";
+
+/// Where the replacement of `path` is staged: `<path>.combine-staging`.
+pub fn staging_path(path: &Path) -> PathBuf {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(STAGING_SUFFIX);
+    PathBuf::from(staging)
+}
+
+// Copies `merged` to the staging file of `member` and syncs it. Renaming it
+// over `member` then replaces the member atomically: a crash leaves either the
+// untouched member or a staging file for `recover_staging`. The staging file
+// is removed on error, or when the returned path is dropped unpersisted.
+fn stage_replacement(merged: &Path, member: &Path, audit: &AuditLog) -> io::Result<TempPath> {
+    let staging = staging_path(member);
+    let staged = fs::copy(merged, &staging).and_then(|_| sync_path(&staging));
+    if let Err(e) = staged {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+    let staging = TempPath::from_path(staging);
+    audit.record(AuditEvent::WroteTemp, member, &staging)?;
+    Ok(staging)
+}
+
+// An output whose data is complete in a temp file next to its target. The
+// temp is removed if it is dropped before being committed.
+struct StagedOutput {
+    member: usize,
+    target: PathBuf,
+    temp: TempPath,
+    _claim: TargetClaim,
+}
+
+impl StagedOutput {
+    // Renames the temp over the target, returning the member and the target.
+    fn commit(
+        self,
+        paths: &[PathBuf],
+        options: &MergeOptions,
+        samples: &[Sample],
+        audit: &AuditLog,
+    ) -> io::Result<(usize, PathBuf)> {
+        let path = &paths[self.member];
+        let temp_path = self.temp.to_path_buf();
+        // A patch is not a copy of the member, so it keeps no attributes.
+        #[cfg(unix)]
+        if options.preserve_xattrs && (options.replace || !options.emit_patch) {
+            match xattr::copy_all(path, &temp_path) {
+                Ok(copied) => {
+                    log::debug!("Copied {} extended attributes of {:?}", copied.count, path);
+                    for (name, e) in copied.failed {
+                        log::warn!(
+                            "Could not copy extended attribute {:?} of {:?} to {:?}: {}",
+                            name,
+                            path,
+                            self.target,
+                            e
+                        );
+                    }
+                }
+                Err(e) => log::warn!(
+                    "Could not copy extended attributes of {:?} to {:?}: {}",
+                    path,
+                    self.target,
+                    e
+                ),
+            }
+        }
+        self.temp
+            .persist(&self.target)
+            .map_err(|e| output_error(e.error, &self.target))?;
+        audit.record(AuditEvent::Renamed, &self.target, &temp_path)?;
+        if options.fsync {
+            sync_path(member_dir(&self.target))?;
+        }
+        if options.replace {
+            verify_samples(path, samples)?;
+            log::debug!("Replaced original {:?} with merged content", path);
+        } else if options.emit_patch {
+            log::debug!(
+                "Created patch {:?} for incomplete original {:?}",
+                self.target,
+                path
+            );
+        } else {
+            verify_samples(&self.target, samples)?;
+            log::debug!(
+                "Created merged file {:?} for incomplete original {:?}",
+                self.target,
+                path
+            );
+        }
+        Ok((self.member, self.target))
+    }
+}
+
+/// What `recover_staging` did with a leftover staging file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StagingRecovery {
+    /// It held all of its member's data and was renamed over the member.
+    Completed,
+    /// It was cut short or is missing data its member has, so it was removed.
+    Discarded,
+}
+
+impl StagingRecovery {
+    pub fn label(self) -> &'static str {
+        match self {
+            StagingRecovery::Completed => "completed",
+            StagingRecovery::Discarded => "discarded",
+        }
+    }
+}
+
+/// Finishes the replacements an interrupted `--replace` left under `dir`.
+/// A staging file the size of its member that holds every non-zero byte of
+/// it is renamed over the member; any other is removed. Staging files whose
+/// member is gone are left alone with a warning.
+pub fn recover_staging(dir: &Path) -> io::Result<Vec<(PathBuf, StagingRecovery)>> {
+    let mut recovered = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current_dir) = dirs.pop() {
+        for entry in fs::read_dir(&current_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let Some(member) = path
+                .to_str()
+                .and_then(|p| p.strip_suffix(STAGING_SUFFIX))
+                .map(PathBuf::from)
+            else {
+                continue;
+            };
+            if !member.is_file() {
+                log::warn!(
+                    "Leaving staging file {:?}: its member {:?} is gone",
+                    path,
+                    member
+                );
+                continue;
+            }
+            let outcome = if staging_holds_member(&path, &member)? {
+                sync_path(&path)?;
+                fs::rename(&path, &member)?;
+                StagingRecovery::Completed
+            } else {
+                fs::remove_file(&path)?;
+                StagingRecovery::Discarded
+            };
+            recovered.push((member, outcome));
+        }
+    }
+    Ok(recovered)
+}
+
+// Whether `staging` has the length of `member` and every non-zero byte of it,
+// so swapping it in can only add data. Bytes in pieces the member's aria2
+// control file marks as never downloaded don't count.
+fn staging_holds_member(staging: &Path, member: &Path) -> io::Result<bool> {
+    let size = fs::metadata(member)?.len();
+    if fs::metadata(staging)?.len() != size {
+        return Ok(false);
+    }
+    let member_path = [member.to_path_buf()];
+    let controls = load_controls(&member_path, size);
+    let mut staged = BufReader::new(File::open(staging)?);
+    let mut original = File::open(member)?;
+    let mut buffers = vec![vec![0u8; DEFAULT_CHUNK_SIZE]];
+    let mut staged_buffer = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = original.read(&mut buffers[0])?;
+        if n == 0 {
+            return Ok(true);
+        }
+        mask_missing_pieces(&controls, &mut buffers, &[false], offset, n);
+        staged.read_exact(&mut staged_buffer[..n])?;
+        if !check_chunk_sanity(&buffers[0][..n], &staged_buffer[..n]) {
+            return Ok(false);
+        }
+        offset += n as u64;
+    }
+}
+
+/// Directory the merged data of a group whose first member is `first` is
+/// staged in: `temp_dir` when set, else next to `first`, unless that is
+/// read-only, e.g. a snapshot, and an output directory is set to take it.
+pub fn merge_temp_dir(first: &Path, options: &MergeOptions) -> io::Result<PathBuf> {
+    if let Some(dir) = &options.temp_dir {
+        return Ok(dir.clone());
+    }
+    let dir = member_dir(first);
+    if let Some(output_dir) = &options.output_dir
+        && dir_is_read_only(dir)?
+    {
+        log::debug!(
+            "{:?} is read-only, staging its merge in {:?}",
+            dir,
+            output_dir.dir
+        );
+        return Ok(output_dir.dir.clone());
+    }
+    Ok(dir.to_path_buf())
+}
+
+/// Directory holding `path`, falling back to the current directory for bare
+/// file names such as those read by `--paths-from`.
+pub fn member_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => {
+            log::debug!(
+                "No parent directory for {:?}, using the current directory",
+                path
+            );
+            Path::new(".")
+        }
+    }
+}
+
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{name}.merged";
+
+/// Expands `{name}`, `{stem}`, `{ext}` and `{parent}`, the name of the
+/// member's directory, in `template` for the member at `path`. Results are
+/// placed relative to the member's directory and may go one level up, e.g.
+/// `../complete/{name}`, but no further and never outside `root`.
+pub fn render_output_path(template: &str, path: &Path, root: Option<&Path>) -> io::Result<PathBuf> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let parent = member_dir(path);
+    let parent_name = match parent.file_name() {
+        Some(name) => Some(name.to_os_string()),
+        // `.` or `..`; only the resolved directory has a name.
+        None => fs::canonicalize(parent)
+            .ok()
+            .and_then(|dir| dir.file_name().map(|name| name.to_os_string())),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| invalid(format!("Unclosed placeholder in template {:?}", template)))?;
+        match &rest[open + 1..open + close] {
+            "name" => rendered.push_str(&name),
+            "stem" => rendered.push_str(&stem),
+            "ext" => rendered.push_str(&ext),
+            "parent" => match &parent_name {
+                Some(dir) => rendered.push_str(&dir.to_string_lossy()),
+                None => {
+                    return Err(invalid(format!("{:?} has no parent directory name", path)));
+                }
+            },
+            other => {
+                return Err(invalid(format!(
+                    "Unknown placeholder {{{}}} in template {:?}",
+                    other, template
+                )));
+            }
+        }
+        rest = &rest[open + close + 1..];
+    }
+    rendered.push_str(rest);
+
+    // Relative to the member's directory: a file name, at most one `..`
+    // ahead of it.
+    let relative = normalize_lexically(Path::new(&rendered));
+    let mut components = relative.components().peekable();
+    components.next_if_eq(&Component::ParentDir);
+    let output = normalize_lexically(&parent.join(&relative));
+    if !components.all(|c| matches!(c, Component::Normal(_)))
+        || relative.file_name().is_none()
+        || output == normalize_lexically(parent)
+    {
+        return Err(invalid(format!(
+            "Template {:?} renders {:?} outside of {:?} and its parent",
+            template, output, parent
+        )));
+    }
+    if let Some(root) = root
+        && !lexically_inside(&output, &normalize_lexically(root))
+    {
+        return Err(invalid(format!(
+            "Template {:?} renders {:?} outside of the root {:?}",
+            template, output, root
+        )));
+    }
+    Ok(output)
+}
+
+// Whether the normalized `path` lies strictly below the normalized `dir`. An
+// empty `dir` is the current directory, which `..` leaves.
+fn lexically_inside(path: &Path, dir: &Path) -> bool {
+    path != dir
+        && path.starts_with(dir)
+        && (!dir.as_os_str().is_empty()
+            || !matches!(path.components().next(), Some(Component::ParentDir)))
+}
+
+/// Where the merged output for the member at `path` is written: the rendered
+/// template, mirrored into `output_dir` when one is set.
+pub fn output_path(options: &MergeOptions, path: &Path) -> io::Result<PathBuf> {
+    let rendered =
+        render_output_path(&options.output_template, path, options.scan_root.as_deref())?;
+    let Some(output) = &options.output_dir else {
+        return Ok(rendered);
+    };
+    let relative = output
+        .root
+        .as_ref()
+        .and_then(|root| rendered.strip_prefix(normalize_lexically(root)).ok())
+        .filter(|rel| !rel.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .or_else(|| rendered.file_name().map(PathBuf::from))
+        .unwrap_or(rendered);
+    Ok(output.dir.join(relative))
+}
+
+// Applies `policy` to a mirrored output that already exists or is `taken` by
+// another output of the group, returning the path to write or, when
+// skipping, the existing path as the error.
+fn resolve_collision(
+    target: PathBuf,
+    policy: OnCollision,
+    taken: &[&PathBuf],
+) -> Result<PathBuf, PathBuf> {
+    let exists = |path: &PathBuf| path.exists() || taken.contains(&path);
+    if !exists(&target) {
+        return Ok(target);
+    }
+    match policy {
+        OnCollision::Skip => Err(target),
+        OnCollision::Overwrite => {
+            log::debug!("Overwriting existing output {:?}", target);
+            Ok(target)
+        }
+        OnCollision::Suffix => {
+            let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = target
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+            let suffixed = (1..)
+                .map(|n| target.with_file_name(format!("{}-{}{}", stem, n, ext)))
+                .find(|candidate| !exists(candidate))
+                .expect("some suffix is free");
+            log::debug!("Output {:?} exists, writing {:?} instead", target, suffixed);
+            Ok(suffixed)
+        }
+    }
+}
+
+// Whether `output` names one of `members`, however either is spelled:
+// rendered outputs are normalized, members keep the spelling they were
+// found under.
+fn collides_with_member(output: &Path, members: &[PathBuf]) -> io::Result<bool> {
+    let normalized = normalize_lexically(output);
+    for member in members {
+        if normalize_lexically(member) == normalized
+            || (output.exists() && same_file(output, member)?)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Resolves `.` and `..` without touching the filesystem. A relative path
+// keeps the leading `..` it can't resolve; `..` at the root stays there.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                None | Some(Component::ParentDir) => normalized.push(".."),
+                _ => {}
+            },
+            c => normalized.push(c),
+        }
+    }
+    normalized
 }
 
 fn check_word_sanity(w: u64, or_w: u64) -> bool {
@@ -122,305 +2285,4511 @@ fn check_word_sanity(w: u64, or_w: u64) -> bool {
     true
 }
 
-fn check_sanity_and_completes(paths: &[PathBuf]) -> io::Result<Option<(NamedTempFile, Vec<bool>)>> {
-    if paths.is_empty() {
+// Mostly complete members match the OR across most of a chunk even when the
+// chunk as a whole differs. Comparing fixed blocks with memcmp first leaves
+// the byte and word checks to the blocks that actually differ.
+const COMPARE_BLOCK: usize = 4096;
+
+fn differing_blocks<'a>(
+    buffer: &'a [u8],
+    or_chunk: &'a [u8],
+) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+    buffer
+        .chunks(COMPARE_BLOCK)
+        .zip(or_chunk.chunks(COMPARE_BLOCK))
+        .filter(|(block, or_block)| block != or_block)
+}
+
+fn check_chunk_sanity(buffer: &[u8], or_chunk: &[u8]) -> bool {
+    differing_blocks(buffer, or_chunk).all(|(block, or_block)| check_block_sanity(block, or_block))
+}
+
+fn check_block_sanity(buffer: &[u8], or_chunk: &[u8]) -> bool {
+    let (prefix, words, suffix) = unsafe { buffer.align_to::<u64>() };
+    let (or_prefix, or_words, or_suffix) = unsafe { or_chunk.align_to::<u64>() };
+
+    prefix
+        .iter()
+        .zip(or_prefix.iter())
+        .all(|(b, or_b)| *b == 0 || *b == *or_b)
+        && words
+            .iter()
+            .zip(or_words.iter())
+            .all(|(w, or_w)| check_word_sanity(*w, *or_w))
+        && suffix
+            .iter()
+            .zip(or_suffix.iter())
+            .all(|(b, or_b)| *b == 0 || *b == *or_b)
+}
+
+fn first_conflict(buffer: &[u8], or_chunk: &[u8]) -> Option<usize> {
+    (0..buffer.len()).find(|&i| buffer[i] != 0 && buffer[i] != or_chunk[i])
+}
+
+/// Counts positions where any member conflicts with the OR, returning the
+/// count and the last such position. Callers only pass chunks with at least
+/// one conflict.
+fn count_conflicting_positions(members: &[&[u8]], or_chunk: &[u8]) -> (u64, usize) {
+    let mut count = 0;
+    let mut last = 0;
+    for i in 0..or_chunk.len() {
+        if members.iter().any(|m| m[i] != 0 && m[i] != or_chunk[i]) {
+            count += 1;
+            last = i;
+        }
+    }
+    (count, last)
+}
+
+/// Replaces every conflicting position in `or_chunk` with the byte chosen by
+/// `mode`, returning how many positions were resolved. A `seeded` group's
+/// first member wins wherever it has data, even against a majority; callers
+/// pass false once that member is dropped.
+fn resolve_chunk_conflicts(
+    buffers: &[Vec<u8>],
+    or_chunk: &mut [u8],
+    mode: ConflictMode,
+    order: &[usize],
+    seeded: bool,
+) -> u64 {
+    let mut resolved = 0;
+    for pos in 0..or_chunk.len() {
+        let or_b = or_chunk[pos];
+        if !buffers.iter().any(|b| b[pos] != 0 && b[pos] != or_b) {
+            continue;
+        }
+        let mut preferred = order.iter().map(|&i| buffers[i][pos]).filter(|&b| b != 0);
+        or_chunk[pos] = match mode {
+            ConflictMode::Strict => unreachable!("strict mode never resolves conflicts"),
+            _ if seeded && buffers[0][pos] != 0 => buffers[0][pos],
+            ConflictMode::Salvage => preferred.next().unwrap_or(0),
+            ConflictMode::Majority => {
+                let mut best = (0u8, 0usize);
+                for value in preferred {
+                    let count = buffers.iter().filter(|b| b[pos] == value).count();
+                    if count > best.1 {
+                        best = (value, count);
+                    }
+                }
+                best.0
+            }
+        };
+        resolved += 1;
+    }
+    resolved
+}
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Inclusive bounds for the per-member read chunk size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkBounds {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for ChunkBounds {
+    fn default() -> Self {
+        ChunkBounds {
+            min: DEFAULT_CHUNK_SIZE,
+            max: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+// Below this read throughput the reads are assumed to be seek-bound.
+const SLOW_READ_BYTES_PER_SEC: f64 = 64.0 * 1_048_576.0;
+// Above this larger chunks buy nothing, so give the memory back.
+const FAST_READ_BYTES_PER_SEC: f64 = 1024.0 * 1_048_576.0;
+
+/// Doubles the chunk size when the last read was slow and halves it when it
+/// was fast, staying within `bounds`.
+fn next_chunk_size(
+    current: usize,
+    bytes_read: usize,
+    read_time: Duration,
+    bounds: ChunkBounds,
+) -> usize {
+    if bounds.min >= bounds.max || bytes_read == 0 {
+        return current.clamp(bounds.min, bounds.max);
+    }
+    let secs = read_time.as_secs_f64();
+    let throughput = if secs > 0.0 {
+        bytes_read as f64 / secs
+    } else {
+        f64::INFINITY
+    };
+    let next = if throughput < SLOW_READ_BYTES_PER_SEC {
+        current.saturating_mul(2)
+    } else if throughput > FAST_READ_BYTES_PER_SEC {
+        current / 2
+    } else {
+        current
+    };
+    next.clamp(bounds.min, bounds.max)
+}
+
+fn count_missing(buffer: &[u8], or_chunk: &[u8]) -> u64 {
+    differing_blocks(buffer, or_chunk)
+        .map(|(block, or_block)| {
+            block
+                .iter()
+                .zip(or_block.iter())
+                .filter(|(b, or_b)| b != or_b)
+                .count() as u64
+        })
+        .sum()
+}
+
+type MemberReader = Box<dyn Read + Send>;
+
+// Opens a member positioned at the given offset.
+type MemberOpener<'a> = &'a dyn Fn(&Path, u64) -> io::Result<MemberReader>;
+
+fn open_member(path: &Path, offset: u64) -> io::Result<MemberReader> {
+    let mut file = File::open(path)?;
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    Ok(Box::new(BufReader::new(file)))
+}
+
+// Builds the pool that reads members of one group concurrently, or `None`
+// when reads stay on the calling thread. The size is capped by the member
+// count and the available cores so nested pools don't oversubscribe.
+fn member_read_pool(parallel: usize, members: usize) -> io::Result<Option<rayon::ThreadPool>> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = parallel.min(members).min(cores);
+    if threads <= 1 {
         return Ok(None);
     }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("member-read-{}", i))
+        .build()
+        .map(Some)
+        .map_err(io::Error::other)
+}
+
+// Fills each live member's buffer with its next `chunk_size` bytes, leaving
+// dropped members alone. Results are in member order.
+fn read_member_chunks(
+    readers: &mut [MemberReader],
+    buffers: &mut [Vec<u8>],
+    dropped: &[bool],
+    chunk_size: usize,
+    pool: Option<&rayon::ThreadPool>,
+) -> Vec<io::Result<()>> {
+    let read = |(reader, buffer): (&mut MemberReader, &mut Vec<u8>), dropped: &bool| {
+        if *dropped {
+            Ok(())
+        } else {
+            reader.read_exact(&mut buffer[..chunk_size])
+        }
+    };
+    match pool {
+        Some(pool) => pool.install(|| {
+            readers
+                .par_iter_mut()
+                .zip(buffers.par_iter_mut())
+                .zip(dropped.par_iter())
+                .map(|(pair, dropped)| read(pair, dropped))
+                .collect()
+        }),
+        None => readers
+            .iter_mut()
+            .zip(buffers.iter_mut())
+            .zip(dropped)
+            .map(|(pair, dropped)| read(pair, dropped))
+            .collect(),
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+type Uring = crate::uring::UringReader;
+
+// Stand-in so the read loop needs no cfg; it can never be constructed.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+enum Uring {}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn open_uring(paths: &[PathBuf], options: &MergeOptions) -> io::Result<Option<Uring>> {
+    if !options.io_uring {
+        return Ok(None);
+    }
+    match Uring::open(paths) {
+        Ok(uring) => Ok(Some(uring)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e),
+        Err(e) => {
+            log::warn!("io_uring unavailable, reading synchronously: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn open_uring(_paths: &[PathBuf], options: &MergeOptions) -> io::Result<Option<Uring>> {
+    if options.io_uring {
+        log::warn!("Built without io_uring support, reading synchronously");
+    }
+    Ok(None)
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn read_uring_chunks(
+    uring: &mut Uring,
+    buffers: &mut [Vec<u8>],
+    dropped: &[bool],
+    offset: u64,
+    chunk_size: usize,
+) -> Vec<io::Result<()>> {
+    uring.read_chunks(buffers, dropped, offset, chunk_size)
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn read_uring_chunks(
+    uring: &mut Uring,
+    _buffers: &mut [Vec<u8>],
+    _dropped: &[bool],
+    _offset: u64,
+    _chunk_size: usize,
+) -> Vec<io::Result<()>> {
+    match *uring {}
+}
+
+// A group needs at least this many readable members to keep merging after
+// `drop_unreadable` removed the others; a lone member would only be copied.
+// With a reference one member suffices, as the reference completes it.
+const MIN_READABLE_MEMBERS: usize = 2;
+
+/// The size most members share, preferring the earliest member's size on a
+/// tie, so a single odd member can't set the size for the whole group.
+pub fn majority_size(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut counts: Vec<(u64, usize)> = Vec::new();
+    for p in paths {
+        let size = fs::metadata(p)?.len();
+        match counts.iter_mut().find(|(s, _)| *s == size) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((size, 1)),
+        }
+    }
+    // `max_by_key` keeps the last maximum, so search from the back.
+    Ok(counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map_or(0, |(size, _)| *size))
+}
+
+// `paths` with its seed member moved to the front, or None when it has no
+// seed or the seed already leads.
+fn seed_first(paths: &[PathBuf], options: &MergeOptions, basename: &str) -> Option<Vec<PathBuf>> {
+    let mut seeds = paths
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| options.seed_members.contains(*p));
+    let (index, seed) = seeds.next()?;
+    if seeds.next().is_some() {
+        log::warn!(
+            "Group {} has several seed members, using the first: {:?}",
+            basename,
+            seed
+        );
+    }
+    if index == 0 {
+        return None;
+    }
+    let mut reordered = paths.to_vec();
+    let seed = reordered.remove(index);
+    reordered.insert(0, seed);
+    Some(reordered)
+}
+
+fn is_seeded(paths: &[PathBuf], options: &MergeOptions) -> bool {
+    paths
+        .first()
+        .is_some_and(|p| options.seed_members.contains(p))
+}
+
+// The seed member's size when the group leads with one, otherwise the
+// majority size.
+fn group_size(paths: &[PathBuf], options: &MergeOptions) -> io::Result<u64> {
+    if is_seeded(paths, options) {
+        return Ok(fs::metadata(&paths[0])?.len());
+    }
+    majority_size(paths)
+}
+
+// How many distinct files `paths` name, counting hardlinks to the same inode
+// once.
+#[cfg(unix)]
+fn distinct_files(paths: &[PathBuf]) -> io::Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+    let mut files = BTreeSet::new();
+    for p in paths {
+        let meta = fs::metadata(p)?;
+        files.insert((meta.dev(), meta.ino()));
+    }
+    Ok(files.len())
+}
+
+// Without inode numbers only different spellings of one path are counted
+// once.
+#[cfg(not(unix))]
+fn distinct_files(paths: &[PathBuf]) -> io::Result<usize> {
+    let mut files = BTreeSet::new();
+    for p in paths {
+        files.insert(fs::canonicalize(p)?);
+    }
+    Ok(files.len())
+}
+
+// Whether every member is `size` bytes long and byte for byte the same as
+// the first. Each other member is compared with the first in one sequential
+// pass, on rayon's pool, rather than chunk by chunk in turn. Gives up with
+// `TimedOut` once `group_timeout` has passed since `start_time`.
+fn members_identical(
+    paths: &[PathBuf],
+    size: u64,
+    options: &MergeOptions,
+    start_time: Instant,
+) -> io::Result<bool> {
+    for p in paths {
+        if fs::metadata(p)?.len() != size {
+            return Ok(false);
+        }
+    }
+    let Some((first, others)) = paths.split_first() else {
+        return Ok(true);
+    };
+    let same = others
+        .par_iter()
+        .map(|p| files_equal(first, p, options, start_time))
+        .collect::<io::Result<Vec<bool>>>()?;
+    Ok(same.into_iter().all(|same| same))
+}
+
+// Reads `a` and `b` side by side until they differ or both end.
+fn files_equal(
+    a: &Path,
+    b: &Path,
+    options: &MergeOptions,
+    start_time: Instant,
+) -> io::Result<bool> {
+    let mut a = File::open(a)?;
+    let mut b = File::open(b)?;
+    let mut buf_a = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut buf_b = vec![0u8; DEFAULT_CHUNK_SIZE];
+    loop {
+        if let Some(timeout) = options.group_timeout
+            && start_time.elapsed() >= timeout
+        {
+            return Err(MergeError::TimedOut(timeout).into());
+        }
+        let len = read_full(&mut a, &mut buf_a)?;
+        if read_full(&mut b, &mut buf_b[..len])? != len || buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+        if len < buf_a.len() {
+            // `b` may have grown since its size was checked.
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+    }
+}
+
+// Fills `buf` unless the file ends first, returning the bytes read.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Members with data when some, but not all, members are zero bytes. A
+// zero-byte member holds nothing to merge and can't take the merged data.
+fn drop_zero_byte_members(paths: &[PathBuf], basename: &str) -> io::Result<Option<Vec<PathBuf>>> {
+    let mut kept = Vec::with_capacity(paths.len());
+    for p in paths {
+        if fs::metadata(p)?.len() > 0 {
+            kept.push(p.clone());
+        }
+    }
+    if kept.is_empty() || kept.len() == paths.len() {
+        return Ok(None);
+    }
+    log::debug!(
+        "Dropping {} zero-byte member(s) of group {}",
+        paths.len() - kept.len(),
+        basename
+    );
+    Ok(Some(kept))
+}
+
+// Members whose size differs from `size`, when at least two members have it.
+fn drop_size_outliers(paths: &[PathBuf], size: u64, basename: &str) -> io::Result<Vec<PathBuf>> {
+    let mut kept = Vec::with_capacity(paths.len());
+    let mut outliers = Vec::new();
+    for p in paths {
+        if fs::metadata(p)?.len() == size {
+            kept.push(p.clone());
+        } else {
+            outliers.push(p);
+        }
+    }
+    if outliers.is_empty() || kept.len() < 2 {
+        return Ok(paths.to_vec());
+    }
+    for p in outliers {
+        log::warn!(
+            "Dropping {:?} from group {}: its size differs from the {} bytes most members share",
+            p,
+            basename,
+            size
+        );
+    }
+    Ok(kept)
+}
+
+// Members whose non-zero bytes disagree with the majority at more than
+// `percent` of the positions where they hold data and a majority exists. A
+// majority needs a value held by at least two members and by more members
+// than any other value, so groups of fewer than three never drop anyone, and
+// at least two members are always kept.
+fn drop_junk_members(
+    paths: &[PathBuf],
+    size: u64,
+    percent: f64,
+    basename: &str,
+) -> io::Result<Vec<PathBuf>> {
+    if paths.len() < 3 {
+        return Ok(paths.to_vec());
+    }
+    for p in paths {
+        if fs::metadata(p)?.len() != size {
+            // The sanity pass reports the mismatch.
+            return Ok(paths.to_vec());
+        }
+    }
+    let mut readers = paths
+        .iter()
+        .map(|p| File::open(p).map(BufReader::new))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut buffers = vec![vec![0u8; DEFAULT_CHUNK_SIZE]; paths.len()];
+    let mut compared = vec![0u64; paths.len()];
+    let mut disagreeing = vec![0u64; paths.len()];
+    let mut values = Vec::with_capacity(paths.len());
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(DEFAULT_CHUNK_SIZE as u64) as usize;
+        for (reader, buffer) in readers.iter_mut().zip(buffers.iter_mut()) {
+            reader.read_exact(&mut buffer[..len])?;
+        }
+        for i in 0..len {
+            values.clear();
+            values.extend(buffers.iter().map(|b| b[i]).filter(|&b| b != 0));
+            let Some(majority) = majority_byte(&mut values) else {
+                continue;
+            };
+            for (m, buffer) in buffers.iter().enumerate() {
+                if buffer[i] != 0 {
+                    compared[m] += 1;
+                    disagreeing[m] += (buffer[i] != majority) as u64;
+                }
+            }
+        }
+        remaining -= len as u64;
+    }
+
+    let share = |m: usize| disagreeing[m] as f64 * 100.0 / compared[m].max(1) as f64;
+    let junk: Vec<usize> = (0..paths.len()).filter(|&m| share(m) > percent).collect();
+    if junk.is_empty() || paths.len() - junk.len() < 2 {
+        return Ok(paths.to_vec());
+    }
+    for &m in &junk {
+        log::warn!(
+            "Dropping {:?} from group {}: {:.1}% of its data disagrees with the other members, so it is not the same content",
+            paths[m],
+            basename,
+            share(m)
+        );
+    }
+    Ok((0..paths.len())
+        .filter(|m| !junk.contains(m))
+        .map(|m| paths[m].clone())
+        .collect())
+}
+
+// The value more of `values` hold than any other, if at least two hold it.
+fn majority_byte(values: &mut [u8]) -> Option<u8> {
+    values.sort_unstable();
+    let mut best: Option<(u8, usize)> = None;
+    let mut tied = false;
+    for run in values.chunk_by(|a, b| a == b) {
+        match best {
+            Some((_, count)) if run.len() < count => {}
+            Some((_, count)) if run.len() == count => tied = true,
+            _ => {
+                best = Some((run[0], run.len()));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|&(_, count)| count >= 2 && !tied)
+        .map(|(value, _)| value)
+}
+
+/// Suffix of the temp file a `resumable_temp` merge writes; its checkpoint
+/// sits next to it with `.state` appended. Neither is ever a member.
+pub const RESUME_SUFFIX: &str = ".combine-resume";
+
+// How far a resumable merge got, checkpointed next to its temp file so an
+// interrupted merge can continue instead of starting over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ResumeState {
+    // Conflict mode and preference the merged prefix was resolved with.
+    mode: String,
+    // Path, size and modification time in nanoseconds of each member, then
+    // of the reference file.
+    inputs: Vec<(PathBuf, u64, u128)>,
+    offset: u64,
+    all_identical: bool,
+    has_gaps: bool,
+    resolved_conflicts: u64,
+    is_complete: Vec<bool>,
+    missing_bytes: Vec<u64>,
+}
+
+impl ResumeState {
+    // One `key value` line per field; paths come last on their lines.
+    fn render(&self) -> String {
+        let mut out = format!(
+            "mode {}\noffset {}\nidentical {}\ngaps {}\nresolved {}\n",
+            self.mode,
+            self.offset,
+            self.all_identical as u8,
+            self.has_gaps as u8,
+            self.resolved_conflicts
+        );
+        for (i, (path, size, mtime)) in self.inputs.iter().enumerate() {
+            match (self.is_complete.get(i), self.missing_bytes.get(i)) {
+                (Some(&complete), Some(missing)) => out.push_str(&format!(
+                    "member {} {} {} {} {}\n",
+                    size,
+                    mtime,
+                    complete as u8,
+                    missing,
+                    path.display()
+                )),
+                _ => out.push_str(&format!(
+                    "reference {} {} {}\n",
+                    size,
+                    mtime,
+                    path.display()
+                )),
+            }
+        }
+        out
+    }
+
+    // Returns `None` for anything malformed, which restarts the merge.
+    fn parse(text: &str) -> Option<ResumeState> {
+        let mut state = ResumeState {
+            mode: String::new(),
+            inputs: Vec::new(),
+            offset: 0,
+            all_identical: false,
+            has_gaps: false,
+            resolved_conflicts: 0,
+            is_complete: Vec::new(),
+            missing_bytes: Vec::new(),
+        };
+        for line in text.lines() {
+            let (key, rest) = line.split_once(' ')?;
+            match key {
+                "mode" => state.mode = rest.to_string(),
+                "offset" => state.offset = rest.parse().ok()?,
+                "identical" => state.all_identical = rest == "1",
+                "gaps" => state.has_gaps = rest == "1",
+                "resolved" => state.resolved_conflicts = rest.parse().ok()?,
+                "member" => {
+                    let mut fields = rest.splitn(5, ' ');
+                    let size = fields.next()?.parse().ok()?;
+                    let mtime = fields.next()?.parse().ok()?;
+                    state.is_complete.push(fields.next()? == "1");
+                    state.missing_bytes.push(fields.next()?.parse().ok()?);
+                    state
+                        .inputs
+                        .push((PathBuf::from(fields.next()?), size, mtime));
+                }
+                "reference" => {
+                    let mut fields = rest.splitn(3, ' ');
+                    let size = fields.next()?.parse().ok()?;
+                    let mtime = fields.next()?.parse().ok()?;
+                    state
+                        .inputs
+                        .push((PathBuf::from(fields.next()?), size, mtime));
+                }
+                _ => return None,
+            }
+        }
+        Some(state)
+    }
+}
+
+// Identifies the inputs of a merge, so a checkpoint is only trusted while
+// none of them changed.
+fn resume_inputs(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+) -> io::Result<Vec<(PathBuf, u64, u128)>> {
+    paths
+        .iter()
+        .map(|p| p.as_path())
+        .chain(reference)
+        .map(|p| {
+            let meta = fs::metadata(p)?;
+            let mtime = meta
+                .modified()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            Ok((p.to_path_buf(), meta.len(), mtime))
+        })
+        .collect()
+}
+
+// The predictable temp file of a resumable merge keyed by its first member,
+// and its checkpoint.
+struct ResumeFiles {
+    temp: PathBuf,
+    state: PathBuf,
+}
+
+impl ResumeFiles {
+    fn new(dir: &Path, key: &Path, options: &MergeOptions) -> ResumeFiles {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key.as_os_str().as_encoded_bytes());
+        let file_name = key.file_name().unwrap_or_default().to_string_lossy();
+        let temp = dir.join(format!(
+            "{}{}-{:016x}{}",
+            options.temp_prefix,
+            file_name,
+            hasher.finish(),
+            RESUME_SUFFIX
+        ));
+        let mut state = temp.clone().into_os_string();
+        state.push(".state");
+        ResumeFiles {
+            temp,
+            state: PathBuf::from(state),
+        }
+    }
+
+    // The checkpoint, if it was taken for exactly these inputs and mode and
+    // the temp file still holds everything before its offset.
+    fn load(&self, inputs: &[(PathBuf, u64, u128)], mode: &str) -> Option<ResumeState> {
+        let state = ResumeState::parse(&fs::read_to_string(&self.state).ok()?)?;
+        let temp_len = fs::metadata(&self.temp).ok()?.len();
+        (state.inputs == inputs && state.mode == mode && temp_len >= state.offset).then_some(state)
+    }
+
+    // Makes everything written so far durable, then atomically replaces the
+    // checkpoint.
+    fn checkpoint(&self, temp: &File, state: &ResumeState) -> io::Result<()> {
+        temp.sync_data()?;
+        let mut pending = self.state.clone().into_os_string();
+        pending.push(".tmp");
+        fs::write(&pending, state.render())?;
+        File::open(&pending)?.sync_all()?;
+        fs::rename(&pending, &self.state)
+    }
+
+    fn discard(&self) {
+        let _ = fs::remove_file(&self.state);
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+// Where a merge writes its output: a scratch temp removed when dropped, or
+// the predictable temp of a resumable merge, which outlives errors.
+enum MergeTemp {
+    Scratch(NamedTempFile),
+    Resumable(ResumeFiles),
+}
+
+impl MergeTemp {
+    // Removes what a resumable merge left behind once its result is final.
+    fn discard(&self) {
+        if let MergeTemp::Resumable(files) = self {
+            files.discard();
+        }
+    }
+}
+
+// Checkpoints are taken about this often, and at least every 64th of the
+// file, so a small file still resumes part way.
+const RESUME_CHECKPOINT_BYTES: u64 = 256 << 20;
+
+pub fn check_sanity_and_completes(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    mtimes: &[SystemTime],
+    options: &MergeOptions,
+) -> io::Result<SanityOutcome> {
+    check_sanity_and_completes_with_opener(paths, reference, mtimes, options, &open_member)
+}
+
+type TempWriter = Box<dyn Write + Send>;
+
+fn check_sanity_and_completes_with_opener(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    mtimes: &[SystemTime],
+    options: &MergeOptions,
+    open: MemberOpener,
+) -> io::Result<SanityOutcome> {
+    check_sanity_and_completes_with_io(paths, reference, mtimes, options, open, &|f| Box::new(f))
+}
+
+fn check_sanity_and_completes_with_io(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    mtimes: &[SystemTime],
+    options: &MergeOptions,
+    open: MemberOpener,
+    wrap_temp: &dyn Fn(File) -> TempWriter,
+) -> io::Result<SanityOutcome> {
+    if paths.is_empty() {
+        return Ok(SanityOutcome::Empty);
+    }
+
+    let deadline = options
+        .group_timeout
+        .map(|timeout| (Instant::now() + timeout, timeout));
+    let size = group_size(paths, options)?;
+    if size == 0 {
+        return Ok(SanityOutcome::Empty);
+    }
+
+    for p in paths.iter().map(|p| p.as_path()).chain(reference) {
+        if fs::metadata(p)?.len() != size {
+            return Ok(SanityOutcome::SizeMismatch {
+                path: p.to_path_buf(),
+            });
+        }
+    }
+
+    log::debug!("Checking sanity for {} files of size {}", paths.len(), size);
+    let controls = load_controls(paths, size);
+
+    let temp_dir = merge_temp_dir(&paths[0], options)?;
+    fs::create_dir_all(&temp_dir).map_err(|e| output_error(e, &temp_dir))?;
+    ensure_writable(&temp_dir)?;
+    // A checkpoint only holds the state every merge keeps, so merges that
+    // also collect holes, donors or samples always start over.
+    let resumable = options.resumable_temp
+        && !options.track_recovered
+        && !options.warn_unrecovered
+        && !options.ignore_trailing_zeros
+        && !options.report_unrecoverable
+        && options.sample_verify.is_none();
+    let mode = format!("{:?} {:?}", options.conflict_mode, options.prefer);
+    let (merge_temp, inputs, restored) = if resumable {
+        let files = ResumeFiles::new(&temp_dir, &paths[0], options);
+        let inputs = resume_inputs(paths, reference)?;
+        let restored = files.load(&inputs, &mode);
+        if restored.is_none() && files.state.exists() {
+            log::debug!("Restarting the merge of {:?}: its inputs changed", paths[0]);
+        }
+        (MergeTemp::Resumable(files), inputs, restored)
+    } else {
+        let temp =
+            create_temp(&temp_dir, &paths[0], options).map_err(|e| output_error(e, &temp_dir))?;
+        (MergeTemp::Scratch(temp), Vec::new(), None)
+    };
+    let start = restored.as_ref().map_or(0, |state| state.offset);
+    let temp_file = match &merge_temp {
+        MergeTemp::Scratch(temp) => temp.reopen()?,
+        MergeTemp::Resumable(files) => {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&files.temp)
+                .map_err(|e| output_error(e, &files.temp))?;
+            file.set_len(start)?;
+            file
+        }
+    };
+    let mut writer_file = temp_file.try_clone()?;
+    writer_file.seek(SeekFrom::Start(start))?;
+    let mut writer = BufWriter::new(wrap_temp(writer_file));
+    let mut read_back = if options.paranoid {
+        Some((temp_file.try_clone()?, Vec::new()))
+    } else {
+        None
+    };
+
+    let mut uring = open_uring(paths, options)?;
+    let mut readers: Vec<MemberReader> = Vec::with_capacity(paths.len());
+    if uring.is_none() {
+        for p in paths {
+            readers.push(open(p, start)?);
+        }
+    }
+    let mut reference_reader = match reference {
+        Some(p) => Some(open(p, start)?),
+        None => None,
+    };
+    let mut dropped = vec![false; paths.len()];
+    let pool = match uring {
+        Some(_) => None,
+        None => member_read_pool(options.parallel_files_per_group, paths.len())?,
+    };
+
+    // The run reserved the buffers before starting the group; keep to the
+    // chunk size they were reserved at.
+    let chunk_bounds = match &options.memory_budget {
+        Some(budget) => {
+            let count = buffer_count(paths.len(), reference.is_some(), options);
+            budget.plan(count, options.chunk_bounds).1
+        }
+        None => options.chunk_bounds,
+    };
+    let mut buf_size = DEFAULT_CHUNK_SIZE.clamp(chunk_bounds.min, chunk_bounds.max);
+    // One buffer per member, then the OR chunk and the reference chunk if
+    // there is a reference.
+    let mut pooled = PooledBuffers::take(
+        paths.len() + 1 + reference.is_some() as usize,
+        buf_size,
+        options.memory_budget.is_none(),
+    );
+    let mut no_reference = Vec::new();
+    let (buffers, chunks) = pooled.buffers.split_at_mut(paths.len());
+    let (or_chunk, reference_chunk) = match chunks {
+        [or_chunk] => (or_chunk, &mut no_reference),
+        [or_chunk, reference_chunk] => (or_chunk, reference_chunk),
+        _ => unreachable!("the OR and reference chunks follow the member buffers"),
+    };
+    let mut is_complete = vec![true; paths.len()];
+    let mut all_identical = true;
+    let mut resolved_conflicts = 0u64;
+    let mut conflict_counts: Option<ConflictCounts> = None;
+    let mut unrecovered =
+        (options.warn_unrecovered || options.ignore_trailing_zeros || options.report_unrecoverable)
+            .then(|| UnrecoveredHoles {
+                keep_all: options.report_unrecoverable,
+                ..UnrecoveredHoles::default()
+            });
+    let mut sampler = options
+        .sample_verify
+        .map(|percent| Sampler::new(size, percent, options.sample_seed));
+    let mut has_gaps = false;
+    let mut completeness = options.track_recovered.then(|| CompletenessMap::new(paths));
+    let mut missing_bytes = vec![0u64; paths.len()];
+    let mut missing_in_chunk = vec![0u64; paths.len()];
+    let seeded = is_seeded(paths, options);
+    let order = preference_order(options.prefer, mtimes);
+
+    let mut processed = 0u64;
+    if let Some(state) = restored {
+        log::debug!(
+            "Resuming the merge of {:?} at offset {} of {}",
+            paths[0],
+            state.offset,
+            size
+        );
+        processed = state.offset;
+        all_identical = state.all_identical;
+        has_gaps = state.has_gaps;
+        resolved_conflicts = state.resolved_conflicts;
+        is_complete = state.is_complete;
+        missing_bytes = state.missing_bytes;
+    }
+    let checkpoint_every = (size / 64).clamp(1, RESUME_CHECKPOINT_BYTES);
+    let mut last_checkpoint = processed;
+    while processed < size {
+        // A resumable merge keeps its checkpoint, so a rerun picks up here.
+        if let Some((deadline, timeout)) = deadline
+            && Instant::now() >= deadline
+        {
+            return Err(MergeError::TimedOut(timeout).into());
+        }
+        let chunk_size = ((size - processed) as usize).min(buf_size);
+        let buffers_slice = &mut *buffers;
+        let or_chunk_slice = &mut or_chunk[..chunk_size];
+
+        let read_start = Instant::now();
+        let results = match uring.as_mut() {
+            Some(uring) => read_uring_chunks(uring, buffers_slice, &dropped, processed, chunk_size),
+            None => read_member_chunks(
+                &mut readers,
+                buffers_slice,
+                &dropped,
+                chunk_size,
+                pool.as_ref(),
+            ),
+        };
+        for (i, result) in results.into_iter().enumerate() {
+            let buffer = &mut buffers_slice[i][..chunk_size];
+            if dropped[i] {
+                buffer.fill(0);
+                continue;
+            }
+            if let Err(mut e) = result {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    // The member shrank after the size check; report where it now ends.
+                    let offset = fs::metadata(&paths[i]).map_or(processed, |m| m.len());
+                    e = MergeError::TruncatedMember(paths[i].clone(), offset).into();
+                }
+                if !options.drop_unreadable {
+                    return Err(e);
+                }
+                log::warn!(
+                    "Dropping unreadable member {:?} at offset {}: {}",
+                    paths[i],
+                    processed,
+                    e
+                );
+                dropped[i] = true;
+                buffer.fill(0);
+                let left = dropped.iter().filter(|&&d| !d).count();
+                if left == 0 || (reference.is_none() && left < MIN_READABLE_MEMBERS) {
+                    return Err(MergeError::TooFewReadable(left).into());
+                }
+            }
+        }
+        let read_time = read_start.elapsed();
+        mask_missing_pieces(&controls, buffers_slice, &dropped, processed, chunk_size);
+
+        or_chunk_slice.copy_from_slice(&buffers_slice[0][..chunk_size]);
+
+        let or_chunk_ptr = or_chunk_slice.as_ptr();
+        let (prefix, words, suffix) = unsafe { or_chunk_slice.align_to_mut::<u64>() };
+
+        for b in prefix.iter_mut() {
+            let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
+            for buffer in &buffers_slice[1..] {
+                *b |= buffer[offset];
+            }
+        }
+        for (j, w) in words.iter_mut().enumerate() {
+            for buffer in &buffers_slice[1..] {
+                let (_, other_words, _) = unsafe { buffer[..chunk_size].align_to::<u64>() };
+                *w |= other_words[j];
+            }
+        }
+        for b in suffix.iter_mut() {
+            let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
+            for buffer in &buffers_slice[1..] {
+                *b |= buffer[offset];
+            }
+        }
+
+        let verified_conflict = take_verified_pieces(
+            &controls,
+            buffers_slice,
+            &dropped,
+            processed,
+            or_chunk_slice,
+        );
+
+        // The reference is a complete copy: it replaces the OR, zeros
+        // included, and members never conflict with it.
+        if let Some(reader) = reference_reader.as_mut() {
+            let reference_slice = &mut reference_chunk[..chunk_size];
+            reader.read_exact(reference_slice)?;
+            or_chunk_slice.copy_from_slice(reference_slice);
+        }
+
+        // Members equal to each other but missing what the reference or a
+        // verified piece supplies are not duplicates of the merged file.
+        if all_identical {
+            all_identical = buffers_slice
+                .iter()
+                .all(|buffer| &buffer[..chunk_size] == or_chunk_slice);
+        }
+
+        let complete_before_chunk = is_complete.clone();
+        let mut first_conflict_in_chunk = verified_conflict.filter(|_| reference.is_none());
+        for i in 0..paths.len() {
+            let buffer_slice = &buffers_slice[i][..chunk_size];
+            missing_in_chunk[i] = 0;
+            if buffer_slice != or_chunk_slice {
+                is_complete[i] = false;
+                missing_in_chunk[i] = count_missing(buffer_slice, or_chunk_slice);
+                let sane = reference.is_some() || check_chunk_sanity(buffer_slice, or_chunk_slice);
+                if !sane && let Some(pos) = first_conflict(buffer_slice, or_chunk_slice) {
+                    first_conflict_in_chunk =
+                        Some(first_conflict_in_chunk.map_or(pos, |p| p.min(pos)));
+                }
+            }
+        }
+        if let Some(pos) = first_conflict_in_chunk {
+            if options.conflict_mode == ConflictMode::Strict && !options.count_all_conflicts {
+                merge_temp.discard();
+                return Ok(SanityOutcome::Conflict {
+                    offset: processed + pos as u64,
+                    counts: None,
+                });
+            }
+            if options.conflict_mode == ConflictMode::Strict {
+                // The group has already failed; keep scanning only to count.
+                let members: Vec<&[u8]> = buffers_slice.iter().map(|b| &b[..chunk_size]).collect();
+                let (positions, last) = count_conflicting_positions(&members, or_chunk_slice);
+                let counts = conflict_counts.get_or_insert(ConflictCounts {
+                    positions: 0,
+                    first: processed + pos as u64,
+                    last: 0,
+                });
+                counts.positions += positions;
+                counts.last = processed + last as u64;
+            } else {
+                resolved_conflicts += resolve_chunk_conflicts(
+                    buffers_slice,
+                    or_chunk_slice,
+                    options.conflict_mode,
+                    &order,
+                    seeded && !dropped[0],
+                );
+                if options
+                    .conflict_budget
+                    .is_some_and(|budget| resolved_conflicts > budget)
+                {
+                    merge_temp.discard();
+                    return Ok(SanityOutcome::ConflictBudgetExceeded {
+                        offset: processed + pos as u64,
+                        conflicts: resolved_conflicts,
+                    });
+                }
+                // Members may now match the resolved chunk where they didn't match the OR.
+                for i in 0..paths.len() {
+                    let buffer_slice = &buffers_slice[i][..chunk_size];
+                    is_complete[i] = complete_before_chunk[i] && buffer_slice == or_chunk_slice;
+                    missing_in_chunk[i] = count_missing(buffer_slice, or_chunk_slice);
+                }
+            }
+        }
+        for (total, chunk) in missing_bytes.iter_mut().zip(missing_in_chunk.iter()) {
+            *total += chunk;
+        }
+
+        if conflict_counts.is_none() {
+            writer.write_all(or_chunk_slice)?;
+            if let Some((file, scratch)) = read_back.as_mut() {
+                writer.flush()?;
+                verify_written(file, processed, or_chunk_slice, scratch)?;
+            }
+        }
+        has_gaps = has_gaps || or_chunk_slice.contains(&0);
+        if let Some(map) = completeness.as_mut() {
+            let members: Vec<&[u8]> = buffers_slice.iter().map(|b| &b[..chunk_size]).collect();
+            for (i, buffer) in members.iter().enumerate() {
+                map.record(i, processed, buffer, or_chunk_slice);
+            }
+            map.record_donors(processed, &members, or_chunk_slice);
+        }
+        if let Some(holes) = unrecovered.as_mut() {
+            holes.record(processed, or_chunk_slice);
+        }
+        if let Some(sampler) = sampler.as_mut() {
+            sampler.feed(processed, or_chunk_slice);
+        }
+        processed += chunk_size as u64;
+        if let MergeTemp::Resumable(files) = &merge_temp
+            && conflict_counts.is_none()
+            && !dropped.contains(&true)
+            && processed < size
+            && processed - last_checkpoint >= checkpoint_every
+        {
+            writer.flush()?;
+            let state = ResumeState {
+                mode: mode.clone(),
+                inputs: inputs.clone(),
+                offset: processed,
+                all_identical,
+                has_gaps,
+                resolved_conflicts,
+                is_complete: is_complete.clone(),
+                missing_bytes: missing_bytes.clone(),
+            };
+            files.checkpoint(&temp_file, &state)?;
+            last_checkpoint = processed;
+        }
+
+        let next_size =
+            next_chunk_size(buf_size, chunk_size * paths.len(), read_time, chunk_bounds);
+        if next_size != buf_size {
+            log::debug!("Adjusting chunk size from {} to {}", buf_size, next_size);
+            buf_size = next_size;
+            for buffer in buffers.iter_mut().chain(Some(&mut *or_chunk)) {
+                buffer.resize(buf_size, 0);
+                buffer.shrink_to_fit();
+            }
+            if reference.is_some() {
+                reference_chunk.resize(buf_size, 0);
+                reference_chunk.shrink_to_fit();
+            }
+        }
+    }
+
+    log::debug!("Processed {} of {} bytes for group", processed, size);
+    if let Some(counts) = conflict_counts {
+        merge_temp.discard();
+        return Ok(SanityOutcome::Conflict {
+            offset: counts.first,
+            counts: Some(counts),
+        });
+    }
+    writer.flush()?;
+    // From here on the merged temp is cleaned up like any scratch temp.
+    let temp = match merge_temp {
+        MergeTemp::Scratch(temp) => temp,
+        MergeTemp::Resumable(files) => {
+            let _ = fs::remove_file(&files.state);
+            NamedTempFile::from_parts(temp_file, TempPath::from_path(files.temp))
+        }
+    };
+    Ok(SanityOutcome::Passed {
+        temp,
+        is_complete,
+        all_identical,
+        resolved_conflicts,
+        missing_bytes,
+        unrecovered,
+        has_gaps,
+        completeness,
+        samples: sampler.map_or_else(Vec::new, |s| s.samples),
+    })
+}
+
+// Reads the aria2 control file next to each member. Files that can't be read
+// or describe a download of another size are ignored, leaving that member to
+// the zero-byte heuristic.
+fn load_controls(paths: &[PathBuf], size: u64) -> Vec<Option<ControlFile>> {
+    paths
+        .iter()
+        .map(|path| match ControlFile::load(path) {
+            Ok(Some(control)) if control.total_length == size => {
+                log::debug!("Using the aria2 control file of {:?}", path);
+                Some(control)
+            }
+            Ok(Some(control)) => {
+                log::warn!(
+                    "Ignoring the aria2 control file of {:?}: it describes {} bytes, not {}",
+                    path,
+                    control.total_length,
+                    size
+                );
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Ignoring the aria2 control file of {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Zeroes the pieces of each member's chunk at `offset` that its aria2 control
+// file marks as never downloaded, so leftover junk there reads as missing.
+fn mask_missing_pieces(
+    controls: &[Option<ControlFile>],
+    buffers: &mut [Vec<u8>],
+    dropped: &[bool],
+    offset: u64,
+    len: usize,
+) {
+    for (i, control) in controls.iter().enumerate() {
+        if let Some(control) = control
+            && !dropped[i]
+        {
+            for (range, state) in control.pieces_in(offset, len) {
+                if state == PieceState::Missing {
+                    buffers[i][range].fill(0);
+                }
+            }
+        }
+    }
+}
+
+// Copies the pieces each member's control file marks as verified into the OR
+// chunk at `offset` as they are, zeros included, so another member's bytes
+// there show up as conflicts. Returns the first position in the chunk where
+// two members' verified pieces disagree.
+fn take_verified_pieces(
+    controls: &[Option<ControlFile>],
+    buffers: &[Vec<u8>],
+    dropped: &[bool],
+    offset: u64,
+    or_chunk: &mut [u8],
+) -> Option<usize> {
+    if controls.iter().all(Option::is_none) {
+        return None;
+    }
+    let mut verified_by: Vec<Option<usize>> = vec![None; or_chunk.len()];
+    let mut conflict: Option<usize> = None;
+    for (i, control) in controls.iter().enumerate() {
+        let Some(control) = control else { continue };
+        if dropped[i] {
+            continue;
+        }
+        for (range, state) in control.pieces_in(offset, or_chunk.len()) {
+            if state != PieceState::Complete {
+                continue;
+            }
+            for pos in range {
+                if let Some(k) = verified_by[pos]
+                    && buffers[k][pos] != buffers[i][pos]
+                {
+                    conflict = Some(conflict.map_or(pos, |c| c.min(pos)));
+                }
+                verified_by[pos] = Some(i);
+                or_chunk[pos] = buffers[i][pos];
+            }
+        }
+    }
+    conflict
+}
+
+// Syncs `written` at `offset` to disk, drops it from the page cache so the
+// read really goes to the device, and compares what comes back. `scratch`
+// is grown as needed to hold the read.
+fn verify_written(
+    file: &File,
+    offset: u64,
+    written: &[u8],
+    scratch: &mut Vec<u8>,
+) -> io::Result<()> {
+    file.sync_data()?;
+    drop_cached(file, offset, written.len() as u64);
+    if scratch.len() < written.len() {
+        scratch.resize(written.len(), 0);
+    }
+    let read = &mut scratch[..written.len()];
+    read_exact_at(file, read, offset)?;
+    match read.iter().zip(written).position(|(a, b)| a != b) {
+        Some(i) => Err(MergeError::WriteBackMismatch(offset + i as u64).into()),
+        None => Ok(()),
+    }
+}
+
+// Asks the kernel to drop `len` bytes at `offset` from the page cache.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn drop_cached(file: &File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the descriptor is open for the duration of the call; the
+    // advice only affects caching.
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+}
+
+// Without `posix_fadvise` the read back may be served from the cache, so it
+// only catches corruption between the buffer and the page cache.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn drop_cached(_file: &File, _offset: u64, _len: u64) {}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+// Positioned reads need a platform extension, so seek instead. Clones of the
+// handle share its cursor, so it is put back for the writer.
+#[cfg(not(unix))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let position = file.stream_position()?;
+    file.seek(SeekFrom::Start(offset))?;
+    let read = file.read_exact(buf);
+    file.seek(SeekFrom::Start(position))?;
+    read
+}
+
+/// Bytes read from each member when splitting a size-only group by content.
+pub const PREFIX_CLUSTER_BYTES: u64 = 4096;
+
+/// Splits `paths` into clusters whose first `prefix_len` bytes are mutually
+/// compatible, i.e. wherever two members both hold non-zero bytes they agree.
+/// Members are assigned greedily in order to the first compatible cluster, so
+/// a member with an all-zero prefix joins the first cluster.
+pub fn cluster_by_prefix(paths: &[PathBuf], prefix_len: u64) -> io::Result<Vec<Vec<PathBuf>>> {
+    let mut clusters: Vec<(Vec<Vec<u8>>, Vec<PathBuf>)> = Vec::new();
+    for path in paths {
+        let mut prefix = Vec::new();
+        File::open(path)?
+            .take(prefix_len)
+            .read_to_end(&mut prefix)?;
+        let compatible = |other: &Vec<u8>| {
+            prefix
+                .iter()
+                .zip(other)
+                .all(|(&a, &b)| a == 0 || b == 0 || a == b)
+        };
+        match clusters
+            .iter_mut()
+            .find(|(prefixes, _)| prefixes.iter().all(compatible))
+        {
+            Some((prefixes, members)) => {
+                prefixes.push(prefix);
+                members.push(path.clone());
+            }
+            None => clusters.push((vec![prefix], vec![path.clone()])),
+        }
+    }
+    Ok(clusters.into_iter().map(|(_, members)| members).collect())
+}
+
+/// Windows sampled from each member when clustering a group by content.
+pub const CONTENT_CLUSTER_SAMPLES: u64 = 16;
+
+// Union-find root of `i`, halving paths on the way.
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Splits equally sized `paths` into clusters that share content. Up to
+/// `samples` windows of `window_len` bytes spread evenly over the files are
+/// compared pairwise under the sanity rule, and compatible pairs are joined
+/// with a union-find. Compatibility isn't transitive, so a cluster can still
+/// conflict where no window looked. Clusters keep the order of their first
+/// member.
+pub fn cluster_by_content(
+    paths: &[PathBuf],
+    samples: u64,
+    window_len: u64,
+) -> io::Result<Vec<Vec<PathBuf>>> {
+    let Some(first) = paths.first() else {
+        return Ok(Vec::new());
+    };
+    let size = fs::metadata(first)?.len();
+    if size == 0 || window_len == 0 {
+        return Ok(vec![paths.to_vec()]);
+    }
+    let window_len = window_len.min(size);
+    let span = size - window_len;
+    let samples = samples.clamp(1, span / window_len + 1);
+    let offsets: Vec<u64> = (0..samples)
+        .map(|k| {
+            if samples == 1 {
+                0
+            } else {
+                span * k / (samples - 1)
+            }
+        })
+        .collect();
+
+    let mut windows = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut file = File::open(path)?;
+        let mut member = vec![0; (window_len * samples) as usize];
+        for (offset, window) in offsets.iter().zip(member.chunks_mut(window_len as usize)) {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.read_exact(window)?;
+        }
+        windows.push(member);
+    }
+
+    let mut parents: Vec<usize> = (0..paths.len()).collect();
+    for a in 0..paths.len() {
+        for b in a + 1..paths.len() {
+            let compatible = windows[a]
+                .iter()
+                .zip(&windows[b])
+                .all(|(&x, &y)| x == 0 || y == 0 || x == y);
+            if compatible {
+                let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+                parents[root_a.max(root_b)] = root_a.min(root_b);
+            }
+        }
+    }
+
+    let mut clusters: Vec<(usize, Vec<PathBuf>)> = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let root = find_root(&mut parents, i);
+        match clusters.iter_mut().find(|(r, _)| *r == root) {
+            Some((_, members)) => members.push(path.clone()),
+            None => clusters.push((root, vec![path.clone()])),
+        }
+    }
+    Ok(clusters.into_iter().map(|(_, members)| members).collect())
+}
+
+/// What a full merge of a group is expected to do, judged by `probe_group`
+/// from only the first and last chunk of each member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProbeVerdict {
+    AlreadyComplete,
+    LikelyRecoverable,
+    LikelyConflict,
+    /// Members are empty or differ in size, so the group would be skipped.
+    Unmergeable,
+}
+
+impl ProbeVerdict {
+    pub fn label(self) -> &'static str {
+        match self {
+            ProbeVerdict::AlreadyComplete => "already complete",
+            ProbeVerdict::LikelyRecoverable => "likely recoverable",
+            ProbeVerdict::LikelyConflict => "likely conflict",
+            ProbeVerdict::Unmergeable => "unmergeable",
+        }
+    }
+}
+
+type FileOpener<'a> = &'a dyn Fn(&Path) -> io::Result<File>;
+
+/// Handles to a group's files that stay open across passes over the group,
+/// so each file is opened once however many windows are read from it.
+struct ReaderPool<'a> {
+    paths: Vec<&'a Path>,
+    open: FileOpener<'a>,
+    files: Vec<Option<File>>,
+}
+
+impl<'a> ReaderPool<'a> {
+    fn new(paths: Vec<&'a Path>, open: FileOpener<'a>) -> ReaderPool<'a> {
+        let files = paths.iter().map(|_| None).collect();
+        ReaderPool { paths, open, files }
+    }
+
+    /// Reads `len` bytes at `offset` from file `index`. Each read seeks, so
+    /// earlier reads never affect where the next one starts.
+    fn read_at(&mut self, index: usize, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let file = match &mut self.files[index] {
+            Some(file) => file,
+            slot => slot.insert((self.open)(self.paths[index])?),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; len];
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Runs the sanity and OR logic on the first and last chunk of every member
+/// only. The verdict is an extrapolation: holes or conflicts in the middle of
+/// the files are not seen.
+pub fn probe_group(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    options: &MergeOptions,
+) -> io::Result<ProbeVerdict> {
+    probe_group_with_opener(paths, reference, options, &|p| File::open(p))
+}
+
+fn probe_group_with_opener(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    options: &MergeOptions,
+    open: FileOpener,
+) -> io::Result<ProbeVerdict> {
+    if paths.is_empty() {
+        return Ok(ProbeVerdict::Unmergeable);
+    }
+    let size = fs::metadata(&paths[0])?.len();
+    if size == 0 {
+        return Ok(ProbeVerdict::Unmergeable);
+    }
+    for p in paths[1..].iter().map(|p| p.as_path()).chain(reference) {
+        if fs::metadata(p)?.len() != size {
+            return Ok(ProbeVerdict::Unmergeable);
+        }
+    }
+
+    let chunk = DEFAULT_CHUNK_SIZE.clamp(options.chunk_bounds.min, options.chunk_bounds.max) as u64;
+    let head = chunk.min(size);
+    let tail_start = size.saturating_sub(chunk).max(head);
+    let mut windows = vec![(0, head as usize)];
+    if tail_start < size {
+        windows.push((tail_start, (size - tail_start) as usize));
+    }
+
+    let controls = load_controls(paths, size);
+    let no_drops = vec![false; paths.len()];
+    // The reference, if any, is the pool's last file.
+    let mut pool = ReaderPool::new(
+        paths.iter().map(|p| p.as_path()).chain(reference).collect(),
+        open,
+    );
+    let mut complete = true;
+    for (offset, len) in windows {
+        let mut buffers = (0..paths.len())
+            .map(|i| pool.read_at(i, offset, len))
+            .collect::<io::Result<Vec<_>>>()?;
+        mask_missing_pieces(&controls, &mut buffers, &no_drops, offset, len);
+        let reference_chunk = match reference {
+            Some(_) => Some(pool.read_at(paths.len(), offset, len)?),
+            None => None,
+        };
+        let mut or_chunk = vec![0; len];
+        for buffer in &buffers {
+            for (or_b, b) in or_chunk.iter_mut().zip(buffer) {
+                *or_b |= b;
+            }
+        }
+        let verified_conflict =
+            take_verified_pieces(&controls, &buffers, &no_drops, offset, &mut or_chunk);
+        // As in the full pass, the reference replaces the OR and overrides
+        // every conflict.
+        if let Some(r) = reference_chunk {
+            or_chunk = r;
+        }
+        let sane = reference.is_some()
+            || verified_conflict.is_none()
+                && buffers
+                    .iter()
+                    .all(|buffer| check_chunk_sanity(buffer, &or_chunk));
+        if !sane {
+            return Ok(ProbeVerdict::LikelyConflict);
+        }
+        complete &= buffers.iter().all(|buffer| *buffer == or_chunk);
+    }
+    Ok(if complete {
+        ProbeVerdict::AlreadyComplete
+    } else {
+        ProbeVerdict::LikelyRecoverable
+    })
+}
+
+/// Bytes a merge of the group would fill in its members, judged by a full
+/// sanity pass whose merged data only goes to a temp file. `None` when the
+/// group can't be merged because its members are empty, conflict or differ
+/// in size.
+pub fn recoverable_bytes(
+    paths: &[PathBuf],
+    reference: Option<&Path>,
+    options: &MergeOptions,
+) -> io::Result<Option<u64>> {
+    let mut mtimes = Vec::with_capacity(paths.len());
+    for p in paths {
+        mtimes.push(fs::metadata(p)?.modified()?);
+    }
+    Ok(
+        match check_sanity_and_completes(paths, reference, &mtimes, options)? {
+            SanityOutcome::Passed { missing_bytes, .. } => Some(missing_bytes.iter().sum()),
+            _ => None,
+        },
+    )
+}
+
+const SAMPLE_BLOCK: u64 = 4096;
+
+/// Offset of the first byte where the files at `a` and `b` differ, or the
+/// shorter length when one is a prefix of the other. `None` if they match.
+pub fn first_difference(a: &Path, b: &Path) -> io::Result<Option<u64>> {
+    let mut ra = BufReader::new(File::open(a)?);
+    let mut rb = BufReader::new(File::open(b)?);
+    let mut offset = 0u64;
+    loop {
+        let (chunk_a, chunk_b) = (ra.fill_buf()?, rb.fill_buf()?);
+        let len = chunk_a.len().min(chunk_b.len());
+        if len == 0 {
+            return Ok((chunk_a.len() != chunk_b.len()).then_some(offset));
+        }
+        if let Some(i) = (0..len).find(|&i| chunk_a[i] != chunk_b[i]) {
+            return Ok(Some(offset + i as u64));
+        }
+        ra.consume(len);
+        rb.consume(len);
+        offset += len as u64;
+    }
+}
+
+/// Hash of a block of merged output, captured while merging so the written
+/// file can be spot-checked without keeping the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub offset: u64,
+    pub len: u64,
+    pub hash: u64,
+}
+
+// Hashes randomly chosen blocks of the merged stream as chunks pass by.
+struct Sampler {
+    blocks: Vec<u64>,
+    next: usize,
+    current: Option<DefaultHasher>,
+    size: u64,
+    samples: Vec<Sample>,
+}
+
+impl Sampler {
+    fn new(size: u64, percent: f64, seed: u64) -> Sampler {
+        let total = size.div_ceil(SAMPLE_BLOCK);
+        let wanted = ((total as f64 * percent / 100.0).ceil() as u64).clamp(1, total);
+        let blocks: Vec<u64> = if wanted == total {
+            (0..total).collect()
+        } else {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let mut chosen = BTreeSet::new();
+            while (chosen.len() as u64) < wanted {
+                chosen.insert(rng.u64(0..total));
+            }
+            chosen.into_iter().collect()
+        };
+        Sampler {
+            blocks,
+            next: 0,
+            current: None,
+            size,
+            samples: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, offset: u64, chunk: &[u8]) {
+        let chunk_end = offset + chunk.len() as u64;
+        while let Some(&block) = self.blocks.get(self.next) {
+            let start = block * SAMPLE_BLOCK;
+            let end = (start + SAMPLE_BLOCK).min(self.size);
+            if start >= chunk_end {
+                break;
+            }
+            let from = (start.max(offset) - offset) as usize;
+            let to = (end.min(chunk_end) - offset) as usize;
+            let hasher = self.current.get_or_insert_with(DefaultHasher::new);
+            hasher.write(&chunk[from..to]);
+            if end > chunk_end {
+                break;
+            }
+            self.samples.push(Sample {
+                offset: start,
+                len: end - start,
+                hash: hasher.finish(),
+            });
+            self.current = None;
+            self.next += 1;
+        }
+    }
+}
+
+/// Re-reads every sampled block of `path` and fails if any differs from
+/// what the merge produced.
+pub fn verify_samples(path: &Path, samples: &[Sample]) -> io::Result<()> {
+    if samples.is_empty() {
+        return Ok(());
+    }
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; SAMPLE_BLOCK as usize];
+    for sample in samples {
+        let block = &mut buffer[..sample.len as usize];
+        file.seek(SeekFrom::Start(sample.offset))?;
+        file.read_exact(block)?;
+        let mut hasher = DefaultHasher::new();
+        hasher.write(block);
+        if hasher.finish() != sample.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Sample verification failed for {:?} at offset {}",
+                    path, sample.offset
+                ),
+            ));
+        }
+    }
+    log::debug!("Verified {} sampled blocks of {:?}", samples.len(), path);
+    Ok(())
+}
+
+const PREVIEW_WINDOW: u64 = 64;
+const PREVIEW_ROW: usize = 16;
+const PREVIEW_MAX_MEMBERS: usize = 8;
+
+/// Renders an aligned hexdump of the bytes around `offset` for each member,
+/// marking columns where members hold differing non-zero bytes with `^^`.
+fn format_conflict_preview(paths: &[PathBuf], offset: u64) -> io::Result<String> {
+    use std::fmt::Write as _;
+
+    let size = fs::metadata(&paths[0])?.len();
+    let start = offset.saturating_sub(PREVIEW_WINDOW / 2) & !(PREVIEW_ROW as u64 - 1);
+    let end = (start + PREVIEW_WINDOW).min(size);
+    let shown = &paths[..paths.len().min(PREVIEW_MAX_MEMBERS)];
+
+    let mut windows = Vec::with_capacity(shown.len());
+    for p in shown {
+        let mut file = File::open(p)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut window = vec![0u8; (end - start) as usize];
+        file.read_exact(&mut window)?;
+        windows.push(window);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "first conflict at offset {:#x}", offset).unwrap();
+    for (i, p) in shown.iter().enumerate() {
+        writeln!(out, "  [{}] {}", i, p.display()).unwrap();
+    }
+    if paths.len() > shown.len() {
+        writeln!(
+            out,
+            "  ... {} more members not shown",
+            paths.len() - shown.len()
+        )
+        .unwrap();
+    }
+
+    let len = (end - start) as usize;
+    for row in (0..len).step_by(PREVIEW_ROW) {
+        let row_end = (row + PREVIEW_ROW).min(len);
+        for (i, window) in windows.iter().enumerate() {
+            let hex: Vec<String> = window[row..row_end]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            writeln!(
+                out,
+                "  [{}] {:08x}: {}",
+                i,
+                start + row as u64,
+                hex.join(" ")
+            )
+            .unwrap();
+        }
+        let markers: Vec<&str> = (row..row_end)
+            .map(|pos| {
+                let mut non_zero = windows.iter().map(|w| w[pos]).filter(|&b| b != 0);
+                let first = non_zero.next();
+                if non_zero.any(|b| Some(b) != first) {
+                    "^^"
+                } else {
+                    "  "
+                }
+            })
+            .collect();
+        writeln!(out, "      {:8}  {}", "", markers.join(" ").trim_end()).unwrap();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io;
+    use tempfile::tempdir;
+
+    thread_local! {
+        static SYNCED_PATHS: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn record_sync(path: &Path) {
+        SYNCED_PATHS.with(|synced| synced.borrow_mut().push(path.to_path_buf()));
+    }
+
+    #[test]
+    fn test_merge_options_builder() {
+        let options = MergeOptions::builder().build();
+        assert_eq!(options, MergeOptions::default());
+        assert_eq!(options.output_template, DEFAULT_OUTPUT_TEMPLATE);
+        assert_eq!(options.conflict_mode, ConflictMode::Strict);
+        assert_eq!(options.chunk_bounds, ChunkBounds::default());
+        assert!(!options.replace);
+
+        let options = MergeOptions::builder()
+            .replace(true)
+            .prefer(Prefer::Older)
+            .output_template("{stem}.fixed{ext}")
+            .build();
+        assert!(options.replace);
+        assert_eq!(options.prefer, Prefer::Older);
+        assert_eq!(options.output_template, "{stem}.fixed{ext}");
+    }
+
+    #[test]
+    fn test_single_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data = vec![1u8, 2, 3];
+        fs::write(&p1, &data)?;
+
+        let paths = vec![p1];
+
+        if let SanityOutcome::Passed {
+            temp, is_complete, ..
+        } = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )? {
+            assert_eq!(is_complete, vec![true]);
+            assert_eq!(fs::read(temp.path())?, data);
+        } else {
+            panic!("Expected Some for single file");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_mismatch() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 2, 3])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![4u8, 5])?;
+
+        let paths = vec![p1, p2];
+        let res = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )?;
+        assert!(matches!(res, SanityOutcome::SizeMismatch { path } if path == paths[1]));
+
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::builder().build())?;
+        assert!(matches!(stats.status, GroupStatus::SizeMismatch));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanity_fail() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![2u8, 0])?;
+
+        let paths = vec![p1, p2];
+        let res = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )?;
+        assert!(matches!(res, SanityOutcome::Conflict { offset: 0, .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compatible_merge_multiple() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data1 = vec![1u8, 0, 0];
+        fs::write(&p1, &data1)?;
+
+        let p2 = dir.path().join("b");
+        let data2 = vec![0u8, 1, 0];
+        fs::write(&p2, &data2)?;
+
+        let p3 = dir.path().join("c");
+        let data3 = vec![1u8, 1, 0];
+        fs::write(&p3, &data3)?;
+
+        let paths = vec![p1, p2, p3];
+
+        if let SanityOutcome::Passed {
+            temp, is_complete, ..
+        } = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )? {
+            assert_eq!(is_complete, vec![false, false, true]);
+            assert_eq!(fs::read(temp.path())?, vec![1u8, 1, 0]);
+        } else {
+            panic!("Expected Some for compatible merge");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_creates_merged_for_incomplete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let sub1 = dir.path().join("sub1");
+        fs::create_dir(&sub1)?;
+        let file1 = sub1.join("video.mkv");
+        let data_incomplete = vec![0u8, 0, 0];
+        fs::write(&file1, &data_incomplete)?;
+
+        let sub2 = dir.path().join("sub2");
+        fs::create_dir(&sub2)?;
+        let file2 = sub2.join("video.mkv");
+        let data_complete = vec![4u8, 5, 6];
+        fs::write(&file2, &data_complete)?;
+
+        let paths = vec![file1.clone(), file2.clone()];
+        let stats = process_group(&paths, "video.mkv", None, &MergeOptions::builder().build())?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(!stats.all_identical);
+        assert_eq!(stats.merged_files.len(), 1);
+
+        let merged1 = sub1.join("video.mkv.merged");
+        assert!(merged1.exists());
+        assert_eq!(fs::read(&merged1)?, data_complete);
+
+        let merged2 = sub2.join("video.mkv.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_no_merged_on_conflict() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![2u8, 0])?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::builder().build())?;
+
+        assert!(matches!(stats.status, GroupStatus::Failed));
+
+        let merged1 = dir.path().join("a.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = dir.path().join("b.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_no_merged_all_complete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data = vec![4u8, 5, 6];
+        fs::write(&p1, &data)?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &data)?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::builder().build())?;
+
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert!(stats.all_identical);
+
+        let merged1 = dir.path().join("a.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = dir.path().join("b.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_replace_for_incomplete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let sub1 = dir.path().join("sub1");
+        fs::create_dir(&sub1)?;
+        let file1 = sub1.join("video.mkv");
+        let data_incomplete = vec![0u8, 0, 0];
+        fs::write(&file1, &data_incomplete)?;
+
+        let sub2 = dir.path().join("sub2");
+        fs::create_dir(&sub2)?;
+        let file2 = sub2.join("video.mkv");
+        let data_complete = vec![4u8, 5, 6];
+        fs::write(&file2, &data_complete)?;
+
+        let paths = vec![file1.clone(), file2.clone()];
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            None,
+            &MergeOptions::builder().replace(true).build(),
+        )?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+
+        assert_eq!(fs::read(&file1)?, data_complete);
+        assert_eq!(fs::read(&file2)?, data_complete);
+
+        let merged1 = sub1.join("video.mkv.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = sub2.join("video.mkv.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_stages_and_recovers() -> io::Result<()> {
+        let dir = tempdir()?;
+        let member = dir.path().join("video.mkv");
+        assert_eq!(
+            staging_path(&member),
+            dir.path().join("video.mkv.combine-staging")
+        );
+        fs::write(&member, [1u8, 0, 3, 0])?;
+        let merged = dir.path().join("merged");
+        fs::write(&merged, [1u8, 2, 3, 4])?;
+        stage_replacement(&merged, &member, &AuditLog::new(None, "dummy"))?.persist(&member)?;
+        assert_eq!(fs::read(&member)?, vec![1u8, 2, 3, 4]);
+        assert!(!staging_path(&member).exists());
+
+        // Left by a crash: a finished staging file, one cut short and one
+        // missing data its member has.
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub)?;
+        let finished = sub.join("a.mkv");
+        fs::write(&finished, [1u8, 0, 3, 0])?;
+        fs::write(staging_path(&finished), [1u8, 2, 3, 4])?;
+        let short = dir.path().join("b.mkv");
+        fs::write(&short, [1u8, 0, 3, 0])?;
+        fs::write(staging_path(&short), [1u8, 2])?;
+        let lossy = dir.path().join("c.mkv");
+        fs::write(&lossy, [1u8, 0, 3, 0])?;
+        fs::write(staging_path(&lossy), [1u8, 2, 0, 4])?;
+        let orphan = staging_path(&dir.path().join("gone.mkv"));
+        fs::write(&orphan, [1u8])?;
+
+        let mut recovered = recover_staging(dir.path())?;
+        recovered.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            recovered,
+            vec![
+                (short.clone(), StagingRecovery::Discarded),
+                (lossy.clone(), StagingRecovery::Discarded),
+                (finished.clone(), StagingRecovery::Completed),
+            ]
+        );
+        assert_eq!(fs::read(&finished)?, vec![1u8, 2, 3, 4]);
+        assert_eq!(fs::read(&short)?, vec![1u8, 0, 3, 0]);
+        assert_eq!(fs::read(&lossy)?, vec![1u8, 0, 3, 0]);
+        for member in [&finished, &short, &lossy] {
+            assert!(!staging_path(member).exists());
+        }
+        assert!(orphan.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_normalize_all_replaces_complete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let incomplete = dir.path().join("a.mkv");
+        fs::write(&incomplete, vec![4u8, 0, 0])?;
+        let complete = dir.path().join("b.mkv");
+        fs::write(&complete, vec![4u8, 5, 6])?;
+        File::options()
+            .write(true)
+            .open(&complete)?
+            .set_modified(SystemTime::UNIX_EPOCH)?;
+
+        let paths = vec![incomplete.clone(), complete.clone()];
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            None,
+            &MergeOptions::builder()
+                .replace(true)
+                .normalize_all(true)
+                .build(),
+        )?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(&incomplete)?, vec![4u8, 5, 6]);
+        assert_eq!(fs::read(&complete)?, vec![4u8, 5, 6]);
+        assert_ne!(fs::metadata(&complete)?.modified()?, SystemTime::UNIX_EPOCH);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_skips_target_in_flight() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file1 = dir.path().join("a.mkv");
+        fs::write(&file1, vec![4u8, 0, 0])?;
+        let file2 = dir.path().join("b.mkv");
+        fs::write(&file2, vec![0u8, 5, 6])?;
+        let paths = vec![file1.clone(), file2.clone()];
+        let merged1 = dir.path().join("a.mkv.merged");
+        let merged2 = dir.path().join("b.mkv.merged");
+
+        // Another group is mid-write on a.mkv.merged, reached via a different
+        // spelling of the same path.
+        let claim = TargetClaim::acquire(&dir.path().join(".").join("a.mkv.merged"))
+            .expect("target is free");
+        assert!(TargetClaim::acquire(&merged1).is_none());
+        fs::write(&merged1, b"partial write from the other group")?;
+
+        let stats = process_group(&paths, "video.mkv", None, &MergeOptions::builder().build())?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.merged_files, vec![merged2.clone()]);
+        assert_eq!(fs::read(&merged1)?, b"partial write from the other group");
+        assert_eq!(fs::read(&merged2)?, vec![4u8, 5, 6]);
+
+        drop(claim);
+        let stats = process_group(&paths, "video.mkv", None, &MergeOptions::builder().build())?;
+        assert_eq!(stats.merged_files, vec![merged1.clone(), merged2]);
+        assert_eq!(fs::read(&merged1)?, vec![4u8, 5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_bare_relative_members() -> io::Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.mkv"), vec![4u8, 0, 0])?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub/a.mkv"), vec![0u8, 5, 6])?;
+
+        // Relative members resolve against the current directory; resolve
+        // them against the temp dir instead so no test touches the process'
+        // working directory.
+        let options = MergeOptions::builder().build();
+        let relative = [PathBuf::from("a.mkv"), PathBuf::from("sub/a.mkv")];
+        assert_eq!(member_dir(&relative[0]), Path::new("."));
+        assert_eq!(member_dir(&relative[1]), Path::new("sub"));
+        let outputs = relative
+            .iter()
+            .map(|p| output_path(&options, p))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            outputs,
+            vec![
+                PathBuf::from("a.mkv.merged"),
+                PathBuf::from("sub/a.mkv.merged")
+            ]
+        );
+
+        let paths: Vec<PathBuf> = relative
+            .iter()
+            .map(|p| dir.path().join(member_dir(p)).join(p.file_name().unwrap()))
+            .collect();
+        let stats = process_group(&paths, "a.mkv", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        for output in &outputs {
+            assert_eq!(fs::read(dir.path().join(output))?, vec![4u8, 5, 6]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_reference_completes_partial() -> io::Result<()> {
+        let dir = tempdir()?;
+        let sub1 = dir.path().join("sub1");
+        fs::create_dir(&sub1)?;
+        let file1 = sub1.join("video.mkv");
+        fs::write(&file1, vec![4u8, 0, 0, 7])?;
+
+        let sub2 = dir.path().join("sub2");
+        fs::create_dir(&sub2)?;
+        let file2 = sub2.join("video.mkv");
+        fs::write(&file2, vec![0u8, 5, 0, 7])?;
+
+        let reference = dir.path().join("reference.mkv");
+        let data_complete = vec![4u8, 5, 6, 7];
+        fs::write(&reference, &data_complete)?;
+
+        let paths = vec![file1.clone(), file2.clone()];
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            Some(&reference),
+            &MergeOptions::builder().build(),
+        )?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.merged_files.len(), 2);
+        assert_eq!(fs::read(sub1.join("video.mkv.merged"))?, data_complete);
+        assert_eq!(fs::read(sub2.join("video.mkv.merged"))?, data_complete);
+        assert_eq!(fs::read(&reference)?, data_complete);
+        assert!(!stats.all_identical);
+
+        // Identical members are not duplicates of what the reference fills in.
+        fs::write(&file2, vec![4u8, 0, 0, 7])?;
+        for merged in [sub1.join("video.mkv.merged"), sub2.join("video.mkv.merged")] {
+            fs::remove_file(merged)?;
+        }
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            Some(&reference),
+            &MergeOptions::builder().build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(sub1.join("video.mkv.merged"))?, data_complete);
+        assert!(!stats.all_identical);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reference_overrides_conflicts() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![9u8, 0])?;
+
+        let reference = dir.path().join("ref");
+        fs::write(&reference, vec![1u8, 2])?;
+
+        let paths = vec![p1];
+        if let SanityOutcome::Passed {
+            temp, is_complete, ..
+        } = check_sanity_and_completes(
+            &paths,
+            Some(&reference),
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )? {
+            assert_eq!(is_complete, vec![false]);
+            assert_eq!(fs::read(temp.path())?, vec![1u8, 2]);
+        } else {
+            panic!("Expected reference to override the conflict");
+        }
+
+        // A zero in the reference is data too: it overrides the member's byte.
+        fs::write(&paths[0], vec![9u8, 5])?;
+        fs::write(&reference, vec![1u8, 0])?;
+        if let SanityOutcome::Passed {
+            temp, is_complete, ..
+        } = check_sanity_and_completes(
+            &paths,
+            Some(&reference),
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )? {
+            assert_eq!(is_complete, vec![false]);
+            assert_eq!(fs::read(temp.path())?, vec![1u8, 0]);
+        } else {
+            panic!("Expected reference to override the conflict");
+        }
+        // A member matching the reference except for its zero isn't complete.
+        fs::write(&paths[0], vec![1u8, 5])?;
+        assert_eq!(
+            probe_group(&paths, Some(&reference), &MergeOptions::default())?,
+            ProbeVerdict::LikelyRecoverable
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_preview_marks_differing_byte() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut data1 = vec![0u8; 100];
+        let mut data2 = vec![0u8; 100];
+        data1[40] = 0xaa;
+        data2[40] = 0xbb;
+        data1[41] = 0x11;
+        data2[42] = 0x22;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &data1)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &data2)?;
+
+        let paths = vec![p1, p2];
+        let offset = match check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().build(),
+        )? {
+            SanityOutcome::Conflict { offset, .. } => offset,
+            _ => panic!("Expected a conflict"),
+        };
+        assert_eq!(offset, 40);
+
+        let preview = format_conflict_preview(&paths, offset)?;
+        let lines: Vec<&str> = preview.lines().collect();
+        assert_eq!(lines[0], "first conflict at offset 0x28");
+        assert!(lines.contains(&"  [0] 00000020: 00 00 00 00 00 00 00 00 aa 11 00 00 00 00 00 00"));
+        assert!(lines.contains(&"  [1] 00000020: 00 00 00 00 00 00 00 00 bb 00 22 00 00 00 00 00"));
+        let marker_line = lines
+            .iter()
+            .filter(|l| l.contains("^^"))
+            .collect::<Vec<_>>();
+        assert_eq!(marker_line.len(), 1);
+        let aa_line = lines.iter().find(|l| l.contains(" aa ")).unwrap();
+        assert_eq!(marker_line[0].find("^^"), aa_line.find("aa"));
+        assert!(lines.iter().any(|l| l.contains("00000030")));
+        assert!(!lines.iter().any(|l| l.contains("00000040")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_output_path_templates() -> io::Result<()> {
+        let member = Path::new("/data/show/video.mkv");
+        assert_eq!(
+            render_output_path(DEFAULT_OUTPUT_TEMPLATE, member, None)?,
+            PathBuf::from("/data/show/video.mkv.merged")
+        );
+        assert_eq!(
+            render_output_path("{stem}-combined.{ext}", member, None)?,
+            PathBuf::from("/data/show/video-combined.mkv")
+        );
+        assert_eq!(
+            render_output_path("complete/{name}", member, None)?,
+            PathBuf::from("/data/show/complete/video.mkv")
+        );
+        // A `complete/` sibling of the member's directory, one level up.
+        assert_eq!(
+            render_output_path("../complete/{parent}/{name}", member, None)?,
+            PathBuf::from("/data/complete/show/video.mkv")
+        );
+        assert_eq!(
+            render_output_path("../complete/{name}", Path::new("./show/video.mkv"), None)?,
+            PathBuf::from("complete/video.mkv")
+        );
+        assert_eq!(
+            render_output_path("../{name}", Path::new("video.mkv"), None)?,
+            PathBuf::from("../video.mkv")
+        );
+        assert!(render_output_path("../../{name}", member, None).is_err());
+        assert!(render_output_path("..", member, None).is_err());
+        assert!(render_output_path(".", member, None).is_err());
+        // Nor past the scanned root.
+        let root = Some(Path::new("/data/show"));
+        assert!(render_output_path("../complete/{name}", member, root).is_err());
+        assert!(render_output_path("complete/{name}", member, root).is_ok());
+        assert!(render_output_path("{nope}", member, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_output_template_collision() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![0u8, 2])?;
+
+        let paths = vec![p1, p2];
+        assert!(
+            process_group(
+                &paths,
+                "dummy",
+                None,
+                &MergeOptions::builder().output_template("{name}").build()
+            )
+            .is_err()
+        );
+
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .output_template("complete/{name}")
+                .build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("complete/a"))?, vec![1u8, 2]);
+        assert_eq!(fs::read(dir.path().join("complete/b"))?, vec![1u8, 2]);
+
+        // Outputs colliding with each other are caught before either is written.
+        assert!(
+            process_group(
+                &paths,
+                "dummy",
+                None,
+                &MergeOptions::builder().output_template("same").build()
+            )
+            .is_err()
+        );
+        assert!(!dir.path().join("same").exists());
+
+        // Members spelled with a leading `./` still collide with outputs.
+        #[cfg(unix)]
+        {
+            let depth = std::env::current_dir()?.components().count() - 1;
+            let dotted: Vec<PathBuf> = paths
+                .iter()
+                .map(|p| {
+                    let mut spelled = PathBuf::from(".");
+                    spelled.extend(std::iter::repeat_n("..", depth));
+                    spelled.join(p.strip_prefix("/").unwrap())
+                })
+                .collect();
+            assert!(dotted[0].starts_with("./"));
+            let options = MergeOptions::builder().output_template("{name}").build();
+            assert!(process_group(&dotted, "dummy", None, &options).is_err());
+            assert_eq!(fs::read(&paths[0])?, vec![1u8, 0]);
+            assert_eq!(fs::read(&paths[1])?, vec![0u8, 2]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_budget_aborts_as_divergent() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let p2 = dir.path().join("b");
+        let mut other = vec![1u8; 100];
+        other[..10].fill(2);
+        fs::write(&p1, vec![1u8; 100])?;
+        fs::write(&p2, &other)?;
+        let paths = vec![p1, p2];
+        let options = |budget| {
+            MergeOptions::builder()
+                .conflict_mode(ConflictMode::Salvage)
+                .conflict_budget(Some(budget))
+                .build()
+        };
+
+        let stats = process_group(&paths, "dummy", None, &options(5))?;
+        assert!(matches!(stats.status, GroupStatus::Divergent));
+        assert_eq!(stats.first_conflict, Some(0));
+        assert!(!dir.path().join("b.merged").exists());
+
+        let stats = process_group(&paths, "dummy", None, &options(10))?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("b.merged"))?, vec![1u8; 100]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_incomplete_groups_probe() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251 + 1) as u8).collect();
+        let dense = [dir.path().join("dense-a"), dir.path().join("dense-b")];
+        for p in &dense {
+            fs::write(p, &data)?;
+        }
+        let mut partial = data.clone();
+        partial[50_000..60_000].fill(0);
+        let sparse = [dir.path().join("sparse-a"), dir.path().join("sparse-b")];
+        fs::write(&sparse[0], &partial)?;
+        fs::write(&sparse[1], &data)?;
+
+        let chunk_bounds = ChunkBounds {
+            min: 4096,
+            max: 4096,
+        };
+        let options = MergeOptions::builder()
+            .only_incomplete_groups(true)
+            .chunk_bounds(chunk_bounds)
+            .build();
+        let stats = process_group(&dense, "dense", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::LikelyComplete));
+        assert!(!stats.all_identical);
+
+        let stats = process_group(&sparse, "sparse", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(&stats.merged_files[0])?, data);
+
+        // Without the probe the dense group gets its full pass.
+        let options = MergeOptions::builder().chunk_bounds(chunk_bounds).build();
+        let stats = process_group(&dense, "dense", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert!(stats.all_identical);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xor_detect_drops_garbage_member() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..30_000u32).map(|i| (i % 251 + 1) as u8).collect();
+        let mut paths = Vec::new();
+        for (name, hole) in [
+            ("a", 0..8_000),
+            ("b", 10_000..18_000),
+            ("c", 20_000..28_000),
+        ] {
+            let mut partial = data.clone();
+            partial[hole].fill(0);
+            let path = dir.path().join(name);
+            fs::write(&path, &partial)?;
+            paths.push(path);
+        }
+        let mut rng = fastrand::Rng::with_seed(7);
+        let garbage: Vec<u8> = (0..data.len()).map(|_| rng.u8(1..)).collect();
+        let junk = dir.path().join("junk");
+        fs::write(&junk, &garbage)?;
+        paths.push(junk.clone());
+
+        assert!(matches!(
+            process_group(&paths, "size-30000", None, &MergeOptions::default())?.status,
+            GroupStatus::Failed
+        ));
+
+        let options = MergeOptions::builder().xor_detect(Some(50.0)).build();
+        let stats = process_group(&paths, "size-30000", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.merged_files.len(), 3);
+        for merged in &stats.merged_files {
+            assert_eq!(fs::read(merged)?, data);
+        }
+        assert!(!dir.path().join("junk.merged").exists());
+        assert_eq!(fs::read(&junk)?, garbage);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_if_any_complete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let complete = dir.path().join("complete");
+        fs::write(&complete, vec![1u8, 2, 3])?;
+        let partial = dir.path().join("partial");
+        fs::write(&partial, vec![1u8, 0, 3])?;
+        let paths = vec![complete, partial];
+
+        let options = MergeOptions::builder().skip_if_any_complete(true).build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert!(stats.merged_files.is_empty());
+        assert!(!dir.path().join("partial.merged").exists());
+
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(
+            fs::read(dir.path().join("partial.merged"))?,
+            vec![1u8, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_patch_round_trip() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let p2 = dir.path().join("b");
+        let mut data1 = vec![5u8; 1000];
+        data1[100..200].fill(0);
+        let mut data2 = vec![5u8; 1000];
+        data2[600..].fill(0);
+        fs::write(&p1, &data1)?;
+        fs::write(&p2, &data2)?;
+        let paths = vec![p1.clone(), p2.clone()];
+
+        let options = MergeOptions::builder().emit_patch(true).build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        let patches = [
+            dir.path().join("a.merged.patch"),
+            dir.path().join("b.merged.patch"),
+        ];
+        assert_eq!(stats.merged_files, patches);
+        assert!(!dir.path().join("a.merged").exists());
+
+        for (member, patch) in paths.iter().zip(&patches) {
+            assert!(fs::metadata(patch)?.len() < 1000);
+            crate::patch::apply_patch(File::open(patch)?, member)?;
+            assert_eq!(fs::read(member)?, vec![5u8; 1000]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_output_dir_is_not_writable() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, [1u8, 0])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [0u8, 2])?;
+        let out = dir.path().join("out");
+        fs::create_dir(&out)?;
+        fs::set_permissions(&out, fs::Permissions::from_mode(0o555))?;
+
+        let options = MergeOptions::builder()
+            .output_dir(Some(OutputDir {
+                dir: out.clone(),
+                root: None,
+            }))
+            .build();
+        let err = process_group(&[p1, p2], "a@2", None, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::OutputNotWritable(out.clone()))
+        );
+        assert_eq!(fs::read_dir(&out)?.count(), 0);
+
+        let denied = output_error(io::ErrorKind::PermissionDenied.into(), &out);
+        assert!(MergeError::from_io(&denied).is_some());
+        let other = output_error(io::ErrorKind::NotFound.into(), &out);
+        assert!(MergeError::from_io(&other).is_none());
+        fs::set_permissions(&out, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_members_stage_in_output_dir() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let snapshot = dir.path().join("snapshot");
+        fs::create_dir(&snapshot)?;
+        let p1 = snapshot.join("a");
+        fs::write(&p1, [1u8, 0])?;
+        let p2 = snapshot.join("b");
+        fs::write(&p2, [0u8, 2])?;
+        fs::set_permissions(&snapshot, fs::Permissions::from_mode(0o555))?;
+        let paths = [p1, p2];
+
+        let err = process_group(&paths, "a@2", None, &MergeOptions::default()).unwrap_err();
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::OutputNotWritable(snapshot.clone()))
+        );
+
+        let out = dir.path().join("out");
+        let options = MergeOptions::builder()
+            .output_dir(Some(OutputDir {
+                dir: out.clone(),
+                root: None,
+            }))
+            .build();
+        assert_eq!(merge_temp_dir(&paths[0], &options)?, out);
+        let stats = process_group(&paths, "a@2", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(out.join("a.merged"))?, vec![1u8, 2]);
+        assert_eq!(fs::read(out.join("b.merged"))?, vec![1u8, 2]);
+        assert_eq!(fs::read_dir(&snapshot)?.count(), 2);
+
+        let staging = dir.path().join("staging");
+        let options = MergeOptions::builder()
+            .temp_dir(Some(staging.clone()))
+            .build();
+        assert_eq!(merge_temp_dir(&paths[0], &options)?, staging);
+        fs::set_permissions(&snapshot, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_dir_collision_policies() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("x/v.mkv");
+        let p2 = dir.path().join("y/v.mkv");
+        fs::create_dir_all(dir.path().join("x"))?;
+        fs::create_dir_all(dir.path().join("y"))?;
+        fs::write(&p1, vec![1u8, 0])?;
+        fs::write(&p2, vec![0u8, 2])?;
+        let paths = vec![p1, p2];
+
+        for policy in [
+            OnCollision::Skip,
+            OnCollision::Suffix,
+            OnCollision::Overwrite,
+        ] {
+            // Without a root both members mirror to out/v.mkv, which already
+            // holds an earlier run's output.
+            let out = dir.path().join(format!("{:?}", policy));
+            fs::create_dir_all(&out)?;
+            fs::write(out.join("v.mkv"), b"old")?;
+            let options = MergeOptions::builder()
+                .output_template("{name}")
+                .output_dir(Some(OutputDir {
+                    dir: out.clone(),
+                    root: None,
+                }))
+                .on_collision(policy)
+                .build();
+            let stats = process_group(&paths, "v.mkv", None, &options)?;
+
+            let mut names: Vec<_> = fs::read_dir(&out)?
+                .map(|e| e.map(|e| e.file_name().into_string().unwrap()))
+                .collect::<io::Result<_>>()?;
+            names.sort();
+            match policy {
+                OnCollision::Skip => {
+                    assert!(matches!(stats.status, GroupStatus::Skipped));
+                    assert_eq!(names, ["v.mkv"]);
+                    assert_eq!(fs::read(out.join("v.mkv"))?, b"old");
+                }
+                OnCollision::Suffix => {
+                    assert!(matches!(stats.status, GroupStatus::Merged));
+                    assert_eq!(names, ["v-1.mkv", "v-2.mkv", "v.mkv"]);
+                    assert_eq!(fs::read(out.join("v.mkv"))?, b"old");
+                    assert_eq!(fs::read(out.join("v-2.mkv"))?, vec![1u8, 2]);
+                }
+                OnCollision::Overwrite => {
+                    assert!(matches!(stats.status, GroupStatus::Merged));
+                    assert_eq!(names, ["v.mkv"]);
+                    assert_eq!(fs::read(out.join("v.mkv"))?, vec![1u8, 2]);
+                }
+            }
+        }
+
+        // With a root the two members keep their distinct relative paths.
+        let options = MergeOptions::builder()
+            .output_dir(Some(OutputDir {
+                dir: dir.path().join("mirror"),
+                root: Some(dir.path().to_path_buf()),
+            }))
+            .build();
+        assert_eq!(
+            output_path(&options, &paths[1])?,
+            dir.path().join("mirror/y/v.mkv.merged")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefer_newer_resolves_conflict() -> io::Result<()> {
+        let dir = tempdir()?;
+        let older = dir.path().join("older");
+        fs::write(&older, vec![1u8, 0, 3])?;
+        let newer = dir.path().join("newer");
+        fs::write(&newer, vec![2u8, 5, 0])?;
+
+        let epoch = SystemTime::UNIX_EPOCH;
+        File::options()
+            .write(true)
+            .open(&older)?
+            .set_modified(epoch + Duration::from_secs(1_000))?;
+        File::options()
+            .write(true)
+            .open(&newer)?
+            .set_modified(epoch + Duration::from_secs(2_000))?;
+
+        let paths = vec![older, newer];
+        let mut mtimes = Vec::new();
+        for p in &paths {
+            mtimes.push(fs::metadata(p)?.modified()?);
+        }
+
+        for (prefer, expected) in [
+            (Prefer::Newer, vec![2u8, 5, 3]),
+            (Prefer::Older, vec![1u8, 5, 3]),
+            (Prefer::First, vec![1u8, 5, 3]),
+        ] {
+            match check_sanity_and_completes(
+                &paths,
+                None,
+                &mtimes,
+                &MergeOptions::builder()
+                    .conflict_mode(ConflictMode::Salvage)
+                    .prefer(prefer)
+                    .build(),
+            )? {
+                SanityOutcome::Passed {
+                    temp,
+                    is_complete,
+                    resolved_conflicts,
+                    ..
+                } => {
+                    assert_eq!(fs::read(temp.path())?, expected);
+                    assert_eq!(is_complete, vec![false, false]);
+                    assert_eq!(resolved_conflicts, 1);
+                }
+                _ => panic!("Expected salvage mode to resolve the conflict"),
+            }
+        }
+
+        let res = check_sanity_and_completes(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder().prefer(Prefer::Newer).build(),
+        )?;
+        assert!(matches!(res, SanityOutcome::Conflict { offset: 0, .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_majority_conflict_mode() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut paths = Vec::new();
+        for (name, data) in [("a", [1u8, 0]), ("b", [2u8, 0]), ("c", [2u8, 7])] {
+            let p = dir.path().join(name);
+            fs::write(&p, data)?;
+            paths.push(p);
+        }
+        let mtimes = [SystemTime::UNIX_EPOCH; 3];
+
+        match check_sanity_and_completes(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder()
+                .conflict_mode(ConflictMode::Majority)
+                .build(),
+        )? {
+            SanityOutcome::Passed {
+                temp, is_complete, ..
+            } => {
+                assert_eq!(fs::read(temp.path())?, vec![2u8, 7]);
+                assert_eq!(is_complete, vec![false, false, true]);
+            }
+            _ => panic!("Expected majority mode to resolve the conflict"),
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_process_group_tracing_span() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0, 3])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![1u8, 2, 0])?;
+
+        let paths = vec![p1, p2];
+        process_group(&paths, "video.mkv", None, &MergeOptions::builder().build())?;
+
+        assert!(logs_contain(
+            "process_group{group=\"video.mkv\" members=2 size=3}"
+        ));
+        assert!(logs_contain("status=\"merged\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_output_most_complete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut paths = Vec::new();
+        for (name, data) in [
+            ("least", [1u8, 0, 0, 0]),
+            ("most", [1u8, 2, 3, 0]),
+            ("middle", [0u8, 2, 0, 4]),
+        ] {
+            let sub = dir.path().join(name);
+            fs::create_dir(&sub)?;
+            let p = sub.join("video.mkv");
+            fs::write(&p, data)?;
+            paths.push(p);
+        }
+
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            None,
+            &MergeOptions::builder().output_most_complete(true).build(),
+        )?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        let expected = dir.path().join("most/video.mkv.merged");
+        assert_eq!(stats.merged_files, vec![expected.clone()]);
+        assert_eq!(fs::read(&expected)?, vec![1u8, 2, 3, 4]);
+        assert!(!dir.path().join("least/video.mkv.merged").exists());
+        assert!(!dir.path().join("middle/video.mkv.merged").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribute_hardlinks_members() -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir()?;
+        let mut paths = Vec::new();
+        for (name, data) in [
+            ("least", [1u8, 0, 0, 0]),
+            ("most", [1u8, 2, 3, 0]),
+            ("middle", [0u8, 2, 0, 4]),
+        ] {
+            let sub = dir.path().join(name);
+            fs::create_dir(&sub)?;
+            let p = sub.join("video.mkv");
+            fs::write(&p, data)?;
+            paths.push(p);
+        }
+
+        let stats = process_group(
+            &paths,
+            "video.mkv",
+            None,
+            &MergeOptions::builder()
+                .output_most_complete(true)
+                .distribute(true)
+                .build(),
+        )?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        let merged = dir.path().join("most/video.mkv.merged");
+        assert_eq!(stats.merged_files, vec![merged.clone()]);
+        let merged_meta = fs::metadata(&merged)?;
+        assert_eq!(merged_meta.nlink(), 4);
+        for p in &paths {
+            assert_eq!(fs::read(p)?, vec![1u8, 2, 3, 4]);
+            assert_eq!(fs::metadata(p)?.ino(), merged_meta.ino());
+        }
+        // No temp files are left behind.
+        for name in ["least", "most", "middle"] {
+            let expected = if name == "most" { 2 } else { 1 };
+            assert_eq!(fs::read_dir(dir.path().join(name))?.count(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsync_syncs_outputs_and_dirs() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, [1u8, 0, 3])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [1u8, 2, 0])?;
+        let paths = vec![p1.clone(), p2.clone()];
+        let synced = || SYNCED_PATHS.with(|synced| synced.take());
+
+        process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(synced().is_empty());
+
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .fsync(true)
+                .output_template("{name}.synced")
+                .build(),
+        )?;
+        assert_eq!(stats.merged_files.len(), 2);
+        let synced = synced();
+        // Each output's temp file, then its directory.
+        assert_eq!(synced.len(), 4);
+        assert_eq!(synced[1], dir.path());
+        assert_eq!(synced[3], dir.path());
+        assert_eq!(fs::read(&stats.merged_files[0])?, vec![1u8, 2, 3]);
+
+        process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder().fsync(true).replace(true).build(),
+        )?;
+        assert_eq!(SYNCED_PATHS.with(|synced| synced.take()).len(), 4);
+        assert_eq!(fs::read(&p1)?, vec![1u8, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_merged_artifact_with_verify_only() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, [1u8, 0, 3, 0])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [1u8, 2, 0, 0])?;
+        let artifacts = dir.path().join("artifacts");
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let stats = process_group(
+            &paths,
+            "video.mkv@4",
+            None,
+            &MergeOptions::builder()
+                .verify_only(true)
+                .keep_merged_artifact(Some(artifacts.clone()))
+                .build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert!(stats.merged_files.is_empty());
+        assert_eq!(
+            fs::read(artifacts.join("video.mkv@4.or"))?,
+            vec![1u8, 2, 3, 0]
+        );
+        assert_eq!(fs::read_dir(&artifacts)?.count(), 1);
+        // Verifying leaves the members alone and writes no outputs.
+        assert_eq!(fs::read(&p1)?, vec![1u8, 0, 3, 0]);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_majority_size_with_outlier_first() -> io::Result<()> {
+        let dir = tempdir()?;
+        let outlier = dir.path().join("outlier");
+        fs::write(&outlier, [9u8; 7])?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, [1u8, 0, 3, 4])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [1u8, 2, 0, 4])?;
+        let p3 = dir.path().join("c");
+        fs::write(&p3, [0u8, 2, 3, 0])?;
+
+        let paths = vec![outlier.clone(), p1, p2, p3];
+        assert_eq!(majority_size(&paths)?, 4);
+        // The outlier is flagged rather than blamed on the members that agree.
+        let res = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::default(),
+        )?;
+        assert!(matches!(res, SanityOutcome::SizeMismatch { path } if path == outlier));
+
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder().drop_size_outliers(true).build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.bytes_processed, 4);
+        assert_eq!(stats.merged_files.len(), 3);
+        for merged in &stats.merged_files {
+            assert_eq!(fs::read(merged)?, vec![1u8, 2, 3, 4]);
+        }
+        assert!(!dir.path().join("outlier.merged").exists());
+        assert_eq!(fs::read(&outlier)?, vec![9u8; 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_refuses_symlinks_outside_root() -> io::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(root.join("inside"))?;
+        fs::create_dir(&outside)?;
+        std::os::unix::fs::symlink(&outside, root.join("link"))?;
+        let p1 = root.join("inside/video.mkv");
+        fs::write(&p1, [1u8, 0, 3])?;
+        fs::write(outside.join("video.mkv"), [1u8, 2, 0])?;
+        let p2 = root.join("link/video.mkv");
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let within = |root: &Path| {
+            MergeOptions::builder()
+                .replace(true)
+                .replace_within(Some(fs::canonicalize(root).unwrap()))
+                .build()
+        };
+        let stats = process_group(&paths, "video.mkv", None, &within(&root))?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(&p1)?, vec![1u8, 2, 3]);
+        assert_eq!(fs::read(outside.join("video.mkv"))?, vec![1u8, 2, 0]);
+
+        // Allowing the outside tree replaces it too.
+        let stats = process_group(&paths, "video.mkv", None, &within(dir.path()))?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(outside.join("video.mkv"))?, vec![1u8, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_but_divergent_group() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, [1u8, 2, 3, 4])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [1u8, 2, 5, 4])?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Divergent));
+        assert_eq!(stats.first_conflict, Some(2));
+
+        // A partial that conflicts is an ordinary failure.
+        fs::write(&p2, [1u8, 2, 5, 0])?;
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_skip_matches_bytewise_checks() {
+        let or_chunk: Vec<u8> = (0..3 * COMPARE_BLOCK + 100)
+            .map(|i| (i % 251 + 1) as u8)
+            .collect();
+        let mut holes = or_chunk.clone();
+        holes[5..9].fill(0);
+        holes[2 * COMPARE_BLOCK + 50..].fill(0);
+        let mut conflict = holes.clone();
+        conflict[COMPARE_BLOCK + 7] ^= 0xff;
+        let mut tail_conflict = or_chunk.clone();
+        *tail_conflict.last_mut().unwrap() ^= 0xff;
+
+        for buffer in [&or_chunk, &holes, &conflict, &tail_conflict] {
+            let bytewise_missing = buffer.iter().zip(&or_chunk).filter(|(b, o)| b != o).count();
+            let bytewise_sane = buffer.iter().zip(&or_chunk).all(|(b, o)| *b == 0 || b == o);
+            assert_eq!(count_missing(buffer, &or_chunk), bytewise_missing as u64);
+            assert_eq!(check_chunk_sanity(buffer, &or_chunk), bytewise_sane);
+            // Unaligned starts split blocks differently from the word view.
+            assert_eq!(
+                check_chunk_sanity(&buffer[3..], &or_chunk[3..]),
+                bytewise_sane
+            );
+        }
+        assert_eq!(count_missing(&or_chunk, &or_chunk), 0);
+        assert!(!check_chunk_sanity(&conflict, &or_chunk));
+        assert_eq!(
+            first_conflict(&conflict, &or_chunk),
+            Some(COMPARE_BLOCK + 7)
+        );
+    }
+
+    #[test]
+    fn test_next_chunk_size_policy() {
+        let bounds = ChunkBounds {
+            min: 1 << 20,
+            max: 8 << 20,
+        };
+        let mib = 1 << 20;
+        // 4 MiB in one second is seek-bound: grow.
+        assert_eq!(
+            next_chunk_size(mib, 4 * mib, Duration::from_secs(1), bounds),
+            2 * mib
+        );
+        // Growth stops at the upper bound.
+        assert_eq!(
+            next_chunk_size(8 * mib, 4 * mib, Duration::from_secs(1), bounds),
+            8 * mib
+        );
+        // 256 MiB/s is in the comfortable band: keep.
+        assert_eq!(
+            next_chunk_size(2 * mib, 64 * mib, Duration::from_millis(250), bounds),
+            2 * mib
+        );
+        // 4 GiB/s is fast: shrink, but not below the lower bound.
+        assert_eq!(
+            next_chunk_size(4 * mib, 4096 * mib, Duration::from_secs(1), bounds),
+            2 * mib
+        );
+        assert_eq!(
+            next_chunk_size(mib, 4096 * mib, Duration::from_secs(1), bounds),
+            mib
+        );
+        // Fixed bounds disable adaptation.
+        assert_eq!(
+            next_chunk_size(mib, 4 * mib, Duration::from_secs(1), ChunkBounds::default()),
+            mib
+        );
+    }
+
+    #[test]
+    fn test_small_chunk_bounds_merge() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251 + 1) as u8).collect();
+        let mut partial1 = data.clone();
+        let mut partial2 = data.clone();
+        partial1[..500].fill(0);
+        partial2[500..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &partial1)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &partial2)?;
+
+        let paths = vec![p1, p2];
+        let bounds = ChunkBounds { min: 16, max: 256 };
+        match check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder().chunk_bounds(bounds).build(),
+        )? {
+            SanityOutcome::Passed { temp, .. } => assert_eq!(fs::read(temp.path())?, data),
+            _ => panic!("Expected the merge to pass"),
+        }
+        Ok(())
+    }
+
+    struct FailingReader {
+        data: Vec<u8>,
+        pos: usize,
+        fail_at: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos >= self.fail_at {
+                return Err(io::Error::other("bad sector"));
+            }
+            let n = buf.len().min(self.fail_at - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_strict_sanity_all_positions_counts_conflicts() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data = vec![7u8; 1000];
+        let mut other = data.clone();
+        for pos in [10, 11, 300, 999] {
+            other[pos] = 9;
+        }
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &data)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &other)?;
+
+        let paths = vec![p1, p2];
+        let res = check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder()
+                .chunk_bounds(ChunkBounds { min: 64, max: 64 })
+                .count_all_conflicts(true)
+                .build(),
+        )?;
+        match res {
+            SanityOutcome::Conflict { offset, counts } => {
+                assert_eq!(offset, 10);
+                assert_eq!(
+                    counts,
+                    Some(ConflictCounts {
+                        positions: 4,
+                        first: 10,
+                        last: 999
+                    })
+                );
+            }
+            _ => panic!("Expected a conflict"),
+        }
+
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder().count_all_conflicts(true).build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Divergent));
+        assert_eq!(stats.first_conflict, Some(10));
+        assert_eq!(stats.conflicts.map(|c| c.positions), Some(4));
+        assert!(stats.merged_files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn_unrecovered_reports_shared_holes() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut data = vec![5u8; 1000];
+        data[100..200].fill(0);
+        data[500..510].fill(0);
+        let mut partial = data.clone();
+        partial[..50].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &data)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &partial)?;
+
+        let paths = vec![p1, p2];
+        match check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &MergeOptions::builder()
+                .chunk_bounds(ChunkBounds { min: 64, max: 64 })
+                .warn_unrecovered(true)
+                .build(),
+        )? {
+            SanityOutcome::Passed {
+                is_complete,
+                unrecovered: Some(holes),
+                ..
+            } => {
+                assert_eq!(is_complete, vec![true, false]);
+                assert_eq!(holes.bytes, 110);
+                assert_eq!(holes.holes, 2);
+                assert_eq!(holes.ranges, vec![100..200, 500..510]);
+            }
+            _ => panic!("Expected the merge to pass with a holes report"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_member_after_grouping() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..64u8).map(|b| b + 1).collect();
+        let mut partial = data.clone();
+        partial[32..].fill(0);
+        let good = dir.path().join("good");
+        fs::write(&good, &data)?;
+        let short = dir.path().join("short");
+        fs::write(&short, &partial)?;
+        let other = dir.path().join("other");
+        fs::write(&other, &partial)?;
+
+        // Truncate the member between the size check and the first read.
+        let opener = |p: &Path, offset| -> io::Result<MemberReader> {
+            if p.ends_with("short") {
+                File::options().write(true).open(p)?.set_len(40)?;
+            }
+            open_member(p, offset)
+        };
+        let paths = vec![good, short.clone(), other];
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+        let bounds = ChunkBounds { min: 16, max: 16 };
+
+        let err = check_sanity_and_completes_with_opener(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder().chunk_bounds(bounds).build(),
+            &opener,
+        )
+        .err()
+        .expect("truncated member should fail the group");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::TruncatedMember(short, 40))
+        );
+
+        // Restore the full size so the second run truncates it again.
+        fs::write(&paths[1], &partial)?;
+        match check_sanity_and_completes_with_opener(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder()
+                .chunk_bounds(bounds)
+                .drop_unreadable(true)
+                .build(),
+            &opener,
+        )? {
+            SanityOutcome::Passed {
+                temp, is_complete, ..
+            } => {
+                assert_eq!(fs::read(temp.path())?, data);
+                assert_eq!(is_complete, vec![true, false, false]);
+            }
+            _ => panic!("Expected the remaining members to complete the merge"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_trailing_zeros_notes_gap() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut data = vec![3u8; 100];
+        data[90..].fill(0);
+        let mut first = data.clone();
+        first[..40].fill(0);
+        let mut second = data.clone();
+        second[40..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &first)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &second)?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        // Without the flag the unfilled tail leaves the group with gaps.
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .output_template("{name}.plain")
+                .build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.trailing_gap, None);
+        assert!(!stats.fully_reconstructed);
+
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder().ignore_trailing_zeros(true).build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.trailing_gap, Some(10));
+        assert!(stats.fully_reconstructed);
+        assert_eq!(stats.merged_files.len(), 2);
+        for merged in &stats.merged_files {
+            assert_eq!(fs::read(merged)?, data);
+        }
+
+        // A hole before the tail is not a trailing gap.
+        second[20..30].fill(0);
+        fs::write(&p2, &second)?;
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .ignore_trailing_zeros(true)
+                .output_template("{name}.again")
+                .build(),
+        )?;
+        assert_eq!(stats.trailing_gap, None);
+        assert!(!stats.fully_reconstructed);
+        assert_eq!(stats.unrecovered.map(|holes| holes.holes), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_verify_detects_tampering() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251 + 1) as u8).collect();
+        let mut first = data.clone();
+        first[..20_000].fill(0);
+        let mut second = data.clone();
+        second[20_000..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &first)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &second)?;
+
+        let paths = vec![p1, p2];
+        let options = MergeOptions::builder()
+            .sample_verify(Some(100.0))
+            .chunk_bounds(ChunkBounds {
+                min: 1000,
+                max: 1000,
+            })
+            .build();
+        let samples = match check_sanity_and_completes(
+            &paths,
+            None,
+            &vec![SystemTime::UNIX_EPOCH; paths.len()],
+            &options,
+        )? {
+            SanityOutcome::Passed { samples, .. } => samples,
+            _ => panic!("Expected the merge to pass"),
+        };
+        assert_eq!(samples.len(), 10);
+        assert_eq!(samples[9].len, 40_000 - 9 * 4096);
+
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert_eq!(stats.merged_files.len(), 2);
+        let output = &stats.merged_files[0];
+        verify_samples(output, &samples)?;
+
+        let mut tampered = data.clone();
+        tampered[30_000] ^= 0xff;
+        fs::write(output, &tampered)?;
+        let err = verify_samples(output, &samples).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A partial sample is reproducible for a given seed.
+        let sampled = |seed| {
+            let mut sampler = Sampler::new(data.len() as u64, 25.0, seed);
+            sampler.feed(0, &data);
+            sampler.samples
+        };
+        assert_eq!(sampled(7).len(), 3);
+        assert_eq!(sampled(7), sampled(7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_files_per_group_matches_sequential() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 241 + 1) as u8).collect();
+        let mut paths = Vec::new();
+        for i in 0..4 {
+            let mut partial = data.clone();
+            for (j, b) in partial.iter_mut().enumerate() {
+                if (j / 700) % 4 == i {
+                    *b = 0;
+                }
+            }
+            let p = dir.path().join(format!("m{}", i));
+            fs::write(&p, &partial)?;
+            paths.push(p);
+        }
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+
+        let mut results = Vec::new();
+        for parallel in [1, 2, 4, 16] {
+            let options = MergeOptions::builder()
+                .parallel_files_per_group(parallel)
+                .chunk_bounds(ChunkBounds {
+                    min: 1000,
+                    max: 1000,
+                })
+                .build();
+            match check_sanity_and_completes(&paths, None, &mtimes, &options)? {
+                SanityOutcome::Passed {
+                    temp,
+                    is_complete,
+                    missing_bytes,
+                    ..
+                } => results.push((fs::read(temp.path())?, is_complete, missing_bytes)),
+                _ => panic!("Expected the merge to pass"),
+            }
+        }
+        assert_eq!(results[0].0, data);
+        assert_eq!(results[0].1, vec![false; 4]);
+        for result in &results[1..] {
+            assert_eq!(result, &results[0]);
+        }
+
+        // Exercise the pooled read path even on a single-core machine.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let mut readers: Vec<MemberReader> = paths
+            .iter()
+            .map(|p| open_member(p, 0))
+            .collect::<io::Result<_>>()?;
+        let mut buffers = vec![vec![0; 100]; paths.len()];
+        let results = read_member_chunks(
+            &mut readers,
+            &mut buffers,
+            &[false, true, false, false],
+            100,
+            Some(&pool),
+        );
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(buffers[0], vec![0; 100]);
+        assert_eq!(buffers[1], vec![0; 100]);
+        assert_eq!(buffers[2], data[..100]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_group_verdicts() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 199 + 1) as u8).collect();
+        let mut first = data.clone();
+        first[..2500].fill(0);
+        let mut second = data.clone();
+        second[2500..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &first)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &second)?;
+        let options = MergeOptions::builder()
+            .chunk_bounds(ChunkBounds {
+                min: 1000,
+                max: 1000,
+            })
+            .build();
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let verdict = probe_group(&paths, None, &options)?;
+        assert_eq!(verdict, ProbeVerdict::LikelyRecoverable);
+        assert_eq!(verdict.label(), "likely recoverable");
+        // Nothing is written while probing.
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+
+        fs::write(&p2, &data)?;
+        fs::write(&p1, &data)?;
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::AlreadyComplete
+        );
+
+        let mut conflicting = data.clone();
+        conflicting[4999] ^= 0xff;
+        fs::write(&p2, &conflicting)?;
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::LikelyConflict
+        );
+
+        fs::write(&p2, &data[1..])?;
+        assert_eq!(
+            probe_group(&paths, None, &options)?,
+            ProbeVerdict::Unmergeable
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_prefix_and_deterministic_names() -> io::Result<()> {
+        let dir = tempdir()?;
+        let key = dir.path().join("movie.mkv");
+        let options = MergeOptions::builder().temp_prefix(".tc-").build();
+        let random = create_temp(dir.path(), &key, &options)?;
+        let name = random.path().file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with(".tc-"), "{}", name);
+
+        let options = MergeOptions::builder()
+            .temp_prefix(".tc-")
+            .deterministic_temp(true)
+            .build();
+        let first = create_temp(dir.path(), &key, &options)?;
+        let name = first
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(name.starts_with(".tc-movie.mkv-"), "{}", name);
+        // A second temp for the same key gets a distinct, still predictable name.
+        let second = create_temp(dir.path(), &key, &options)?;
+        assert_eq!(
+            second.path().file_name().unwrap().to_string_lossy(),
+            format!("{}.1", name)
+        );
+        drop(first);
+        let again = create_temp(dir.path(), &key, &options)?;
+        assert_eq!(again.path().file_name().unwrap().to_string_lossy(), name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fully_reconstructed_from_incomplete_members() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..300u32).map(|i| (i % 97 + 1) as u8).collect();
+        let mut first = data.clone();
+        first[..100].fill(0);
+        let mut second = data.clone();
+        second[100..200].fill(0);
+        let mut third = data.clone();
+        third[200..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &first)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &second)?;
+        let p3 = dir.path().join("c");
+        fs::write(&p3, &third)?;
 
-    let size = fs::metadata(&paths[0])?.len();
-    if size == 0 {
-        return Ok(None);
-    }
+        let paths = vec![p1.clone(), p2.clone(), p3.clone()];
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(stats.fully_reconstructed);
+        for merged in &stats.merged_files {
+            assert_eq!(fs::read(merged)?, data);
+        }
 
-    for p in &paths[1..] {
-        if fs::metadata(p)?.len() != size {
-            log::error!("Size mismatch in group for path {:?}", p);
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Size mismatch in group",
-            ));
+        // Every member misses the same range, so a gap is left behind.
+        for (path, partial) in [(&p1, &mut first), (&p2, &mut second), (&p3, &mut third)] {
+            partial[150..160].fill(0);
+            fs::write(path, &partial)?;
         }
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .output_template("{name}.again")
+                .build(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(!stats.fully_reconstructed);
+        Ok(())
     }
 
-    log::debug!("Checking sanity for {} files of size {}", paths.len(), size);
+    #[test]
+    fn test_track_recovered_ranges() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 113 + 1) as u8).collect();
+        let mut partial = data.clone();
+        partial[10..20].fill(0);
+        // Spans the 1000 byte chunk boundary.
+        partial[900..1100].fill(0);
+        partial[2990..].fill(0);
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &partial)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &data)?;
 
-    let temp_dir = paths[0].parent().ok_or(io::Error::new(
-        io::ErrorKind::InvalidInput,
-        "No parent directory for first path",
-    ))?;
-    let temp = NamedTempFile::new_in(temp_dir)?;
-    let file = temp.reopen()?;
-    let mut writer = BufWriter::new(file);
+        let paths = vec![p1.clone(), p2.clone()];
+        let options = MergeOptions::builder()
+            .track_recovered(true)
+            .chunk_bounds(ChunkBounds {
+                min: 1000,
+                max: 1000,
+            })
+            .build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        let map = stats.completeness.expect("tracking was requested");
+        assert_eq!(
+            map.recovered(&p1),
+            Some(&[(10, 10), (900, 200), (2990, 10)][..])
+        );
+        assert_eq!(map.recovered(&p2), Some(&[][..]));
 
-    let mut readers: Vec<BufReader<File>> = Vec::with_capacity(paths.len());
-    for p in paths {
-        readers.push(BufReader::new(File::open(p)?));
+        let stats = process_group(
+            &paths,
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .output_template("{name}.again")
+                .build(),
+        )?;
+        assert_eq!(stats.completeness, None);
+        Ok(())
     }
 
-    const BUF_SIZE: usize = 1 << 20;
-    let mut buffers: Vec<Vec<u8>> = (0..paths.len()).map(|_| vec![0; BUF_SIZE]).collect();
-    let mut is_complete = vec![true; paths.len()];
-    let mut or_chunk = vec![0; BUF_SIZE];
+    #[test]
+    fn test_track_recovered_donors() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 113 + 1) as u8).collect();
+        let mut front = data.clone();
+        front[1500..].fill(0);
+        let mut back = data.clone();
+        back[..1200].fill(0);
+        let p1 = dir.path().join("front");
+        fs::write(&p1, &front)?;
+        let p2 = dir.path().join("back");
+        fs::write(&p2, &back)?;
 
-    let mut processed = 0u64;
-    while processed < size {
-        let chunk_size = ((size - processed) as usize).min(BUF_SIZE);
-        let buffers_slice = &mut buffers;
-        let or_chunk_slice = &mut or_chunk[..chunk_size];
+        let options = MergeOptions::builder()
+            .track_recovered(true)
+            .chunk_bounds(ChunkBounds {
+                min: 1000,
+                max: 1000,
+            })
+            .build();
+        let stats = process_group(&[p1, p2], "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        // Both hold 1200..1500, so nobody gained it; the front run spans the
+        // chunk boundary at 1000.
+        let map = stats.completeness.expect("tracking was requested");
+        assert_eq!(map.donors, vec![(0, 1200, Some(0)), (1500, 1500, Some(1))]);
+        Ok(())
+    }
 
-        for (i, reader) in readers.iter_mut().enumerate() {
-            reader.read_exact(&mut buffers_slice[i][..chunk_size])?;
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn test_io_uring_matches_sync() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 233 + 1) as u8).collect();
+        let mut paths = Vec::new();
+        for i in 0..3 {
+            let mut partial = data.clone();
+            for (j, b) in partial.iter_mut().enumerate() {
+                if (j / 900) % 3 == i {
+                    *b = 0;
+                }
+            }
+            let p = dir.path().join(format!("m{}", i));
+            fs::write(&p, &partial)?;
+            paths.push(p);
         }
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
 
-        or_chunk_slice.copy_from_slice(&buffers_slice[0][..chunk_size]);
+        let mut results = Vec::new();
+        for io_uring in [false, true] {
+            let options = MergeOptions::builder()
+                .io_uring(io_uring)
+                .chunk_bounds(ChunkBounds {
+                    min: 1000,
+                    max: 1000,
+                })
+                .build();
+            match check_sanity_and_completes(&paths, None, &mtimes, &options)? {
+                SanityOutcome::Passed {
+                    temp,
+                    is_complete,
+                    missing_bytes,
+                    ..
+                } => results.push((fs::read(temp.path())?, is_complete, missing_bytes)),
+                _ => panic!("Expected the merge to pass"),
+            }
+        }
+        assert_eq!(results[0].0, data);
+        assert_eq!(results[1], results[0]);
 
-        let or_chunk_ptr = or_chunk_slice.as_ptr();
-        let (prefix, words, suffix) = unsafe { or_chunk_slice.align_to_mut::<u64>() };
+        // Reading past the end is reported like the synchronous path.
+        let mut uring = crate::uring::UringReader::open(&paths)?;
+        let mut buffers = vec![vec![0; 100]; paths.len()];
+        let results = uring.read_chunks(&mut buffers, &[false, true, false], 9_950, 100);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(results[1].is_ok());
+        Ok(())
+    }
 
-        for b in prefix.iter_mut() {
-            let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-            for i in 1..paths.len() {
-                *b |= buffers_slice[i][offset];
-            }
+    #[test]
+    fn test_probe_reuses_member_handles() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..8000u32).map(|i| (i % 199 + 1) as u8).collect();
+        let mut paths = Vec::new();
+        for i in 0..12 {
+            let mut partial = data.clone();
+            partial[i * 500..(i + 1) * 500].fill(0);
+            let p = dir.path().join(format!("m{}", i));
+            fs::write(&p, &partial)?;
+            paths.push(p);
         }
-        for (j, w) in words.iter_mut().enumerate() {
-            for i in 1..paths.len() {
-                let (_, other_words, _) =
-                    unsafe { buffers_slice[i][..chunk_size].align_to::<u64>() };
-                *w |= other_words[j];
+        let reference = dir.path().join("reference");
+        fs::write(&reference, &data)?;
+        let options = MergeOptions::builder()
+            .chunk_bounds(ChunkBounds {
+                min: 1000,
+                max: 1000,
+            })
+            .build();
+
+        let opened = std::cell::Cell::new(0);
+        let counting = |p: &Path| {
+            opened.set(opened.get() + 1);
+            File::open(p)
+        };
+        let verdict = probe_group_with_opener(&paths, Some(&reference), &options, &counting)?;
+        assert_eq!(verdict, ProbeVerdict::LikelyRecoverable);
+        // Two windows per file, but every file is opened once.
+        assert_eq!(opened.get(), paths.len() + 1);
+
+        let mut pool = ReaderPool::new(vec![paths[0].as_path()], &counting);
+        assert_eq!(pool.read_at(0, 7000, 1000)?, data[7000..]);
+        assert_eq!(pool.read_at(0, 0, 10)?, vec![0; 10]);
+        assert_eq!(pool.read_at(0, 500, 10)?, data[500..510]);
+        assert_eq!(opened.get(), paths.len() + 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_unreadable_member() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..64u8).map(|b| b + 1).collect();
+        let mut partial = data.clone();
+        partial[32..].fill(0);
+        let flaky = dir.path().join("flaky");
+        fs::write(&flaky, &partial)?;
+        let good1 = dir.path().join("good1");
+        fs::write(&good1, &data)?;
+        let good2 = dir.path().join("good2");
+        fs::write(&good2, &partial)?;
+
+        let opener = |p: &Path, offset| -> io::Result<MemberReader> {
+            if p.ends_with("flaky") {
+                Ok(Box::new(FailingReader {
+                    data: fs::read(p)?,
+                    pos: offset as usize,
+                    fail_at: 20,
+                }))
+            } else {
+                open_member(p, offset)
             }
-        }
-        for b in suffix.iter_mut() {
-            let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-            for i in 1..paths.len() {
-                *b |= buffers_slice[i][offset];
+        };
+        let paths = vec![flaky, good1, good2];
+        let bounds = ChunkBounds { min: 16, max: 16 };
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+
+        let res = check_sanity_and_completes_with_opener(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder().chunk_bounds(bounds).build(),
+            &opener,
+        );
+        assert_eq!(
+            res.err().map(|e| e.to_string()),
+            Some("bad sector".to_string())
+        );
+
+        match check_sanity_and_completes_with_opener(
+            &paths,
+            None,
+            &mtimes,
+            &MergeOptions::builder()
+                .chunk_bounds(bounds)
+                .drop_unreadable(true)
+                .build(),
+            &opener,
+        )? {
+            SanityOutcome::Passed {
+                temp, is_complete, ..
+            } => {
+                assert_eq!(fs::read(temp.path())?, data);
+                assert_eq!(is_complete, vec![false, true, false]);
             }
+            _ => panic!("Expected the readable members to complete the merge"),
         }
 
-        for i in 0..paths.len() {
-            let buffer_slice = &buffers_slice[i][..chunk_size];
-            if buffer_slice != or_chunk_slice {
-                is_complete[i] = false;
-                let (prefix, words, suffix) = unsafe { buffer_slice.align_to::<u64>() };
-                let (or_prefix, or_words, or_suffix) = unsafe { or_chunk_slice.align_to::<u64>() };
+        // Dropping one of two members would leave nothing to merge with.
+        let err = check_sanity_and_completes_with_opener(
+            &paths[..2],
+            None,
+            &mtimes[..2],
+            &MergeOptions::builder()
+                .chunk_bounds(bounds)
+                .drop_unreadable(true)
+                .build(),
+            &opener,
+        )
+        .err()
+        .expect("a single readable member should fail the group");
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::TooFewReadable(1))
+        );
+        Ok(())
+    }
 
-                if !prefix
-                    .iter()
-                    .zip(or_prefix.iter())
-                    .all(|(b, or_b)| *b == 0 || *b == *or_b)
-                {
-                    return Ok(None);
-                }
-                if !words
-                    .iter()
-                    .zip(or_words.iter())
-                    .all(|(w, or_w)| check_word_sanity(*w, *or_w))
-                {
-                    return Ok(None);
-                }
-                if !suffix
-                    .iter()
-                    .zip(or_suffix.iter())
-                    .all(|(b, or_b)| *b == 0 || *b == *or_b)
+    #[test]
+    fn test_memory_budget_bounds_concurrent_groups() {
+        let budget = MemoryBudget::new(1000);
+        let bounds = ChunkBounds { min: 10, max: 100 };
+
+        // Groups that fit keep their chunk size; bigger ones are held to the
+        // chunks that fit, and one too big even at the smallest runs alone.
+        assert_eq!(budget.plan(6, bounds), (600, bounds));
+        assert_eq!(
+            budget.plan(20, bounds),
+            (1000, ChunkBounds { min: 10, max: 50 })
+        );
+        assert_eq!(
+            budget.plan(200, bounds),
+            (1000, ChunkBounds { min: 10, max: 10 })
+        );
+
+        // Threads reserving at once wait their turn instead of going over.
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _reservation = budget.reserve(400);
+                    std::thread::sleep(Duration::from_millis(5));
+                });
+            }
+        });
+        assert_eq!(budget.peak(), 800);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn test_paranoid_detects_write_back_mismatch() -> io::Result<()> {
+        // Corrupts one byte past the first chunk on its way to disk.
+        struct FaultyWriter {
+            file: File,
+            written: u64,
+        }
+        impl Write for FaultyWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let mut data = buf.to_vec();
+                if let Some(i) = 20u64.checked_sub(self.written)
+                    && let Some(byte) = data.get_mut(i as usize)
                 {
-                    return Ok(None);
+                    *byte ^= 0xff;
                 }
+                self.file.write_all(&data)?;
+                self.written += buf.len() as u64;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.file.flush()
             }
         }
+        let faulty = |file: File| -> TempWriter { Box::new(FaultyWriter { file, written: 0 }) };
 
-        writer.write_all(or_chunk_slice)?;
-        processed += chunk_size as u64;
-    }
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let p2 = dir.path().join("b");
+        fs::write(&p1, [1u8, 0].repeat(32))?;
+        fs::write(&p2, [0u8, 2].repeat(32))?;
+        let paths = vec![p1, p2];
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+        let options = |paranoid| {
+            MergeOptions::builder()
+                .chunk_bounds(ChunkBounds { min: 16, max: 16 })
+                .paranoid(paranoid)
+                .build()
+        };
+        let check = |options: &MergeOptions, wrap: &dyn Fn(File) -> TempWriter| {
+            check_sanity_and_completes_with_io(&paths, None, &mtimes, options, &open_member, wrap)
+        };
 
-    log::debug!("Processed {} of {} bytes for group", processed, size);
-    writer.flush()?;
-    Ok(Some((temp, is_complete)))
-}
+        // Without paranoid mode the corruption goes unnoticed.
+        let outcome = check(&options(false), &faulty)?;
+        assert!(matches!(outcome, SanityOutcome::Passed { .. }));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::io;
-    use tempfile::tempdir;
+        let err = check(&options(true), &faulty)
+            .err()
+            .expect("the corrupted chunk should fail the group");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::WriteBackMismatch(20))
+        );
+
+        let outcome = check(&options(true), &|file| Box::new(file))?;
+        assert!(matches!(outcome, SanityOutcome::Passed { .. }));
+        Ok(())
+    }
 
     #[test]
-    fn test_single_file() -> io::Result<()> {
+    fn test_audit_log_records_replace_in_order() -> io::Result<()> {
         let dir = tempdir()?;
         let p1 = dir.path().join("a");
-        let data = vec![1u8, 2, 3];
-        fs::write(&p1, &data)?;
+        fs::write(&p1, [1u8, 0, 3])?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, [1u8, 2, 3])?;
+        let journal = dir.path().join("audit.jsonl");
 
-        let paths = vec![p1];
+        process_group(
+            &[p1.clone(), p2],
+            "dummy",
+            None,
+            &MergeOptions::builder()
+                .replace(true)
+                .audit_log(Some(journal.clone()))
+                .build(),
+        )?;
+        assert_eq!(fs::read(&p1)?, vec![1u8, 2, 3]);
 
-        if let Some((temp, is_complete)) = check_sanity_and_completes(&paths)? {
-            assert_eq!(is_complete, vec![true]);
-            assert_eq!(fs::read(temp.path())?, data);
-        } else {
-            panic!("Expected Some for single file");
+        let journal = fs::read_to_string(&journal)?;
+        let events: Vec<&str> = journal
+            .lines()
+            .map(|line| {
+                line.split("\"event\":\"")
+                    .nth(1)
+                    .unwrap()
+                    .split('"')
+                    .next()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(events, vec!["intend-replace", "wrote-temp", "renamed"]);
+        let staging = crate::report::json_escape(&staging_path(&p1).to_string_lossy());
+        for line in journal.lines() {
+            assert!(line.contains("\"group\":\"dummy\""));
+            assert!(line.contains(&format!("\"temp\":{}", staging)));
         }
         Ok(())
     }
 
     #[test]
-    fn test_size_mismatch() -> io::Result<()> {
+    fn test_resumable_temp_continues_from_checkpoint() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 2, 3])?;
+        let data: Vec<u8> = (0..64u8).map(|b| b + 1).collect();
+        let mut partial = data.clone();
+        partial[32..].fill(0);
+        let a = dir.path().join("a");
+        fs::write(&a, &data)?;
+        let b = dir.path().join("b");
+        fs::write(&b, &partial)?;
+        let paths = vec![a, b];
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+        let options = MergeOptions::builder()
+            .chunk_bounds(ChunkBounds { min: 16, max: 16 })
+            .resumable_temp(true)
+            .build();
 
-        let p2 = dir.path().join("b");
-        fs::write(&p2, vec![4u8, 5])?;
+        let starts = std::sync::Mutex::new(Vec::new());
+        let failing = |p: &Path, offset| -> io::Result<MemberReader> {
+            starts.lock().unwrap().push(offset);
+            Ok(Box::new(FailingReader {
+                data: fs::read(p)?,
+                pos: offset as usize,
+                fail_at: 40,
+            }))
+        };
+        let resume_files = |dir: &Path| -> io::Result<usize> {
+            Ok(fs::read_dir(dir)?
+                .filter_map(Result::ok)
+                .filter(|e| e.file_name().to_string_lossy().contains(RESUME_SUFFIX))
+                .count())
+        };
+        let res = check_sanity_and_completes_with_opener(&paths, None, &mtimes, &options, &failing);
+        assert!(res.is_err());
+        assert_eq!(resume_files(dir.path())?, 2);
 
-        let paths = vec![p1, p2];
-        let res = check_sanity_and_completes(&paths);
+        // A changed member invalidates the checkpoint.
+        File::options()
+            .write(true)
+            .open(&paths[1])?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(60))?;
+        starts.lock().unwrap().clear();
+        let res = check_sanity_and_completes_with_opener(&paths, None, &mtimes, &options, &failing);
         assert!(res.is_err());
+        assert_eq!(*starts.lock().unwrap(), vec![0, 0]);
+
+        starts.lock().unwrap().clear();
+        let recording = |p: &Path, offset| -> io::Result<MemberReader> {
+            starts.lock().unwrap().push(offset);
+            open_member(p, offset)
+        };
+        match check_sanity_and_completes_with_opener(&paths, None, &mtimes, &options, &recording)? {
+            SanityOutcome::Passed {
+                temp, is_complete, ..
+            } => {
+                assert_eq!(fs::read(temp.path())?, data);
+                assert_eq!(is_complete, vec![true, false]);
+            }
+            _ => panic!("Expected the resumed merge to pass"),
+        }
+        assert_eq!(*starts.lock().unwrap(), vec![32, 32]);
+        assert_eq!(resume_files(dir.path())?, 0);
         Ok(())
     }
 
     #[test]
-    fn test_sanity_fail() -> io::Result<()> {
+    fn test_zero_byte_members() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 0])?;
+        let empty1 = dir.path().join("empty1");
+        fs::write(&empty1, b"")?;
+        let empty2 = dir.path().join("empty2");
+        fs::write(&empty2, b"")?;
 
-        let p2 = dir.path().join("b");
-        fs::write(&p2, vec![2u8, 0])?;
+        // A group of only zero-byte members is skipped, or reported as empty.
+        let all_empty = vec![empty1.clone(), empty2];
+        let stats = process_group(&all_empty, "empty", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        let options = MergeOptions::builder()
+            .include_zero_byte_files(true)
+            .build();
+        let stats = process_group(&all_empty, "empty", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Empty));
+        assert!(stats.merged_files.is_empty());
 
-        let paths = vec![p1, p2];
-        let res = check_sanity_and_completes(&paths)?;
-        assert!(res.is_none());
+        // In a group with data the zero-byte member is dropped.
+        let a = dir.path().join("a");
+        fs::write(&a, [1u8, 0, 3])?;
+        let b = dir.path().join("b");
+        fs::write(&b, [0u8, 2, 3])?;
+        let mixed = vec![empty1.clone(), a.clone(), b.clone()];
+        for options in [MergeOptions::default(), options] {
+            let stats = process_group(&mixed, "mixed", None, &options)?;
+            assert!(matches!(stats.status, GroupStatus::Merged));
+            assert_eq!(stats.merged_files.len(), 2);
+            assert_eq!(stats.bytes_recovered, 2);
+            assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![1, 2, 3]);
+            assert!(!dir.path().join("empty1.merged").exists());
+            assert_eq!(fs::metadata(&empty1)?.len(), 0);
+            for merged in stats.merged_files {
+                fs::remove_file(merged)?;
+            }
+        }
         Ok(())
     }
 
+    struct SlowReader {
+        inner: MemberReader,
+        delay: Duration,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.delay);
+            self.inner.read(buf)
+        }
+    }
+
     #[test]
-    fn test_compatible_merge_multiple() -> io::Result<()> {
+    fn test_group_timeout_abandons_slow_group() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        let data1 = vec![1u8, 0, 0];
-        fs::write(&p1, &data1)?;
+        let mut data = vec![7u8; 1024];
+        data[..512].fill(0);
+        let a = dir.path().join("a");
+        fs::write(&a, &data)?;
+        data[..512].fill(7);
+        data[512..].fill(0);
+        let b = dir.path().join("b");
+        fs::write(&b, &data)?;
+        let paths = vec![a, b];
+        let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+        let slow = |p: &Path, offset| -> io::Result<MemberReader> {
+            Ok(Box::new(SlowReader {
+                inner: open_member(p, offset)?,
+                delay: Duration::from_millis(5),
+            }))
+        };
+        let timeout = Duration::from_millis(30);
+        let options = MergeOptions::builder()
+            .chunk_bounds(ChunkBounds { min: 16, max: 16 })
+            .group_timeout(Some(timeout))
+            .build();
 
-        let p2 = dir.path().join("b");
-        let data2 = vec![0u8, 1, 0];
-        fs::write(&p2, &data2)?;
-
-        let p3 = dir.path().join("c");
-        let data3 = vec![1u8, 1, 0];
-        fs::write(&p3, &data3)?;
+        let err = check_sanity_and_completes_with_opener(&paths, None, &mtimes, &options, &slow)
+            .err()
+            .expect("Expected the slow merge to time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            MergeError::from_io(&err),
+            Some(&MergeError::TimedOut(timeout))
+        );
+        // The temp file is gone and no output was written.
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+        Ok(())
+    }
 
-        let paths = vec![p1, p2, p3];
+    #[test]
+    fn test_buffer_pool_reuses_buffers_across_groups() -> io::Result<()> {
+        // Buffers given back are handed out again, resized.
+        let first = PooledBuffers::take(3, 64, true);
+        let ptrs: Vec<*const u8> = first.buffers.iter().map(|b| b.as_ptr()).collect();
+        let mut first = first;
+        first.buffers[0].fill(9);
+        drop(first);
+        let second = PooledBuffers::take(2, 32, true);
+        assert!(second.buffers.iter().all(|b| ptrs.contains(&b.as_ptr())));
+        assert!(second.buffers.iter().all(|b| b.len() == 32));
+        drop(second);
 
-        if let Some((temp, is_complete)) = check_sanity_and_completes(&paths)? {
-            assert_eq!(is_complete, vec![false, false, true]);
-            assert_eq!(fs::read(temp.path())?, vec![1u8, 1, 0]);
-        } else {
-            panic!("Expected Some for compatible merge");
+        // Groups with differing member counts merge the same whether their
+        // buffers come from the pool or not.
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=200u8).collect();
+        let mut results = Vec::new();
+        for (members, pooled) in [(2, true), (4, true), (3, true), (3, false)] {
+            let mut paths = Vec::new();
+            for m in 0..members {
+                let mut partial = data.clone();
+                for (i, b) in partial.iter_mut().enumerate() {
+                    if i % members != m {
+                        *b = 0;
+                    }
+                }
+                let p = dir.path().join(format!("{}-{}-{}", members, pooled, m));
+                fs::write(&p, &partial)?;
+                paths.push(p);
+            }
+            let mtimes = vec![SystemTime::UNIX_EPOCH; paths.len()];
+            let mut builder =
+                MergeOptions::builder().chunk_bounds(ChunkBounds { min: 16, max: 64 });
+            if !pooled {
+                builder = builder.max_memory(Some(u64::MAX));
+            }
+            match check_sanity_and_completes(&paths, None, &mtimes, &builder.build())? {
+                SanityOutcome::Passed { temp, .. } => results.push(fs::read(temp.path())?),
+                _ => panic!("Expected the merge to pass"),
+            }
         }
+        assert!(results.iter().all(|merged| *merged == data));
         Ok(())
     }
 
     #[test]
-    fn test_process_group_creates_merged_for_incomplete() -> io::Result<()> {
+    fn test_skip_reasons() -> io::Result<()> {
         let dir = tempdir()?;
-        let sub1 = dir.path().join("sub1");
-        fs::create_dir(&sub1)?;
-        let file1 = sub1.join("video.mkv");
-        let data_incomplete = vec![0u8, 0, 0];
-        fs::write(&file1, &data_incomplete)?;
+        let complete = dir.path().join("complete");
+        fs::write(&complete, vec![1u8, 2, 3])?;
+        let copy = dir.path().join("copy");
+        fs::write(&copy, vec![1u8, 2, 3])?;
+        let partial = dir.path().join("partial");
+        fs::write(&partial, vec![1u8, 0, 3])?;
 
-        let sub2 = dir.path().join("sub2");
-        fs::create_dir(&sub2)?;
-        let file2 = sub2.join("video.mkv");
-        let data_complete = vec![4u8, 5, 6];
-        fs::write(&file2, &data_complete)?;
+        let stats = process_group(
+            &[complete.clone(), copy],
+            "dummy",
+            None,
+            &MergeOptions::default(),
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert_eq!(stats.skip_reason, Some(SkipReason::AllComplete));
 
-        let paths = vec![file1.clone(), file2.clone()];
-        let stats = process_group(&paths, "video.mkv", false)?;
+        let paths = vec![complete, partial];
+        let options = MergeOptions::builder().skip_if_any_complete(true).build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert_eq!(stats.skip_reason, Some(SkipReason::HasComplete));
 
-        assert!(matches!(stats.status, GroupStatus::Merged));
-        assert_eq!(stats.merged_files.len(), 1);
+        let options = MergeOptions::builder().verify_only(true).build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert_eq!(stats.skip_reason, Some(SkipReason::VerifyOnly));
 
-        let merged1 = sub1.join("video.mkv.merged");
-        assert!(merged1.exists());
-        assert_eq!(fs::read(&merged1)?, data_complete);
+        let stats = process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.skip_reason, None);
 
-        let merged2 = sub2.join("video.mkv.merged");
-        assert!(!merged2.exists());
+        let empty = [dir.path().join("e1"), dir.path().join("e2")];
+        for path in &empty {
+            fs::write(path, b"")?;
+        }
+        let stats = process_group(&empty, "empty", None, &MergeOptions::default())?;
+        assert_eq!(stats.skip_reason, Some(SkipReason::ZeroByte));
         Ok(())
     }
 
     #[test]
-    fn test_process_group_no_merged_on_conflict() -> io::Result<()> {
+    fn test_atomic_group_writes_nothing_on_failure() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 0])?;
-
-        let p2 = dir.path().join("b");
-        fs::write(&p2, vec![2u8, 0])?;
+        let root = dir.path().join("root");
+        let members = [root.join("x/v"), root.join("y/v"), root.join("z/v")];
+        for (i, member) in members.iter().enumerate() {
+            fs::create_dir_all(member.parent().unwrap())?;
+            let mut data = vec![0u8; 3];
+            data[i] = i as u8 + 1;
+            fs::write(member, data)?;
+        }
+        let out = dir.path().join("out");
+        fs::create_dir(&out)?;
+        // The second output's directory can't be created.
+        fs::write(out.join("y"), b"")?;
 
-        let paths = vec![p1.clone(), p2.clone()];
-        let stats = process_group(&paths, "dummy", false)?;
+        let output_dir = Some(OutputDir {
+            dir: out.clone(),
+            root: Some(root.clone()),
+        });
+        let options = MergeOptions::builder()
+            .output_dir(output_dir.clone())
+            .build();
+        assert!(process_group(&members, "v@3", None, &options).is_err());
+        assert_eq!(fs::read(out.join("x/v.merged"))?, vec![1u8, 2, 3]);
 
-        assert!(matches!(stats.status, GroupStatus::Failed));
+        fs::remove_dir_all(out.join("x"))?;
+        let options = MergeOptions::builder()
+            .output_dir(output_dir)
+            .atomic_group(true)
+            .build();
+        assert!(process_group(&members, "v@3", None, &options).is_err());
+        assert_eq!(fs::read_dir(out.join("x"))?.count(), 0);
 
-        let merged1 = dir.path().join("a.merged");
-        assert!(!merged1.exists());
+        // Under --replace the second staging file can't be written.
+        fs::create_dir(staging_path(&members[1]))?;
+        let options = MergeOptions::builder()
+            .replace(true)
+            .atomic_group(true)
+            .build();
+        assert!(process_group(&members, "v@3", None, &options).is_err());
+        assert_eq!(fs::read(&members[0])?, vec![1u8, 0, 0]);
+        assert!(!staging_path(&members[0]).exists());
 
-        let merged2 = dir.path().join("b.merged");
-        assert!(!merged2.exists());
+        fs::remove_dir(staging_path(&members[1]))?;
+        let stats = process_group(&members, "v@3", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        for member in &members {
+            assert_eq!(fs::read(member)?, vec![1u8, 2, 3]);
+        }
         Ok(())
     }
 
     #[test]
-    fn test_process_group_no_merged_all_complete() -> io::Result<()> {
+    fn test_seed_member_leads_the_merge() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        let data = vec![4u8, 5, 6];
-        fs::write(&p1, &data)?;
+        let a = dir.path().join("a");
+        fs::write(&a, [1u8, 0, 3, 0])?;
+        let b = dir.path().join("b");
+        fs::write(&b, [1u8, 0, 3, 0])?;
+        let seed = dir.path().join("seed");
+        fs::write(&seed, [9u8, 2, 0, 4])?;
+        let paths = vec![a.clone(), b.clone(), seed.clone()];
 
-        let p2 = dir.path().join("b");
-        fs::write(&p2, &data)?;
+        // Without a seed the majority wins the conflict at offset 0.
+        let options = MergeOptions::builder()
+            .conflict_mode(ConflictMode::Majority)
+            .build();
+        process_group(&paths, "dummy", None, &options)?;
+        assert_eq!(
+            fs::read(dir.path().join("seed.merged"))?,
+            vec![1u8, 2, 3, 4]
+        );
 
-        let paths = vec![p1.clone(), p2.clone()];
-        let stats = process_group(&paths, "dummy", false)?;
+        let options = MergeOptions::builder()
+            .conflict_mode(ConflictMode::Majority)
+            .seed_members(BTreeSet::from([seed.clone()]))
+            .build();
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![9u8, 2, 3, 4]);
+        assert_eq!(
+            fs::read(dir.path().join("seed.merged"))?,
+            vec![9u8, 2, 3, 4]
+        );
+
+        // The seed's size is the group's, even against the majority.
+        fs::write(&seed, [9u8, 2, 0])?;
+        let stats = process_group(&paths, "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::SizeMismatch));
+        assert_eq!(stats.bytes_processed, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_identical_check() -> io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, [1u8, 0, 3])?;
+        fs::write(&b, [1u8, 0, 3])?;
+        let options = MergeOptions::builder().fast_identical_check(true).build();
 
+        let stats = process_group(&[a.clone(), b.clone()], "dummy", None, &options)?;
         assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert_eq!(stats.skip_reason, Some(SkipReason::AllIdentical));
+        assert!(stats.all_identical);
 
-        let merged1 = dir.path().join("a.merged");
-        assert!(!merged1.exists());
+        // Members that differ take the normal path.
+        fs::write(&b, [0u8, 2, 3])?;
+        let stats = process_group(&[a.clone(), b.clone()], "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.skip_reason, None);
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![1u8, 2, 3]);
 
-        let merged2 = dir.path().join("b.merged");
-        assert!(!merged2.exists());
+        // The comparison is held to the group's timeout.
+        fs::write(&b, [1u8, 0, 3])?;
+        let options = MergeOptions::builder()
+            .fast_identical_check(true)
+            .group_timeout(Some(Duration::ZERO))
+            .build();
+        let err = process_group(&[a, b], "dummy", None, &options).unwrap_err();
+        assert!(matches!(
+            MergeError::from_io(&err),
+            Some(MergeError::TimedOut(_))
+        ));
         Ok(())
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_process_group_replace_for_incomplete() -> io::Result<()> {
+    fn test_preserve_xattrs() -> io::Result<()> {
+        use std::ffi::OsStr;
         let dir = tempdir()?;
-        let sub1 = dir.path().join("sub1");
-        fs::create_dir(&sub1)?;
-        let file1 = sub1.join("video.mkv");
-        let data_incomplete = vec![0u8, 0, 0];
-        fs::write(&file1, &data_incomplete)?;
-
-        let sub2 = dir.path().join("sub2");
-        fs::create_dir(&sub2)?;
-        let file2 = sub2.join("video.mkv");
-        let data_complete = vec![4u8, 5, 6];
-        fs::write(&file2, &data_complete)?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, [1u8, 0, 0, 0])?;
+        fs::write(&b, [0u8, 0, 3, 4])?;
+        let name = OsStr::new("user.origin");
+        match xattr::set(&a, name, b"tracker-a") {
+            // The filesystem under the temp dir may not take user attributes.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+            result => result?,
+        }
+        let paths = vec![a.clone(), b.clone()];
 
-        let paths = vec![file1.clone(), file2.clone()];
-        let stats = process_group(&paths, "video.mkv", true)?;
+        process_group(&paths, "dummy", None, &MergeOptions::default())?;
+        assert!(xattr::get(&dir.path().join("a.merged"), name).is_err());
 
+        let options = MergeOptions::builder().preserve_xattrs(true).build();
+        for merged in ["a.merged", "b.merged"] {
+            fs::remove_file(dir.path().join(merged))?;
+        }
+        let stats = process_group(&paths, "dummy", None, &options)?;
         assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(
+            xattr::get(&dir.path().join("a.merged"), name)?,
+            b"tracker-a"
+        );
+        assert!(xattr::get(&dir.path().join("b.merged"), name).is_err());
 
-        assert_eq!(fs::read(&file1)?, data_complete);
-        assert_eq!(fs::read(&file2)?, data_complete);
+        let options = MergeOptions::builder()
+            .replace(true)
+            .preserve_xattrs(true)
+            .build();
+        process_group(&paths, "dummy", None, &options)?;
+        assert_eq!(fs::read(&a)?, vec![1u8, 0, 3, 4]);
+        assert_eq!(xattr::get(&a, name)?, b"tracker-a");
+        Ok(())
+    }
 
-        let merged1 = sub1.join("video.mkv.merged");
-        assert!(!merged1.exists());
+    #[test]
+    fn test_single_member_after_dedup_is_skipped() -> io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, [1u8, 0, 3, 0])?;
+        fs::hard_link(&a, &b)?;
+        let options = MergeOptions::default();
 
-        let merged2 = sub2.join("video.mkv.merged");
-        assert!(!merged2.exists());
+        let stats = process_group(&[a.clone(), b.clone()], "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert_eq!(stats.skip_reason, Some(SkipReason::SingleAfterDedup));
+        assert!(stats.merged_files.is_empty());
+        assert!(!dir.path().join("a.merged").exists());
+
+        // Dropping a zero-byte member can leave one file too.
+        let empty = dir.path().join("empty");
+        fs::write(&empty, [])?;
+        let stats = process_group(&[a.clone(), empty], "dummy", None, &options)?;
+        assert_eq!(stats.skip_reason, Some(SkipReason::SingleAfterDedup));
+
+        // A reference can still complete a lone member.
+        let reference = dir.path().join("reference");
+        fs::write(&reference, [1u8, 2, 3, 4])?;
+        let stats = process_group(&[a.clone(), b.clone()], "dummy", Some(&reference), &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![1u8, 2, 3, 4]);
+        assert_eq!(fs::read(dir.path().join("b.merged"))?, vec![1u8, 2, 3, 4]);
+        for merged in ["a.merged", "b.merged"] {
+            fs::remove_file(dir.path().join(merged))?;
+        }
+        let stats = process_group(
+            std::slice::from_ref(&a),
+            "dummy",
+            Some(&reference),
+            &options,
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![1u8, 2, 3, 4]);
+        fs::remove_file(dir.path().join("a.merged"))?;
+
+        // A third, distinct member makes the group worth merging.
+        let c = dir.path().join("c");
+        fs::write(&c, [0u8, 2, 0, 4])?;
+        let stats = process_group(&[a, b, c], "dummy", None, &options)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(dir.path().join("c.merged"))?, vec![1u8, 2, 3, 4]);
         Ok(())
     }
 }
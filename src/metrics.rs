@@ -0,0 +1,192 @@
+//! Run totals in the Prometheus text format, pushed to a Pushgateway so
+//! scheduled runs show up in monitoring.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::run::MergeReport;
+
+/// Job label the metrics are pushed under.
+pub const METRICS_JOB: &str = "torrent_combine";
+
+// Bounds connecting to, and each read and write with, the Pushgateway.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Renders the totals of `report` as Prometheus text exposition.
+pub fn metrics_text(report: &MergeReport) -> String {
+    let counts = &report.counts;
+    let metrics: [(&str, &str, &str, String); 6] = [
+        (
+            "groups_total",
+            "counter",
+            "Groups found to process.",
+            report.total_groups.to_string(),
+        ),
+        (
+            "merged_total",
+            "counter",
+            "Groups whose merged data was written.",
+            counts.merged.to_string(),
+        ),
+        (
+            "skipped_total",
+            "counter",
+            "Groups with nothing to write.",
+            counts.skipped.to_string(),
+        ),
+        (
+            "failed_total",
+            "counter",
+            "Groups that failed.",
+            report.failures.len().to_string(),
+        ),
+        (
+            "bytes_recovered_total",
+            "counter",
+            "Bytes filled in the members that merges wrote.",
+            counts.bytes_recovered.to_string(),
+        ),
+        (
+            "last_run_duration_seconds",
+            "gauge",
+            "Time spent processing groups in the last run.",
+            format!("{:.3}", report.elapsed.as_secs_f64()),
+        ),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {}_{} {}", METRICS_JOB, name, help);
+        let _ = writeln!(text, "# TYPE {}_{} {}", METRICS_JOB, name, kind);
+        let _ = writeln!(text, "{}_{} {}", METRICS_JOB, name, value);
+    }
+    text
+}
+
+/// POSTs `body` to the Pushgateway at `url`, an `http://host[:port][/path]`
+/// base URL, under the [`METRICS_JOB`] job. TLS is not supported.
+pub fn push_metrics(url: &str, body: &str) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("Pushgateway URL must start with http://"))?;
+    let (authority, base) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    if authority.is_empty() {
+        return Err(invalid("Pushgateway URL has no host"));
+    }
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let socket = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid("Pushgateway host did not resolve"))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket, PUSH_TIMEOUT)?;
+    stream.set_read_timeout(Some(PUSH_TIMEOUT))?;
+    stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {}/metrics/job/{} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        base,
+        METRICS_JOB,
+        authority,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Pushgateway answered {:?}",
+            status
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::GroupCounts;
+    use std::net::TcpListener;
+
+    fn report() -> MergeReport {
+        MergeReport {
+            files: 6,
+            total_groups: 3,
+            capped: None,
+            unchanged: 0,
+            counts: GroupCounts {
+                merged: 2,
+                skipped: 1,
+                bytes_recovered: 4096,
+                ..GroupCounts::default()
+            },
+            groups: Vec::new(),
+            failures: Vec::new(),
+            verification: None,
+            elapsed: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_metrics_text_format() {
+        let text = metrics_text(&report());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 18);
+        assert_eq!(
+            &lines[..3],
+            &[
+                "# HELP torrent_combine_groups_total Groups found to process.",
+                "# TYPE torrent_combine_groups_total counter",
+                "torrent_combine_groups_total 3",
+            ]
+        );
+        assert!(lines.contains(&"torrent_combine_merged_total 2"));
+        assert!(lines.contains(&"torrent_combine_skipped_total 1"));
+        assert!(lines.contains(&"torrent_combine_failed_total 0"));
+        assert!(lines.contains(&"torrent_combine_bytes_recovered_total 4096"));
+        assert!(lines.contains(&"# TYPE torrent_combine_last_run_duration_seconds gauge"));
+        assert!(lines.contains(&"torrent_combine_last_run_duration_seconds 1.500"));
+        assert!(text.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_push_metrics_posts_body() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = std::thread::spawn(move || -> io::Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&request).ends_with("metric 1\n") {
+                let n = stream.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")?;
+            Ok(String::from_utf8_lossy(&request).into_owned())
+        });
+
+        push_metrics(&format!("http://127.0.0.1:{}/gw/", port), "metric 1\n")?;
+        let request = server.join().expect("server thread")?;
+        assert!(request.starts_with("POST /gw/metrics/job/torrent_combine HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 9\r\n"));
+        assert!(request.ends_with("\r\n\r\nmetric 1\n"));
+
+        assert!(push_metrics("https://example.invalid", "").is_err());
+        Ok(())
+    }
+}
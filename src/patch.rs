@@ -0,0 +1,203 @@
+//! Sparse patches holding only the bytes a merge recovered for one member.
+//!
+//! A patch is the magic `TCPATCH1`, the little-endian `u64` length of the file
+//! it applies to, then zero or more runs, each a little-endian `u64` offset,
+//! a `u64` length and that many bytes. Runs are in ascending offset order and
+//! don't overlap.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"TCPATCH1";
+
+// Unchanged gaps shorter than a run header are cheaper to carry along than
+// to split the run around.
+const MIN_GAP: u64 = 16;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid patch: {}", msg),
+    )
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Copies exactly `len` bytes a buffer at a time, so a run's length never
+// decides how much is held in memory.
+fn copy_exact(reader: &mut impl Read, writer: &mut impl Write, len: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(len), writer)? != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "patch ends inside a run",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a patch turning `original` into `merged` to `out`, returning how
+/// many bytes its runs carry. Both files must have the same length.
+pub fn write_patch(original: &Path, merged: &Path, out: impl Write) -> io::Result<u64> {
+    let len = File::open(original)?.metadata()?.len();
+    if File::open(merged)?.metadata()?.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} and {:?} differ in length", original, merged),
+        ));
+    }
+    let runs = differing_runs(original, merged)?;
+
+    let mut out = io::BufWriter::new(out);
+    out.write_all(MAGIC)?;
+    out.write_all(&len.to_le_bytes())?;
+    let mut source = File::open(merged)?;
+    let mut carried = 0;
+    for (offset, run_len) in runs {
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&run_len.to_le_bytes())?;
+        source.seek(SeekFrom::Start(offset))?;
+        copy_exact(&mut source, &mut out, run_len)?;
+        carried += run_len;
+    }
+    out.flush()?;
+    Ok(carried)
+}
+
+// `(offset, len)` runs where the files differ, with short equal gaps folded
+// into the surrounding run.
+fn differing_runs(a: &Path, b: &Path) -> io::Result<Vec<(u64, u64)>> {
+    let mut ra = BufReader::new(File::open(a)?);
+    let mut rb = BufReader::new(File::open(b)?);
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    let mut offset = 0u64;
+    loop {
+        let (chunk_a, chunk_b) = (ra.fill_buf()?, rb.fill_buf()?);
+        let len = chunk_a.len().min(chunk_b.len());
+        if len == 0 {
+            return Ok(runs);
+        }
+        for i in 0..len {
+            if chunk_a[i] == chunk_b[i] {
+                continue;
+            }
+            let pos = offset + i as u64;
+            match runs.last_mut() {
+                Some((start, run_len)) if pos - (*start + *run_len) < MIN_GAP => {
+                    *run_len = pos + 1 - *start;
+                }
+                _ => runs.push((pos, 1)),
+            }
+        }
+        ra.consume(len);
+        rb.consume(len);
+        offset += len as u64;
+    }
+}
+
+/// Applies the patch read from `patch` to `target` in place, returning how
+/// many bytes were written. The whole patch is validated before `target` is
+/// touched, with the runs' data spooled to a temp file meanwhile.
+pub fn apply_patch(patch: impl Read, target: &Path) -> io::Result<u64> {
+    let mut patch = BufReader::new(patch);
+    let mut magic = [0u8; 8];
+    patch.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("bad magic".to_string()));
+    }
+    let len = read_u64(&mut patch)?;
+    let target_len = std::fs::metadata(target)?.len();
+    if len != target_len {
+        return Err(invalid(format!(
+            "made for a file of {} bytes, {:?} has {}",
+            len, target, target_len
+        )));
+    }
+
+    let mut spool = tempfile::tempfile()?;
+    let mut runs = Vec::new();
+    let mut end = 0u64;
+    while !patch.fill_buf()?.is_empty() {
+        let offset = read_u64(&mut patch)?;
+        let run_len = read_u64(&mut patch)?;
+        if offset < end || offset.checked_add(run_len).is_none_or(|e| e > len) {
+            return Err(invalid(format!(
+                "run of {} bytes at {} is out of order or past the end",
+                run_len, offset
+            )));
+        }
+        copy_exact(&mut patch, &mut spool, run_len)?;
+        end = offset + run_len;
+        runs.push((offset, run_len));
+    }
+
+    spool.seek(SeekFrom::Start(0))?;
+    let mut spool = BufReader::new(spool);
+    let mut file = OpenOptions::new().write(true).open(target)?;
+    let mut applied = 0;
+    for (offset, run_len) in runs {
+        file.seek(SeekFrom::Start(offset))?;
+        copy_exact(&mut spool, &mut file, run_len)?;
+        applied += run_len;
+    }
+    file.sync_all()?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_patch_round_trip() -> io::Result<()> {
+        let dir = tempdir()?;
+        let original = dir.path().join("original");
+        let merged = dir.path().join("merged");
+        let mut partial = vec![7u8; 4096];
+        partial[10..20].fill(0);
+        partial[25..30].fill(0);
+        partial[4000..].fill(0);
+        fs::write(&original, &partial)?;
+        fs::write(&merged, vec![7u8; 4096])?;
+
+        let mut patch = Vec::new();
+        assert_eq!(write_patch(&original, &merged, &mut patch)?, 20 + 96);
+        // The 5-byte gap between the first two holes is folded into one run.
+        assert_eq!(patch.len(), 16 + 2 * 16 + 20 + 96);
+
+        assert_eq!(apply_patch(&patch[..], &original)?, 20 + 96);
+        assert_eq!(fs::read(&original)?, fs::read(&merged)?);
+
+        fs::write(&original, vec![0u8; 10])?;
+        assert!(apply_patch(&patch[..], &original).is_err());
+        assert_eq!(fs::read(&original)?, vec![0u8; 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_run_longer_than_its_data() -> io::Result<()> {
+        let dir = tempdir()?;
+        let target = dir.path().join("target");
+        // A sparse file, so the run below fits it without taking the space.
+        let len = 1u64 << 36;
+        File::create(&target)?.set_len(len)?;
+
+        let mut patch = MAGIC.to_vec();
+        for field in [len, 0, len] {
+            patch.extend_from_slice(&field.to_le_bytes());
+        }
+        patch.extend_from_slice(b"short");
+        let err = apply_patch(&patch[..], &target).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let mut head = [1u8; 5];
+        File::open(&target)?.read_exact(&mut head)?;
+        assert_eq!(head, [0u8; 5]);
+        Ok(())
+    }
+}
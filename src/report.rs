@@ -0,0 +1,458 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::merger::{CompletenessMap, UnrecoveredHoles};
+use crate::run::MergeReport;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedGroup {
+    pub name: String,
+    pub reason: String,
+    pub first_conflict: Option<u64>,
+    pub members: Vec<PathBuf>,
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_failed_text(failures: &[FailedGroup]) -> String {
+    let mut out = String::new();
+    for failure in failures {
+        out.push_str(&format!("group: {}\n", failure.name));
+        out.push_str(&format!("  reason: {}\n", failure.reason));
+        if let Some(offset) = failure.first_conflict {
+            out.push_str(&format!("  first conflict offset: {}\n", offset));
+        }
+        for member in &failure.members {
+            out.push_str(&format!("  member: {}\n", member.display()));
+        }
+    }
+    out
+}
+
+fn failed_json_object(failure: &FailedGroup) -> String {
+    let members: Vec<String> = failure
+        .members
+        .iter()
+        .map(|m| json_escape(&m.to_string_lossy()))
+        .collect();
+    format!(
+        "{{\"group\":{},\"reason\":{},\"first_conflict_offset\":{},\"members\":[{}]}}",
+        json_escape(&failure.name),
+        json_escape(&failure.reason),
+        failure
+            .first_conflict
+            .map_or("null".to_string(), |o| o.to_string()),
+        members.join(",")
+    )
+}
+
+fn format_failed_json(failures: &[FailedGroup]) -> String {
+    let entries: Vec<String> = failures.iter().map(failed_json_object).collect();
+    format!("[{}]\n", entries.join(","))
+}
+
+fn format_failed_jsonl(failures: &[FailedGroup]) -> String {
+    failures
+        .iter()
+        .map(|failure| failed_json_object(failure) + "\n")
+        .collect()
+}
+
+const DELIMITED_HEADER: [&str; 4] = ["group", "reason", "first_conflict_offset", "member"];
+
+// Quotes fields holding the delimiter, quotes or line breaks, doubling any
+// embedded quotes, so paths survive a round trip.
+fn delimited_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn delimited_row(fields: &[&str], delimiter: char) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|f| delimited_field(f, delimiter))
+        .collect();
+    fields.join(&delimiter.to_string()) + "\n"
+}
+
+/// One row per member, so every row holds a single path.
+fn format_failed_delimited(failures: &[FailedGroup], delimiter: char) -> String {
+    let mut out = delimited_row(&DELIMITED_HEADER, delimiter);
+    for failure in failures {
+        let offset = failure
+            .first_conflict
+            .map_or(String::new(), |o| o.to_string());
+        for member in &failure.members {
+            out.push_str(&delimited_row(
+                &[
+                    &failure.name,
+                    &failure.reason,
+                    &offset,
+                    &member.to_string_lossy(),
+                ],
+                delimiter,
+            ));
+        }
+    }
+    out
+}
+
+/// Splits delimited text into rows of fields, honouring quoted fields.
+pub fn parse_delimited(text: &str, delimiter: char) -> io::Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+        } else if c == '"' && field.is_empty() {
+            in_quotes = true;
+        } else if c == delimiter {
+            row.push(std::mem::take(&mut field));
+        } else if c == '\n' {
+            row.push(std::mem::take(&mut field));
+            rows.push(std::mem::take(&mut row));
+        } else if c != '\r' {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unterminated quoted field",
+        ));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Reads back a report written as CSV or TSV, joining consecutive rows of
+/// the same group.
+pub fn parse_failed_delimited(text: &str, delimiter: char) -> io::Result<Vec<FailedGroup>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut failures: Vec<FailedGroup> = Vec::new();
+    for row in parse_delimited(text, delimiter)?.into_iter().skip(1) {
+        let [name, reason, offset, member] = <[String; 4]>::try_from(row)
+            .map_err(|row| invalid(format!("Expected 4 fields, got {}", row.len())))?;
+        let first_conflict = match offset.as_str() {
+            "" => None,
+            o => Some(
+                o.parse()
+                    .map_err(|_| invalid(format!("Bad offset {:?}", o)))?,
+            ),
+        };
+        match failures.last_mut() {
+            Some(last) if last.name == name => last.members.push(PathBuf::from(member)),
+            _ => failures.push(FailedGroup {
+                name,
+                reason,
+                first_conflict,
+                members: vec![PathBuf::from(member)],
+            }),
+        }
+    }
+    Ok(failures)
+}
+
+/// Output format of the failed-groups report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Csv,
+    Tsv,
+    Json,
+    Jsonl,
+}
+
+impl ReportFormat {
+    /// Picks the format from the file extension, falling back to text.
+    pub fn for_path(path: &Path) -> ReportFormat {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("csv") => ReportFormat::Csv,
+            Some("tsv") => ReportFormat::Tsv,
+            Some("json") => ReportFormat::Json,
+            Some("jsonl") => ReportFormat::Jsonl,
+            _ => ReportFormat::Text,
+        }
+    }
+}
+
+/// Writes the failed groups to `path` in `format`, or in the format implied
+/// by the extension when `format` is `None`. Nothing is written when there
+/// were no failures.
+pub fn write_failed_report(
+    path: &Path,
+    failures: &[FailedGroup],
+    format: Option<ReportFormat>,
+) -> io::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let contents = match format.unwrap_or_else(|| ReportFormat::for_path(path)) {
+        ReportFormat::Text => format_failed_text(failures),
+        ReportFormat::Csv => format_failed_delimited(failures, ','),
+        ReportFormat::Tsv => format_failed_delimited(failures, '\t'),
+        ReportFormat::Json => format_failed_json(failures),
+        ReportFormat::Jsonl => format_failed_jsonl(failures),
+    };
+    fs::write(path, contents)
+}
+
+fn members_json_object(name: &str, map: &CompletenessMap) -> String {
+    let path_json = |p: &PathBuf| json_escape(&p.to_string_lossy());
+    let members: Vec<String> = map.files.iter().map(|(p, _)| path_json(p)).collect();
+    let donations: Vec<String> = map
+        .donors
+        .iter()
+        .map(|(offset, len, donor)| {
+            format!(
+                "{{\"offset\":{},\"length\":{},\"donor\":{}}}",
+                offset,
+                len,
+                donor.map_or("null".to_string(), |i| path_json(&map.files[i].0))
+            )
+        })
+        .collect();
+    format!(
+        "{{\"group\":{},\"members\":[{}],\"donations\":[{}]}}",
+        json_escape(name),
+        members.join(","),
+        donations.join(",")
+    )
+}
+
+/// Writes, for each named group, which member donated every run of the
+/// output that some member lacked, as a JSON array. A `null` donor is the
+/// reference file.
+pub fn write_members_report(path: &Path, groups: &[(&str, &CompletenessMap)]) -> io::Result<()> {
+    let entries: Vec<String> = groups
+        .iter()
+        .map(|(name, map)| members_json_object(name, map))
+        .collect();
+    fs::write(path, format!("[{}]\n", entries.join(",")))
+}
+
+fn unrecoverable_json_object(
+    name: &str,
+    piece_length: Option<u64>,
+    holes: &UnrecoveredHoles,
+) -> String {
+    let ranges: Vec<String> = holes
+        .ranges
+        .iter()
+        .map(|range| {
+            let pieces = piece_length.map_or(String::new(), |len| {
+                format!(
+                    ",\"first_piece\":{},\"last_piece\":{}",
+                    range.start / len,
+                    (range.end - 1) / len
+                )
+            });
+            format!(
+                "{{\"offset\":{},\"length\":{}{}}}",
+                range.start,
+                range.end - range.start,
+                pieces
+            )
+        })
+        .collect();
+    format!(
+        "{{\"group\":{},\"bytes\":{},\"piece_length\":{},\"ranges\":[{}]}}",
+        json_escape(name),
+        holes.bytes,
+        piece_length.map_or("null".to_string(), |len| len.to_string()),
+        ranges.join(",")
+    )
+}
+
+/// Writes, for each named group, the byte ranges of the merged output that
+/// every member had zeroed, as a JSON array. With a piece length each range
+/// also names the first and last torrent piece it touches.
+pub fn write_unrecoverable_report(
+    path: &Path,
+    groups: &[(&str, Option<u64>, &UnrecoveredHoles)],
+) -> io::Result<()> {
+    let entries: Vec<String> = groups
+        .iter()
+        .map(|(name, piece_length, holes)| unrecoverable_json_object(name, *piece_length, holes))
+        .collect();
+    fs::write(path, format!("[{}]\n", entries.join(",")))
+}
+
+/// Renders the totals of one run as a single-line JSON object, for streaming
+/// one line per watch cycle.
+pub fn stats_json(cycle: u64, report: &MergeReport) -> String {
+    let counts = &report.counts;
+    format!(
+        "{{\"cycle\":{},\"files\":{},\"groups\":{},\"unchanged\":{},\"processed\":{},\"merged\":{},\"skipped\":{},\"failed\":{},\"bytes_read\":{},\"elapsed_secs\":{:.3}}}",
+        cycle,
+        report.files,
+        report.total_groups,
+        report.unchanged,
+        counts.processed,
+        counts.merged,
+        counts.skipped,
+        report.failures.len(),
+        counts.bytes_read,
+        report.elapsed.as_secs_f64(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn failure() -> FailedGroup {
+        FailedGroup {
+            name: "video \"1\".mkv@3".to_string(),
+            reason: "conflict".to_string(),
+            first_conflict: Some(2),
+            members: vec![PathBuf::from("/a/video.mkv"), PathBuf::from("/b/video.mkv")],
+        }
+    }
+
+    #[test]
+    fn test_failed_report_formats() -> io::Result<()> {
+        let dir = tempdir()?;
+        let text = dir.path().join("failed.txt");
+        write_failed_report(&text, &[failure()], None)?;
+        assert_eq!(
+            fs::read_to_string(&text)?,
+            "group: video \"1\".mkv@3\n  reason: conflict\n  first conflict offset: 2\n  member: /a/video.mkv\n  member: /b/video.mkv\n"
+        );
+
+        let json = dir.path().join("failed.json");
+        write_failed_report(&json, &[failure()], None)?;
+        assert_eq!(
+            fs::read_to_string(&json)?,
+            "[{\"group\":\"video \\\"1\\\".mkv@3\",\"reason\":\"conflict\",\"first_conflict_offset\":2,\"members\":[\"/a/video.mkv\",\"/b/video.mkv\"]}]\n"
+        );
+
+        let empty = dir.path().join("empty.txt");
+        write_failed_report(&empty, &[], None)?;
+        assert!(!empty.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delimited_report_round_trip() -> io::Result<()> {
+        let mut tricky = failure();
+        tricky.members[1] = PathBuf::from("/b/video, \"final\".mkv");
+        let second = FailedGroup {
+            name: "size-3".to_string(),
+            reason: "size mismatch".to_string(),
+            first_conflict: None,
+            members: vec![PathBuf::from("/c/a\tb.mkv")],
+        };
+        let failures = vec![tricky, second];
+
+        let dir = tempdir()?;
+        let csv = dir.path().join("failed.csv");
+        write_failed_report(&csv, &failures, None)?;
+        let text = fs::read_to_string(&csv)?;
+        assert!(text.contains("\"/b/video, \"\"final\"\".mkv\""));
+        assert_eq!(parse_failed_delimited(&text, ',')?, failures);
+
+        let tsv = dir.path().join("failed.out");
+        write_failed_report(&tsv, &failures, Some(ReportFormat::Tsv))?;
+        assert_eq!(
+            parse_failed_delimited(&fs::read_to_string(&tsv)?, '\t')?,
+            failures
+        );
+
+        let jsonl = dir.path().join("failed.jsonl");
+        write_failed_report(&jsonl, &failures, None)?;
+        assert_eq!(fs::read_to_string(&jsonl)?.lines().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_members_report_json() -> io::Result<()> {
+        let map = CompletenessMap {
+            files: vec![
+                (PathBuf::from("/a/video.mkv"), vec![(4, 2)]),
+                (PathBuf::from("/b/video.mkv"), vec![(0, 4)]),
+            ],
+            donors: vec![(0, 4, Some(0)), (4, 2, Some(1)), (6, 1, None)],
+        };
+        let dir = tempdir()?;
+        let path = dir.path().join("members.json");
+        write_members_report(&path, &[("video.mkv@7", &map)])?;
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "[{\"group\":\"video.mkv@7\",\"members\":[\"/a/video.mkv\",\"/b/video.mkv\"],\"donations\":[{\"offset\":0,\"length\":4,\"donor\":\"/a/video.mkv\"},{\"offset\":4,\"length\":2,\"donor\":\"/b/video.mkv\"},{\"offset\":6,\"length\":1,\"donor\":null}]}]\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrecoverable_report_lists_shared_gap() -> io::Result<()> {
+        use crate::merger::{MergeOptions, SanityOutcome, check_sanity_and_completes};
+        use std::time::SystemTime;
+
+        let dir = tempdir()?;
+        let mut a = vec![7u8; 100];
+        a[10..20].fill(0);
+        a[40..70].fill(0);
+        let mut b = vec![7u8; 100];
+        b[40..70].fill(0);
+        b[90..].fill(0);
+        let paths = vec![dir.path().join("a"), dir.path().join("b")];
+        fs::write(&paths[0], &a)?;
+        fs::write(&paths[1], &b)?;
+
+        let options = MergeOptions::builder().report_unrecoverable(true).build();
+        let holes =
+            match check_sanity_and_completes(&paths, None, &[SystemTime::UNIX_EPOCH; 2], &options)?
+            {
+                SanityOutcome::Passed {
+                    unrecovered: Some(holes),
+                    ..
+                } => holes,
+                _ => panic!("Expected the merge to pass with unrecovered holes"),
+            };
+        let path = dir.path().join("gaps.json");
+        write_unrecoverable_report(&path, &[("size-100", Some(16), &holes)])?;
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "[{\"group\":\"size-100\",\"bytes\":30,\"piece_length\":16,\"ranges\":[{\"offset\":40,\"length\":30,\"first_piece\":2,\"last_piece\":4}]}]\n"
+        );
+        Ok(())
+    }
+}
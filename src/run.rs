@@ -0,0 +1,1612 @@
+//! A whole run as a library call: discovery, grouping and merging, handing
+//! back a [`MergeReport`] instead of printing a summary.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+
+use crate::client::Client;
+use crate::group::{
+    DEFAULT_HEAD_BYTES, DEFAULT_MTIME_WINDOW, DedupKey, ExtFilter, GroupKey, InputOrder,
+    KeySettings, collect_large_files, dedup_spellings, group_files, split_size_only_groups,
+};
+use crate::merger::{
+    self, GroupStats, GroupStatus, MergeError, MergeOptions, ProbeVerdict, SkipReason,
+    first_difference,
+};
+use crate::report::FailedGroup;
+use crate::space;
+use crate::torrent::TorrentIndex;
+
+/// What to scan, how to group it and how to merge it. `Default` scans
+/// nothing, so set `root_dir` or `paths`.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// Directory scanned for members of over 1 MiB.
+    pub root_dir: Option<PathBuf>,
+    /// Member paths to group instead of scanning `root_dir`.
+    pub paths: Option<Vec<PathBuf>>,
+    /// Skip scanned files larger than this many bytes.
+    pub max_size: Option<u64>,
+    /// Don't scan deeper than this many directories below `root_dir`.
+    pub max_depth: Option<usize>,
+    /// Skip scanned files last modified longer ago than this, counted from
+    /// the start of each discovery.
+    pub since: Option<Duration>,
+    pub include_ext: Vec<String>,
+    pub exclude_ext: Vec<String>,
+    pub dedup_mode: DedupKey,
+    pub dedup_within_dir_only: bool,
+    /// Where `SizeAndPieceLength` grouping looks for `.torrent` files;
+    /// `root_dir` by default.
+    pub torrent_dir: Option<PathBuf>,
+    pub client: Option<Client>,
+    pub head_bytes: u64,
+    /// Bucket width for `SizeAndMtimeWindow` grouping.
+    pub mtime_window: Duration,
+    /// Block size for `SizeAndFirstPieceHash` grouping; taken from each
+    /// file's `.torrent` when unset.
+    pub piece_length: Option<u64>,
+    pub content_clusters: bool,
+    /// Known-good complete copy merged into matching groups.
+    pub reference_file: Option<PathBuf>,
+    /// Only merge the reference file into the group with this name.
+    pub reference_group: Option<String>,
+    /// Only process the group with this name.
+    pub group: Option<String>,
+    pub shuffle_order: bool,
+    pub seed: Option<u64>,
+    /// Only process the first this many groups.
+    pub max_groups: Option<usize>,
+    /// Count a file listed under several spellings, e.g. `./a` and `a`, as
+    /// one member.
+    pub normalize_paths: bool,
+    /// Order of the members within each group; the first seeds the merge.
+    pub input_order: InputOrder,
+    /// Hold back the next group while the one-minute load average is above
+    /// this.
+    pub pause_on_high_load: Option<f64>,
+    /// Threads merging groups at once, on a pool of the run's own; rayon's
+    /// current pool when unset.
+    pub num_threads: Option<usize>,
+    /// Let [`run`] merge even when the free-space preflight comes up short.
+    pub ignore_space_check: bool,
+    /// Trusted mirror of `root_dir` that written outputs are compared with.
+    pub verify_against: Option<PathBuf>,
+    /// Start no new group once this many groups failed their sanity check.
+    pub max_failures: Option<usize>,
+    /// Called as each group finishes; the run itself logs no group lines.
+    pub on_group: Option<OnGroup>,
+    pub options: MergeOptions,
+}
+
+/// Callback handed each [`GroupResult`] as soon as its group finishes, from
+/// the worker that ran it, so groups finishing at once may call it
+/// concurrently.
+#[derive(Clone)]
+pub struct OnGroup(pub Arc<GroupCallback>);
+
+/// What an [`OnGroup`] calls.
+pub type GroupCallback = dyn Fn(&GroupResult, GroupProgress) + Send + Sync;
+
+impl fmt::Debug for OnGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnGroup(..)")
+    }
+}
+
+/// How far a run had got when a group finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupProgress {
+    /// Groups finished so far, this one included.
+    pub done: usize,
+    /// Groups the run will process.
+    pub total: usize,
+    /// Percentage of the run complete, weighted by the bytes of each group's
+    /// members so one huge group isn't outweighed by many tiny ones.
+    pub percentage: f64,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            root_dir: None,
+            paths: None,
+            max_size: None,
+            max_depth: None,
+            since: None,
+            include_ext: Vec::new(),
+            exclude_ext: Vec::new(),
+            dedup_mode: DedupKey::default(),
+            dedup_within_dir_only: false,
+            torrent_dir: None,
+            client: None,
+            head_bytes: DEFAULT_HEAD_BYTES,
+            mtime_window: DEFAULT_MTIME_WINDOW,
+            piece_length: None,
+            content_clusters: false,
+            reference_file: None,
+            reference_group: None,
+            group: None,
+            shuffle_order: false,
+            seed: None,
+            max_groups: None,
+            normalize_paths: true,
+            input_order: InputOrder::default(),
+            pause_on_high_load: None,
+            num_threads: None,
+            ignore_space_check: false,
+            verify_against: None,
+            max_failures: None,
+            on_group: None,
+            options: MergeOptions::default(),
+        }
+    }
+}
+
+/// The groups a run will process, in processing order.
+#[derive(Debug, Default)]
+pub struct Discovery {
+    /// Files found before grouping.
+    pub files: usize,
+    /// Number of `.torrent` files loaded for `SizeAndPieceLength` grouping.
+    pub torrents: Option<usize>,
+    /// Canonical path and size of the reference file.
+    pub reference: Option<(PathBuf, u64)>,
+    /// Groups found, before `max_groups` applied.
+    pub total_groups: usize,
+    /// Set to `max_groups` when it dropped some groups.
+    pub capped: Option<usize>,
+    /// Groups left out because they haven't changed since a previous watch
+    /// cycle processed them.
+    pub unchanged: usize,
+    pub groups: Vec<(GroupKey, Vec<PathBuf>)>,
+}
+
+/// Outcome of one group.
+#[derive(Debug)]
+pub struct GroupResult {
+    pub name: String,
+    pub members: Vec<PathBuf>,
+    /// See [`GroupKey::piece_length`].
+    pub piece_length: Option<u64>,
+    pub result: io::Result<GroupStats>,
+}
+
+/// Outputs compared against a trusted mirror.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// Outputs written, whether or not the mirror has a copy of them.
+    pub outputs: usize,
+    /// Every output with a trusted copy and how it compared.
+    pub checked: Vec<(PathBuf, VerifyOutcome)>,
+}
+
+/// How one output compared with its trusted copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    Matched,
+    /// The first offset where the two differ.
+    Differs(u64),
+    /// Either file couldn't be read; the error as text.
+    Failed(String),
+}
+
+/// Everything a run found and did.
+#[derive(Debug)]
+pub struct MergeReport {
+    pub files: usize,
+    pub total_groups: usize,
+    pub capped: Option<usize>,
+    pub unchanged: usize,
+    pub counts: GroupCounts,
+    /// Every processed group in processing order.
+    pub groups: Vec<GroupResult>,
+    /// Groups that failed, with what to put in a failed-groups report.
+    pub failures: Vec<FailedGroup>,
+    pub verification: Option<Verification>,
+    /// Wall-clock time spent processing groups.
+    pub elapsed: Duration,
+}
+
+/// Discovers, groups and merges everything `config` describes. Unlike the
+/// binary it never prompts, and a free-space shortfall fails the run unless
+/// `ignore_space_check` is set.
+pub fn run(config: &RunConfig) -> io::Result<MergeReport> {
+    run_discovered(config, discover(config)?)
+}
+
+/// One cycle of watching a tree: like [`run`], but groups whose members are
+/// unchanged since `seen` last recorded them are not processed again.
+pub fn run_watched(config: &RunConfig, seen: &mut Seen) -> io::Result<MergeReport> {
+    let mut discovery = discover(config)?;
+    seen.skip_unchanged(&mut discovery);
+    let report = run_discovered(config, discovery)?;
+    seen.record(&report);
+    Ok(report)
+}
+
+fn run_discovered(config: &RunConfig, discovery: Discovery) -> io::Result<MergeReport> {
+    with_pool(config.num_threads, || {
+        ensure_free_space(config, &discovery.groups)?;
+        process_on_pool(config, discovery)
+    })
+}
+
+/// Warns about every filesystem [`check_free_space`] finds short, failing
+/// with `StorageFull` unless `ignore_space_check` is set.
+pub fn ensure_free_space(
+    config: &RunConfig,
+    groups: &[(GroupKey, Vec<PathBuf>)],
+) -> io::Result<()> {
+    let short = check_free_space(groups, &config.options)?;
+    for (needed, free, dir) in &short {
+        log::warn!(
+            "Merging may need up to {} bytes on the filesystem of {:?}, but only {} are free",
+            needed,
+            dir,
+            free
+        );
+    }
+    if !short.is_empty() && !config.ignore_space_check {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            "Not enough free space for merged outputs",
+        ));
+    }
+    Ok(())
+}
+
+// Runs `f` on a pool of its own with `num_threads` threads, leaving rayon's
+// global pool to whoever embeds the library. Without `num_threads`, or if
+// the pool can't be built, `f` runs on the current pool.
+fn with_pool<R: Send>(num_threads: Option<usize>, f: impl FnOnce() -> R + Send) -> R {
+    let Some(threads) = num_threads else {
+        return f();
+    };
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool.install(f),
+        Err(e) => {
+            log::warn!(
+                "Could not start a pool of {} threads ({}); using the default pool",
+                threads,
+                e
+            );
+            f()
+        }
+    }
+}
+
+/// Finds the members `config` describes and groups them, keeping groups
+/// with something to merge.
+pub fn discover(config: &RunConfig) -> io::Result<Discovery> {
+    let mut files = match (&config.paths, &config.root_dir) {
+        (Some(paths), _) => paths.clone(),
+        (None, Some(root_dir)) => collect_large_files(
+            root_dir,
+            config.max_size,
+            config.max_depth,
+            config
+                .since
+                .map(|since| SystemTime::now().checked_sub(since).unwrap_or(UNIX_EPOCH)),
+            &ExtFilter::new(&config.include_ext, &config.exclude_ext),
+            config.options.include_zero_byte_files,
+        )?,
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Nothing to scan: set a root directory or member paths",
+            ));
+        }
+    };
+    if config.normalize_paths {
+        files = dedup_spellings(files);
+    }
+    let found = files.len();
+
+    let reference = match &config.reference_file {
+        Some(path) => {
+            let canonical = fs::canonicalize(path)?;
+            let size = fs::metadata(&canonical)?.len();
+            files.retain(|f| fs::canonicalize(f).ok().as_ref() != Some(&canonical));
+            Some((canonical, size))
+        }
+        None => None,
+    };
+
+    let torrent_dir = config.torrent_dir.as_ref().or(config.root_dir.as_ref());
+    let torrents = match (&config.dedup_mode, torrent_dir) {
+        (DedupKey::SizeAndPieceLength, Some(dir)) => Some(TorrentIndex::load(dir)?),
+        (DedupKey::SizeAndPieceLength, None) => {
+            log::warn!("No torrent directory to load torrents from; grouping by size only");
+            None
+        }
+        (DedupKey::SizeAndFirstPieceHash, Some(dir)) if config.piece_length.is_none() => {
+            Some(TorrentIndex::load(dir)?)
+        }
+        _ => None,
+    };
+    let key_settings = KeySettings {
+        head_bytes: config.head_bytes,
+        mtime_window: config.mtime_window,
+        piece_length: config.piece_length,
+    };
+    let groups = split_size_only_groups(
+        group_files(
+            files,
+            &config.dedup_mode,
+            config.dedup_within_dir_only,
+            torrents.as_ref().unwrap_or(&TorrentIndex::default()),
+            config.client,
+            &key_settings,
+        ),
+        config.content_clusters,
+    );
+
+    let reference_group = config.reference_group.as_deref();
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .filter(|(group_key, paths)| {
+            paths.len() >= 2
+                || (!paths.is_empty()
+                    && reference_for_group(group_key, &reference, reference_group).is_some())
+        })
+        .collect();
+    if config.input_order == InputOrder::Stable {
+        for (_, paths) in &mut groups {
+            paths.sort();
+        }
+    }
+    if let Some(name) = &config.group {
+        groups = select_group(groups, name)?;
+    }
+    let total_groups = groups.len();
+    if config.shuffle_order {
+        shuffle_groups(&mut groups, config.seed);
+    }
+    let capped = config
+        .max_groups
+        .filter(|&max| cap_groups(&mut groups, max));
+
+    Ok(Discovery {
+        files: found,
+        torrents: torrents.map(|t| t.len()),
+        reference,
+        total_groups,
+        capped,
+        unchanged: 0,
+        groups,
+    })
+}
+
+// Size and modification time of each member, sorted by path.
+type Fingerprint = Vec<(PathBuf, u64, SystemTime)>;
+
+fn fingerprint(paths: &[PathBuf]) -> io::Result<Fingerprint> {
+    let mut fingerprint = paths
+        .iter()
+        .map(|p| {
+            let meta = fs::metadata(p)?;
+            Ok((p.clone(), meta.len(), meta.modified()?))
+        })
+        .collect::<io::Result<Fingerprint>>()?;
+    fingerprint.sort();
+    Ok(fingerprint)
+}
+
+/// Groups processed by earlier watch cycles, with their members' sizes and
+/// modification times as of the end of that cycle.
+#[derive(Debug, Default)]
+pub struct Seen {
+    groups: HashMap<String, Fingerprint>,
+}
+
+impl Seen {
+    /// Leaves out the groups whose members match what was recorded for them.
+    pub fn skip_unchanged(&self, discovery: &mut Discovery) {
+        let before = discovery.groups.len();
+        discovery.groups.retain(|(key, paths)| {
+            self.groups
+                .get(&key.name())
+                .is_none_or(|recorded| fingerprint(paths).ok().as_ref() != Some(recorded))
+        });
+        discovery.unchanged = before - discovery.groups.len();
+    }
+
+    /// Remembers every group `report` processed without an I/O error, so
+    /// unchanged groups are skipped from the next cycle on. Fingerprints are
+    /// taken now, after any replacement rewrote members.
+    pub fn record(&mut self, report: &MergeReport) {
+        for group in report.groups.iter().filter(|g| g.result.is_ok()) {
+            if let Ok(fingerprint) = fingerprint(&group.members) {
+                self.groups.insert(group.name.clone(), fingerprint);
+            }
+        }
+    }
+}
+
+/// Reads only the first and last chunk of each discovered group and counts
+/// how many groups get each verdict.
+pub fn probe(config: &RunConfig, discovery: &Discovery) -> BTreeMap<ProbeVerdict, usize> {
+    with_pool(config.num_threads, || probe_on_pool(config, discovery))
+}
+
+/// Groups a merge would improve right now: their members pass the sanity
+/// check and some would gain bytes. With `quick` each group is only probed
+/// like [`probe`] does. Nothing is written but temp files.
+pub fn recoverable_groups(
+    config: &RunConfig,
+    discovery: &Discovery,
+    quick: bool,
+) -> Vec<(GroupKey, Vec<PathBuf>)> {
+    let reference_group = config.reference_group.as_deref();
+    with_pool(config.num_threads, || {
+        discovery
+            .groups
+            .par_iter()
+            .filter(|(group_key, paths)| {
+                let group_reference =
+                    reference_for_group(group_key, &discovery.reference, reference_group);
+                let recoverable = if quick {
+                    merger::probe_group(paths, group_reference, &config.options)
+                        .map(|verdict| verdict == ProbeVerdict::LikelyRecoverable)
+                } else {
+                    merger::recoverable_bytes(paths, group_reference, &config.options)
+                        .map(|bytes| bytes.is_some_and(|bytes| bytes > 0))
+                };
+                recoverable.unwrap_or_else(|e| {
+                    log::warn!("Could not check group {}: {}", group_key.name(), e);
+                    false
+                })
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+fn probe_on_pool(config: &RunConfig, discovery: &Discovery) -> BTreeMap<ProbeVerdict, usize> {
+    let reference_group = config.reference_group.as_deref();
+    discovery
+        .groups
+        .par_iter()
+        .filter_map(|(group_key, paths)| {
+            let group_reference =
+                reference_for_group(group_key, &discovery.reference, reference_group);
+            match merger::probe_group(paths, group_reference, &config.options) {
+                Ok(verdict) => {
+                    log::debug!("Probe {}: {}", group_key.name(), verdict.label());
+                    Some(verdict)
+                }
+                Err(e) => {
+                    log::warn!("Failed to probe group {}: {}", group_key.name(), e);
+                    None
+                }
+            }
+        })
+        .fold(BTreeMap::new, |mut verdicts, verdict| {
+            *verdicts.entry(verdict).or_insert(0) += 1;
+            verdicts
+        })
+        .reduce(BTreeMap::new, |mut a, b| {
+            for (verdict, count) in b {
+                *a.entry(verdict).or_insert(0) += count;
+            }
+            a
+        })
+}
+
+/// Bytes hardlinks save across `DedupKey::Inode` groups: every path past the
+/// first would otherwise be a copy of its own.
+pub fn hardlink_savings(groups: &[(GroupKey, Vec<PathBuf>)]) -> u64 {
+    groups
+        .iter()
+        .map(|(key, paths)| key.size() * paths.len().saturating_sub(1) as u64)
+        .sum()
+}
+
+/// Merges every discovered group, then compares the outputs with
+/// `verify_against` when it is set. `DedupKey::Inode` groups are only
+/// reported, so they are refused here.
+pub fn process(config: &RunConfig, discovery: Discovery) -> io::Result<MergeReport> {
+    with_pool(config.num_threads, || process_on_pool(config, discovery))
+}
+
+fn process_on_pool(config: &RunConfig, discovery: Discovery) -> io::Result<MergeReport> {
+    #[cfg(unix)]
+    if matches!(config.dedup_mode, DedupKey::Inode) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Inode groups share storage already and are only reported, not merged",
+        ));
+    }
+    let start = Instant::now();
+    let reference = &discovery.reference;
+    let reference_group = config.reference_group.as_deref();
+    // Groups already running when `max_failures` is reached still finish.
+    let failed = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let total = discovery.groups.len();
+    let total_bytes = discovery
+        .groups
+        .iter()
+        .map(|(group_key, paths)| group_bytes(group_key, paths))
+        .sum();
+    let done = AtomicUsize::new(0);
+    let done_bytes = AtomicU64::new(0);
+
+    // Groups are started in order from this thread, each once its buffers
+    // fit the memory budget, so a group waits for memory before it takes a
+    // worker rather than while holding one. Each group's totals are kept
+    // under its index and combined in order at the end.
+    let finished = Mutex::new(Vec::new());
+    rayon::in_place_scope_fifo(|scope| {
+        for (index, (group_key, paths)) in discovery.groups.into_iter().enumerate() {
+            if stopped.load(Ordering::Relaxed) {
+                let mut totals = Totals::default();
+                totals.counts.not_started += 1;
+                finished
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((index, totals));
+                continue;
+            }
+            if let Some(threshold) = config.pause_on_high_load {
+                wait_for_load(threshold, &group_key);
+            }
+            let group_reference = reference_for_group(&group_key, reference, reference_group);
+            let reservation = config.options.memory_budget.as_ref().map(|budget| {
+                let count =
+                    merger::buffer_count(paths.len(), group_reference.is_some(), &config.options);
+                budget.reserve(budget.plan(count, config.options.chunk_bounds).0)
+            });
+            let bytes = group_bytes(&group_key, &paths);
+            let (failed, stopped, finished) = (&failed, &stopped, &finished);
+            let (done, done_bytes) = (&done, &done_bytes);
+            scope.spawn_fifo(move |_| {
+                let _reservation = reservation;
+                let mut totals = Totals::default();
+                // Groups already queued when `max_failures` is reached don't start.
+                if stopped.load(Ordering::Relaxed) {
+                    totals.counts.not_started += 1;
+                    finished.lock().unwrap_or_else(|e| e.into_inner()).push((index, totals));
+                    return;
+                }
+                process_group_entry(
+                    &group_key,
+                    paths,
+                    group_reference,
+                    &config.options,
+                    &mut totals,
+                );
+                if let Some(max) = config.max_failures
+                    && totals.groups.last().is_some_and(|group| {
+                        matches!(&group.result, Ok(stats) if matches!(stats.status, GroupStatus::Failed))
+                    })
+                    && failed.fetch_add(1, Ordering::Relaxed) + 1 >= max
+                {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                let done_bytes = done_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+                if let Some(on_group) = &config.on_group
+                    && let Some(group) = totals.groups.last()
+                {
+                    let percentage = percentage(done, total, done_bytes, total_bytes);
+                    (on_group.0)(
+                        group,
+                        GroupProgress {
+                            done,
+                            total,
+                            percentage,
+                        },
+                    );
+                }
+                finished.lock().unwrap_or_else(|e| e.into_inner()).push((index, totals));
+            });
+        }
+    });
+    let mut finished = finished.into_inner().unwrap_or_else(|e| e.into_inner());
+    finished.sort_by_key(|(index, _)| *index);
+    let totals = finished
+        .into_iter()
+        .map(|(_, totals)| totals)
+        .fold(Totals::default(), Totals::combine);
+
+    let verification = match &config.verify_against {
+        Some(trusted) => {
+            let outputs: Vec<(PathBuf, PathBuf)> = totals
+                .groups
+                .iter()
+                .filter_map(|group| group.result.as_ref().ok())
+                .flat_map(|stats| stats.outputs.iter().cloned())
+                .collect();
+            let root = config
+                .root_dir
+                .as_deref()
+                .filter(|_| config.paths.is_none());
+            Some(Verification {
+                outputs: outputs.len(),
+                checked: verify_against(&outputs, root, trusted),
+            })
+        }
+        None => None,
+    };
+
+    Ok(MergeReport {
+        files: discovery.files,
+        total_groups: discovery.total_groups,
+        capped: discovery.capped,
+        unchanged: discovery.unchanged,
+        counts: totals.counts,
+        groups: totals.groups,
+        failures: totals.failures,
+        verification,
+        elapsed: start.elapsed(),
+    })
+}
+
+// Bytes a group reads across its members.
+fn group_bytes(group_key: &GroupKey, paths: &[PathBuf]) -> u64 {
+    group_key.size() * paths.len() as u64
+}
+
+// Percentage of the run complete by bytes, or by groups when every group is
+// empty.
+fn percentage(done: usize, total: usize, done_bytes: u64, total_bytes: u64) -> f64 {
+    let fraction = if total_bytes == 0 {
+        done as f64 / total as f64
+    } else {
+        done_bytes as f64 / total_bytes as f64
+    };
+    fraction * 100.0
+}
+
+// How long a group waits before the load average is checked again.
+const LOAD_PAUSE: Duration = Duration::from_secs(5);
+
+/// One-minute load average, or `None` where the system doesn't report one.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut loads = [0f64; 3];
+    (unsafe { libc::getloadavg(loads.as_mut_ptr(), 3) } >= 1).then_some(loads[0])
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
+// An unknown load never pauses.
+fn should_pause(load: Option<f64>, threshold: f64) -> bool {
+    load.is_some_and(|load| load > threshold)
+}
+
+// Sleeps until the load average drops to `threshold`, checked only at group
+// boundaries so a group in flight is never interrupted.
+fn wait_for_load(threshold: f64, group_key: &GroupKey) {
+    let mut load = load_average();
+    if !should_pause(load, threshold) {
+        return;
+    }
+    log::debug!(
+        "Load average {:.2} is above {}, pausing before group {}",
+        load.unwrap_or_default(),
+        threshold,
+        group_key.name()
+    );
+    while should_pause(load, threshold) {
+        std::thread::sleep(LOAD_PAUSE);
+        load = load_average();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    counts: GroupCounts,
+    failures: Vec<FailedGroup>,
+    groups: Vec<GroupResult>,
+}
+
+impl Totals {
+    fn combine(mut self, other: Totals) -> Totals {
+        self.counts = self.counts.combine(other.counts);
+        self.failures.extend(other.failures);
+        self.groups.extend(other.groups);
+        self
+    }
+}
+
+fn process_group_entry(
+    group_key: &GroupKey,
+    paths: Vec<PathBuf>,
+    group_reference: Option<&Path>,
+    options: &MergeOptions,
+    totals: &mut Totals,
+) {
+    let name = group_key.name();
+    let result = match options.group_timeout {
+        Some(timeout) => {
+            process_group_with_timeout(&paths, &name, group_reference, options, timeout)
+        }
+        None => merger::process_group(&paths, &name, group_reference, options),
+    };
+    tally_group(
+        &name,
+        &paths,
+        &result,
+        &mut totals.counts,
+        &mut totals.failures,
+    );
+    totals.groups.push(GroupResult {
+        name,
+        members: paths,
+        piece_length: group_key.piece_length(),
+        result,
+    });
+}
+
+// How much longer than the group timeout the run waits for a worker, so a
+// merge that notices the timeout itself can clean up before it is reported.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+// Runs the group on its own thread and stops waiting for it shortly after
+// `timeout`. A worker stuck in a read can't be stopped; if the read ever
+// returns, the merge sees the timeout has passed and gives up before
+// writing any output. Writes already under way finish, one atomic rename
+// per output.
+fn process_group_with_timeout(
+    paths: &[PathBuf],
+    name: &str,
+    group_reference: Option<&Path>,
+    options: &MergeOptions,
+    timeout: Duration,
+) -> io::Result<GroupStats> {
+    let (sender, receiver) = mpsc::channel();
+    let paths = paths.to_vec();
+    let name_owned = name.to_string();
+    let group_reference = group_reference.map(Path::to_path_buf);
+    let options = options.clone();
+    std::thread::Builder::new()
+        .name(format!("group {}", name))
+        .spawn(move || {
+            let result =
+                merger::process_group(&paths, &name_owned, group_reference.as_deref(), &options);
+            // The run may have stopped waiting.
+            let _ = sender.send(result);
+        })?;
+    match receiver.recv_timeout(timeout + TIMEOUT_GRACE) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            log::warn!(
+                "Abandoning group {}: no progress within {:.1}s, its worker is left blocked",
+                name,
+                timeout.as_secs_f64()
+            );
+            Err(MergeError::TimedOut(timeout).into())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::other("group worker panicked")),
+    }
+}
+
+// Counts one group's outcome. Group lines are left to `on_group`; the run
+// itself stays quiet.
+fn tally_group(
+    group_name: &str,
+    paths: &[PathBuf],
+    result: &io::Result<GroupStats>,
+    counts: &mut GroupCounts,
+    failures: &mut Vec<FailedGroup>,
+) {
+    let failure = |reason: String, first_conflict: Option<u64>| FailedGroup {
+        name: group_name.to_string(),
+        reason,
+        first_conflict,
+        members: paths.to_vec(),
+    };
+
+    match result {
+        Ok(stats) => {
+            counts.processed += 1;
+            counts.busy_time += stats.processing_time;
+            counts.bytes_recovered += stats.bytes_recovered;
+            // Failed groups stop reading at the first conflict unless every
+            // position was counted.
+            if matches!(stats.status, GroupStatus::Merged | GroupStatus::Skipped)
+                || stats.conflicts.is_some()
+            {
+                counts.bytes_read += stats.bytes_processed * paths.len() as u64;
+            }
+            if stats.all_identical {
+                counts.identical += 1;
+            }
+            match stats.status {
+                GroupStatus::Merged => counts.merged += 1,
+                GroupStatus::Skipped => {
+                    counts.skipped += 1;
+                    let reason = stats.skip_reason.unwrap_or(SkipReason::AllComplete);
+                    if let Some(i) = SkipReason::ALL.iter().position(|&r| r == reason) {
+                        counts.skipped_by_reason[i] += 1;
+                    }
+                }
+                GroupStatus::Empty => counts.empty += 1,
+                GroupStatus::LikelyComplete => counts.likely_complete += 1,
+                GroupStatus::Failed => {
+                    let reason = match stats.conflicts {
+                        Some(c) => {
+                            format!("conflict ({} positions, last at {})", c.positions, c.last)
+                        }
+                        None => "conflict".to_string(),
+                    };
+                    failures.push(failure(reason, stats.first_conflict));
+                }
+                GroupStatus::Divergent => {
+                    counts.divergent += 1;
+                    failures.push(failure(
+                        "complete but divergent".to_string(),
+                        stats.first_conflict,
+                    ));
+                }
+                GroupStatus::SizeMismatch => {
+                    counts.size_mismatch += 1;
+                    failures.push(failure("size mismatch".to_string(), None));
+                }
+            }
+        }
+        Err(e) => {
+            log::debug!("Error processing group {}: {:?}", group_name, e);
+            match MergeError::from_io(e) {
+                Some(MergeError::TimedOut(_)) => {
+                    counts.timed_out += 1;
+                    failures.push(failure("timeout".to_string(), None));
+                }
+                Some(MergeError::OutputNotWritable(path)) => {
+                    counts.not_writable += 1;
+                    failures.push(failure(format!("output not writable: {:?}", path), None));
+                }
+                _ => failures.push(failure(format!("error: {}", e), None)),
+            }
+        }
+    }
+}
+
+/// When per-group log lines color their status.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Only when logging to a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Whether to color, given whether the log goes to a terminal.
+    pub fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => terminal,
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// The reference file `group_key` merges against: the one named by
+/// `reference_group`, or else any group of the reference's size.
+fn reference_for_group<'a>(
+    group_key: &GroupKey,
+    reference: &'a Option<(PathBuf, u64)>,
+    reference_group: Option<&str>,
+) -> Option<&'a Path> {
+    let (path, size) = reference.as_ref()?;
+    let matches = match reference_group {
+        Some(name) => group_key.name() == name,
+        None => group_key.size() == *size,
+    };
+    matches.then_some(path.as_path())
+}
+
+/// Totals over the groups of a run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCounts {
+    pub processed: usize,
+    pub merged: usize,
+    pub skipped: usize,
+    pub identical: usize,
+    pub size_mismatch: usize,
+    pub divergent: usize,
+    /// Groups that failed because an output could not be written.
+    pub not_writable: usize,
+    /// Groups skipped without a full pass because a quick probe found no
+    /// zeros.
+    pub likely_complete: usize,
+    /// Groups whose members are all zero bytes, counted apart from skipped
+    /// groups with `include_zero_byte_files`.
+    pub empty: usize,
+    /// Groups abandoned after `group_timeout`.
+    pub timed_out: usize,
+    /// Groups never started because `max_failures` stopped the run.
+    pub not_started: usize,
+    /// Skipped groups by reason, indexed like [`SkipReason::ALL`].
+    pub skipped_by_reason: [usize; SkipReason::ALL.len()],
+    /// Bytes read across every member of the groups that were read in full.
+    pub bytes_read: u64,
+    /// Bytes filled in the members that merges wrote.
+    pub bytes_recovered: u64,
+    /// Summed per-group processing time.
+    pub busy_time: Duration,
+}
+
+impl GroupCounts {
+    fn combine(self, other: GroupCounts) -> GroupCounts {
+        GroupCounts {
+            processed: self.processed + other.processed,
+            merged: self.merged + other.merged,
+            skipped: self.skipped + other.skipped,
+            identical: self.identical + other.identical,
+            size_mismatch: self.size_mismatch + other.size_mismatch,
+            divergent: self.divergent + other.divergent,
+            not_writable: self.not_writable + other.not_writable,
+            likely_complete: self.likely_complete + other.likely_complete,
+            empty: self.empty + other.empty,
+            timed_out: self.timed_out + other.timed_out,
+            not_started: self.not_started + other.not_started,
+            skipped_by_reason: std::array::from_fn(|i| {
+                self.skipped_by_reason[i] + other.skipped_by_reason[i]
+            }),
+            bytes_read: self.bytes_read + other.bytes_read,
+            bytes_recovered: self.bytes_recovered + other.bytes_recovered,
+            busy_time: self.busy_time + other.busy_time,
+        }
+    }
+}
+
+/// Throughput of reading `bytes` in `time`, in MiB per second.
+pub fn mb_per_sec(bytes: u64, time: Duration) -> f64 {
+    (bytes as f64 / 1_048_576.0) / time.as_secs_f64()
+}
+
+/// Returns `(aggregate, wall_clock)` MB/s. Groups run in parallel, so the
+/// summed per-group time is larger than the wall-clock time of the run.
+pub fn aggregate_throughput(
+    bytes_read: u64,
+    busy_time: Duration,
+    wall_time: Duration,
+) -> (f64, f64) {
+    (
+        mb_per_sec(bytes_read, busy_time),
+        mb_per_sec(bytes_read, wall_time),
+    )
+}
+
+/// Compares the estimated output bytes on each filesystem against its free
+/// space, returning `(needed, free, sample_dir)` for every short filesystem.
+/// Directories whose filesystem or free space can't be determined are
+/// skipped with a warning rather than failing the run.
+pub fn check_free_space(
+    groups: &[(GroupKey, Vec<PathBuf>)],
+    options: &MergeOptions,
+) -> io::Result<Vec<(u64, u64, PathBuf)>> {
+    let sized: Vec<(u64, Vec<PathBuf>)> = groups
+        .iter()
+        .map(|(key, paths)| (key.size(), paths.clone()))
+        .collect();
+    let needs = space::estimate_space_needs(&sized, options, rayon::current_num_threads())?;
+
+    let mut per_fs: HashMap<u64, (u64, PathBuf)> = HashMap::new();
+    for (dir, bytes) in needs {
+        // Output directories may not exist yet; they land on their nearest
+        // existing ancestor's filesystem.
+        let existing = dir
+            .ancestors()
+            .find(|d| d.exists())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let id = match space::filesystem_id(&existing) {
+            Ok(id) => id,
+            Err(e) => {
+                log::warn!("Not checking free space for {:?}: {}", existing, e);
+                continue;
+            }
+        };
+        let entry = per_fs.entry(id).or_insert((0, existing));
+        entry.0 += bytes;
+    }
+
+    let mut short = Vec::new();
+    for (needed, dir) in per_fs.into_values() {
+        let free = match space::free_bytes(&dir) {
+            Ok(free) => free,
+            Err(e) => {
+                log::warn!("Not checking free space for {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        if needed > free {
+            short.push((needed, free, dir));
+        }
+    }
+    Ok(short)
+}
+
+/// Keeps only the group called `name`, failing if there is none.
+fn select_group(
+    groups: Vec<(GroupKey, Vec<PathBuf>)>,
+    name: &str,
+) -> io::Result<Vec<(GroupKey, Vec<PathBuf>)>> {
+    let selected: Vec<_> = groups
+        .into_iter()
+        .filter(|(key, _)| key.name() == name)
+        .collect();
+    if selected.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No group named {:?} to process", name),
+        ));
+    }
+    Ok(selected)
+}
+
+/// Drops every group past the first `max`, returning whether any were dropped.
+fn cap_groups(groups: &mut Vec<(GroupKey, Vec<PathBuf>)>, max: usize) -> bool {
+    let capped = groups.len() > max;
+    groups.truncate(max);
+    capped
+}
+
+fn shuffle_groups(groups: &mut [(GroupKey, Vec<PathBuf>)], seed: Option<u64>) {
+    // Start from a fixed order so a seed reproduces the same shuffle
+    // regardless of HashMap iteration order.
+    groups.sort_by_cached_key(|(key, _)| key.name());
+    let mut rng = seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed);
+    rng.shuffle(groups);
+}
+
+/// Compares each written output with the trusted copy at its member's path
+/// relative to `root` (its file name without a root) inside `trusted`.
+/// Returns every output that has a trusted copy with how they compared; a
+/// read error fails that output's check rather than the run.
+pub fn verify_against(
+    outputs: &[(PathBuf, PathBuf)],
+    root: Option<&Path>,
+    trusted: &Path,
+) -> Vec<(PathBuf, VerifyOutcome)> {
+    outputs
+        .par_iter()
+        .filter_map(|(member, output)| {
+            let relative = root
+                .and_then(|root| member.strip_prefix(root).ok())
+                .or_else(|| member.file_name().map(Path::new))?;
+            let trusted_copy = trusted.join(relative);
+            if !trusted_copy.is_file() {
+                log::debug!("No trusted copy of {:?} at {:?}", output, trusted_copy);
+                return None;
+            }
+            let outcome = match first_difference(output, &trusted_copy) {
+                Ok(None) => VerifyOutcome::Matched,
+                Ok(Some(offset)) => VerifyOutcome::Differs(offset),
+                Err(e) => VerifyOutcome::Failed(e.to_string()),
+            };
+            Some((output.clone(), outcome))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process_groups(groups: Vec<(GroupKey, Vec<PathBuf>)>) -> io::Result<MergeReport> {
+        let discovery = Discovery {
+            groups,
+            ..Discovery::default()
+        };
+        process(&RunConfig::default(), discovery)
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_run_leaves_group_lines_to_the_caller() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, [1u8, 0])?;
+        fs::write(&b, [0u8, 2])?;
+        let group_key = GroupKey::FilenameAndSize("video.mkv".to_string(), 2);
+
+        // `log` records only reach the test subscriber through the bridge, and
+        // groups run on this thread so the captured logs see them.
+        let _ = tracing_log::LogTracer::init();
+        let mut totals = Totals::default();
+        process_group_entry(
+            &group_key,
+            vec![a, b],
+            None,
+            &MergeOptions::default(),
+            &mut totals,
+        );
+        assert_eq!(totals.counts.merged, 1);
+        assert!(!logs_contain("Group 'video.mkv@2'"));
+        assert!(!logs_contain("-> Created merged file"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_should_pause_on_high_load() {
+        assert!(should_pause(Some(8.5), 4.0));
+        assert!(!should_pause(Some(4.0), 4.0));
+        assert!(!should_pause(Some(0.3), 4.0));
+        assert!(!should_pause(None, 0.0));
+        // A generous threshold never holds a run back on this machine.
+        wait_for_load(f64::MAX, &GroupKey::SizeOnly(0));
+    }
+
+    #[test]
+    fn test_process_groups_counts_many_tiny_groups() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut groups = Vec::new();
+        for i in 0..200u64 {
+            let a = dir.path().join(format!("{}-a", i));
+            let b = dir.path().join(format!("{}-b", i));
+            let size = i + 2;
+            let (data_a, data_b) = match i % 3 {
+                // Mergeable: complementary halves.
+                0 => (
+                    [0u8, 1].repeat(size as usize / 2 + 1),
+                    [1u8, 0].repeat(size as usize / 2 + 1),
+                ),
+                // Already complete duplicates.
+                1 => (vec![7u8; size as usize], vec![7u8; size as usize]),
+                // Conflicting content.
+                _ => (vec![1u8; size as usize], vec![2u8; size as usize]),
+            };
+            fs::write(&a, &data_a[..size as usize])?;
+            fs::write(&b, &data_b[..size as usize])?;
+            groups.push((GroupKey::SizeOnly(size), vec![a, b]));
+        }
+
+        let MergeReport {
+            counts, failures, ..
+        } = process_groups(groups)?;
+
+        assert_eq!(counts.processed, 200);
+        assert_eq!(counts.merged, 67);
+        assert_eq!(counts.skipped, 67);
+        assert_eq!(counts.skipped_by_reason, [67, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(counts.identical, 67);
+        assert_eq!(counts.size_mismatch, 0);
+        let full_reads: u64 = (0..200u64)
+            .filter(|i| i % 3 != 2)
+            .map(|i| (i + 2) * 2)
+            .sum();
+        assert_eq!(counts.bytes_read, full_reads);
+        assert_eq!(failures.len(), 66);
+        assert!(failures.iter().all(|f| f.first_conflict == Some(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shuffle_groups_seed_is_reproducible() {
+        let groups = || -> Vec<(GroupKey, Vec<PathBuf>)> {
+            (0..32)
+                .map(|i| (GroupKey::SizeOnly(i), Vec::new()))
+                .collect()
+        };
+        let names = |groups: &[(GroupKey, Vec<PathBuf>)]| -> Vec<String> {
+            groups.iter().map(|(key, _)| key.name()).collect()
+        };
+
+        let mut first = groups();
+        shuffle_groups(&mut first, Some(42));
+        let mut reversed = groups();
+        reversed.reverse();
+        shuffle_groups(&mut reversed, Some(42));
+        assert_eq!(names(&first), names(&reversed));
+
+        let mut other_seed = groups();
+        shuffle_groups(&mut other_seed, Some(7));
+        assert_ne!(names(&first), names(&other_seed));
+    }
+
+    #[test]
+    fn test_select_group_by_name() -> io::Result<()> {
+        let groups = vec![
+            (
+                GroupKey::FilenameAndSize("video.mkv".to_string(), 8),
+                vec![PathBuf::from("a/video.mkv"), PathBuf::from("b/video.mkv")],
+            ),
+            (
+                GroupKey::FilenameAndSize("other.mkv".to_string(), 8),
+                vec![PathBuf::from("a/other.mkv"), PathBuf::from("b/other.mkv")],
+            ),
+            (GroupKey::SizeOnly(8), vec![PathBuf::from("c/x")]),
+        ];
+
+        let selected = select_group(groups.clone(), "video.mkv@8")?;
+        assert_eq!(selected, groups[..1]);
+        let selected = select_group(groups.clone(), "size-8")?;
+        assert_eq!(selected, groups[2..]);
+        let err = select_group(groups, "missing.mkv@8").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cap_groups() {
+        let mut groups: Vec<(GroupKey, Vec<PathBuf>)> = (0..5)
+            .map(|i| (GroupKey::SizeOnly(i), Vec::new()))
+            .collect();
+        assert!(!cap_groups(&mut groups, 5));
+        assert_eq!(groups.len(), 5);
+        assert!(cap_groups(&mut groups, 2));
+        let sizes: Vec<u64> = groups.iter().map(|(key, _)| key.size()).collect();
+        assert_eq!(sizes, [0, 1]);
+    }
+
+    #[test]
+    fn test_verify_against_trusted_mirror() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        let trusted = dir.path().join("trusted");
+        let mut outputs = Vec::new();
+        for (name, merged, good) in [
+            ("same.mkv", &b"abcd"[..], &b"abcd"[..]),
+            ("differs.mkv", b"abXd", b"abcd"),
+            ("short.mkv", b"ab", b"abcd"),
+            ("untrusted.mkv", b"abcd", b""),
+            ("gone.mkv", b"abcd", b"abcd"),
+        ] {
+            let member = root.join("show").join(name);
+            let output = root.join("show").join(format!("{}.merged", name));
+            fs::create_dir_all(member.parent().unwrap())?;
+            fs::write(&output, merged)?;
+            if !good.is_empty() {
+                fs::create_dir_all(trusted.join("show"))?;
+                fs::write(trusted.join("show").join(name), good)?;
+            }
+            outputs.push((member, output));
+        }
+
+        // An output that can't be read fails its own check only.
+        let show = root.join("show");
+        fs::remove_file(show.join("gone.mkv.merged"))?;
+
+        let mut checked = verify_against(&outputs, Some(&root), &trusted);
+        checked.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(checked.len(), 4);
+        assert_eq!(
+            checked[0],
+            (show.join("differs.mkv.merged"), VerifyOutcome::Differs(2))
+        );
+        assert_eq!(checked[1].0, show.join("gone.mkv.merged"));
+        assert!(matches!(checked[1].1, VerifyOutcome::Failed(_)));
+        assert_eq!(
+            checked[2],
+            (show.join("same.mkv.merged"), VerifyOutcome::Matched)
+        );
+        assert_eq!(
+            checked[3],
+            (show.join("short.mkv.merged"), VerifyOutcome::Differs(2))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_throughput() {
+        let mut counts = GroupCounts::default();
+        for (bytes, millis) in [(64 * 1_048_576, 1_000), (32 * 1_048_576, 500), (0, 500)] {
+            counts = counts.combine(GroupCounts {
+                processed: 1,
+                bytes_read: bytes,
+                busy_time: Duration::from_millis(millis),
+                ..GroupCounts::default()
+            });
+        }
+        assert_eq!(counts.processed, 3);
+        assert_eq!(counts.bytes_read, 96 * 1_048_576);
+        assert_eq!(counts.busy_time, Duration::from_secs(2));
+
+        let (aggregate, wall_clock) =
+            aggregate_throughput(counts.bytes_read, counts.busy_time, Duration::from_secs(1));
+        assert!((aggregate - 48.0).abs() < 1e-9);
+        assert!((wall_clock - 96.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_only_failed_report_lists_failing_group() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bad_a = dir.path().join("bad-a");
+        let bad_b = dir.path().join("bad-b");
+        fs::write(&bad_a, [1u8, 0, 3])?;
+        fs::write(&bad_b, [1u8, 0, 4])?;
+        let good_a = dir.path().join("good-a");
+        let good_b = dir.path().join("good-b");
+        fs::write(&good_a, [1u8, 0])?;
+        fs::write(&good_b, [0u8, 2])?;
+        let groups = vec![
+            (GroupKey::SizeOnly(3), vec![bad_a.clone(), bad_b.clone()]),
+            (GroupKey::SizeOnly(2), vec![good_a, good_b]),
+        ];
+
+        let failures = process_groups(groups)?.failures;
+        let report_path = dir.path().join("failed.txt");
+        crate::report::write_failed_report(&report_path, &failures, None)?;
+
+        let report = fs::read_to_string(&report_path)?;
+        assert!(report.contains("group: size-3"));
+        assert!(report.contains("first conflict offset: 2"));
+        assert!(report.contains(&format!("member: {}", bad_a.display())));
+        assert!(report.contains(&format!("member: {}", bad_b.display())));
+        assert!(!report.contains("size-2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_skips_unchanged_groups() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("a"))?;
+        fs::create_dir(dir.path().join("b"))?;
+        let a = dir.path().join("a/file.bin");
+        let b = dir.path().join("b/file.bin");
+        fs::write(&a, [1u8, 0, 3, 0])?;
+        fs::write(&b, [0u8, 2, 0, 4])?;
+        let config = RunConfig {
+            paths: Some(vec![a.clone(), b]),
+            ..RunConfig::default()
+        };
+
+        let mut seen = Seen::default();
+        let first = run_watched(&config, &mut seen)?;
+        assert_eq!(first.counts.processed, 1);
+        assert_eq!(first.unchanged, 0);
+
+        let second = run_watched(&config, &mut seen)?;
+        assert_eq!(second.counts.processed, 0);
+        assert_eq!(second.unchanged, 1);
+
+        fs::write(&a, [1u8, 2, 3, 0])?;
+        let third = run_watched(&config, &mut seen)?;
+        assert_eq!(third.counts.processed, 1);
+        assert_eq!(third.unchanged, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_timeout_marks_group_failed() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, [1u8, 0])?;
+        fs::write(&b, [0u8, 2])?;
+        let group_key = GroupKey::FilenameAndSize("video.mkv".to_string(), 2);
+        let paths = vec![a, b];
+
+        let mut totals = Totals::default();
+        let options = MergeOptions::builder()
+            .group_timeout(Some(Duration::ZERO))
+            .build();
+        process_group_entry(&group_key, paths.clone(), None, &options, &mut totals);
+        assert_eq!(totals.counts.timed_out, 1);
+        assert_eq!(totals.failures[0].reason, "timeout");
+        assert!(!dir.path().join("a.merged").exists());
+
+        let mut totals = Totals::default();
+        let options = MergeOptions::builder()
+            .group_timeout(Some(Duration::from_secs(60)))
+            .build();
+        process_group_entry(&group_key, paths.clone(), None, &options, &mut totals);
+        assert_eq!(totals.counts.merged, 1);
+        assert_eq!(fs::read(dir.path().join("a.merged"))?, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_runs_use_their_own_pools() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for (sub, data) in [("a", [1u8, 0]), ("b", [0u8, 2])] {
+            fs::create_dir(dir.path().join(sub))?;
+            let path = dir.path().join(sub).join("video.mkv");
+            fs::write(&path, data)?;
+            paths.push(path);
+        }
+        // Each run builds its own pool, so runs with differing thread counts
+        // in one process don't clash over rayon's global pool.
+        for threads in [2, 3] {
+            let config = RunConfig {
+                paths: Some(paths.clone()),
+                num_threads: Some(threads),
+                ignore_space_check: true,
+                ..RunConfig::default()
+            };
+            let report = run(&config)?;
+            assert_eq!(report.counts.merged, 1);
+            assert_eq!(
+                with_pool(Some(threads), rayon::current_num_threads),
+                threads
+            );
+            for path in &paths {
+                fs::remove_file(path.with_extension("mkv.merged"))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recoverable_groups_lists_only_recoverable() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for (name, a, b) in [
+            ("recover.mkv", [1u8, 0], [0u8, 2]),
+            ("complete.mkv", [3, 4], [3, 4]),
+            ("conflict.mkv", [1, 0], [2, 0]),
+        ] {
+            for (sub, data) in [("a", a), ("b", b)] {
+                fs::create_dir_all(dir.path().join(sub))?;
+                let path = dir.path().join(sub).join(name);
+                fs::write(&path, data)?;
+                paths.push(path);
+            }
+        }
+        let config = RunConfig {
+            paths: Some(paths),
+            ..RunConfig::default()
+        };
+        let discovery = discover(&config)?;
+        assert_eq!(discovery.groups.len(), 3);
+
+        for quick in [false, true] {
+            let recoverable = recoverable_groups(&config, &discovery, quick);
+            let names: Vec<String> = recoverable.iter().map(|(key, _)| key.name()).collect();
+            assert_eq!(names, vec!["recover.mkv@2"]);
+            assert_eq!(recoverable[0].1.len(), 2);
+        }
+        // Nothing was written next to the members.
+        assert_eq!(fs::read_dir(dir.path().join("a"))?.count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_failures_stops_starting_groups() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for i in 0..5 {
+            for (sub, data) in [("a", [1u8, 0]), ("b", [2u8, 0])] {
+                fs::create_dir_all(dir.path().join(sub))?;
+                let path = dir.path().join(sub).join(format!("conflict{}.mkv", i));
+                fs::write(&path, data)?;
+                paths.push(path);
+            }
+        }
+        let config = RunConfig {
+            paths: Some(paths),
+            num_threads: Some(1),
+            ignore_space_check: true,
+            max_failures: Some(2),
+            ..RunConfig::default()
+        };
+        let report = run(&config)?;
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.counts.processed, 2);
+        assert_eq!(report.counts.not_started, 3);
+
+        let report = run(&RunConfig {
+            max_failures: None,
+            ..config
+        })?;
+        assert_eq!(report.failures.len(), 5);
+        assert_eq!(report.counts.not_started, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget_holds_groups_back() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let groups = |sub: &str| -> io::Result<Discovery> {
+            let mut groups = Vec::new();
+            for i in 0..12 {
+                let a = dir.path().join(format!("{}-{}-a", sub, i));
+                let b = dir.path().join(format!("{}-{}-b", sub, i));
+                fs::write(&a, [1u8, 0])?;
+                fs::write(&b, [0u8, 2])?;
+                groups.push((GroupKey::SizeOnly(2 + i), vec![a, b]));
+            }
+            Ok(Discovery {
+                groups,
+                ..Discovery::default()
+            })
+        };
+        // Two members and the OR chunk at 1 KiB each.
+        let config = |threads: usize, limit: u64| RunConfig {
+            num_threads: Some(threads),
+            options: MergeOptions::builder()
+                .chunk_bounds(merger::ChunkBounds {
+                    min: 1024,
+                    max: 1024,
+                })
+                .max_memory(Some(limit))
+                .build(),
+            ..RunConfig::default()
+        };
+
+        // Room for two of the groups at once.
+        let limited = config(4, 7000);
+        let report = process(&limited, groups("four")?)?;
+        assert_eq!(report.counts.merged, 12);
+        let budget = limited.options.memory_budget.as_ref().unwrap();
+        assert!(budget.peak() >= 3 * 1024);
+        assert!(budget.peak() <= 7000);
+
+        // On a single thread the group waiting for memory runs the one
+        // holding it instead of deadlocking.
+        let report = process(&config(1, 3 * 1024), groups("one")?)?;
+        assert_eq!(report.counts.merged, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_group_sees_each_group_as_it_finishes() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut groups = Vec::new();
+        for name in ["one.mkv", "two.mkv"] {
+            let a = dir.path().join(format!("a-{}", name));
+            let b = dir.path().join(format!("b-{}", name));
+            fs::write(&a, [1u8, 0])?;
+            fs::write(&b, [0u8, 2])?;
+            groups.push((GroupKey::FilenameAndSize(name.to_string(), 2), vec![a, b]));
+        }
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let on_group = {
+            let seen = Arc::clone(&seen);
+            OnGroup(Arc::new(move |group: &GroupResult, progress| {
+                seen.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((group.name.clone(), progress));
+            }))
+        };
+        let config = RunConfig {
+            num_threads: Some(1),
+            on_group: Some(on_group),
+            ..RunConfig::default()
+        };
+        let discovery = Discovery {
+            groups,
+            ..Discovery::default()
+        };
+        let report = process(&config, discovery)?;
+        assert_eq!(report.counts.merged, 2);
+
+        let seen = seen.lock().unwrap_or_else(|e| e.into_inner());
+        let progress = |done, percentage| GroupProgress {
+            done,
+            total: 2,
+            percentage,
+        };
+        assert_eq!(
+            *seen,
+            [
+                ("one.mkv@2".to_string(), progress(1, 50.0)),
+                ("two.mkv@2".to_string(), progress(2, 100.0)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_is_weighted_by_bytes() {
+        let total_bytes = 10 * 2 + 20 + 480 * 2;
+        assert_eq!(percentage(1, 3, 20, total_bytes), 2.0);
+        assert_eq!(percentage(2, 3, 40, total_bytes), 4.0);
+        assert_eq!(percentage(3, 3, 1000, total_bytes), 100.0);
+
+        // Only empty groups: fall back to the group count.
+        assert_eq!(percentage(1, 2, 0, 0), 50.0);
+    }
+}
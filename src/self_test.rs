@@ -0,0 +1,140 @@
+//! End-to-end check of the merge on generated data, run on the user's own
+//! filesystem.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::merger::{self, GroupStatus, MergeOptions};
+
+// Spans a few read chunks so chunk boundaries are exercised.
+const SELF_TEST_SIZE: usize = 3 * merger::DEFAULT_CHUNK_SIZE + 4099;
+const STRIPE: usize = 64 * 1024;
+
+/// Outcome of one self-test case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCase {
+    pub name: &'static str,
+    pub passed: bool,
+    /// What went wrong, empty when the case passed.
+    pub detail: String,
+}
+
+/// Generates groups under a fresh temp directory in `parent`, merges each
+/// and checks the outcome: complementary partials must reconstruct the
+/// original, identical complete copies must be skipped and members that
+/// conflict must fail. `seed` makes the generated data reproducible.
+pub fn self_test(parent: &Path, seed: u64) -> io::Result<Vec<SelfTestCase>> {
+    let dir = tempfile::tempdir_in(parent)?;
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let original: Vec<u8> = (0..SELF_TEST_SIZE).map(|_| rng.u8(1..)).collect();
+    let options = MergeOptions::default();
+
+    let partials: Vec<Vec<u8>> = (0..3)
+        .map(|m| {
+            let mut partial = original.clone();
+            for (stripe, chunk) in partial.chunks_mut(STRIPE).enumerate() {
+                if stripe % 3 != m {
+                    chunk.fill(0);
+                }
+            }
+            partial
+        })
+        .collect();
+    let complementary = check_case(dir.path(), "complementary", &partials, &options, |stats| {
+        if !matches!(stats.status, GroupStatus::Merged) {
+            return Err(format!("expected merged, got {:?}", stats.status));
+        }
+        for merged in &stats.merged_files {
+            if fs::read(merged).map_err(|e| e.to_string())? != original {
+                return Err(format!("{:?} differs from the original", merged));
+            }
+        }
+        match stats.merged_files.len() {
+            3 => Ok(()),
+            n => Err(format!("expected 3 merged files, got {}", n)),
+        }
+    })?;
+
+    let copies = vec![original.clone(), original.clone()];
+    let identical = check_case(dir.path(), "identical", &copies, &options, |stats| {
+        match (&stats.status, stats.merged_files.len()) {
+            (GroupStatus::Skipped, 0) => Ok(()),
+            (status, n) => Err(format!(
+                "expected skipped, got {:?} with {} outputs",
+                status, n
+            )),
+        }
+    })?;
+
+    let mut conflicting = partials[0].clone();
+    let offset = rng.usize(..STRIPE);
+    conflicting[offset] = conflicting[offset].wrapping_add(1).max(1);
+    let conflict = check_case(
+        dir.path(),
+        "conflict",
+        &[partials[0].clone(), conflicting],
+        &options,
+        |stats| match (&stats.status, stats.first_conflict) {
+            (GroupStatus::Failed, Some(found)) if found == offset as u64 => Ok(()),
+            (status, found) => Err(format!(
+                "expected a failure at offset {}, got {:?} at {:?}",
+                offset, status, found
+            )),
+        },
+    )?;
+
+    Ok(vec![complementary, identical, conflict])
+}
+
+// Writes `members` to a subdirectory named after the case, merges them and
+// judges the outcome with `check`. Merge errors fail the case rather than
+// the whole self-test.
+fn check_case(
+    dir: &Path,
+    name: &'static str,
+    members: &[Vec<u8>],
+    options: &MergeOptions,
+    check: impl FnOnce(&merger::GroupStats) -> Result<(), String>,
+) -> io::Result<SelfTestCase> {
+    let case_dir = dir.join(name);
+    fs::create_dir(&case_dir)?;
+    let paths = members
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            let path = case_dir.join(format!("member-{}", i));
+            fs::write(&path, data)?;
+            Ok(path)
+        })
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    let verdict = match merger::process_group(&paths, name, None, options) {
+        Ok(stats) => check(&stats),
+        Err(e) => Err(format!("merge failed: {}", e)),
+    };
+    Ok(SelfTestCase {
+        name,
+        passed: verdict.is_ok(),
+        detail: verdict.err().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_self_test_passes() -> io::Result<()> {
+        let dir = tempdir()?;
+        let cases = self_test(dir.path(), 42)?;
+        let names: Vec<&str> = cases.iter().map(|case| case.name).collect();
+        assert_eq!(names, vec!["complementary", "identical", "conflict"]);
+        for case in &cases {
+            assert!(case.passed, "{}: {}", case.name, case.detail);
+        }
+        // Everything generated is cleaned up.
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+}
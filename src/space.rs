@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::merger::{MergeOptions, member_dir, merge_temp_dir, output_path};
+
+/// Estimates the bytes each directory needs while merging `groups`, given as
+/// `(size, members)`. Every member is assumed incomplete, plus the temp file
+/// the merge is staged in. Merged outputs stay on disk so their needs add up
+/// across groups; replacements only need transient space, so for those a
+/// directory needs the `concurrency` largest groups at once.
+pub fn estimate_space_needs(
+    groups: &[(u64, Vec<PathBuf>)],
+    options: &MergeOptions,
+    concurrency: usize,
+) -> io::Result<BTreeMap<PathBuf, u64>> {
+    let mut per_dir: HashMap<PathBuf, Vec<u64>> = HashMap::new();
+    for (size, paths) in groups {
+        let Some(first) = paths.first() else {
+            continue;
+        };
+        let mut needs: HashMap<PathBuf, u64> = HashMap::new();
+        *needs.entry(merge_temp_dir(first, options)?).or_default() += size;
+        for path in paths {
+            let dir = if options.replace {
+                parent_dir(path)
+            } else {
+                parent_dir(&output_path(options, path)?)
+            };
+            *needs.entry(dir).or_default() += size;
+        }
+        for (dir, bytes) in needs {
+            per_dir.entry(dir).or_default().push(bytes);
+        }
+    }
+
+    Ok(per_dir
+        .into_iter()
+        .map(|(dir, mut needs)| {
+            let total = if options.replace {
+                needs.sort_unstable_by(|a, b| b.cmp(a));
+                needs.iter().take(concurrency.max(1)).sum()
+            } else {
+                needs.iter().sum()
+            };
+            (dir, total)
+        })
+        .collect())
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    member_dir(path).to_path_buf()
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    pub fn filesystem_id(path: &Path) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.dev())
+    }
+
+    fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat)
+    }
+
+    pub fn free_bytes(path: &Path) -> io::Result<u64> {
+        let stat = statvfs(path)?;
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    pub fn read_only_filesystem(path: &Path) -> io::Result<bool> {
+        Ok(statvfs(path)?.f_flag & libc::ST_RDONLY != 0)
+    }
+}
+
+// Without statvfs neither the filesystem nor its free space is known, and a
+// read-only mount only shows up as a failed write.
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn filesystem_id(_path: &Path) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn free_bytes(_path: &Path) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn read_only_filesystem(_path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Identifier of the filesystem holding `path`. Fails with `Unsupported`
+/// off unix.
+pub fn filesystem_id(path: &Path) -> io::Result<u64> {
+    sys::filesystem_id(path)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+/// Fails with `Unsupported` off unix.
+pub fn free_bytes(path: &Path) -> io::Result<u64> {
+    sys::free_bytes(path)
+}
+
+/// Whether the filesystem holding `path` is mounted read-only, as snapshots
+/// are. Always false off unix.
+pub fn read_only_filesystem(path: &Path) -> io::Result<bool> {
+    sys::read_only_filesystem(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups() -> Vec<(u64, Vec<PathBuf>)> {
+        vec![
+            (
+                100,
+                vec![PathBuf::from("/a/x.mkv"), PathBuf::from("/b/x.mkv")],
+            ),
+            (
+                40,
+                vec![PathBuf::from("/a/y.mkv"), PathBuf::from("/a/z/y.mkv")],
+            ),
+            (
+                7,
+                vec![PathBuf::from("/b/w.mkv"), PathBuf::from("/b/w2.mkv")],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_estimate_space_needs() -> io::Result<()> {
+        let needs = estimate_space_needs(&groups(), &MergeOptions::builder().build(), 4)?;
+        let expected = BTreeMap::from([
+            (PathBuf::from("/a"), 100 + 100 + 40 + 40),
+            (PathBuf::from("/a/z"), 40),
+            (PathBuf::from("/b"), 100 + 7 * 3),
+        ]);
+        assert_eq!(needs, expected);
+
+        let options = MergeOptions::builder()
+            .output_template("merged/{name}")
+            .build();
+        let needs = estimate_space_needs(&groups(), &options, 4)?;
+        assert_eq!(needs[&PathBuf::from("/a/merged")], 100 + 40);
+        assert_eq!(needs[&PathBuf::from("/a")], 100 + 40);
+
+        // Replacements are transient, so only the largest in-flight groups count.
+        let options = MergeOptions::builder().replace(true).build();
+        let needs = estimate_space_needs(&groups(), &options, 1)?;
+        assert_eq!(needs[&PathBuf::from("/a")], 200);
+        assert_eq!(needs[&PathBuf::from("/b")], 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bencode::{self, Value};
+
+/// A file described by a `.torrent`, relative to the download directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Byte offset of the file within the torrent's concatenated data.
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentInfo {
+    pub piece_length: u64,
+    pub first_piece_hash: Option<[u8; 20]>,
+    pub files: Vec<TorrentFile>,
+}
+
+/// What a member path inherits from the torrent that describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TorrentVersion {
+    pub piece_length: u64,
+    /// Only set for the file at offset 0, whose first piece it covers.
+    pub first_piece_hash: Option<[u8; 20]>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid torrent: {}", msg),
+    )
+}
+
+fn path_component(bytes: &[u8]) -> io::Result<&str> {
+    let component = std::str::from_utf8(bytes).map_err(|_| invalid("non UTF-8 path"))?;
+    if component.is_empty() || component == ".." || component.contains('/') {
+        return Err(invalid("unsafe path component"));
+    }
+    Ok(component)
+}
+
+pub fn parse_torrent(data: &[u8]) -> io::Result<TorrentInfo> {
+    let root = bencode::parse(data)?;
+    let info = root.get("info").ok_or_else(|| invalid("missing info"))?;
+    let piece_length = info
+        .get("piece length")
+        .and_then(Value::as_int)
+        .filter(|&l| l > 0)
+        .ok_or_else(|| invalid("missing piece length"))? as u64;
+    let first_piece_hash = info
+        .get("pieces")
+        .and_then(Value::as_bytes)
+        .and_then(|pieces| pieces.get(..20))
+        .map(|hash| hash.try_into().expect("20 bytes"));
+    let name = path_component(
+        info.get("name")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| invalid("missing name"))?,
+    )?;
+
+    let mut files = Vec::new();
+    match info.get("files").and_then(Value::as_list) {
+        Some(entries) => {
+            let mut offset = 0;
+            for entry in entries {
+                let length = entry
+                    .get("length")
+                    .and_then(Value::as_int)
+                    .filter(|&l| l >= 0)
+                    .ok_or_else(|| invalid("missing file length"))?
+                    as u64;
+                let mut path = PathBuf::from(name);
+                for component in entry
+                    .get("path")
+                    .and_then(Value::as_list)
+                    .ok_or_else(|| invalid("missing file path"))?
+                {
+                    let bytes = component
+                        .as_bytes()
+                        .ok_or_else(|| invalid("bad path component"))?;
+                    path.push(path_component(bytes)?);
+                }
+                files.push(TorrentFile {
+                    path,
+                    length,
+                    offset,
+                });
+                offset += length;
+            }
+        }
+        None => {
+            let length = info
+                .get("length")
+                .and_then(Value::as_int)
+                .filter(|&l| l >= 0)
+                .ok_or_else(|| invalid("missing length"))? as u64;
+            files.push(TorrentFile {
+                path: PathBuf::from(name),
+                length,
+                offset: 0,
+            });
+        }
+    }
+
+    Ok(TorrentInfo {
+        piece_length,
+        first_piece_hash,
+        files,
+    })
+}
+
+/// Torrent metadata used to tell apart files that share a name and size.
+#[derive(Debug, Default)]
+pub struct TorrentIndex {
+    torrents: Vec<TorrentInfo>,
+}
+
+impl TorrentIndex {
+    pub fn new(torrents: Vec<TorrentInfo>) -> TorrentIndex {
+        TorrentIndex { torrents }
+    }
+
+    /// Parses every `.torrent` under `dir`, skipping unreadable ones.
+    pub fn load(dir: &Path) -> io::Result<TorrentIndex> {
+        let mut torrents = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(current_dir) = dirs.pop() {
+            for entry in fs::read_dir(&current_dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("torrent"))
+                {
+                    match fs::read(&path).and_then(|data| parse_torrent(&data)) {
+                        Ok(info) => torrents.push(info),
+                        Err(e) => log::warn!("Skipping torrent {:?}: {}", path, e),
+                    }
+                }
+            }
+        }
+        Ok(TorrentIndex { torrents })
+    }
+
+    pub fn len(&self) -> usize {
+        self.torrents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.torrents.is_empty()
+    }
+
+    /// Finds the torrent file whose relative path is the longest suffix of
+    /// `path` with the same length, preferring earlier torrents on ties.
+    pub fn lookup(&self, path: &Path, size: u64) -> Option<TorrentVersion> {
+        let mut best: Option<(usize, TorrentVersion)> = None;
+        for torrent in &self.torrents {
+            for file in &torrent.files {
+                if file.length != size || !path.ends_with(&file.path) {
+                    continue;
+                }
+                let depth = file.path.components().count();
+                if best.is_none_or(|(d, _)| depth > d) {
+                    let first_piece_hash = torrent.first_piece_hash.filter(|_| file.offset == 0);
+                    best = Some((
+                        depth,
+                        TorrentVersion {
+                            piece_length: torrent.piece_length,
+                            first_piece_hash,
+                        },
+                    ));
+                }
+            }
+        }
+        best.map(|(_, version)| version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multi_file_torrent() -> io::Result<()> {
+        let data = b"d4:infod5:filesld6:lengthi10e4:pathl1:a5:x.mkveed6:lengthi5e4:pathl5:y.srteee4:name3:rel12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let info = parse_torrent(data)?;
+        assert_eq!(info.piece_length, 16384);
+        assert_eq!(info.first_piece_hash, Some([b'a'; 20]));
+        assert_eq!(
+            info.files,
+            vec![
+                TorrentFile {
+                    path: PathBuf::from("rel/a/x.mkv"),
+                    length: 10,
+                    offset: 0,
+                },
+                TorrentFile {
+                    path: PathBuf::from("rel/y.srt"),
+                    length: 5,
+                    offset: 10,
+                },
+            ]
+        );
+
+        let index = TorrentIndex::new(vec![info]);
+        let version = index.lookup(Path::new("/dl/rel/y.srt"), 5);
+        assert_eq!(
+            version,
+            Some(TorrentVersion {
+                piece_length: 16384,
+                first_piece_hash: None,
+            })
+        );
+        assert_eq!(index.lookup(Path::new("/dl/rel/y.srt"), 6), None);
+
+        let unsafe_path = b"d4:infod6:lengthi1e4:name2:..12:piece lengthi1eee";
+        assert!(parse_torrent(unsafe_path).is_err());
+        Ok(())
+    }
+}
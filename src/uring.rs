@@ -0,0 +1,132 @@
+//! Reads every member's current chunk through one io_uring so the reads are
+//! in flight together, for devices a single synchronous reader can't keep
+//! busy.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use io_uring::{IoUring, opcode, types};
+
+// Reads are submitted in batches of at most this many entries.
+const RING_ENTRIES: u32 = 64;
+// Failed waits tolerated while draining reads before giving up.
+const DRAIN_ATTEMPTS: u32 = 100;
+
+pub(crate) struct UringReader {
+    ring: IoUring,
+    files: Vec<File>,
+}
+
+impl UringReader {
+    pub(crate) fn open(paths: &[PathBuf]) -> io::Result<UringReader> {
+        let files = paths.iter().map(File::open).collect::<io::Result<_>>()?;
+        Ok(UringReader {
+            ring: IoUring::new(RING_ENTRIES)?,
+            files,
+        })
+    }
+
+    /// Fills the first `chunk_size` bytes of each live member's buffer from
+    /// `offset`, leaving dropped members alone. Results are in member order,
+    /// like the synchronous path: a member ending early is `UnexpectedEof`.
+    pub(crate) fn read_chunks(
+        &mut self,
+        buffers: &mut [Vec<u8>],
+        dropped: &[bool],
+        offset: u64,
+        chunk_size: usize,
+    ) -> Vec<io::Result<()>> {
+        let mut filled = vec![0usize; self.files.len()];
+        let mut results: Vec<Option<io::Result<()>>> =
+            dropped.iter().map(|&d| d.then_some(Ok(()))).collect();
+        loop {
+            let pending: Vec<usize> = (0..self.files.len())
+                .filter(|&i| results[i].is_none())
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            for batch in pending.chunks(RING_ENTRIES as usize) {
+                for &i in batch {
+                    let buffer = &mut buffers[i][filled[i]..chunk_size];
+                    let entry = opcode::Read::new(
+                        types::Fd(self.files[i].as_raw_fd()),
+                        buffer.as_mut_ptr(),
+                        buffer.len() as u32,
+                    )
+                    .offset(offset + filled[i] as u64)
+                    .build()
+                    .user_data(i as u64);
+                    // The batch never exceeds the ring size and every earlier
+                    // entry has completed, so there is room.
+                    unsafe { self.ring.submission().push(&entry) }
+                        .expect("submission queue has room");
+                }
+                let mut completed = 0;
+                while completed < batch.len() {
+                    match self.ring.submit_and_wait(batch.len() - completed) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            self.drain(batch.len() - completed);
+                            for &i in batch {
+                                results[i].get_or_insert_with(|| {
+                                    Err(io::Error::new(e.kind(), e.to_string()))
+                                });
+                            }
+                            return results.into_iter().map(Option::unwrap).collect();
+                        }
+                    }
+                    for cqe in self.ring.completion() {
+                        completed += 1;
+                        let i = cqe.user_data() as usize;
+                        match cqe.result() {
+                            res if res < 0 => {
+                                let e = io::Error::from_raw_os_error(-res);
+                                // Interrupted reads are retried in the next round.
+                                if e.kind() != io::ErrorKind::Interrupted {
+                                    results[i] = Some(Err(e));
+                                }
+                            }
+                            0 => results[i] = Some(Err(io::ErrorKind::UnexpectedEof.into())),
+                            res => {
+                                filled[i] += res as usize;
+                                if filled[i] == chunk_size {
+                                    results[i] = Some(Ok(()));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    // Waits for `remaining` pushed reads to complete, discarding their
+    // results. Submitted reads write into the caller's buffers, so none may
+    // be left in flight when `read_chunks` returns, nor leave completions
+    // behind for the next call. If the ring keeps failing the process is
+    // aborted: unwinding would free buffers the kernel may still write to.
+    fn drain(&mut self, mut remaining: usize) {
+        let mut failures = 0;
+        while remaining > 0 {
+            match self.ring.submit_and_wait(remaining) {
+                Ok(_) => failures = 0,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    failures += 1;
+                    if failures >= DRAIN_ATTEMPTS {
+                        log::error!("Could not drain {} io_uring reads: {}", remaining, e);
+                        std::process::abort();
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+            remaining -= self.ring.completion().count();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Extended attributes, carried from a member onto the file that replaces it
+//! or holds its merged data. Linux and macOS are supported; on other unix
+//! systems every call fails with `Unsupported`.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "name contains a NUL byte".to_string(),
+        )
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn check(ret: isize) -> io::Result<usize> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+// A null buffer asks for the size; macOS may reject an empty non-null one.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn buf_ptr(buf: &mut [u8]) -> *mut libc::c_void {
+    if buf.is_empty() {
+        std::ptr::null_mut()
+    } else {
+        buf.as_mut_ptr().cast()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sys_list(path: &CString, buf: &mut [u8]) -> io::Result<usize> {
+    check(unsafe { libc::listxattr(path.as_ptr(), buf_ptr(buf).cast(), buf.len()) })
+}
+
+#[cfg(target_os = "linux")]
+fn sys_get(path: &CString, name: &CString, buf: &mut [u8]) -> io::Result<usize> {
+    check(unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf_ptr(buf), buf.len()) })
+}
+
+#[cfg(target_os = "linux")]
+fn sys_set(path: &CString, name: &CString, value: &[u8]) -> io::Result<()> {
+    check(unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        ) as isize
+    })
+    .map(|_| ())
+}
+
+// macOS adds a position, only used by resource forks, and an options word.
+#[cfg(target_os = "macos")]
+fn sys_list(path: &CString, buf: &mut [u8]) -> io::Result<usize> {
+    check(unsafe { libc::listxattr(path.as_ptr(), buf_ptr(buf).cast(), buf.len(), 0) })
+}
+
+#[cfg(target_os = "macos")]
+fn sys_get(path: &CString, name: &CString, buf: &mut [u8]) -> io::Result<usize> {
+    check(unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf_ptr(buf), buf.len(), 0, 0) })
+}
+
+#[cfg(target_os = "macos")]
+fn sys_set(path: &CString, name: &CString, value: &[u8]) -> io::Result<()> {
+    check(unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        ) as isize
+    })
+    .map(|_| ())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn sys_list(_path: &CString, _buf: &mut [u8]) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn sys_get(_path: &CString, _name: &CString, _buf: &mut [u8]) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn sys_set(_path: &CString, _name: &CString, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Asks for the size first, then reads; retries if it grew in between.
+fn read_sized(mut read: impl FnMut(&mut [u8]) -> io::Result<usize>) -> io::Result<Vec<u8>> {
+    loop {
+        let mut buf = vec![0u8; read(&mut [])?];
+        match read(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Names of the extended attributes of `path`.
+pub fn list(path: &Path) -> io::Result<Vec<OsString>> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let names = read_sized(|buf| sys_list(&path, buf))?;
+    Ok(names
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsString::from_vec(name.to_vec()))
+        .collect())
+}
+
+/// Value of the extended attribute `name` of `path`.
+pub fn get(path: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    read_sized(|buf| sys_get(&path, &name, buf))
+}
+
+/// Sets the extended attribute `name` of `path`, creating or replacing it.
+pub fn set(path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    sys_set(&path, &name, value)
+}
+
+/// Outcome of `copy_all`.
+#[derive(Debug, Default)]
+pub struct Copied {
+    /// Attributes copied.
+    pub count: usize,
+    /// Attributes that couldn't be read or set, with the error for each.
+    pub failed: Vec<(OsString, io::Error)>,
+}
+
+/// Copies every extended attribute of `from` onto `to`. Each attribute is
+/// copied on its own, so one that can't be read or set doesn't stop the
+/// rest; only failing to list them is an error.
+pub fn copy_all(from: &Path, to: &Path) -> io::Result<Copied> {
+    let mut copied = Copied::default();
+    for name in list(from)? {
+        match get(from, &name).and_then(|value| set(to, &name, &value)) {
+            Ok(()) => copied.count += 1,
+            Err(e) => copied.failed.push((name, e)),
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_all() -> io::Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::write(&from, b"a")?;
+        fs::write(&to, b"b")?;
+        match set(&from, OsStr::new("user.comment"), b"seen twice") {
+            // The filesystem under the temp dir may not take user attributes.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+            result => result?,
+        }
+        set(&from, OsStr::new("user.empty"), b"")?;
+
+        let copied = copy_all(&from, &to)?;
+        assert_eq!(copied.count, 2);
+        assert!(copied.failed.is_empty());
+        let mut names = list(&to)?;
+        names.sort();
+        assert_eq!(names, vec!["user.comment", "user.empty"]);
+        assert_eq!(get(&to, OsStr::new("user.comment"))?, b"seen twice");
+        assert_eq!(get(&to, OsStr::new("user.empty"))?, b"");
+        assert!(get(&to, OsStr::new("user.missing")).is_err());
+
+        // Devices take no user attributes; every failure is reported.
+        #[cfg(target_os = "linux")]
+        {
+            let copied = copy_all(&from, Path::new("/dev/null"))?;
+            assert_eq!(copied.count, 0);
+            let mut failed: Vec<_> = copied.failed.into_iter().map(|(name, _)| name).collect();
+            failed.sort();
+            assert_eq!(failed, vec!["user.comment", "user.empty"]);
+        }
+        Ok(())
+    }
+}
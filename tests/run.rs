@@ -0,0 +1,53 @@
+use std::fs;
+use std::io;
+
+use torrent_combine::merger::GroupStatus;
+use torrent_combine::run::{self, RunConfig};
+
+#[test]
+fn test_run_reports_a_merged_tree() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let size = 1_500_000;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+    for (release, missing) in [("a", 0..size / 2), ("b", size / 2..size)] {
+        let mut partial = data.clone();
+        partial[missing].fill(0);
+        fs::create_dir_all(dir.path().join(release))?;
+        fs::write(dir.path().join(release).join("video.mkv"), &partial)?;
+    }
+    // Too small to be scanned.
+    fs::write(dir.path().join("a").join("small.mkv"), [1u8, 2, 3])?;
+
+    let report = run::run(&RunConfig {
+        root_dir: Some(dir.path().to_path_buf()),
+        ..RunConfig::default()
+    })?;
+
+    assert_eq!(report.files, 2);
+    assert_eq!(report.total_groups, 1);
+    assert_eq!(report.capped, None);
+    assert_eq!(report.counts.processed, 1);
+    assert_eq!(report.counts.merged, 1);
+    assert_eq!(report.counts.bytes_read, 2 * size as u64);
+    assert!(report.failures.is_empty());
+    assert!(report.verification.is_none());
+
+    let [group] = report.groups.as_slice() else {
+        panic!("expected one group, got {:?}", report.groups);
+    };
+    assert_eq!(group.name, format!("video.mkv@{}", size));
+    assert_eq!(group.members.len(), 2);
+    let stats = group.result.as_ref().expect("group merged");
+    assert!(matches!(stats.status, GroupStatus::Merged));
+    assert_eq!(stats.merged_files.len(), 2);
+    for merged in &stats.merged_files {
+        assert_eq!(fs::read(merged)?, data);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_needs_something_to_scan() {
+    let err = run::run(&RunConfig::default()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}